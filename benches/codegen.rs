@@ -0,0 +1,125 @@
+/*
+This module contains the benchmarks of the bytecode encoder, the IR processing and the whole
+compilation of LKQL sources
+*/
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use lkql_jit::errors::LKQLError;
+use lkql_jit::lkqlc::bc::{Program, GGET, ISEQS, JMP, KPRI, KSHORT, KSTR, MOV, RET0};
+use lkql_jit::lkqlc::builder::ProgramBuilder;
+use lkql_jit::lkqlc::ir::{process_ir, IRArg, IRInstABC, IRInstAD, IRInstruction, Primitive, OPT_LEVEL_SAFE};
+
+/// Number of conditional blocks in the large synthetic programs
+const BLOCK_COUNT: usize = 2000;
+
+
+// --- Synthetic programs
+
+/// Get the result value, the benchmarked inputs are all valid
+fn valid<T>(res: Result<T, LKQLError>) -> T {
+    match res {
+        Err(e) => panic!("Unexpected error in a benchmark : {}", e),
+        Ok(value) => value
+    }
+}
+
+/// Build the program that loads a global and returns
+fn small_program() -> Program {
+    let mut builder = ProgramBuilder::new();
+    let print_index = builder.add_string("print");
+    builder.set_frame_size(2)
+        .emit_ad(GGET, 0, print_index)
+        .emit_ad(RET0, 0, 1);
+    valid(builder.finish())
+}
+
+/// Build a program with one string constant and one forward jump per block
+fn large_program() -> Program {
+    let mut builder = ProgramBuilder::new();
+    builder.set_frame_size(3);
+    for i in 0..BLOCK_COUNT {
+        let string_index = builder.add_string(&format!("string_{}", i));
+        builder.emit_ad(KSTR, 0, string_index)
+            .emit_ad(ISEQS, 0, string_index)
+            .emit_ad(JMP, 3, 0x8001)
+            .emit_ad(KSHORT, 1, i as u16);
+    }
+    builder.emit_ad(RET0, 0, 1);
+    valid(builder.finish())
+}
+
+/// Build an IR with labelled jumps, nil clears to merge and self moves to remove, the
+/// string operands are not checked by the processing
+fn large_ir() -> Vec<IRInstruction> {
+    let mut ir = Vec::new();
+    for i in 0..BLOCK_COUNT {
+        let label = i as u64;
+        ir.push(IRInstruction::AD(IRInstAD::new(KSTR, IRArg::Slot(0), IRArg::Str(i as u16))));
+        ir.push(IRInstruction::AD(IRInstAD::new(ISEQS, IRArg::Slot(0), IRArg::Str(i as u16))));
+        ir.push(IRInstruction::AD(IRInstAD::new(JMP, IRArg::Slot(4), IRArg::Jump(label))));
+        ir.push(IRInstruction::AD(IRInstAD::new(KPRI, IRArg::Slot(1), IRArg::Primitive(Primitive::Nil))));
+        ir.push(IRInstruction::AD(IRInstAD::new(KPRI, IRArg::Slot(2), IRArg::Primitive(Primitive::Nil))));
+        ir.push(IRInstruction::AD(IRInstAD::new(MOV, IRArg::Slot(3), IRArg::Slot(3))));
+        let mut target = IRInstruction::ABC(IRInstABC::new(MOV, IRArg::Slot(3), IRArg::Slot(0), IRArg::None));
+        target.add_label(label);
+        ir.push(target);
+    }
+    ir.push(IRInstruction::AD(IRInstAD::new(RET0, IRArg::Slot(0), IRArg::Literal(1))));
+    ir
+}
+
+/// Generate a script with many functions made of string comparisons
+#[cfg(not(feature = "bytecode-only"))]
+fn large_script() -> String {
+    let mut res = String::new();
+    for i in 0..100 {
+        res.push_str(&format!("fun check_{}(x) =", i));
+        for j in 0..10 {
+            res.push_str(&format!(" if x == \"string_{}_{}\" then {} else", i, j, j));
+        }
+        res.push_str(" -1\n");
+        res.push_str(&format!("assert(check_{}(\"string_{}_9\") == 9)\n", i, i));
+    }
+    res
+}
+
+
+// --- Benchmarks
+
+fn bench_encode(c: &mut Criterion) {
+    let small = small_program();
+    let large = large_program();
+    c.bench_function("encode small program", |b| b.iter(|| black_box(&small).encode()));
+    c.bench_function("encode large program", |b| b.iter(|| black_box(&large).encode()));
+}
+
+fn bench_process_ir(c: &mut Criterion) {
+    c.bench_function("process large IR", |b| b.iter_batched(
+        large_ir,
+        |mut ir| valid(process_ir(&mut ir, OPT_LEVEL_SAFE)),
+        BatchSize::SmallInput
+    ));
+}
+
+#[cfg(not(feature = "bytecode-only"))]
+fn bench_compile(c: &mut Criterion) {
+    use lkql_jit::lkqlc::compile_lkql_buffer;
+    use lkql_jit::lkqlc::env::CompilationOptions;
+
+    let options = CompilationOptions::new();
+    let small = "fun double(x) = x * 2\nassert(double(21) == 42)";
+    let large = large_script();
+    c.bench_function("compile small script", |b| b.iter(|| {
+        valid(compile_lkql_buffer(black_box(small), "small.lkql", &options))
+    }));
+    c.bench_function("compile large script", |b| b.iter(|| {
+        valid(compile_lkql_buffer(black_box(&large), "large.lkql", &options))
+    }));
+}
+
+// The compilation of LKQL sources needs langkit
+#[cfg(feature = "bytecode-only")]
+fn bench_compile(_c: &mut Criterion) {}
+
+criterion_group!(benches, bench_encode, bench_process_ir, bench_compile);
+criterion_main!(benches);
@@ -6,6 +6,11 @@ use std::process::Command;
 const PATH_TO_LKQL_LIB_DIR: &str = "/home/guerrier/Documents/AdaCore/langkit-query-language/lkql/build/lib/relocatable/prod";
 
 fn main() {
+    // Nothing to build or link for the bytecode layer alone
+    if env::var("CARGO_FEATURE_BYTECODE_ONLY").is_ok() {
+        return;
+    }
+
     // Make the lua jit library
     Command::new("make")
         .arg("-C")
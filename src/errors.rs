@@ -2,22 +2,168 @@
 This module contains all error types for the LKQL compilation and execution
 */
 
-use crate::lkql_wrapper::lkql_base_entity;
+use std::fmt;
+
+
+// --- The structure to represents a location in the LKQL sources
+
+#[derive(Debug, Copy, Clone)]
+pub struct SourceLocation {
+    pub line: u32,
+    pub column: u16
+}
+
+
+// --- The diagnostic codes, they are stable identifiers for the tools
+
+pub const E_UNKNOWN_NODE: &str = "E-UNKNOWN-NODE";
+pub const E_UNDECLARED_VAR: &str = "E-UNDECLARED-VAR";
+pub const E_INVALID_LITERAL: &str = "E-INVALID-LITERAL";
+pub const E_UNKNOWN_OPERATOR: &str = "E-UNKNOWN-OPERATOR";
+pub const E_UNSUPPORTED: &str = "E-UNSUPPORTED";
+pub const E_TOO_COMPLEX: &str = "E-TOO-COMPLEX";
+pub const E_REDECLARATION: &str = "E-REDECLARATION";
+pub const E_INVALID_BYTECODE: &str = "E-INVALID-BYTECODE";
+pub const E_LANGKIT: &str = "E-LANGKIT";
+
+pub const W_REDECLARATION: &str = "W-REDECLARATION";
 
 
 // --- The structure to represents an error in LKQL
 
 pub struct LKQLError {
-    pub message: String
+    pub code: &'static str,
+    pub message: String,
+    pub location: Option<SourceLocation>
 }
 
 impl LKQLError {
     // --- Creation methods ---
 
-    /// Create a new exception just with its message
-    pub fn new(message: String) -> LKQLError {
+    /// Create a new exception just with its code and message
+    pub fn new(code: &'static str, message: String) -> LKQLError {
+        LKQLError {
+            code,
+            message,
+            location: None
+        }
+    }
+
+    /// Create a new exception with its code, message and the location of the faulty node
+    pub fn new_located(code: &'static str, message: String, location: SourceLocation) -> LKQLError {
         LKQLError {
-            message
+            code,
+            message,
+            location: Some(location)
+        }
+    }
+}
+
+impl fmt::Display for LKQLError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}:{}: {} [{}]", location.line, location.column, self.message, self.code),
+            None => write!(f, "{} [{}]", self.message, self.code)
+        }
+    }
+}
+
+
+// --- The structure to represents a non fatal diagnostic of the compilation
+
+#[derive(Debug, Clone)]
+pub struct LKQLWarning {
+    pub code: &'static str,
+    pub message: String,
+    pub location: Option<SourceLocation>
+}
+
+impl LKQLWarning {
+    /// Create a new warning with its code, message and the location of the concerned node
+    pub fn new_located(code: &'static str, message: String, location: SourceLocation) -> LKQLWarning {
+        LKQLWarning {
+            code,
+            message,
+            location: Some(location)
         }
     }
-}
\ No newline at end of file
+}
+
+impl fmt::Display for LKQLWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}:{}: warning: {} [{}]", location.line, location.column, self.message, self.code),
+            None => write!(f, "warning: {} [{}]", self.message, self.code)
+        }
+    }
+}
+
+
+// --- JSON formatting of the diagnostics
+
+/// Format the diagnostics of a compiled file as a JSON array of objects with the file, line,
+/// column, severity, code and message fields, the line and column are null for an unlocated one
+pub fn diagnostics_to_json(file: &str, warnings: &Vec<LKQLWarning>, error: Option<&LKQLError>) -> String {
+    let mut objects = Vec::new();
+    for warning in warnings {
+        objects.push(json_diagnostic(file, &warning.location, "warning", warning.code, &warning.message));
+    }
+    if let Some(error) = error {
+        objects.push(json_diagnostic(file, &error.location, "error", error.code, &error.message));
+    }
+    format!("[{}]", objects.join(","))
+}
+
+/// Format one diagnostic as a JSON object
+fn json_diagnostic(file: &str, location: &Option<SourceLocation>, severity: &str, code: &str, message: &str) -> String {
+    let (line, column) = match location {
+        Some(location) => (location.line.to_string(), location.column.to_string()),
+        None => (String::from("null"), String::from("null"))
+    };
+    format!(
+        "{{\"file\":{},\"line\":{},\"column\":{},\"severity\":{},\"code\":{},\"message\":{}}}",
+        json_string(file), line, column, json_string(severity), json_string(code), json_string(message)
+    )
+}
+
+/// Format the string as a JSON string literal
+fn json_string(string: &str) -> String {
+    let mut res = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c)
+        }
+    }
+    res.push('"');
+    res
+}
+
+
+// --- Tests of the diagnostics
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The warnings come before the error, the strings are escaped and the unlocated
+    /// diagnostics have null positions
+    #[test]
+    fn json_diagnostics() {
+        let warnings = vec![
+            LKQLWarning::new_located(W_REDECLARATION, String::from("x is \"declared\""), SourceLocation { line: 2, column: 5 })
+        ];
+        let error = LKQLError::new(E_TOO_COMPLEX, String::from("Too deep\n"));
+        assert_eq!(
+            diagnostics_to_json("dir\\test.lkql", &warnings, Some(&error)),
+            "[{\"file\":\"dir\\\\test.lkql\",\"line\":2,\"column\":5,\"severity\":\"warning\",\"code\":\"W-REDECLARATION\",\"message\":\"x is \\\"declared\\\"\"},\
+             {\"file\":\"dir\\\\test.lkql\",\"line\":null,\"column\":null,\"severity\":\"error\",\"code\":\"E-TOO-COMPLEX\",\"message\":\"Too deep\\n\"}]"
+        );
+        assert_eq!(diagnostics_to_json("test.lkql", &Vec::new(), None), "[]");
+    }
+}
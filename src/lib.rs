@@ -0,0 +1,17 @@
+/*
+This module is the library root of the project.
+It contains the compiler, the runtime and the test modules, the executable defined in "main.rs"
+and the benchmarks are built on it
+*/
+
+pub mod trace;
+#[cfg(not(feature = "bytecode-only"))]
+pub mod luajit;
+#[cfg(not(feature = "bytecode-only"))]
+pub mod lkql_wrapper;
+pub mod lkqlc;
+pub mod errors;
+#[cfg(not(feature = "bytecode-only"))]
+pub mod self_test;
+#[cfg(not(feature = "bytecode-only"))]
+pub mod repl;
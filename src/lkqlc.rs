@@ -3,188 +3,551 @@ This module contains all functions to compile LKQL sources to luajit bytecode
 */
 
 pub mod env;
+pub mod emitter;
 pub mod bc;
 pub mod builtins;
 pub mod ir;
+pub mod builder;
+#[cfg(not(feature = "bytecode-only"))]
+pub mod node;
+#[cfg(any(test, feature = "testing"))]
+pub mod asm;
+#[cfg(not(feature = "bytecode-only"))]
 pub mod nodes;
-
-use std::ffi::CString;
-use std::os::raw::c_uint;
-use std::path::PathBuf;
-use std::ptr::{null, null_mut};
-use widestring::U32String;
-use crate::Cli;
-use crate::errors::LKQLError;
-use crate::lkql_wrapper::*;
-use crate::lkqlc::bc::{GGET, KPRI, KSTR, MOV, UGET};
-use crate::lkqlc::env::{CompilationEnv, LocalResult, UpvalueResult};
-use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction, Primitive};
+#[cfg(not(feature = "bytecode-only"))]
+pub mod lua;
+#[cfg(all(test, not(feature = "bytecode-only")))]
+pub mod test_utils;
+
+// The compilation of LKQL sources needs langkit, the bytecode layer is usable without it
+#[cfg(not(feature = "bytecode-only"))]
+use {
+    std::collections::HashMap,
+    std::ffi::CString,
+    std::os::raw::c_char,
+    std::path::PathBuf,
+    std::ptr::{null, null_mut},
+    crate::errors::{E_LANGKIT, E_REDECLARATION, E_TOO_COMPLEX, E_UNDECLARED_VAR, E_UNKNOWN_NODE, LKQLError, LKQLWarning, W_REDECLARATION},
+    crate::lkql_wrapper::*,
+    crate::lkqlc::bc::{KSTR, MOV, UGET},
+    crate::lkqlc::builtins::{cached_builtin_name, BUILTINS},
+    crate::lkqlc::env::{CompilationEnv, CompilationOptions, LocalResult, UpvalueResult},
+    crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction},
+    crate::lkqlc::node::Node,
+    crate::lkqlc::nodes::fun_call::frame_error,
+};
 
 
 // --- Entry points of the compiler
 
-/// Compile the given buffer in the appropriate bytecode
-pub fn compile_lkql_buffer(buffer: &str, name: &str) -> Vec<u8> {
-    let env = CompilationEnv::new();
-    // TODO : Add the LKQL buffer compilation
-    env.get_bytecode()
+/// Compile the given buffer in the appropriate bytecode, the name is the one of the source in
+/// the diagnostics
+#[cfg(not(feature = "bytecode-only"))]
+pub fn compile_lkql_buffer(buffer: &str, name: &str, options: &CompilationOptions) -> Result<Vec<u8>, LKQLError> {
+    let mut env = CompilationEnv::new(options.clone());
+    compile_lkql_buffer_with_env(buffer, name, &mut env)
+}
+
+/// Compile the given buffer to LuaJIT bytecode reusing the given compilation environment
+/// The environment is reset before the compilation so nothing leaks from a previous one
+#[cfg(not(feature = "bytecode-only"))]
+pub fn compile_lkql_buffer_with_env(buffer: &str, name: &str, env: &mut CompilationEnv) -> Result<Vec<u8>, LKQLError> {
+    env.reset();
+    unsafe {
+        match parse_lkql_buffer(buffer, name, lkql_grammar_rule_LKQL_GRAMMAR_RULE_MAIN_RULE_RULE) {
+            Err(e) => Err(e),
+            Ok(unit) => compile_unit(unit, env)
+        }
+    }
 }
 
 /// Open and compile the given file to LuaJIT bytecode
-pub fn compile_lkql_file(file: &PathBuf, charset: &Option<String>) -> Result<Vec<u8>, LKQLError> {
+#[cfg(not(feature = "bytecode-only"))]
+pub fn compile_lkql_file(file: &PathBuf, charset: &Option<String>, options: &CompilationOptions) -> Result<Vec<u8>, LKQLError> {
+    let mut env = CompilationEnv::new(options.clone());
+    compile_lkql_file_with_env(file, charset, &mut env)
+}
+
+/// Open and compile the given file to LuaJIT bytecode reusing the given compilation environment
+/// The environment is reset before the compilation so nothing leaks from a previous one
+#[cfg(not(feature = "bytecode-only"))]
+pub fn compile_lkql_file_with_env(file: &PathBuf, charset: &Option<String>, env: &mut CompilationEnv) -> Result<Vec<u8>, LKQLError> {
+    env.reset();
+    env.set_chunk_name(chunk_name(file));
     unsafe {
-        // Create the lkql context
-        let ctx = lkql_create_analysis_context(
-            null(),
-            null_mut(),
-            null_mut(),
-            null_mut(),
-            1,
-            8
-        );
-
-        // Get the LKQL script and the charset
-        let file_path_c = CString::new(
-            file
-                .canonicalize()
-                .unwrap()
-                .to_str()
-                .unwrap()
-        ).unwrap();
-
-        let charset_c = CString::new(
-            charset.as_ref().unwrap_or(&String::from("NULL")).as_str()
-        ).unwrap();
-
-        // Create the analysis unit from the LKQL file
-        let unit = lkql_get_analysis_unit_from_file(
-            ctx,
-            file_path_c.as_ptr(),
-            (if charset.is_none() {null()} else {charset_c.as_ptr()}),
-            0,
-            lkql_grammar_rule_LKQL_GRAMMAR_RULE_MAIN_RULE_RULE
-        );
-
-        // Get the unit root node
-        let mut root = new_node();
-        lkql_unit_root(unit, &mut root);
-
-        // Compile the LKQL AST
-        let mut env = CompilationEnv::new();
-        match compile_node(&mut root, &mut env) {
+        match parse_lkql_file(file, charset) {
+            Err(e) => Err(e),
+            Ok(unit) => compile_unit(unit, env)
+        }
+    }
+}
+
+/// Open the given file and emit its Lua source instead of the bytecode
+#[cfg(not(feature = "bytecode-only"))]
+pub fn emit_lua_file(file: &PathBuf, charset: &Option<String>) -> Result<String, LKQLError> {
+    let root = match unsafe { parse_lkql_file(file, charset).and_then(|unit| unit_root(unit)) } {
+        Err(e) => { return Err(e); }
+        Ok(root) => root
+    };
+    lua::LuaEmitter::new().emit_unit(&root)
+}
+
+/// Emit the Lua source of the given buffer
+#[cfg(not(feature = "bytecode-only"))]
+pub fn emit_lua_buffer(buffer: &str, name: &str) -> Result<String, LKQLError> {
+    match parse_lkql_buffer_root(buffer, name) {
+        Err(e) => Err(e),
+        Ok(root) => lua::LuaEmitter::new().emit_unit(&root)
+    }
+}
+
+/// Get the chunk name of a script file : its absolute path prefixed by "@", so LuaJIT shows it
+/// as a file name in the error messages. A path which is not valid UTF-8 is shown with
+/// replacement characters instead of failing
+pub fn chunk_name(file: &std::path::Path) -> String {
+    let absolute = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+    format!("@{}", absolute.to_string_lossy())
+}
+
+/// Parse the given LKQL file and return the textual tree of its nodes, to see what the compiler
+/// receives
+#[cfg(not(feature = "bytecode-only"))]
+pub fn dump_lkql_file_ast(file: &PathBuf, charset: &Option<String>) -> Result<String, LKQLError> {
+    unsafe { parse_lkql_file(file, charset).map(|unit| dump_unit(unit)) }
+}
+
+/// Parse the given buffer and return the textual tree of its nodes
+#[cfg(not(feature = "bytecode-only"))]
+pub fn dump_lkql_buffer_ast(buffer: &str, name: &str) -> Result<String, LKQLError> {
+    unsafe { parse_lkql_buffer(buffer, name, lkql_grammar_rule_LKQL_GRAMMAR_RULE_MAIN_RULE_RULE).map(|unit| dump_unit(unit)) }
+}
+
+/// Parse the given buffer and return the root node of its unit
+#[cfg(not(feature = "bytecode-only"))]
+pub fn parse_lkql_buffer_root(buffer: &str, name: &str) -> Result<Node, LKQLError> {
+    unsafe { parse_lkql_buffer(buffer, name, lkql_grammar_rule_LKQL_GRAMMAR_RULE_MAIN_RULE_RULE).and_then(|unit| unit_root(unit)) }
+}
+
+/// Create the analysis unit of the LKQL buffer parsed with the given grammar rule, it is
+/// always UTF-8
+#[cfg(not(feature = "bytecode-only"))]
+unsafe fn parse_lkql_buffer(buffer: &str, name: &str, rule: lkql_grammar_rule) -> Result<lkql_analysis_unit, LKQLError> {
+    match new_analysis_context() {
+        Err(e) => Err(e),
+        Ok(ctx) => parse_lkql_buffer_in(ctx, buffer, name, rule)
+    }
+}
+
+/// Create the analysis unit of the LKQL buffer in an existing context, the unit lives as long
+/// as the context and replaces a previous one with the same name
+#[cfg(not(feature = "bytecode-only"))]
+unsafe fn parse_lkql_buffer_in(ctx: lkql_analysis_context, buffer: &str, name: &str, rule: lkql_grammar_rule) -> Result<lkql_analysis_unit, LKQLError> {
+    let name_c = CString::new(name).unwrap();
+    let charset_c = CString::new("utf-8").unwrap();
+    check_unit(lkql_get_analysis_unit_from_buffer(
+        ctx,
+        name_c.as_ptr(),
+        charset_c.as_ptr(),
+        buffer.as_ptr() as *const c_char,
+        buffer.len(),
+        rule
+    ))
+}
+
+/// Create the analysis unit of the LKQL file
+#[cfg(not(feature = "bytecode-only"))]
+unsafe fn parse_lkql_file(file: &PathBuf, charset: &Option<String>) -> Result<lkql_analysis_unit, LKQLError> {
+    // Create the lkql context
+    let ctx = match new_analysis_context() {
+        Err(e) => { return Err(e); }
+        Ok(ctx) => ctx
+    };
+
+    // Get the LKQL script and the charset
+    let file_path_c = CString::new(
+        file
+            .canonicalize()
+            .unwrap()
+            .to_str()
+            .unwrap()
+    ).unwrap();
+
+    let charset_c = CString::new(
+        charset.as_ref().unwrap_or(&String::from("NULL")).as_str()
+    ).unwrap();
+
+    // Create the analysis unit from the LKQL file
+    check_unit(lkql_get_analysis_unit_from_file(
+        ctx,
+        file_path_c.as_ptr(),
+        (if charset.is_none() {null()} else {charset_c.as_ptr()}),
+        0,
+        lkql_grammar_rule_LKQL_GRAMMAR_RULE_MAIN_RULE_RULE
+    ))
+}
+
+/// Get the textual tree of the unit nodes, one node per line
+#[cfg(not(feature = "bytecode-only"))]
+unsafe fn dump_unit(unit: lkql_analysis_unit) -> String {
+    let mut res = String::new();
+    dump_node(&Node::unit_root(unit), 0, &mut res);
+    res
+}
+
+/// Append the line of the node and the ones of its children to the dump, indented by their
+/// depth, the leaves also show their text
+#[cfg(not(feature = "bytecode-only"))]
+fn dump_node(node: &Node, depth: usize, res: &mut String) {
+    let indent = "  ".repeat(depth);
+    if node.is_null() {
+        res.push_str(&format!("{}<null>\n", indent));
+        return;
+    }
+
+    let children = node.children();
+    if children.is_empty() {
+        res.push_str(&format!("{}{} {:?}\n", indent, node.kind_name(), node.text()));
+        return;
+    }
+    res.push_str(&format!("{}{}\n", indent, node.kind_name()));
+    for child in &children {
+        dump_node(child, depth + 1, res);
+    }
+}
+
+/// Create the LKQL analysis context to parse the scripts
+#[cfg(not(feature = "bytecode-only"))]
+pub unsafe fn new_analysis_context() -> Result<lkql_analysis_context, LKQLError> {
+    check_context(lkql_create_analysis_context(
+        null(),
+        null_mut(),
+        null_mut(),
+        null_mut(),
+        1,
+        8
+    ))
+}
+
+/// Verify the context returned by langkit, it is null if its creation failed
+#[cfg(not(feature = "bytecode-only"))]
+pub fn check_context(ctx: lkql_analysis_context) -> Result<lkql_analysis_context, LKQLError> {
+    if ctx.is_null() {
+        return Err(LKQLError::new(E_LANGKIT, String::from("Failed to create the analysis context")));
+    }
+    Ok(ctx)
+}
+
+/// Verify the unit returned by langkit, it is null if the source could not be read
+#[cfg(not(feature = "bytecode-only"))]
+pub fn check_unit(unit: lkql_analysis_unit) -> Result<lkql_analysis_unit, LKQLError> {
+    if unit.is_null() {
+        return Err(LKQLError::new(E_LANGKIT, String::from("Failed to create the analysis unit")));
+    }
+    Ok(unit)
+}
+
+/// Get the root node of the unit to compile, a null unit or root is an error instead of a
+/// crash in the traversal
+#[cfg(not(feature = "bytecode-only"))]
+pub unsafe fn unit_root(unit: lkql_analysis_unit) -> Result<Node, LKQLError> {
+    let root = match check_unit(unit) {
+        Err(e) => { return Err(e); }
+        Ok(unit) => Node::unit_root(unit)
+    };
+    if root.is_null() {
+        return Err(LKQLError::new(E_LANGKIT, String::from("The analysis unit has no root node")));
+    }
+    Ok(root)
+}
+
+/// Compile the root of the analysis unit and return the bytecode
+#[cfg(not(feature = "bytecode-only"))]
+unsafe fn compile_unit(unit: lkql_analysis_unit, env: &mut CompilationEnv) -> Result<Vec<u8>, LKQLError> {
+    // Compile the LKQL AST, then the call of the entry point if any
+    let root = match unit_root(unit) {
+        Err(e) => { return Err(e); }
+        Ok(root) => root
+    };
+    let mut builtin_reads = HashMap::new();
+    count_builtin_reads(&root, &mut builtin_reads);
+    env.init_builtins_cache(&builtin_reads);
+    match compile_node(&root, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+    if let Some(entry_point) = env.get_entry_point() {
+        match compile_entry_point_call(&entry_point, env) {
             Err(e) => { return Err(e); }
             Ok(_) => {}
         }
-        env.close_env();
+    }
+    match env.close_env() {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+
+    // Return the bytecode for the LKQL file
+    Ok(env.get_bytecode())
+}
+
+/// Count the identifiers of the tree named like a builtin, the declarations with the name of a
+/// builtin are counted too but a builtin redefined by the script is not cached anyway
+#[cfg(not(feature = "bytecode-only"))]
+fn count_builtin_reads(node: &Node, counts: &mut HashMap<String, usize>) {
+    if node.is_null() {
+        return;
+    }
+    if node.kind() == lkql_node_kind_enum_lkql_identifier {
+        let name = node.text();
+        if BUILTINS.iter().any(|builtin| builtin.name == name) {
+            *counts.entry(name).or_insert(0) += 1;
+        }
+        return;
+    }
+    for child in node.children() {
+        count_builtin_reads(&child, counts);
+    }
+}
 
-        // Return the bytecode for the LKQL file
-        Ok(env.get_bytecode())
+/// Call the entry point function without arguments at the end of the main chunk, the chunk
+/// returns its result
+#[cfg(not(feature = "bytecode-only"))]
+fn compile_entry_point_call(name: &str, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let frame = match env.open_call_frame(0) {
+        Some(frame) => frame,
+        None => {
+            return Err(LKQLError::new(E_TOO_COMPLEX, String::from("No slot left to call the entry point")));
+        }
+    };
+
+    // Load the function in the base slot of the call frame
+    let res_slot = env.get_expr_slot();
+    env.set_expr_slot(Some(frame.base));
+    let is_loaded = load_var_copy(name, env);
+    env.set_expr_slot(res_slot);
+    if is_loaded.is_none() {
+        return Err(LKQLError::new(E_TOO_COMPLEX, String::from("No slot left to call the entry point")));
+    }
+    if is_loaded == Some(false) {
+        return Err(LKQLError::new(E_UNDECLARED_VAR, format!("Unknown entry point : {}", name)));
     }
+
+    // Call it for one result, which is returned by the chunk
+    env.emit_call(&frame, 2, false);
+    env.set_return_slot(Some(frame.base));
+    env.close_call_frame(frame);
+    Ok(())
 }
 
 
 // --- The BIG dispatching function
 
 /// Dispatch the node compilation
-unsafe fn compile_node(node: &mut lkql_base_entity, env: &mut CompilationEnv) -> Result<(), LKQLError> {
-    let kind = lkql_node_kind(node);
-    match kind {
+#[cfg(not(feature = "bytecode-only"))]
+fn compile_node(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Guard the native stack against the too deeply nested nodes
+    if !env.enter_node() {
+        return Err(LKQLError::new_located(
+            E_TOO_COMPLEX,
+            String::from("Maximum nesting depth exceeded, simplify the expression"),
+            node.location()
+        ));
+    }
+
+    let res = match node.kind() {
         // -- Top level node
         lkql_node_kind_enum_lkql_top_level_list => nodes::top_level_list::compile(node, env),
 
         // -- Expressions
         lkql_node_kind_enum_lkql_fun_call => nodes::fun_call::compile(node, env),
+        lkql_node_kind_enum_lkql_bin_op => nodes::bin_op::compile(node, env),
+        lkql_node_kind_enum_lkql_arith_bin_op => nodes::arith_bin_op::compile(node, env),
+        lkql_node_kind_enum_lkql_rel_bin_op => nodes::rel_bin_op::compile(node, env),
+        lkql_node_kind_enum_lkql_in_clause => nodes::in_clause::compile(node, env),
+        lkql_node_kind_enum_lkql_un_op => nodes::un_op::compile(node, env),
+        lkql_node_kind_enum_lkql_block_expr => nodes::block_expr::compile(node, env),
+        lkql_node_kind_enum_lkql_if_then_else => nodes::if_then_else::compile(node, env),
+        lkql_node_kind_enum_lkql_anonymous_function => nodes::lambda::compile(node, env),
+        lkql_node_kind_enum_lkql_query => nodes::query::compile(node, env),
+        lkql_node_kind_enum_lkql_paren_expr => nodes::paren_expr::compile(node, env),
+
+        // -- Declarations
+        lkql_node_kind_enum_lkql_val_decl => nodes::val_decl::compile(node, env),
+        lkql_node_kind_enum_lkql_fun_decl => nodes::fun_decl::compile(node, env),
+        lkql_node_kind_enum_lkql_identifier => nodes::identifier::compile(node, env),
+        lkql_node_kind_enum_lkql_decl_annotation => nodes::decl_annotation::compile(node, env),
 
         // -- Literals
-        lkql_node_kind_enum_lkql_bool_literal_true => nodes::bool_literal::compile_true(node, env),
-        lkql_node_kind_enum_lkql_bool_literal_false => nodes::bool_literal::compile_false(node, env),
+        lkql_node_kind_enum_lkql_bool_literal_true => nodes::bool_literal::compile_true(env),
+        lkql_node_kind_enum_lkql_bool_literal_false => nodes::bool_literal::compile_false(env),
         lkql_node_kind_enum_lkql_integer_literal => nodes::integer_literal::compile(node, env),
+        lkql_node_kind_enum_lkql_decimal_literal => nodes::decimal_literal::compile(node, env),
         lkql_node_kind_enum_lkql_string_literal => nodes::string_literal::compile(node, env),
-
-        // -- Default result is an error
-        _ => panic!("Unknown node kind ({}), cannot proceed to compilation", kind)
-    }
+        lkql_node_kind_enum_lkql_null_literal => nodes::null_literal::compile(node, env),
+        lkql_node_kind_enum_lkql_unit_literal => nodes::unit_literal::compile(node, env),
+        lkql_node_kind_enum_lkql_list_literal => nodes::list_literal::compile(node, env),
+
+        // -- Default result is an error, or a record of the node kind in report mode
+        _ if env.is_reporting_unsupported() => {
+            record_unsupported(node, env);
+            Ok(())
+        }
+        _ => Err(LKQLError::new_located(
+            E_UNKNOWN_NODE,
+            format!("Unknown node kind ({}), cannot proceed to compilation", node.kind_name()),
+            node.location()
+        ))
+    };
+
+    env.exit_node();
+    res
 }
 
 
 // --- Utils functions
 
-/// Create a new entity structure
-fn new_node() -> lkql_base_entity {
-    lkql_base_entity {
-        node: null_mut(),
-        info: lkql_internal_entity_info {
-            md: lkql_internal_metadata {},
-            rebindings: null_mut(),
-            from_rebound: 0
-        }
+/// Record the node kind as unsupported and continue the compilation with a null value in place
+/// of the node, its children are not visited since their meaning depends on the handler
+#[cfg(not(feature = "bytecode-only"))]
+fn record_unsupported(node: &Node, env: &mut CompilationEnv) {
+    env.add_unsupported_node(node.kind_name());
+    match env.get_expr_slot() {
+        Some(slot) => env.emit_load_nil(slot, 1),
+        None => ()
     }
 }
 
-/// Get the text of a node
-unsafe fn node_text(node: &mut lkql_base_entity) -> String {
-    let mut text = new_text();
-    lkql_node_text(node, &mut text);
-    text_to_string(&text)
+/// Compile the expression node in a new temporary slot
+/// Return the temporary slot to free after use and the slot that holds the expression value
+#[cfg(not(feature = "bytecode-only"))]
+fn compile_in_tmp(node: &Node, env: &mut CompilationEnv) -> Result<(u8, u8), LKQLError> {
+    let res_slot = env.get_expr_slot();
+    let tmp = match env.new_tmp() {
+        Some(slot) => slot,
+        None => { return Err(frame_error(node)); }
+    };
+
+    // Compile the expression, a local variable is read in place
+    env.set_expr_slot(Some(tmp));
+    match compile_node(node, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+    let value_slot = env.get_expr_slot().unwrap();
+
+    // Restore the expression slot
+    env.set_expr_slot(res_slot);
+    Ok((tmp, value_slot))
 }
 
-/// Test if the given node is a constant
-unsafe fn node_is_literal(node: &mut lkql_base_entity) -> bool {
-    let kind = lkql_node_kind(node);
-    match kind {
-        lkql_node_kind_enum_lkql_null_literal |
-        lkql_node_kind_enum_lkql_unit_literal |
-        lkql_node_kind_enum_lkql_bool_literal_true |
-        lkql_node_kind_enum_lkql_bool_literal_false |
-        lkql_node_kind_enum_lkql_integer_literal |
-        lkql_node_kind_enum_lkql_string_literal |
-        lkql_node_kind_enum_lkql_list_literal |
-        lkql_node_kind_enum_lkql_object_literal |
-        lkql_node_kind_enum_lkql_block_string_literal => true,
-        _ => false
+/// Compile the expression node with its value placed in the given slot, even if it is a local
+/// variable read in place
+#[cfg(not(feature = "bytecode-only"))]
+fn compile_in_slot(node: &Node, slot: Option<u8>, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let res_slot = env.get_expr_slot();
+    env.set_expr_slot(slot);
+    match compile_node(node, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+
+    // Copy the value if it is in another slot
+    if slot.is_some() && env.get_expr_slot() != slot {
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(
+            MOV,
+            IRArg::Slot(slot.unwrap()),
+            IRArg::Slot(env.get_expr_slot().unwrap())
+        )));
     }
+
+    env.set_expr_slot(res_slot);
+    Ok(())
 }
 
-/// Create a new text structure
-fn new_text() -> lkql_text {
-    lkql_text {
-        chars: null_mut(),
-        length: 0,
-        is_allocated: 0
+/// Compile the operand of an expression in the expression slot if there is one, else in a new
+/// temporary slot. The expression slot is not read before the expression result is written
+/// in it, so a single use operand can be computed directly in it
+/// Return the temporary slot to free after use if any and the slot that holds the operand value
+#[cfg(not(feature = "bytecode-only"))]
+fn compile_in_target(node: &Node, env: &mut CompilationEnv) -> Result<(Option<u8>, u8), LKQLError> {
+    let res_slot = env.get_expr_slot();
+    if res_slot.is_none() {
+        return match compile_in_tmp(node, env) {
+            Err(e) => Err(e),
+            Ok((tmp, value_slot)) => Ok((Some(tmp), value_slot))
+        };
+    }
+
+    // Compile the operand in the expression slot, a local variable is read in place
+    match compile_node(node, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
     }
+    let value_slot = env.get_expr_slot().unwrap();
+    env.set_expr_slot(res_slot);
+    Ok((None, value_slot))
 }
 
-/// Translate an LKQL text to a string
-unsafe fn text_to_string(text: &lkql_text) -> String {
-    let decoded = U32String::from_ptr(text.chars, text.length as usize);
-    decoded.to_string().expect("Cannot decode the UTF-32 string")
+/// Compile the node as a condition which jumps to the true label or to the false label
+/// Comparisons and boolean operations are fused with the branch instead of materializing a
+/// boolean value to test
+#[cfg(not(feature = "bytecode-only"))]
+fn compile_branch(node: &Node, true_label: u64, false_label: u64, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    match node.kind() {
+        lkql_node_kind_enum_lkql_bin_op => nodes::bin_op::compile_branch(node, true_label, false_label, env),
+        lkql_node_kind_enum_lkql_rel_bin_op => nodes::rel_bin_op::compile_branch(node, true_label, false_label, env),
+        lkql_node_kind_enum_lkql_in_clause => nodes::in_clause::compile_branch(node, true_label, false_label, env),
+        lkql_node_kind_enum_lkql_un_op => nodes::un_op::compile_branch(node, true_label, false_label, env),
+        lkql_node_kind_enum_lkql_paren_expr => compile_branch(&nodes::paren_expr::inner_expr(node), true_label, false_label, env),
+        _ => compile_value_branch(node, true_label, false_label, env)
+    }
 }
 
-/// Get the node kind as a string
-unsafe fn node_kind(node: &mut lkql_base_entity) -> String {
-    let mut text = new_text();
-    lkql_kind_name(lkql_node_kind(node), &mut text);
-    text_to_string(&text)
+/// Compile the node value and branch on its truthiness
+#[cfg(not(feature = "bytecode-only"))]
+fn compile_value_branch(node: &Node, true_label: u64, false_label: u64, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let (tmp, value_slot) = match compile_in_target(node, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+    if tmp.is_some() {
+        env.free_tmp(tmp.unwrap());
+    }
+    env.emit_truthy_branch(value_slot, true_label, false_label);
+    Ok(())
+}
+
+/// Verify that the name is not already declared in the current scope, a redeclaration is an
+/// error in strict mode and a warning otherwise
+#[cfg(not(feature = "bytecode-only"))]
+fn check_redeclaration(name: &str, node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let is_declared_global = env.is_top_level() && env.is_declared_global(name);
+    if !env.is_declared_in_scope(name) && !is_declared_global {
+        return Ok(());
+    }
+
+    let message = format!("The symbol {} is already declared in this scope", name);
+    if env.is_strict() {
+        Err(LKQLError::new_located(E_REDECLARATION, message, node.location()))
+    } else {
+        env.add_warning(LKQLWarning::new_located(W_REDECLARATION, message, node.location()));
+        Ok(())
+    }
 }
 
 /// Load the needed variable in the expression slot for a read purpose
 /// If the var is already in a slot just set the expr return slot to this one
-fn load_var(name: &str, env: &mut CompilationEnv) -> bool {
+/// Return if the variable is found, none if there is no slot left to read the global
+#[cfg(not(feature = "bytecode-only"))]
+fn load_var(name: &str, env: &mut CompilationEnv) -> Option<bool> {
     // Try to get the local variable
     match env.get_local(name) {
         LocalResult::Slot(slot) => {
             env.set_expr_slot(Some(slot))
         }
         LocalResult::Name(name) => {
-            // Add the name in the constant table
-            let name_index = env.add_string_constant(String::from(name));
-
             // Add the global getting
-            env.add_instruction(IRInstruction::AD(IRInstAD::new(
-                GGET,
-                IRArg::Slot(env.get_expr_slot().unwrap()),
-                IRArg::Str(name_index)
-            )));
+            if !env.emit_global_get(env.get_expr_slot().unwrap(), &name) { return None; }
         }
         LocalResult::NotFound => {
             // Try to get the variable in the upvalues
@@ -198,31 +561,22 @@ fn load_var(name: &str, env: &mut CompilationEnv) -> bool {
                     )));
                 }
                 UpvalueResult::Name(name) => {
-                    // Add the name in the constant table
-                    let name_index = env.add_string_constant(String::from(name));
-
                     // Add the global getting
-                    env.add_instruction(IRInstruction::AD(IRInstAD::new(
-                        GGET,
-                        IRArg::Slot(env.get_expr_slot().unwrap()),
-                        IRArg::Str(name_index)
-                    )));
+                    if !env.emit_global_get(env.get_expr_slot().unwrap(), &name) { return None; }
                 }
                 UpvalueResult::NotFound => {
+                    // Read a cached builtin from its local
+                    if env.is_cached_builtin(name) {
+                        return load_var(&cached_builtin_name(name), env);
+                    }
+
                     // Try to get the variable in the global scope
                     if env.get_global(name) {
-                        // Add the name in the constant table
-                        let name_index = env.add_string_constant(String::from(name));
-
                         // Add the global getting
-                        env.add_instruction(IRInstruction::AD(IRInstAD::new(
-                            GGET,
-                            IRArg::Slot(env.get_expr_slot().unwrap()),
-                            IRArg::Str(name_index)
-                        )));
+                        if !env.emit_global_get(env.get_expr_slot().unwrap(), &name) { return None; }
                     } else {
                         // Return the failure, cannot load the variable
-                        return false;
+                        return Some(false);
                     }
                 }
             }
@@ -230,11 +584,13 @@ fn load_var(name: &str, env: &mut CompilationEnv) -> bool {
     }
 
     // Return the success
-    true
+    Some(true)
 }
 
 /// Load the needed variable in the expression slot for write purpose (always copy)
-fn load_var_copy(name: &str, env: &mut CompilationEnv) -> bool {
+/// Return if the variable is found, none if there is no slot left to read the global
+#[cfg(not(feature = "bytecode-only"))]
+fn load_var_copy(name: &str, env: &mut CompilationEnv) -> Option<bool> {
     // Try to get the local variable
     match env.get_local(name) {
         LocalResult::Slot(slot) => {
@@ -246,15 +602,8 @@ fn load_var_copy(name: &str, env: &mut CompilationEnv) -> bool {
             )));
         }
         LocalResult::Name(name) => {
-            // Add the name in the constant table
-            let name_index = env.add_string_constant(String::from(name));
-
             // Add the global getting
-            env.add_instruction(IRInstruction::AD(IRInstAD::new(
-                GGET,
-                IRArg::Slot(env.get_expr_slot().unwrap()),
-                IRArg::Str(name_index)
-            )));
+            if !env.emit_global_get(env.get_expr_slot().unwrap(), &name) { return None; }
         }
         LocalResult::NotFound => {
             // Try to get the variable in the upvalues
@@ -268,31 +617,22 @@ fn load_var_copy(name: &str, env: &mut CompilationEnv) -> bool {
                     )));
                 }
                 UpvalueResult::Name(name) => {
-                    // Add the name in the constant table
-                    let name_index = env.add_string_constant(String::from(name));
-
                     // Add the global getting
-                    env.add_instruction(IRInstruction::AD(IRInstAD::new(
-                        GGET,
-                        IRArg::Slot(env.get_expr_slot().unwrap()),
-                        IRArg::Str(name_index)
-                    )));
+                    if !env.emit_global_get(env.get_expr_slot().unwrap(), &name) { return None; }
                 }
                 UpvalueResult::NotFound => {
+                    // Read a cached builtin from its local
+                    if env.is_cached_builtin(name) {
+                        return load_var_copy(&cached_builtin_name(name), env);
+                    }
+
                     // Try to get the variable in the global scope
                     if env.get_global(name) {
-                        // Add the name in the constant table
-                        let name_index = env.add_string_constant(String::from(name));
-
                         // Add the global getting
-                        env.add_instruction(IRInstruction::AD(IRInstAD::new(
-                            GGET,
-                            IRArg::Slot(env.get_expr_slot().unwrap()),
-                            IRArg::Str(name_index)
-                        )));
+                        if !env.emit_global_get(env.get_expr_slot().unwrap(), &name) { return None; }
                     } else {
                         // Return the failure, cannot load the variable
-                        return false;
+                        return Some(false);
                     }
                 }
             }
@@ -300,5 +640,136 @@ fn load_var_copy(name: &str, env: &mut CompilationEnv) -> bool {
     }
 
     // Return the success
-    true
+    Some(true)
+}
+
+
+// --- Tests of the compiler entry points
+
+#[cfg(all(test, not(feature = "bytecode-only")))]
+mod tests {
+    use super::*;
+
+    /// The null results of a failed langkit call are errors, the compiler would crash on them
+    #[test]
+    fn langkit_failures() {
+        assert!(matches!(check_context(null_mut()), Err(e) if e.code == E_LANGKIT));
+        assert!(matches!(unsafe { unit_root(null_mut()) }, Err(e) if e.code == E_LANGKIT));
+    }
+
+    /// The dump of a small script has the expected nodes, indented under their parent
+    #[test]
+    fn ast_dump() {
+        let dump = dump_lkql_buffer_ast("val x = 1 + 2", "AST dump").unwrap_or_else(|e| panic!("{}", e));
+        let lines: Vec<&str> = dump.lines().collect();
+        let mut indent = 0;
+        for kind in ["ValDecl", "ArithBinOp", "IntegerLiteral \"1\"", "IntegerLiteral \"2\""] {
+            let line = lines.iter().find(|line| line.trim_start().starts_with(kind))
+                .unwrap_or_else(|| panic!("No {} node in the dump :\n{}", kind, dump));
+            let line_indent = line.len() - line.trim_start().len();
+            assert!(line_indent >= indent, "The {} node is not nested in its parent :\n{}", kind, dump);
+            indent = line_indent;
+        }
+    }
+
+    /// The same script runs at all the optimization levels, the aggressive one caching the
+    /// builtins in the main chunk makes a different program
+    #[test]
+    fn optimization_levels() {
+        use crate::lkqlc::ir::{OPT_LEVEL_AGGRESSIVE, OPT_LEVEL_NONE, OPT_LEVEL_SAFE};
+        use crate::lkqlc::test_utils::{compile_with, run, test_options};
+
+        let source = "fun f(x) = if x > 1 then img(x) else { val y = x; img(y) }\nassert(f(2) == \"2\")\nassert(f(1) == \"1\")";
+        let sizes: Vec<usize> = [OPT_LEVEL_NONE, OPT_LEVEL_SAFE, OPT_LEVEL_AGGRESSIVE].iter().map(|level| {
+            let mut options = test_options();
+            options.optimization_level = *level;
+            let (_, bytecode) = compile_with(source, options);
+            assert_eq!(run(&bytecode), Ok(()), "At the optimization level {}", level);
+            bytecode.len()
+        }).collect();
+        assert!(sizes[0] >= sizes[1], "{:?}", sizes);
+        assert_ne!(sizes[0], sizes[2], "{:?}", sizes);
+    }
+
+    /// A reused environment compiles each script as a fresh one, the globals of the previous
+    /// script are not declared anymore
+    #[test]
+    fn reuse_env() {
+        use crate::lkqlc::test_utils::{compile, test_options};
+
+        let first = "val a = 1\nfun f(x) = x + a";
+        let (_, expected) = compile(first);
+        let mut env = CompilationEnv::new(test_options());
+        for _ in 0..2 {
+            assert_eq!(compile_lkql_buffer_with_env(first, "unit test", &mut env).ok(), Some(expected.clone()));
+        }
+        match compile_lkql_buffer_with_env("val b = f(a)", "unit test", &mut env) {
+            Err(e) => assert_eq!(e.code, E_UNDECLARED_VAR, "{}", e),
+            Ok(_) => panic!("The globals of the previous script are expected to be cleared")
+        }
+    }
+
+    /// An expression nested deeper than the limit is a located error, a shallower one compiles
+    #[test]
+    fn nesting_depth_limit() {
+        use crate::lkqlc::test_utils::test_options;
+
+        let mut options = test_options();
+        options.max_depth = 16;
+        let mut env = CompilationEnv::new(options);
+        let nested = |depth: usize| format!("\nval x = {}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(compile_lkql_buffer_with_env(&nested(4), "unit test", &mut env).is_ok());
+        match compile_lkql_buffer_with_env(&nested(32), "unit test", &mut env) {
+            Err(e) => {
+                assert_eq!(e.code, E_TOO_COMPLEX, "{}", e);
+                assert_eq!(e.location.map(|location| location.line), Some(2));
+            }
+            Ok(_) => panic!("The nested expression is expected to exceed the depth limit")
+        }
+        assert!(compile_lkql_buffer_with_env(&nested(4), "unit test", &mut env).is_ok());
+    }
+
+    /// In report mode the unsupported node kinds are counted and the supported ones still
+    /// compile, else the first unsupported node is an error
+    #[test]
+    fn report_unsupported_nodes() {
+        use crate::lkqlc::test_utils::{compile_error, compile_with, test_options};
+
+        let source = "val o = {a: 1}\nval p = {b: 2}\nval d = o.a\nval x = 1 + 2\nassert(x == 3)";
+        let mut options = test_options();
+        options.report_unsupported = true;
+        let (env, _) = compile_with(source, options);
+        let unsupported: Vec<(&str, usize)> = env.get_unsupported_nodes().iter()
+            .map(|(kind_name, count)| (kind_name.as_str(), *count))
+            .collect();
+        assert_eq!(unsupported, vec![("DotAccess", 1), ("ObjectLiteral", 2)]);
+
+        let e = compile_error(source);
+        assert_eq!(e.code, E_UNKNOWN_NODE, "{}", e);
+        assert_eq!(e.location.map(|location| location.line), Some(1));
+    }
+
+    /// The entry point selects the function called after the top level code, an unknown one
+    /// is an undeclared symbol
+    #[test]
+    fn entry_point() {
+        use crate::lkqlc::test_utils::{compile_with, run, test_options};
+
+        let source = "fun failing() = assert(false)\nfun passing() = assert(true)";
+        let with_entry_point = |name: &str| {
+            let mut options = test_options();
+            options.entry_point = Some(String::from(name));
+            options
+        };
+        let (_, bytecode) = compile_with(source, with_entry_point("passing"));
+        assert_eq!(run(&bytecode), Ok(()));
+        let (_, bytecode) = compile_with(source, with_entry_point("failing"));
+        assert!(run(&bytecode).is_err());
+
+        let mut env = CompilationEnv::new(with_entry_point("missing"));
+        match compile_lkql_buffer_with_env(source, "unit test", &mut env) {
+            Err(e) => assert_eq!(e.code, E_UNDECLARED_VAR, "{}", e),
+            Ok(_) => panic!("An unknown entry point is expected to fail")
+        }
+    }
 }
@@ -0,0 +1,165 @@
+/*
+This module contains an assembler to build bytecode programs from raw IR instructions
+It bypasses the LKQL node lowering, so the IR processing and the bytecode encoding can be
+tested in isolation
+*/
+
+use crate::lkqlc::bc::{FNEW, NumericConstant, Program};
+use crate::lkqlc::env::{CompilationEnv, CompilationOptions};
+use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction};
+
+
+// --- The assembler structure
+
+pub struct Assembler {
+    env: CompilationEnv,
+}
+
+impl Assembler {
+    /// Create a new assembler, the instructions are added to the main chunk
+    pub fn new(options: CompilationOptions) -> Assembler {
+        Assembler {
+            env: CompilationEnv::new(options)
+        }
+    }
+
+    /// Reserve n contiguous slots in the current function
+    pub fn slots(&mut self, n: u8) -> Vec<u8> {
+        self.env.new_tmps(n).expect("Cannot reserve the slots, all slots are busy")
+    }
+
+    /// Add the raw instruction to the current function
+    pub fn emit(&mut self, inst: IRInstruction) -> &mut Assembler {
+        self.env.add_instruction(inst);
+        self
+    }
+
+    /// Create a new label in the current function
+    pub fn label(&mut self) -> u64 {
+        self.env.new_label()
+    }
+
+    /// Place the label on the next emitted instruction
+    pub fn place(&mut self, label: u64) -> &mut Assembler {
+        self.env.place_label(label);
+        self
+    }
+
+    /// Add a jump to the label
+    pub fn jump(&mut self, label: u64) -> &mut Assembler {
+        self.env.add_jump(label);
+        self
+    }
+
+    /// Add a string constant to the current function and return its index
+    pub fn string(&mut self, string: &str) -> u16 {
+        self.env.add_string_constant(String::from(string))
+    }
+
+    /// Add a numeric constant to the current function and return its index
+    pub fn number(&mut self, constant: NumericConstant) -> u16 {
+        self.env.add_numeric_constant(constant)
+    }
+
+    /// Set the slot returned by the current function
+    pub fn ret(&mut self, slot: u8) -> &mut Assembler {
+        self.env.set_return_slot(Some(slot));
+        self
+    }
+
+    /// Open a child function, next instructions are added to it
+    pub fn open_function(&mut self, arg_count: u8) -> &mut Assembler {
+        self.env.open_env(arg_count);
+        if arg_count > 0 {
+            self.slots(arg_count);
+        }
+        self
+    }
+
+    /// Close the current child function and create its closure in the given slot of the parent
+    pub fn close_function(&mut self, dest_slot: u8) -> &mut Assembler {
+        if let Err(e) = self.env.close_env() {
+            panic!("Cannot assemble the function : {}", e);
+        }
+        let child_index = self.env.add_child_constant();
+        self.env.add_instruction(IRInstruction::AD(IRInstAD::new(
+            FNEW,
+            IRArg::Slot(dest_slot),
+            IRArg::Func(child_index)
+        )));
+        self
+    }
+
+    /// Close the main chunk and return the assembled program
+    pub fn finish(mut self) -> Program {
+        if let Err(e) = self.env.close_env() {
+            panic!("Cannot assemble the program : {}", e);
+        }
+        self.env.take_program()
+    }
+}
+
+
+// --- Tests of the assembler
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lkqlc::bc::{ADDVN, ADDVV, CALL, GGET, ISEQN, ISLT, KSHORT, roundtrip};
+    use crate::lkqlc::ir::IRInstABC;
+
+    fn ad(op_code: u8, a: IRArg, d: IRArg) -> IRInstruction {
+        IRInstruction::AD(IRInstAD::new(op_code, a, d))
+    }
+
+    fn abc(op_code: u8, a: IRArg, b: IRArg, c: IRArg) -> IRInstruction {
+        IRInstruction::ABC(IRInstABC::new(op_code, a, b, c))
+    }
+
+    /// Assemble a loop summing the integers from 1 to 10, the chunk raises an error if the sum
+    /// is not 55
+    fn sum_loop() -> Program {
+        let mut asm = Assembler::new(CompilationOptions::new());
+        let slots = asm.slots(4);
+        let (sum, i, limit, error_fun) = (slots[0], slots[1], slots[2], slots[3]);
+        let one = asm.number(NumericConstant::Int(1));
+        let expected = asm.number(NumericConstant::Int(55));
+        let error_name = asm.string("error");
+        let (loop_label, end_label, ok_label) = (asm.label(), asm.label(), asm.label());
+
+        asm.emit(ad(KSHORT, IRArg::Slot(sum), IRArg::SignedLiteral(0)))
+            .emit(ad(KSHORT, IRArg::Slot(i), IRArg::SignedLiteral(1)))
+            .emit(ad(KSHORT, IRArg::Slot(limit), IRArg::SignedLiteral(10)))
+            .place(loop_label)
+            .emit(ad(ISLT, IRArg::Slot(limit), IRArg::Slot(i)))
+            .jump(end_label)
+            .emit(abc(ADDVV, IRArg::Slot(sum), IRArg::Slot(sum), IRArg::Slot(i)))
+            .emit(abc(ADDVN, IRArg::Slot(i), IRArg::Slot(i), IRArg::Num(one)))
+            .jump(loop_label)
+            .place(end_label)
+            .emit(ad(ISEQN, IRArg::Slot(sum), IRArg::Num(expected)))
+            .jump(ok_label)
+            .emit(ad(GGET, IRArg::Slot(error_fun), IRArg::Str(error_name)))
+            .emit(abc(CALL, IRArg::Slot(error_fun), IRArg::Literal(1), IRArg::Literal(1)))
+            .place(ok_label)
+            .emit(ad(KSHORT, IRArg::Slot(i), IRArg::SignedLiteral(0)));
+        asm.finish()
+    }
+
+    /// The assembled loop is encoded and decoded back
+    #[test]
+    fn assemble_loop() {
+        let program = sum_loop();
+        assert!(roundtrip(&program), "{}", program.disassemble());
+    }
+
+    /// The assembled loop runs to its end
+    #[cfg(not(feature = "bytecode-only"))]
+    #[test]
+    fn run_assembled_loop() {
+        use crate::lkqlc::test_utils::run;
+
+        let program = sum_loop();
+        assert_eq!(run(&program.encode()), Ok(()), "{}", program.disassemble());
+    }
+}
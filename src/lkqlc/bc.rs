@@ -10,7 +10,8 @@ Its composition is :
 [
     MAGIC (3 bytes) |
     VERSION (1 byte) |
-    FLAGS (1 byte or 1 uleb128)
+    FLAGS (1 byte or 1 uleb128) |
+    CHUNK_NAME (1 uleb128 size + bytes if not FLAG_H_IS_STRIPPED else absent)
 ]
 
 A LuaJIT prototype is composed as :
@@ -23,11 +24,12 @@ A LuaJIT prototype is composed as :
     COMPLEX_CONST_COUNT (1 uleb128) |
     NUM_CONST_COUNT (1 uleb128) |
     INST_COUNT (1 uleb128) |
-    DEBUG_INFO_SIZE (1 uleb128 if FLAG_H_IS_STRIPPED else absent) |
-    FIRST_LINE_NB (1 uleb128 if FLAG_H_IS_STRIPPED else absent) |
-    LINE_COUNT (1 uleb128 if FLAG_H_IS_STRIPPED else absent) |
+    DEBUG_INFO_SIZE (1 uleb128 if not FLAG_H_IS_STRIPPED else absent) |
+    FIRST_LINE_NB (1 uleb128 if DEBUG_INFO_SIZE > 0 else absent) |
+    LINE_COUNT (1 uleb128 if DEBUG_INFO_SIZE > 0 else absent) |
     INSTRUCTIONS (4 bytes[]) |
-    CONSTANT_TABLE
+    CONSTANT_TABLE |
+    DEBUG_INFO (DEBUG_INFO_SIZE bytes)
 ]
 
 The LuaJIT constant table is a bytecode section at the end of every prototype that contains
@@ -50,6 +52,28 @@ if the constant is an integer or a number
 Example : 0x00000011 IS A NUMBER WITH HI PART, LO IS 1
           0x00000010 IS AN INTEGER EQUALS TO 1, NO HI PART TO READ
 
+About string constants !
+String constants are stored in the complex constant table of each prototype, there is no way
+to reference the constant table of another prototype in the bytecode format. The same string
+used in a parent and a child function is then encoded twice, this only costs bytecode size
+because LuaJIT interns all strings when loading the bytecode.
+Sharing it through an upvalue would replace a KSTR by an UGET and keep a slot busy in the
+parent, which is slower at runtime, so the duplication is kept. Use
+`Program::duplicated_string_size` to measure it.
+This is also why the global names cannot be interned at the program level : GGET and GSET take
+the name as a constant index of their own prototype. The builtin functions are cached in locals
+of the main chunk instead, the children read them through upvalues without any name constant.
+
+About debug info !
+The debug info of a prototype is made of three sections :
+[
+    LINE_INFO (for each instruction the line delta from FIRST_LINE_NB, on 1, 2 or 4 bytes depending on LINE_COUNT) |
+    UPVALUE_NAMES (for each upvalue its zero terminated name) |
+    VARIABLE_INFO ((zero terminated name, uleb128 start pc delta, uleb128 live range size)[], then a 0 byte)
+]
+The start pc of a variable is relative to the start pc of the previous one, the pcs count the
+function header instruction, so the first emitted instruction is the pc 1
+
 About table constant !
 A table constant is represented as this in the constant pool :
 [
@@ -58,11 +82,18 @@ A table constant is represented as this in the constant pool :
     ARRAY (TABLE_ITEM[]) |
     MAP ((TABLE_ITEM, TABLE_ITEM)[])
 ]
+A TABLE_ITEM is only a nil, a boolean, an integer, a number or a string : LuaJIT reads every item
+type from BCDUMP_KTAB_STR as a string length, so no type is left for a nested table. A list of
+constant lists cannot be a single constant, each inner list is a table constant of its own
+duplicated by TDUP and stored in the outer one
 */
 
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use nano_leb128::ULEB128;
+use crate::errors::{E_INVALID_BYTECODE, E_TOO_COMPLEX, LKQLError};
 
 
 // --- Defining the header macros
@@ -87,6 +118,24 @@ pub const FLAG_P_HAS_ILOOP: u8 = 0b00010000;
 
 pub const JUMP_BIASING: u16 = 0x8000;
 
+// The bits of the upvalue references of a prototype, like in the LuaJIT parser (PROTO_UV_*)
+// A reference with the local bit is a slot of the parent function, the immutable bit tells that
+// this local is never assigned after its capture. Without the local bit, the reference is the
+// index of an upvalue of the parent function, and it has no other bit set
+pub const UV_LOCAL: u16 = 0x8000;
+pub const UV_IMMUTABLE: u16 = 0x4000;
+
+// The maximum number of complex or numeric constants in a prototype, the instructions address
+// them with their 16 bit D operand
+pub const MAX_CONSTANT_COUNT: usize = 0x10000;
+
+// The end marker of the variable info, a name byte under VARNAME_MAX is a LuaJIT internal name
+pub const VARNAME_END: u8 = 0x00;
+pub const VARNAME_MAX: u8 = 0x07;
+
+// The maximum size of an encoded ULEB128 : a 64 bit value takes ceil(64 / 7) bytes
+pub const MAX_ULEB128_SIZE: usize = 10;
+
 
 // --- Defining the operation codes
 
@@ -235,6 +284,23 @@ pub const JFUNCV: u8 = 0x5E;
 pub const FUNCC: u8 = 0x5F;
 pub const FUNCCW: u8 = 0x60;
 
+// The names of the operations indexed by their code, like in the LuaJIT sources
+const OP_NAMES: [&str; 97] = [
+    "ISLT", "ISGE", "ISLE", "ISGT", "ISEQV", "ISNEV", "ISEQS", "ISNES",
+    "ISEQN", "ISNEN", "ISEQP", "ISNEP", "ISTC", "ISFC", "IST", "ISF",
+    "ISTYPE", "ISNUM", "MOV", "NOT", "UNM", "LEN", "ADDVN", "SUBVN",
+    "MULVN", "DIVVN", "MODVN", "ADDNV", "SUBNV", "MULNV", "DIVNV", "MODNV",
+    "ADDVV", "SUBVV", "MULVV", "DIVVV", "MODVV", "POW", "CAT", "KSTR",
+    "KCDATA", "KSHORT", "KNUM", "KPRI", "KNIL", "UGET", "USETV", "USETS",
+    "USETN", "USETP", "UCLO", "FNEW", "TNEW", "TDUP", "GGET", "GSET",
+    "TGETV", "TGETS", "TGETB", "TGETR", "TSETV", "TSETS", "TSETB", "TSETM",
+    "TSETR", "CALLM", "CALL", "CALLMT", "CALLT", "ITERC", "ITERN", "VARG",
+    "ISNEXT", "RETM", "RET", "RET0", "RET1", "FORI", "JFORI", "FORL",
+    "IFORL", "JFORL", "ITERL", "IITERL", "JITERL", "LOOP", "ILOOP", "JLOOP",
+    "JMP", "FUNCF", "IFUNCF", "JFUNCF", "FUNCV", "IFUNCV", "JFUNCV", "FUNCC",
+    "FUNCCW"
+];
+
 
 // --- Defining the constant table macros
 
@@ -256,7 +322,7 @@ pub const BCDUMP_KTAB_STR: u32 = 5;
 // --- Defining the bytecode fundamentals structures
 
 // Structure of a bytecode program
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Program {
     pub header: Header,
     pub prototypes: Vec<Prototype>,
@@ -271,6 +337,45 @@ impl Program {
         }
     }
 
+    /// Get the listing of all the prototypes of the program, the children come before their
+    /// parent and the main chunk is the last one
+    pub fn disassemble(&self) -> String {
+        let mut res = String::new();
+        for (i, proto) in self.prototypes.iter().enumerate() {
+            res.push_str(&format!(
+                "-- PROTOTYPE {} ({} args, {} slots, {} complex consts, {} numeric consts)\n",
+                i,
+                proto.arg_count,
+                proto.required_frame_size(),
+                proto.complex_constants.len(),
+                proto.numeric_constants.len()
+            ));
+            res.push_str(&proto.disassemble());
+        }
+        res
+    }
+
+    /// Get the size in bytes of the string constants which are also defined in another
+    /// prototype of the program
+    pub fn duplicated_string_size(&self) -> usize {
+        // Count the occurrences of each string constant in the prototypes
+        let mut occurrences: HashMap<&Vec<u8>, usize> = HashMap::new();
+        for proto in &self.prototypes {
+            for constant in &proto.complex_constants {
+                if let ComplexConstant::String(kstr) = constant {
+                    *occurrences.entry(&kstr.content).or_insert(0) += 1;
+                }
+            }
+        }
+
+        // Sum the size of the redundant encodings
+        let mut res = 0;
+        for (content, count) in occurrences {
+            res += content.len() * (count - 1);
+        }
+        res
+    }
+
     /// Encode the program into real bytecode
     pub fn encode(&self) -> Vec<u8> {
         // Create the result
@@ -282,7 +387,7 @@ impl Program {
 
         // Add the prototype to the bytecode
         for proto in &self.prototypes {
-            let mut proto_bc = proto.encode();
+            let mut proto_bc = proto.encode(self.header.is_stripped());
             res.append(&mut proto_bc);
         }
 
@@ -292,14 +397,45 @@ impl Program {
         // Return the result
         res
     }
+
+    /// Decode a bytecode produced by the `encode` function into a program
+    pub fn decode(bytecode: &[u8]) -> Result<Program, LKQLError> {
+        let mut reader = BCReader::new(bytecode);
+
+        // Read the header
+        let header = match Header::decode(&mut reader) {
+            Err(e) => { return Err(e); }
+            Ok(h) => h
+        };
+
+        // Read the prototypes until the tail
+        let mut prototypes = Vec::new();
+        loop {
+            match reader.peek_u8() {
+                Err(e) => { return Err(e); }
+                Ok(0) => { break; }
+                Ok(_) => {}
+            };
+            match Prototype::decode(&mut reader, header.is_stripped()) {
+                Err(e) => { return Err(e); }
+                Ok(proto) => prototypes.push(proto)
+            };
+        }
+
+        Ok(Program {
+            header,
+            prototypes
+        })
+    }
 }
 
 // Structure for the bytecode file header
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Header {
     pub magic: [u8; 3],
     pub version: u8,
     pub flags: u8,
+    pub chunk_name: Vec<u8>,
 }
 
 impl Header {
@@ -308,10 +444,26 @@ impl Header {
         Header {
             magic: MAGIC,
             version: CUR_VERSION,
-            flags: 0x0 | FLAG_H_IS_STRIPPED | FLAG_H_HAS_FFI
+            flags: FLAG_H_IS_STRIPPED | FLAG_H_HAS_FFI,
+            chunk_name: Vec::new()
         }
     }
 
+    /// Create a new header for a bytecode with debug information
+    pub fn new_with_debug(chunk_name: String) -> Header {
+        Header {
+            magic: MAGIC,
+            version: CUR_VERSION,
+            flags: FLAG_H_HAS_FFI,
+            chunk_name: Vec::from(chunk_name)
+        }
+    }
+
+    /// Get if the bytecode is stripped of its debug information
+    pub fn is_stripped(&self) -> bool {
+        self.flags & FLAG_H_IS_STRIPPED != 0
+    }
+
     /// Encode the header and return the real bytecode
     pub fn encode(&self) -> Vec<u8> {
         // Create the result from the magic
@@ -321,13 +473,62 @@ impl Header {
         res.push(self.version);
         res.push(self.flags);
 
+        // Add the chunk name if the bytecode has debug information
+        if !self.is_stripped() {
+            encode_uleb128(&ULEB128::from(self.chunk_name.len() as u64), &mut res);
+            res.extend_from_slice(&self.chunk_name);
+        }
+
         // Return the result
         res
     }
+
+    /// Decode a header from the bytecode reader
+    fn decode(reader: &mut BCReader) -> Result<Header, LKQLError> {
+        let mut magic = [0u8; 3];
+        for item in magic.iter_mut() {
+            match reader.read_u8() {
+                Err(e) => { return Err(e); }
+                Ok(byte) => *item = byte
+            };
+        }
+        if magic != MAGIC {
+            return Err(LKQLError::new(E_INVALID_BYTECODE, String::from("Invalid bytecode magic")));
+        }
+
+        let version = match reader.read_u8() {
+            Err(e) => { return Err(e); }
+            Ok(v) => v
+        };
+        let flags = match reader.read_u8() {
+            Err(e) => { return Err(e); }
+            Ok(f) => f
+        };
+
+        // Read the chunk name if the bytecode has debug information
+        let mut chunk_name = Vec::new();
+        if flags & FLAG_H_IS_STRIPPED == 0 {
+            let name_size = match reader.read_uleb128() {
+                Err(e) => { return Err(e); }
+                Ok(size) => size as usize
+            };
+            chunk_name = match reader.read_bytes(name_size) {
+                Err(e) => { return Err(e); }
+                Ok(name) => name
+            };
+        }
+
+        Ok(Header {
+            magic,
+            version,
+            flags,
+            chunk_name
+        })
+    }
 }
 
 // Structure for a function prototype
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Prototype {
     pub flags: u8,
     pub arg_count: u8,
@@ -336,6 +537,7 @@ pub struct Prototype {
     pub upval_references: Vec<u16>,
     pub complex_constants: Vec<ComplexConstant>,
     pub numeric_constants: Vec<NumericConstant>,
+    pub debug_info: Option<DebugInfo>,
 }
 
 impl Prototype {
@@ -348,12 +550,116 @@ impl Prototype {
             instructions: Vec::new(),
             upval_references: Vec::new(),
             complex_constants: Vec::new(),
-            numeric_constants: Vec::new()
+            numeric_constants: Vec::new(),
+            debug_info: None
         }
     }
 
-    /// Encode the prototype and return the real bytecode
-    pub fn encode(&self) -> Vec<u8> {
+    /// Get the number of slots the prototype needs on the Lua stack, the arguments are always
+    /// in the frame
+    pub fn required_frame_size(&self) -> u8 {
+        self.frame_size.max(self.arg_count)
+    }
+
+    /// Get the number of instructions in the prototype
+    pub fn instruction_count(&self) -> usize {
+        self.instructions.len()
+    }
+
+    /// Get the operation codes of the prototype instructions, in order
+    pub fn op_codes(&self) -> Vec<u8> {
+        self.instructions.iter().map(|inst| inst.op_code()).collect()
+    }
+
+    /// Get the listing of the prototype instructions, one per line with its pc, the jump
+    /// targets and the debug info (source line, start of the local variables) if there is one
+    pub fn disassemble(&self) -> String {
+        let mut res = String::new();
+        for (i, inst) in self.instructions.iter().enumerate() {
+            // The pcs start after the function header
+            let pc = i as u32 + 1;
+            let mut line = format!("{:04}  {}", pc, inst);
+            if let BCInstruction::Ad(ad) = inst {
+                if let Some(offset) = ad.jump_offset() {
+                    line.push_str(&format!("  => {:04}", pc as i64 + 1 + offset as i64));
+                }
+            }
+
+            // Annotate the instruction with its debug info
+            if let Some(debug_info) = &self.debug_info {
+                let mut notes = Vec::new();
+                if debug_info.line_count > 0 {
+                    if let Some(delta) = debug_info.line_info.get(i) {
+                        notes.push(format!("line {}", debug_info.first_line + delta));
+                    }
+                }
+                for var_info in &debug_info.var_infos {
+                    if var_info.start_pc == pc {
+                        notes.push(format!("local {}", var_info.name));
+                    }
+                }
+                if !notes.is_empty() {
+                    line = format!("{:<40}; {}", line, notes.join(", "));
+                }
+            }
+            res.push_str(&line);
+            res.push('\n');
+        }
+        res
+    }
+
+    /// Verify that all the constants of the prototype are addressable by the instructions, this
+    /// must be done before the encoding since the constant indexes are truncated to 16 bits
+    pub fn check_constants(&self) -> Result<(), LKQLError> {
+        let complex_count = self.complex_constants.len();
+        let numeric_count = self.numeric_constants.len();
+        if complex_count > MAX_CONSTANT_COUNT || numeric_count > MAX_CONSTANT_COUNT {
+            return Err(LKQLError::new(
+                E_TOO_COMPLEX,
+                format!(
+                    "Too many constants in a function ({} complex and {} numeric, the limit is {} of each), split the function",
+                    complex_count,
+                    numeric_count,
+                    MAX_CONSTANT_COUNT
+                )
+            ));
+        }
+        Ok(())
+    }
+
+    /// Get the flags that the instructions of the prototype imply : variadic if it reads its
+    /// varargs, FFI if it loads cdata constants and loop if it contains a loop instruction
+    pub fn instruction_flags(&self) -> u8 {
+        let mut res = 0;
+        for op_code in self.op_codes() {
+            res |= match op_code {
+                VARG => FLAG_P_IS_VARIADIC,
+                KCDATA => FLAG_P_HAS_FFI,
+                LOOP | ILOOP | FORL | IFORL | ITERL | IITERL => FLAG_P_HAS_ILOOP,
+                _ => 0
+            };
+        }
+        res
+    }
+
+    /// Set the flags implied by the instructions, the other ones are kept
+    pub fn derive_flags(&mut self) {
+        self.flags |= self.instruction_flags();
+    }
+
+    /// Encode the prototype and return the real bytecode, the debug info is only written in a
+    /// not stripped bytecode
+    /// LuaJIT misreads a prototype which uses varargs or cdata without the flag, the loop flag is
+    /// only a marker and a decoded LuaJIT prototype doesn't have it
+    pub fn encode(&self, stripped: bool) -> Vec<u8> {
+        let required_flags = self.instruction_flags() & (FLAG_P_IS_VARIADIC | FLAG_P_HAS_FFI);
+        debug_assert!(
+            self.flags & required_flags == required_flags,
+            "The prototype flags {:#010b} don't match its instructions, they need {:#010b}",
+            self.flags,
+            required_flags
+        );
+
         // Create the result vector
         let mut res = Vec::new();
 
@@ -363,7 +669,7 @@ impl Prototype {
         // Put the flags, arg count and frame size
         res.push(self.flags);
         res.push(self.arg_count);
-        res.push(self.frame_size);
+        res.push(self.required_frame_size());
 
         // Put the upval count
         res.push(self.upval_references.len() as u8);
@@ -380,6 +686,20 @@ impl Prototype {
         uleb = ULEB128::from(self.instructions.len() as u64);
         encode_uleb128(&uleb, &mut res);
 
+        // Put the debug info size and the line range
+        let mut debug_bc = Vec::new();
+        if !stripped {
+            if let Some(debug_info) = &self.debug_info {
+                debug_bc = debug_info.encode();
+            }
+            encode_uleb128(&ULEB128::from(debug_bc.len() as u64), &mut res);
+            if !debug_bc.is_empty() {
+                let debug_info = self.debug_info.as_ref().unwrap();
+                encode_uleb128(&ULEB128::from(debug_info.first_line as u64), &mut res);
+                encode_uleb128(&ULEB128::from(debug_info.line_count as u64), &mut res);
+            }
+        }
+
         // Put the instructions in the result
         for inst in &self.instructions {
             let mut inst_bc = inst.encode();
@@ -388,10 +708,11 @@ impl Prototype {
 
         // Put the constant table in the result
 
-        // The upvalue constants
+        // The upvalue constants, they are 16 bit values in the bytecode byte order (little endian
+        // since the big endian flag is not set)
         for upval in &self.upval_references {
-            res.push(((upval >> 8) & 0xFF) as u8);
             res.push((upval & 0xFF) as u8);
+            res.push(((upval >> 8) & 0xFF) as u8);
         }
 
         // The complex constants
@@ -406,29 +727,291 @@ impl Prototype {
             res.append(&mut numeric_bc);
         }
 
+        // The debug info
+        res.append(&mut debug_bc);
+
         // Add the size at the very start of the bytecode
-        let mut buff = [0u8; 11];
-        let buff_len = ULEB128::from(res.len() as u64).write_into(&mut buff).unwrap();
-        let mut i = buff_len - 1;
-        loop {
-            res.insert(0, buff[i]);
-            if i == 0 { break; }
-            else { i -= 1 }
+        let mut size_bc = Vec::with_capacity(MAX_ULEB128_SIZE);
+        encode_uleb128(&ULEB128::from(res.len() as u64), &mut size_bc);
+        res.splice(0..0, size_bc);
+
+        // Return the result
+        res
+    }
+
+    /// Decode a prototype from the bytecode reader
+    fn decode(reader: &mut BCReader, stripped: bool) -> Result<Prototype, LKQLError> {
+        // Read the size and verify that the whole prototype is available
+        let size = match reader.read_uleb128() {
+            Err(e) => { return Err(e); }
+            Ok(s) => s as usize
+        };
+        let end = match reader.position.checked_add(size) {
+            Some(end) if end <= reader.bytes.len() => end,
+            _ => { return Err(LKQLError::new(E_INVALID_BYTECODE, String::from("Truncated prototype in the bytecode"))); }
+        };
+
+        // Read the fixed sized fields
+        let mut fields = [0u8; 4];
+        for item in fields.iter_mut() {
+            match reader.read_u8() {
+                Err(e) => { return Err(e); }
+                Ok(byte) => *item = byte
+            };
+        }
+        let mut res = Prototype::new(fields[1]);
+        res.flags = fields[0];
+        res.frame_size = fields[2];
+        let upval_count = fields[3] as usize;
+
+        // Read the counts
+        let mut counts = [0usize; 3];
+        for item in counts.iter_mut() {
+            match reader.read_uleb128() {
+                Err(e) => { return Err(e); }
+                Ok(count) => *item = count as usize
+            };
         }
+        let [complex_count, numeric_count, inst_count] = counts;
+
+        // Read the debug info size and the line range
+        let mut debug_fields = [0u64; 3];
+        if !stripped {
+            for i in 0..debug_fields.len() {
+                match reader.read_uleb128() {
+                    Err(e) => { return Err(e); }
+                    Ok(field) => debug_fields[i] = field
+                };
+                if debug_fields[0] == 0 { break; }
+            }
+        }
+        let [debug_size, first_line, line_count] = debug_fields;
+
+        // Read the instructions
+        for _ in 0..inst_count {
+            match BCInstruction::decode(reader) {
+                Err(e) => { return Err(e); }
+                Ok(inst) => res.instructions.push(inst)
+            };
+        }
+
+        // Read the upvalue constants
+        for _ in 0..upval_count {
+            let lo = match reader.read_u8() {
+                Err(e) => { return Err(e); }
+                Ok(byte) => byte as u16
+            };
+            let hi = match reader.read_u8() {
+                Err(e) => { return Err(e); }
+                Ok(byte) => byte as u16
+            };
+            let reference = (hi << 8) | lo;
+
+            // Only the reference to a local of the parent can be immutable
+            if reference & UV_IMMUTABLE != 0 && reference & UV_LOCAL == 0 {
+                return Err(LKQLError::new(
+                    E_INVALID_BYTECODE,
+                    format!("Invalid upvalue reference {:#06X}, an outer upvalue cannot be immutable", reference)
+                ));
+            }
+            res.upval_references.push(reference);
+        }
+
+        // Read the complex constants
+        for _ in 0..complex_count {
+            match ComplexConstant::decode(reader) {
+                Err(e) => { return Err(e); }
+                Ok(constant) => res.complex_constants.push(constant)
+            };
+        }
+
+        // Read the numeric constants
+        for _ in 0..numeric_count {
+            match NumericConstant::decode(reader) {
+                Err(e) => { return Err(e); }
+                Ok(constant) => res.numeric_constants.push(constant)
+            };
+        }
+
+        // Read the debug info
+        if debug_size > 0 {
+            let debug_end = match reader.position.checked_add(debug_size as usize) {
+                Some(debug_end) if debug_end <= end => debug_end,
+                _ => { return Err(LKQLError::new(E_INVALID_BYTECODE, String::from("Debug info size exceeds its prototype"))); }
+            };
+            match DebugInfo::decode(reader, first_line as u32, line_count as u32, inst_count, upval_count) {
+                Err(e) => { return Err(e); }
+                Ok(debug_info) => res.debug_info = Some(debug_info)
+            };
+            if reader.position != debug_end {
+                return Err(LKQLError::new(E_INVALID_BYTECODE, String::from("Debug info size doesn't match its content")));
+            }
+        }
+
+        // Verify that the prototype size was right
+        if reader.position != end {
+            return Err(LKQLError::new(E_INVALID_BYTECODE, String::from("Prototype size doesn't match its content")));
+        }
+
+        Ok(res)
+    }
+}
+
+// Structure for the debug info of a prototype
+#[derive(Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    pub first_line: u32,
+    pub line_count: u32,
+    pub line_info: Vec<u32>,
+    pub upval_names: Vec<String>,
+    pub var_infos: Vec<VarInfo>,
+}
+
+impl DebugInfo {
+    /// Create a new empty debug info
+    pub fn new() -> DebugInfo {
+        DebugInfo::default()
+    }
+
+    /// Get the size in bytes of a line info entry, it depends on the line count like in LuaJIT
+    fn line_entry_size(line_count: u32) -> usize {
+        if line_count < 0x100 { 1 } else if line_count < 0x10000 { 2 } else { 4 }
+    }
+
+    /// Encode the debug info into bytecode
+    pub fn encode(&self) -> Vec<u8> {
+        // Create the result
+        let mut res = Vec::new();
+
+        // Put the line deltas, in little endian like the other multi byte values
+        let entry_size = DebugInfo::line_entry_size(self.line_count);
+        for line in &self.line_info {
+            res.extend_from_slice(&line.to_le_bytes()[..entry_size]);
+        }
+
+        // Put the upvalue names
+        for name in &self.upval_names {
+            res.extend_from_slice(name.as_bytes());
+            res.push(0);
+        }
+
+        // Put the variable info, the start pcs are encoded as deltas
+        let mut last_start = 0;
+        for var_info in &self.var_infos {
+            res.extend_from_slice(var_info.name.as_bytes());
+            res.push(0);
+            encode_uleb128(&ULEB128::from((var_info.start_pc - last_start) as u64), &mut res);
+            encode_uleb128(&ULEB128::from((var_info.end_pc - var_info.start_pc) as u64), &mut res);
+            last_start = var_info.start_pc;
+        }
+        res.push(VARNAME_END);
 
         // Return the result
         res
     }
+
+    /// Decode the debug info of a prototype from the bytecode reader
+    fn decode(reader: &mut BCReader, first_line: u32, line_count: u32, inst_count: usize, upval_count: usize) -> Result<DebugInfo, LKQLError> {
+        let mut res = DebugInfo::new();
+        res.first_line = first_line;
+        res.line_count = line_count;
+
+        // Read the line deltas
+        let entry_size = DebugInfo::line_entry_size(line_count);
+        for _ in 0..inst_count {
+            let mut bytes = [0u8; 4];
+            for item in bytes.iter_mut().take(entry_size) {
+                match reader.read_u8() {
+                    Err(e) => { return Err(e); }
+                    Ok(byte) => *item = byte
+                };
+            }
+            res.line_info.push(u32::from_le_bytes(bytes));
+        }
+
+        // Read the upvalue names
+        for _ in 0..upval_count {
+            match reader.read_name() {
+                Err(e) => { return Err(e); }
+                Ok(name) => res.upval_names.push(name)
+            };
+        }
+
+        // Read the variable info until the end marker
+        let mut last_start = 0;
+        loop {
+            match reader.peek_u8() {
+                Err(e) => { return Err(e); }
+                Ok(VARNAME_END) => {
+                    reader.position += 1;
+                    break;
+                }
+                Ok(byte) if byte < VARNAME_MAX => {
+                    return Err(LKQLError::new(E_INVALID_BYTECODE, String::from("Internal variable names are not supported")));
+                }
+                Ok(_) => {}
+            };
+            let name = match reader.read_name() {
+                Err(e) => { return Err(e); }
+                Ok(name) => name
+            };
+            let start_pc = match reader.read_uleb128() {
+                Err(e) => { return Err(e); }
+                Ok(delta) => match checked_pc(last_start, delta) {
+                    None => { return Err(LKQLError::new(E_INVALID_BYTECODE, format!("Invalid start pc of the variable {}", name))); }
+                    Some(pc) => pc
+                }
+            };
+            let end_pc = match reader.read_uleb128() {
+                Err(e) => { return Err(e); }
+                Ok(size) => match checked_pc(start_pc, size) {
+                    None => { return Err(LKQLError::new(E_INVALID_BYTECODE, format!("Invalid end pc of the variable {}", name))); }
+                    Some(pc) => pc
+                }
+            };
+            res.var_infos.push(VarInfo::new(name, start_pc, end_pc));
+            last_start = start_pc;
+        }
+
+        Ok(res)
+    }
+}
+
+// Structure for the name and the live range of a local variable, the end pc is excluded
+#[derive(Debug, PartialEq)]
+pub struct VarInfo {
+    pub name: String,
+    pub start_pc: u32,
+    pub end_pc: u32,
+}
+
+impl VarInfo {
+    /// Create a new variable info
+    pub fn new(name: String, start_pc: u32, end_pc: u32) -> VarInfo {
+        VarInfo {
+            name,
+            start_pc,
+            end_pc
+        }
+    }
 }
 
 // The instruction enum, to unify instruction types
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum BCInstruction {
     Abc(BCInstABC),
     Ad(BCInstAD)
 }
 
 impl BCInstruction {
+    /// Get the operation code of the instruction, whatever its type
+    pub fn op_code(&self) -> u8 {
+        match self {
+            BCInstruction::Abc(abc) => abc.op_code,
+            BCInstruction::Ad(ad) => ad.op_code
+        }
+    }
+
     /// Encode a function into the bytecode
     pub fn encode(&self) -> Vec<u8> {
         match self {
@@ -436,10 +1019,36 @@ impl BCInstruction {
             BCInstruction::Ad(ad) => ad.encode()
         }
     }
+
+    /// Decode an instruction from the bytecode reader, the instruction type is deduced from the
+    /// operation code
+    fn decode(reader: &mut BCReader) -> Result<BCInstruction, LKQLError> {
+        let inst_int = match reader.read_u32() {
+            Err(e) => { return Err(e); }
+            Ok(i) => i
+        };
+        let op_code = (inst_int & 0xFF) as u8;
+        let a = ((inst_int >> 8) & 0xFF) as u8;
+
+        if op_has_bc(op_code) {
+            Ok(BCInstABC::emit(op_code, a, ((inst_int >> 24) & 0xFF) as u8, ((inst_int >> 16) & 0xFF) as u8))
+        } else {
+            Ok(BCInstAD::emit(op_code, a, ((inst_int >> 16) & 0xFFFF) as u16))
+        }
+    }
+}
+
+impl fmt::Display for BCInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BCInstruction::Abc(abc) => write!(f, "{:<7}{:>4}{:>4}{:>4}", op_name(abc.op_code), abc.a, abc.b, abc.c),
+            BCInstruction::Ad(ad) => write!(f, "{:<7}{:>4}{:>6}", op_name(ad.op_code), ad.a, ad.d)
+        }
+    }
 }
 
 // Structure for a OP A B C instruction
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BCInstABC {
     pub op_code: u8,
     pub a: u8,
@@ -487,7 +1096,7 @@ impl BCInstABC {
 }
 
 // Structure for a OP A D instruction
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BCInstAD {
     pub op_code: u8,
     pub a: u8,
@@ -509,6 +1118,17 @@ impl BCInstAD {
         BCInstruction::Ad(BCInstAD::new(op_code, a, d))
     }
 
+    /// Get the signed offset of a jump from the next instruction, none if the operation is not
+    /// a jump. The D operand is biased by JUMP_BIASING, this removes the bias added when the IR
+    /// jumps are resolved
+    pub fn jump_offset(&self) -> Option<i32> {
+        if op_is_jump(self.op_code) {
+            Some(self.d as i32 - JUMP_BIASING as i32)
+        } else {
+            None
+        }
+    }
+
     /// Encode the instruction as bytecode
     pub fn encode(&self) -> Vec<u8> {
         // Create the result
@@ -532,7 +1152,7 @@ impl BCInstAD {
 }
 
 // The enum for the complex constants
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ComplexConstant {
     String(KStr),
     Table(KTable),
@@ -574,20 +1194,16 @@ impl ComplexConstant {
                 res.append(&mut complex_bc);
             },
 
-            // If signed int constant
+            // If signed int constant, it is a 64 bit cdata encoded as lo|hi
             ComplexConstant::I64(int) => {
                 res.push(BCDUMP_KGC_I64 as u8);
-
-                // PLACEHOLDER | TODO : WHAT IS I64 AND HOW TO USE IT
-                res.push(0u8);
+                encode_lo_hi(*int as u64, &mut res);
             }
 
             // If unsigned int constant
             ComplexConstant::U64(int) => {
                 res.push(BCDUMP_KGC_U64 as u8);
-
-                // PLACEHOLDER | TODO : WHAT IS U64 AND HOW TO USE IT
-                res.push(0u8);
+                encode_lo_hi(*int, &mut res);
             }
 
             // If child
@@ -599,10 +1215,39 @@ impl ComplexConstant {
         // Return the bytecode
         res
     }
+
+    /// Decode a complex constant from the bytecode reader
+    fn decode(reader: &mut BCReader) -> Result<ComplexConstant, LKQLError> {
+        let kind = match reader.read_uleb128() {
+            Err(e) => { return Err(e); }
+            Ok(k) => k as u32
+        };
+
+        match kind {
+            BCDUMP_KGC_CHILD => Ok(ComplexConstant::Child),
+            BCDUMP_KGC_TAB => match KTable::decode(reader) {
+                Err(e) => Err(e),
+                Ok(ktable) => Ok(ComplexConstant::Table(ktable))
+            },
+            BCDUMP_KGC_I64 => match reader.read_lo_hi() {
+                Err(e) => Err(e),
+                Ok(int) => Ok(ComplexConstant::I64(int as i64))
+            },
+            BCDUMP_KGC_U64 => match reader.read_lo_hi() {
+                Err(e) => Err(e),
+                Ok(int) => Ok(ComplexConstant::U64(int))
+            },
+            BCDUMP_KGC_COMPLEX => Err(LKQLError::new(E_INVALID_BYTECODE, String::from("Complex number constants are not supported"))),
+            _ => match reader.read_bytes((kind - BCDUMP_KGC_STR) as usize) {
+                Err(e) => Err(e),
+                Ok(content) => Ok(ComplexConstant::String(KStr { content }))
+            }
+        }
+    }
 }
 
 // The structure for the string constants
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct KStr {
     pub content: Vec<u8>,
 }
@@ -626,11 +1271,12 @@ impl KStr {
     }
 }
 
-// The structure for the table constants
-#[derive(Debug)]
+// The structure for the table constants, the map is ordered so a table always has the same
+// encoding
+#[derive(Debug, PartialEq)]
 pub struct KTable {
     pub array: Vec<TableItem>,
-    pub map: HashMap<TableItem, TableItem>,
+    pub map: BTreeMap<TableItem, TableItem>,
 }
 
 impl KTable {
@@ -638,7 +1284,7 @@ impl KTable {
     pub fn new() -> KTable {
         KTable {
             array: Vec::new(),
-            map: HashMap::new()
+            map: BTreeMap::new()
         }
     }
 
@@ -670,10 +1316,43 @@ impl KTable {
         // Return the bytecode
         res
     }
+
+    /// Decode a table constant from the bytecode reader
+    fn decode(reader: &mut BCReader) -> Result<KTable, LKQLError> {
+        let array_count = match reader.read_uleb128() {
+            Err(e) => { return Err(e); }
+            Ok(c) => c
+        };
+        let map_count = match reader.read_uleb128() {
+            Err(e) => { return Err(e); }
+            Ok(c) => c
+        };
+
+        let mut res = KTable::new();
+        for _ in 0..array_count {
+            match TableItem::decode(reader) {
+                Err(e) => { return Err(e); }
+                Ok(item) => res.array.push(item)
+            };
+        }
+        for _ in 0..map_count {
+            let key = match TableItem::decode(reader) {
+                Err(e) => { return Err(e); }
+                Ok(item) => item
+            };
+            let val = match TableItem::decode(reader) {
+                Err(e) => { return Err(e); }
+                Ok(item) => item
+            };
+            res.map.insert(key, val);
+        }
+
+        Ok(res)
+    }
 }
 
 // The enum for the table item types
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum TableItem {
     String(KStr),
     Int(i32),
@@ -700,7 +1379,8 @@ impl TableItem {
             TableItem::Int(int) => {
                 res.push(BCDUMP_KTAB_INT as u8);
 
-                let uleb = ULEB128::from(*int as u64);
+                // LuaJIT reads the integer as an unsigned 32 bit value, so avoid the sign extension
+                let uleb = ULEB128::from(*int as u32 as u64);
                 encode_uleb128(&uleb, &mut res);
             },
             TableItem::Num(knum) => {
@@ -723,14 +1403,68 @@ impl TableItem {
         // Return the bytecode
         res
     }
+
+    /// Decode a table item from the bytecode reader
+    fn decode(reader: &mut BCReader) -> Result<TableItem, LKQLError> {
+        let kind = match reader.read_uleb128() {
+            Err(e) => { return Err(e); }
+            Ok(k) => k as u32
+        };
+
+        match kind {
+            BCDUMP_KTAB_NIL => Ok(TableItem::Nil),
+            BCDUMP_KTAB_FALSE => Ok(TableItem::False),
+            BCDUMP_KTAB_TRUE => Ok(TableItem::True),
+            BCDUMP_KTAB_INT => match reader.read_uleb128() {
+                Err(e) => Err(e),
+                Ok(int) => Ok(TableItem::Int(int as u32 as i32))
+            },
+            BCDUMP_KTAB_NUM => match reader.read_lo_hi() {
+                Err(e) => Err(e),
+                Ok(bits) => Ok(TableItem::Num(KNum::new(f64::from_bits(bits))))
+            },
+            _ => match reader.read_bytes((kind - BCDUMP_KTAB_STR) as usize) {
+                Err(e) => Err(e),
+                Ok(content) => Ok(TableItem::String(KStr { content }))
+            }
+        }
+    }
 }
 
-// The structure for the numeric constants
+// The structure for the numeric constants, they are compared by their bits so a constant is
+// always equal to itself (even NaN) and can be used as a table key, the ordering is the IEEE 754
+// total order which is consistent with this equality
 #[derive(Debug)]
 pub struct KNum {
     pub value: f64
 }
 
+impl PartialEq for KNum {
+    fn eq(&self, other: &Self) -> bool {
+        self.value.to_bits() == other.value.to_bits()
+    }
+}
+
+impl Eq for KNum {}
+
+impl PartialOrd for KNum {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for KNum {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.value.total_cmp(&other.value)
+    }
+}
+
+impl Hash for KNum {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.value.to_bits().hash(state);
+    }
+}
+
 impl KNum {
     /// Create a new numeric constant from its value
     pub fn new(value: f64) -> KNum {
@@ -775,7 +1509,7 @@ impl KNum {
 }
 
 // The structure for the complex number constants --  NOT USED IN LKQL
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct KComplex {
     pub number: ULEB128,
     pub imaginary: ULEB128,
@@ -789,7 +1523,7 @@ impl KComplex {
 }
 
 // The enum for the numeric constants
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum NumericConstant {
     Int(i32),
     Num(KNum)
@@ -801,7 +1535,8 @@ impl NumericConstant {
         match self {
             NumericConstant::Int(int) => {
                 let mut res = Vec::new();
-                let uleb = ULEB128::from((*int as u64) << 1);
+                // The value is read on 33 bits, so avoid the sign extension
+                let uleb = ULEB128::from((*int as u32 as u64) << 1);
                 encode_uleb128(&uleb, &mut res);
                 res
             }
@@ -810,16 +1545,354 @@ impl NumericConstant {
             }
         }
     }
+
+    /// Decode a numeric constant from the bytecode reader
+    fn decode(reader: &mut BCReader) -> Result<NumericConstant, LKQLError> {
+        let lo = match reader.read_uleb128() {
+            Err(e) => { return Err(e); }
+            Ok(l) => l
+        };
+
+        // The lowest bit tells if there is a hi part, so if the constant is a number
+        if lo & 0x1 == 0 {
+            Ok(NumericConstant::Int((lo >> 1) as u32 as i32))
+        } else {
+            match reader.read_uleb128() {
+                Err(e) => Err(e),
+                Ok(hi) => Ok(NumericConstant::Num(KNum::new(f64::from_bits((hi << 32) | ((lo >> 1) & 0xFFFFFFFF)))))
+            }
+        }
+    }
+}
+
+// Structure to read a bytecode buffer
+struct BCReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BCReader<'a> {
+    /// Create a new reader at the start of the bytecode
+    fn new(bytes: &'a [u8]) -> BCReader<'a> {
+        BCReader {
+            bytes,
+            position: 0
+        }
+    }
+
+    /// Get the next byte without consuming it
+    fn peek_u8(&self) -> Result<u8, LKQLError> {
+        match self.bytes.get(self.position) {
+            None => Err(LKQLError::new(E_INVALID_BYTECODE, String::from("Unexpected end of bytecode"))),
+            Some(byte) => Ok(*byte)
+        }
+    }
+
+    /// Read the next byte
+    fn read_u8(&mut self) -> Result<u8, LKQLError> {
+        let res = self.peek_u8();
+        if res.is_ok() {
+            self.position += 1;
+        }
+        res
+    }
+
+    /// Read the given number of bytes
+    fn read_bytes(&mut self, count: usize) -> Result<Vec<u8>, LKQLError> {
+        let end = match self.position.checked_add(count) {
+            Some(end) if end <= self.bytes.len() => end,
+            _ => { return Err(LKQLError::new(E_INVALID_BYTECODE, String::from("Unexpected end of bytecode"))); }
+        };
+        let res = Vec::from(&self.bytes[self.position..end]);
+        self.position = end;
+        Ok(res)
+    }
+
+    /// Read a 32 bit integer in the native endianness
+    fn read_u32(&mut self) -> Result<u32, LKQLError> {
+        match self.read_bytes(4) {
+            Err(e) => Err(e),
+            Ok(bytes) => Ok(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+    }
+
+    /// Read a zero terminated name
+    fn read_name(&mut self) -> Result<String, LKQLError> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.read_u8() {
+                Err(e) => { return Err(e); }
+                Ok(0) => { break; }
+                Ok(byte) => bytes.push(byte)
+            };
+        }
+        match String::from_utf8(bytes) {
+            Err(_) => Err(LKQLError::new(E_INVALID_BYTECODE, String::from("Invalid UTF-8 name in the debug info"))),
+            Ok(name) => Ok(name)
+        }
+    }
+
+    /// Read an ULEB128 value
+    fn read_uleb128(&mut self) -> Result<u64, LKQLError> {
+        match ULEB128::read_from(&self.bytes[self.position..]) {
+            Err(_) => Err(LKQLError::new(E_INVALID_BYTECODE, String::from("Invalid ULEB128 value in the bytecode"))),
+            Ok((uleb, size)) => {
+                self.position += size;
+                Ok(u64::from(uleb))
+            }
+        }
+    }
+
+    /// Read a 64 bit value encoded as two ULEB128 lo|hi
+    fn read_lo_hi(&mut self) -> Result<u64, LKQLError> {
+        let lo = match self.read_uleb128() {
+            Err(e) => { return Err(e); }
+            Ok(l) => l
+        };
+        match self.read_uleb128() {
+            Err(e) => Err(e),
+            Ok(hi) => Ok((hi << 32) | (lo & 0xFFFFFFFF))
+        }
+    }
 }
 
 
 // --- Utils functions
 
+/// Add a decoded delta to a pc, none if the result is not a valid 32 bit pc
+fn checked_pc(pc: u32, delta: u64) -> Option<u32> {
+    u32::try_from(delta).ok().and_then(|delta| pc.checked_add(delta))
+}
+
 /// Write the given ULEB128 into the given vector
 fn encode_uleb128(uleb: &ULEB128, vec: &mut Vec<u8>) {
-    let mut buff = [0u8; 32];
-    let buff_len = uleb.write_into(&mut buff).unwrap();
+    let mut buff = [0u8; MAX_ULEB128_SIZE];
+    let buff_len = uleb.write_into(&mut buff)
+        .expect("A 64 bit value always fits in 10 ULEB128 bytes");
     for i in 0..buff_len {
         vec.push(buff[i]);
     }
-}
\ No newline at end of file
+}
+/// Write the given 64 bit value as two ULEB128 lo|hi into the given vector
+fn encode_lo_hi(value: u64, vec: &mut Vec<u8>) {
+    encode_uleb128(&ULEB128::from(value & 0xFFFFFFFF), vec);
+    encode_uleb128(&ULEB128::from(value >> 32), vec);
+}
+
+/// Get the name of the operation code
+pub fn op_name(op_code: u8) -> &'static str {
+    OP_NAMES.get(op_code as usize).copied().unwrap_or("???")
+}
+
+/// Get if the D operand of the operation is a biased jump offset
+fn op_is_jump(op_code: u8) -> bool {
+    matches!(op_code, UCLO | ISNEXT | FORI | JFORI | FORL | IFORL | ITERL | IITERL | LOOP | ILOOP | JMP)
+}
+
+/// Get if the operation is an OP A B C instruction, others are OP A D
+pub fn op_has_bc(op_code: u8) -> bool {
+    matches!(op_code, ADDVN..=CAT | TGETV..=TSETB | TSETR | CALLM | CALL | ITERC | ITERN | VARG)
+}
+
+/// Encode the program, decode the result and compare it to the original one, a false result
+/// means that the encoder and the decoder are asymmetric
+pub fn roundtrip(program: &Program) -> bool {
+    match Program::decode(&program.encode()) {
+        Err(_) => false,
+        Ok(decoded) => decoded == *program
+    }
+}
+
+
+// --- Tests of the bytecode encoding
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a program with a child function, constants of all the kinds and debug info
+    fn sample_program() -> Program {
+        let mut child = Prototype::new(1);
+        child.frame_size = 2;
+        child.instructions = vec![BCInstAD::emit(UGET, 1, 0), BCInstAD::emit(RET1, 1, 2)];
+        child.upval_references = vec![UV_LOCAL | UV_IMMUTABLE];
+        let mut child_debug = DebugInfo::new();
+        child_debug.first_line = 1;
+        child_debug.line_count = 1;
+        child_debug.line_info = vec![0, 1];
+        child_debug.upval_names = vec![String::from("base")];
+        child_debug.var_infos = vec![VarInfo::new(String::from("x"), 0, 2)];
+        child.debug_info = Some(child_debug);
+
+        let mut table = KTable::new();
+        table.array = vec![TableItem::Int(1), TableItem::String(KStr::new(String::from("a")))];
+        table.map.insert(TableItem::True, TableItem::Num(KNum::new(2.5)));
+        let mut main = Prototype::new(0);
+        main.flags = FLAG_P_HAS_CHILD | FLAG_P_IS_VARIADIC;
+        main.frame_size = 3;
+        main.instructions = vec![
+            BCInstAD::emit(KSHORT, 0, 40),
+            BCInstAD::emit(FNEW, 1, 0),
+            BCInstAD::emit(TDUP, 2, 1),
+            BCInstAD::emit(KNUM, 2, 1),
+            BCInstAD::emit(KSTR, 2, 3),
+            BCInstAD::emit(RET0, 0, 1)
+        ];
+        main.complex_constants = vec![
+            ComplexConstant::Child,
+            ComplexConstant::Table(table),
+            ComplexConstant::I64(-5),
+            ComplexConstant::String(KStr::new(String::from("hello")))
+        ];
+        main.numeric_constants = vec![NumericConstant::Int(3), NumericConstant::Num(KNum::new(1.5))];
+
+        Program {
+            header: Header::new_with_debug(String::from("@sample.lkql")),
+            prototypes: vec![child, main]
+        }
+    }
+
+    #[test]
+    fn decode_roundtrip() {
+        assert!(roundtrip(&sample_program()));
+    }
+
+    /// Each truncation of a valid bytecode is a decoding error
+    #[test]
+    fn decode_truncated_bytecode() {
+        let bytecode = sample_program().encode();
+        for len in 0..bytecode.len() {
+            assert!(Program::decode(&bytecode[..len]).is_err(), "The bytecode truncated to {} bytes is decoded", len);
+        }
+    }
+
+    /// The decoding of a garbled bytecode returns, with a program or an error, without panic
+    #[test]
+    fn decode_garbled_bytecode() {
+        let bytecode = sample_program().encode();
+        for i in 0..bytecode.len() {
+            for value in [0x00, 0x01, 0x7F, 0x80, 0xFF] {
+                let mut garbled = bytecode.clone();
+                garbled[i] = value;
+                let _ = Program::decode(&garbled);
+            }
+        }
+    }
+
+    /// The sizes and pcs beyond the address space or the 32 bit pcs are errors
+    #[test]
+    fn decode_overflowing_values() {
+        let mut reader = BCReader::new(&[0, 1, 2]);
+        reader.position = 1;
+        assert!(reader.read_bytes(usize::MAX).is_err());
+
+        let mut bytes = vec![b'x', 0];
+        encode_uleb128(&ULEB128::from(u32::MAX as u64), &mut bytes);
+        encode_uleb128(&ULEB128::from(1), &mut bytes);
+        bytes.push(VARNAME_END);
+        assert!(DebugInfo::decode(&mut BCReader::new(&bytes), 0, 0, 0, 0).is_err());
+
+        let mut bytes = vec![b'x', 0];
+        encode_uleb128(&ULEB128::from(u64::MAX), &mut bytes);
+        encode_uleb128(&ULEB128::from(0), &mut bytes);
+        bytes.push(VARNAME_END);
+        assert!(DebugInfo::decode(&mut BCReader::new(&bytes), 0, 0, 0, 0).is_err());
+    }
+
+    /// Encode the smallest program, a main chunk which only returns, and compare it to the
+    /// bytecode that LuaJIT produces for an empty chunk
+    #[test]
+    fn encode_empty_chunk() {
+        let mut main = Prototype::new(0);
+        main.flags = FLAG_P_IS_VARIADIC;
+        main.frame_size = 2;
+        main.instructions = vec![BCInstAD::emit(RET0, 0, 1)];
+        let mut program = Program::new();
+        program.prototypes.push(main);
+
+        assert_eq!(program.encode(), vec![
+            0x1B, 0x4C, 0x4A, CUR_VERSION, FLAG_H_IS_STRIPPED | FLAG_H_HAS_FFI, // Header
+            0x0B, FLAG_P_IS_VARIADIC, 0x00, 0x02, 0x00, // Prototype size, flags, arity, frame size, upvalues
+            0x00, 0x00, 0x01, // Complex constants, numeric constants, instructions
+            RET0, 0x00, 0x01, 0x00,
+            0x00 // End of the prototypes
+        ]);
+    }
+
+    /// The 64 bit maximum takes the 10 ULEB128 bytes and is decoded back, alone or as the two
+    /// lo|hi parts, and as a 64 bit integer constant
+    #[test]
+    fn encode_uleb128_maximum() {
+        let mut bytes = Vec::new();
+        encode_uleb128(&ULEB128::from(u64::MAX), &mut bytes);
+        assert_eq!(bytes.len(), MAX_ULEB128_SIZE);
+        encode_lo_hi(u64::MAX, &mut bytes);
+        let mut reader = BCReader::new(&bytes);
+        assert_eq!(reader.read_uleb128().ok(), Some(u64::MAX));
+        assert_eq!(reader.read_lo_hi().ok(), Some(u64::MAX));
+        assert_eq!(reader.position, bytes.len());
+
+        let mut program = sample_program();
+        program.prototypes[1].complex_constants.push(ComplexConstant::U64(u64::MAX));
+        program.prototypes[1].complex_constants.push(ComplexConstant::I64(i64::MIN));
+        assert!(roundtrip(&program));
+    }
+
+    /// A string constant of both a parent and a child function is encoded in each of them,
+    /// the duplicated size counts the redundant copies only
+    #[test]
+    fn duplicated_strings() {
+        let mut program = sample_program();
+        assert_eq!(program.duplicated_string_size(), 0);
+        let size = program.encode().len();
+
+        program.prototypes[0].complex_constants.push(ComplexConstant::String(KStr::new(String::from("hello"))));
+        assert_eq!(program.duplicated_string_size(), "hello".len());
+        assert!(program.encode().len() > size + "hello".len());
+        program.prototypes[0].complex_constants.push(ComplexConstant::String(KStr::new(String::from("hello"))));
+        assert_eq!(program.duplicated_string_size(), 2 * "hello".len());
+    }
+
+    /// A table has the same encoding whatever the insertion order of its map entries
+    #[test]
+    fn encode_table_deterministically() {
+        let entries = || vec![
+            (TableItem::String(KStr::new(String::from("b"))), TableItem::Int(2)),
+            (TableItem::Int(7), TableItem::True),
+            (TableItem::Num(KNum::new(-0.5)), TableItem::String(KStr::new(String::from("c")))),
+            (TableItem::String(KStr::new(String::from("a"))), TableItem::False),
+            (TableItem::Num(KNum::new(f64::NAN)), TableItem::Int(1))
+        ];
+        let table = |reversed: bool| {
+            let mut res = KTable::new();
+            let mut items = entries();
+            if reversed {
+                items.reverse();
+            }
+            for (key, value) in items {
+                res.map.insert(key, value);
+            }
+            res
+        };
+
+        let encoded = table(false).encode();
+        assert_eq!(table(false).encode(), encoded);
+        assert_eq!(table(true).encode(), encoded);
+    }
+
+    /// A prototype with as many constants of a kind as the D operand can address is valid, one
+    /// more constant is an error instead of a truncated index
+    #[test]
+    fn constant_count_limit() {
+        let mut proto = Prototype::new(0);
+        proto.numeric_constants = (0..MAX_CONSTANT_COUNT as i32).map(NumericConstant::Int).collect();
+        proto.complex_constants = (0..MAX_CONSTANT_COUNT as i64).map(ComplexConstant::I64).collect();
+        assert!(proto.check_constants().is_ok());
+
+        proto.numeric_constants.push(NumericConstant::Int(-1));
+        assert!(matches!(proto.check_constants(), Err(e) if e.code == E_TOO_COMPLEX));
+        proto.numeric_constants.pop();
+        proto.complex_constants.push(ComplexConstant::I64(-1));
+        assert!(matches!(proto.check_constants(), Err(e) if e.code == E_TOO_COMPLEX));
+    }
+}
@@ -0,0 +1,146 @@
+/*
+This module contains a program builder working directly on the bytecode structures
+It doesn't depend on LKQL nor langkit : a frontend targeting LuaJIT can use it to produce a
+bytecode program from raw instructions and constants
+The prototypes are built like in the LuaJIT parser, a child is opened and ended inside its
+parent and it is placed before it in the program
+*/
+
+use std::collections::HashMap;
+use crate::errors::LKQLError;
+use crate::lkqlc::bc::{BCInstABC, BCInstAD, BCInstruction, ComplexConstant, FLAG_P_HAS_CHILD, FLAG_P_IS_VARIADIC, KStr, NumericConstant, Program, Prototype};
+
+
+// --- The builder structures
+
+// The minimum frame size of a prototype, like in the LuaJIT parser
+const MIN_FRAME_SIZE: u8 = 1;
+
+// A prototype being built and the cache of its string constants
+struct PrototypeBuilder {
+    prototype: Prototype,
+    string_indexes: HashMap<String, u16>,
+}
+
+pub struct ProgramBuilder {
+    program: Program,
+    prototype_stack: Vec<PrototypeBuilder>, // The prototypes being built, the current one is the last
+}
+
+impl Default for ProgramBuilder {
+    fn default() -> ProgramBuilder {
+        let mut main = Prototype::new(0);
+        main.flags |= FLAG_P_IS_VARIADIC;
+        ProgramBuilder {
+            program: Program::new(),
+            prototype_stack: vec![PrototypeBuilder {
+                prototype: main,
+                string_indexes: HashMap::new()
+            }]
+        }
+    }
+}
+
+impl ProgramBuilder {
+    /// Create a new builder, the main chunk prototype is open and next instructions are added
+    /// to it
+    pub fn new() -> ProgramBuilder {
+        ProgramBuilder::default()
+    }
+
+    /// Open a child prototype with the given argument count, next instructions are added to it
+    pub fn begin_prototype(&mut self, arg_count: u8) -> &mut ProgramBuilder {
+        self.prototype_stack.push(PrototypeBuilder {
+            prototype: Prototype::new(arg_count),
+            string_indexes: HashMap::new()
+        });
+        self
+    }
+
+    /// End the current child prototype and return its constant index in the parent, a FNEW
+    /// instruction with this index creates its closure
+    pub fn end_prototype(&mut self) -> Result<u16, LKQLError> {
+        if self.prototype_stack.len() <= 1 {
+            panic!("Cannot end the main chunk prototype, use finish instead");
+        }
+        self.close_current()?;
+
+        let parent = &mut self.current().prototype;
+        parent.flags |= FLAG_P_HAS_CHILD;
+        parent.complex_constants.insert(0, ComplexConstant::Child);
+        Ok((parent.complex_constants.len() - 1) as u16)
+    }
+
+    /// Set the number of slots the current prototype uses, the builder doesn't know which
+    /// instruction operands are slots
+    pub fn set_frame_size(&mut self, frame_size: u8) -> &mut ProgramBuilder {
+        self.current().prototype.frame_size = frame_size;
+        self
+    }
+
+    /// Add an instruction with the AD format to the current prototype
+    pub fn emit_ad(&mut self, op_code: u8, a: u8, d: u16) -> &mut ProgramBuilder {
+        self.current().prototype.instructions.push(BCInstruction::Ad(BCInstAD::new(op_code, a, d)));
+        self
+    }
+
+    /// Add an instruction with the ABC format to the current prototype
+    pub fn emit_abc(&mut self, op_code: u8, a: u8, b: u8, c: u8) -> &mut ProgramBuilder {
+        self.current().prototype.instructions.push(BCInstruction::Abc(BCInstABC::new(op_code, a, b, c)));
+        self
+    }
+
+    /// Add a string constant to the current prototype and return its index, a string is only
+    /// added once
+    pub fn add_string(&mut self, string: &str) -> u16 {
+        let builder = self.current();
+        if let Some(index) = builder.string_indexes.get(string) {
+            return *index;
+        }
+        builder.prototype.complex_constants.insert(0, ComplexConstant::String(KStr::new(String::from(string))));
+        let index = (builder.prototype.complex_constants.len() - 1) as u16;
+        builder.string_indexes.insert(String::from(string), index);
+        index
+    }
+
+    /// Add a numeric constant to the current prototype and return its index
+    pub fn add_number(&mut self, constant: NumericConstant) -> u16 {
+        let prototype = &mut self.current().prototype;
+        prototype.numeric_constants.push(constant);
+        (prototype.numeric_constants.len() - 1) as u16
+    }
+
+    /// Add an upvalue reference to the current prototype and return the upvalue index, see
+    /// UV_LOCAL and UV_IMMUTABLE for the reference format
+    pub fn add_upvalue(&mut self, reference: u16) -> u8 {
+        let prototype = &mut self.current().prototype;
+        prototype.upval_references.push(reference);
+        (prototype.upval_references.len() - 1) as u8
+    }
+
+    /// End the main chunk and return the built program
+    pub fn finish(mut self) -> Result<Program, LKQLError> {
+        if self.prototype_stack.len() != 1 {
+            panic!("Cannot finish the program, {} child prototypes are not ended", self.prototype_stack.len() - 1);
+        }
+        self.close_current()?;
+        Ok(self.program)
+    }
+
+    // --- Internal functions
+
+    /// Get the prototype being built
+    fn current(&mut self) -> &mut PrototypeBuilder {
+        self.prototype_stack.last_mut().unwrap()
+    }
+
+    /// Verify the current prototype and move it in the program
+    fn close_current(&mut self) -> Result<(), LKQLError> {
+        let mut prototype = self.prototype_stack.pop().unwrap().prototype;
+        prototype.check_constants()?;
+        prototype.frame_size = prototype.frame_size.max(MIN_FRAME_SIZE);
+        prototype.derive_flags();
+        self.program.prototypes.push(prototype);
+        Ok(())
+    }
+}
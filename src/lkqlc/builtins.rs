@@ -6,17 +6,208 @@ It also contains all functions to fill environments
 
 // --- Symbols
 
-use crate::lkqlc::env::CompilationEnv;
+use std::collections::HashMap;
+use crate::lkqlc::env::{CompilationEnv, LocalResult};
 
-const BUILD_IN_FUNC: [&str; 1] = [
-    "print"
+// The unit value is a null light userdata set as a global by the runtime, it is distinct from
+// nil so "x == ()" and "x == null" are different tests
+pub const UNIT_GLOBAL_NAME: &str = "__lkql_unit";
+
+// In sandbox mode the globals are resolved in this table, set as a global by the runtime
+pub const SANDBOX_GLOBAL_NAME: &str = "__lkql_env";
+
+// The files to analyse are given to the query as a global array of their absolute paths, the
+// runtime sets it before running the bytecode
+pub const FILES_GLOBAL_NAME: &str = "files";
+
+// The root node of the analysed unit, a query without "from" traverses it
+pub const ROOT_GLOBAL_NAME: &str = "root";
+
+// The analysed unit, the runtime sets it with the root node before running the bytecode
+pub const ANALYSIS_UNIT_GLOBAL_NAME: &str = "unit";
+
+// The runtime function returning the array of the nodes of a subtree, in prefix order
+pub const DESCENDANTS_GLOBAL_NAME: &str = "__lkql_descendants";
+
+// The runtime function getting a node property, the patterns use it to read the node kinds
+pub const NODE_PROPERTY_GLOBAL_NAME: &str = "node_property";
+
+// The runtime function guarding an integer result in checked integer mode, it raises an error
+// if the value is beyond the exact integer range of the doubles
+pub const CHECK_INT_GLOBAL_NAME: &str = "__lkql_check_int";
+
+// The runtime function raising an error with its message argument, it is also available in
+// the sandbox table unlike the Lua "error" function
+pub const ERROR_GLOBAL_NAME: &str = "__lkql_error";
+
+// The runtime function testing if its argument is a list, the list patterns use it before
+// reading the length of the subject
+pub const IS_LIST_GLOBAL_NAME: &str = "__lkql_is_list";
+
+// The generator of the table loops, the runtime also puts it in the sandbox table
+pub const NEXT_GLOBAL_NAME: &str = "next";
+
+// The length of a list or a string, it is not a runtime function : its calls are compiled to the
+// LEN instruction, unless the script defines its own function with this name
+pub const LENGTH_BUILTIN_NAME: &str = "length";
+
+// The printing functions, they show the image of their argument followed by a new line, or
+// without it for the second one
+pub const PRINT_BUILTIN_NAME: &str = "print";
+pub const PRINT_NO_NL_BUILTIN_NAME: &str = "print_no_nl";
+
+// The function returning the string image of a value, the interpolated strings use it to
+// convert their expressions
+pub const IMG_BUILTIN_NAME: &str = "img";
+
+// The functions applying a function to the elements of a list, they are written in Lua
+pub const MAP_BUILTIN_NAME: &str = "map";
+pub const FILTER_BUILTIN_NAME: &str = "filter";
+
+// The minimal number of reads of a builtin in the script to cache it in a main chunk local
+pub const MIN_CACHED_BUILTIN_READS: usize = 2;
+
+
+// --- The builtin registry
+
+/// How a builtin function is implemented in the runtime
+pub enum BuiltinImplementation {
+    /// A C function of the runtime library, pushed with lua_pushcclosure
+    Native,
+    /// A Lua chunk returning the function, it is compiled and run when the environment is set up
+    Lua(&'static str),
+}
+
+pub struct Builtin {
+    pub name: &'static str,
+    pub implementation: BuiltinImplementation,
+}
+
+// The builtin functions visible to the scripts, the compiler declares them and the runtime
+// sets them up according to their implementation
+pub const BUILTINS: [Builtin; 6] = [
+    Builtin { name: PRINT_BUILTIN_NAME, implementation: BuiltinImplementation::Native },
+    Builtin { name: PRINT_NO_NL_BUILTIN_NAME, implementation: BuiltinImplementation::Native },
+    Builtin { name: NODE_PROPERTY_GLOBAL_NAME, implementation: BuiltinImplementation::Native },
+    Builtin { name: IMG_BUILTIN_NAME, implementation: BuiltinImplementation::Native },
+    Builtin {
+        name: MAP_BUILTIN_NAME,
+        implementation: BuiltinImplementation::Lua(
+            "return function(list, f) local res = {} for i = 1, #list do res[i] = f(list[i]) end return res end"
+        )
+    },
+    Builtin {
+        name: FILTER_BUILTIN_NAME,
+        implementation: BuiltinImplementation::Lua(
+            "return function(list, pred) local res = {} for i = 1, #list do if pred(list[i]) then res[#res + 1] = list[i] end end return res end"
+        )
+    }
 ];
 
 // --- Util functions
 
 /// Fill a compilation environment with the global symbols
 pub fn add_builtins(env: &mut CompilationEnv) {
-    for func_name in BUILD_IN_FUNC {
-        env.add_global(String::from(func_name));
+    for builtin in &BUILTINS {
+        env.add_global(String::from(builtin.name));
+    }
+    env.add_global(String::from(UNIT_GLOBAL_NAME));
+    env.add_global(String::from(FILES_GLOBAL_NAME));
+    env.add_global(String::from(ANALYSIS_UNIT_GLOBAL_NAME));
+    env.add_global(String::from(ROOT_GLOBAL_NAME));
+}
+
+/// Load the builtin functions in locals of the main chunk, like "local print = print" in Lua,
+/// then they are read from a slot or an upvalue instead of a global lookup
+/// Only the builtins read several times are cached, an other one would take a slot of the main
+/// chunk and a global read for nothing
+pub fn cache_builtins(env: &mut CompilationEnv, read_counts: &HashMap<String, usize>) {
+    for builtin in &BUILTINS {
+        if read_counts.get(builtin.name).copied().unwrap_or(0) < MIN_CACHED_BUILTIN_READS {
+            continue;
+        }
+
+        // The main chunk has free slots and few constants at this point, the reading of the
+        // global always fits
+        match env.add_local(cached_builtin_name(builtin.name)) {
+            Some(LocalResult::Slot(slot)) if env.emit_global_get(slot, builtin.name) => {
+                env.add_cached_builtin(String::from(builtin.name));
+            }
+            _ => ()
+        }
+    }
+}
+
+/// Get the name of the local holding a cached builtin, it cannot be an LKQL identifier
+pub fn cached_builtin_name(name: &str) -> String {
+    format!("${}", name)
+}
+
+
+// --- Tests of the builtins cache
+
+#[cfg(all(test, not(feature = "bytecode-only")))]
+mod tests {
+    use super::*;
+    use crate::lkqlc::bc::{ComplexConstant, GGET};
+    use crate::lkqlc::ir::{OPT_LEVEL_AGGRESSIVE, OPT_LEVEL_SAFE};
+    use crate::lkqlc::test_utils::{compile_with, run, test_options};
+
+    /// At the aggressive level a builtin read several times is read once from the globals, at
+    /// the start of the main chunk, and the function reads it from an upvalue
+    #[test]
+    fn count_builtin_reads() {
+        let source = "fun f(x) = img(x)\nval a = img(1)\nval b = img(2)\nassert(f(3) == \"3\")";
+        let gget_counts = |optimization_level: u8| {
+            let mut options = test_options();
+            options.optimization_level = optimization_level;
+            let (env, bytecode) = compile_with(source, options);
+            assert_eq!(run(&bytecode), Ok(()));
+            let prototypes = &env.get_program().prototypes;
+            let count = |index: usize| prototypes[index].op_codes().iter().filter(|op_code| **op_code == GGET).count();
+            (count(0), count(prototypes.len() - 1))
+        };
+
+        // The main chunk reads the two "img" and "f" and "assert"
+        assert_eq!(gget_counts(OPT_LEVEL_SAFE), (1, 4));
+        // The main chunk reads "img" once, then "f" and "assert"
+        assert_eq!(gget_counts(OPT_LEVEL_AGGRESSIVE), (0, 3));
+    }
+
+    /// Each function reading "print" has its own string constant for the name, the aggressive
+    /// level reads it in the main chunk only so no name is duplicated
+    #[test]
+    fn duplicated_global_names() {
+        let source = "fun a() = print(1)\nfun b() = print(2)\nfun c() = print(3)";
+        let duplicated_size = |optimization_level: u8| {
+            let mut options = test_options();
+            options.optimization_level = optimization_level;
+            let (env, _) = compile_with(source, options);
+            env.get_program().duplicated_string_size()
+        };
+        assert_eq!(duplicated_size(OPT_LEVEL_SAFE), 2 * "print".len());
+        assert_eq!(duplicated_size(OPT_LEVEL_AGGRESSIVE), 0);
+    }
+
+    /// A builtin read once or never is not cached, its name is not a constant of the main chunk
+    /// and it takes no slot of it : only the cached one widens the frame
+    #[test]
+    fn unused_builtin_not_cached() {
+        let frame_size = |source: &str, is_cached: bool| {
+            let mut options = test_options();
+            options.optimization_level = OPT_LEVEL_AGGRESSIVE;
+            let (env, bytecode) = compile_with(source, options);
+            assert_eq!(run(&bytecode), Ok(()));
+            assert_eq!(env.is_cached_builtin(IMG_BUILTIN_NAME), is_cached, "{}", source);
+            assert!(!env.is_cached_builtin(PRINT_BUILTIN_NAME));
+
+            let main = env.get_program().prototypes.last().unwrap();
+            assert!(main.complex_constants.iter().all(|constant| !matches!(
+                constant,
+                ComplexConstant::String(name) if name.content == PRINT_BUILTIN_NAME.as_bytes()
+            )));
+            main.frame_size
+        };
+        assert_eq!(frame_size("val a = img(1)\nval b = img(2)", true), frame_size("val a = img(1)", false) + 1);
     }
 }
@@ -0,0 +1,134 @@
+/*
+This module defines the emitter abstraction, the minimal interface a node handler needs to
+produce its instructions
+The compilation environment is the real emitter, a handler written against the trait can also
+be driven by any other implementation (Ex: one recording the emitted instructions)
+*/
+
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::ir::{IRArg, IRInstABC, IRInstAD, IRInstruction};
+
+
+// --- The emitter trait
+
+pub trait Emitter {
+    /// Get the slot the current expression result goes in, none if the result is not needed
+    fn get_expr_slot(&self) -> Option<u8>;
+
+    /// Emit an instruction with the AD format
+    fn emit_ad(&mut self, op_code: u8, a: IRArg, d: IRArg);
+
+    /// Emit an instruction with the ABC format
+    fn emit_abc(&mut self, op_code: u8, a: IRArg, b: IRArg, c: IRArg);
+
+    /// Add a string constant to the current function and return its index
+    fn add_string_constant(&mut self, string: String) -> u16;
+
+    /// Get a new temporary slot, none if all the slots are busy
+    fn new_tmp(&mut self) -> Option<u8>;
+
+    /// Free a temporary slot
+    fn free_tmp(&mut self, slot: u8);
+}
+
+
+// --- The compilation environment emitter
+
+impl Emitter for CompilationEnv {
+    fn get_expr_slot(&self) -> Option<u8> {
+        CompilationEnv::get_expr_slot(self)
+    }
+
+    fn emit_ad(&mut self, op_code: u8, a: IRArg, d: IRArg) {
+        self.add_instruction(IRInstruction::AD(IRInstAD::new(op_code, a, d)));
+    }
+
+    fn emit_abc(&mut self, op_code: u8, a: IRArg, b: IRArg, c: IRArg) {
+        self.add_instruction(IRInstruction::ABC(IRInstABC::new(op_code, a, b, c)));
+    }
+
+    fn add_string_constant(&mut self, string: String) -> u16 {
+        CompilationEnv::add_string_constant(self, string)
+    }
+
+    fn new_tmp(&mut self) -> Option<u8> {
+        CompilationEnv::new_tmp(self)
+    }
+
+    fn free_tmp(&mut self, slot: u8) {
+        CompilationEnv::free_tmp(self, slot)
+    }
+}
+
+
+// --- Tests of the emitter abstraction
+
+#[cfg(all(test, not(feature = "bytecode-only")))]
+mod tests {
+    use super::*;
+    use crate::lkqlc::bc::{KPRI, KSTR};
+    use crate::lkqlc::nodes::{bool_literal, string_literal};
+
+    // An emitter recording the image of the emitted instructions
+    struct RecordingEmitter {
+        expr_slot: Option<u8>,
+        instructions: Vec<String>,
+        strings: Vec<String>,
+        busy_slots: Vec<u8>
+    }
+
+    impl Emitter for RecordingEmitter {
+        fn get_expr_slot(&self) -> Option<u8> {
+            self.expr_slot
+        }
+
+        fn emit_ad(&mut self, op_code: u8, a: IRArg, d: IRArg) {
+            self.instructions.push(format!("{} {:?} {:?}", op_code, a, d));
+        }
+
+        fn emit_abc(&mut self, op_code: u8, a: IRArg, b: IRArg, c: IRArg) {
+            self.instructions.push(format!("{} {:?} {:?} {:?}", op_code, a, b, c));
+        }
+
+        fn add_string_constant(&mut self, string: String) -> u16 {
+            self.strings.push(string);
+            (self.strings.len() - 1) as u16
+        }
+
+        fn new_tmp(&mut self) -> Option<u8> {
+            let slot = self.busy_slots.len() as u8;
+            self.busy_slots.push(slot);
+            Some(slot)
+        }
+
+        fn free_tmp(&mut self, slot: u8) {
+            self.busy_slots.retain(|busy| *busy != slot);
+        }
+    }
+
+    /// The handlers written against the trait are driven by another emitter than the
+    /// compilation environment
+    #[test]
+    fn record_handler_instructions() {
+        let mut emitter = RecordingEmitter {
+            expr_slot: Some(3),
+            instructions: Vec::new(),
+            strings: Vec::new(),
+            busy_slots: Vec::new()
+        };
+        assert!(bool_literal::compile_true(&mut emitter).is_ok());
+        assert!(bool_literal::compile_false(&mut emitter).is_ok());
+        string_literal::emit_constant(String::from("hello"), 4, &mut emitter);
+        assert_eq!(emitter.instructions, vec![
+            format!("{} Slot(3) Primitive(True)", KPRI),
+            format!("{} Slot(3) Primitive(False)", KPRI),
+            format!("{} Slot(4) Str(0)", KSTR)
+        ]);
+        assert_eq!(emitter.strings, vec![String::from("hello")]);
+
+        // Without result slot, the literals emit nothing
+        emitter.expr_slot = None;
+        assert!(bool_literal::compile_true(&mut emitter).is_ok());
+        assert_eq!(emitter.instructions.len(), 3);
+    }
+}
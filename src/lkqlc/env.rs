@@ -2,66 +2,304 @@
 This module define the lexical environment for the LKQL analysis and compilation
 */
 
-use std::collections::{HashMap, HashSet};
-use std::thread::sleep;
-use crate::lkql_wrapper::{__syscall_slong_t, lkql_source_location};
-use crate::lkqlc::bc::{FLAG_P_HAS_CHILD, BCInstABC, BCInstAD, BCInstruction, JUMP_BIASING, Program, Prototype, RET0, RET1, UCLO, ComplexConstant, KStr};
-use crate::lkqlc::builtins::add_builtins;
-use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction, process_ir, Slot};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::lkqlc::bc::{DebugInfo, Header, VarInfo, CALL, CALLM, FLAG_P_HAS_CHILD, FLAG_P_IS_VARIADIC, ISF, ISNEXT, ITERL, ITERN, JMP, JUMP_BIASING, KNIL, KNUM, KPRI, MOV, Program, Prototype, RET0, RET1, UCLO, ComplexConstant, KStr, KTable, NumericConstant, GGET, GSET, KSTR, TGETS, TGETV, TSETS, TSETV, UGET, UV_IMMUTABLE, UV_LOCAL};
+use crate::errors::{LKQLError, LKQLWarning, SourceLocation};
+use crate::lkql_trace;
+use crate::lkqlc::builtins::{add_builtins, cache_builtins, ERROR_GLOBAL_NAME, NEXT_GLOBAL_NAME, SANDBOX_GLOBAL_NAME};
+use crate::lkqlc::ir::{IRArg, IRInstABC, IRInstAD, IRInstruction, OPT_LEVEL_AGGRESSIVE, OPT_LEVEL_SAFE, Primitive, process_ir, check_constant_args, get_label_position};
+
+
+// --- Define the environment constants
+
+const MIN_FRAME_SIZE: u8 = 1;
+// The maximum frame size of a LuaJIT function (LJ_MAX_SLOTS), the slots above are never given
+pub const MAX_SLOTS: usize = 250;
+pub const DEFAULT_MAX_DEPTH: usize = 512;
+
+
+// --- Define the compilation options
+
+#[derive(Debug, Clone)]
+pub struct CompilationOptions {
+    pub optimization_level: u8, // The level of optimization passes to run on the IR
+    pub max_depth: usize, // The maximum nesting depth of the compiled nodes
+    pub sandbox: bool, // If the globals are resolved in the sandbox table instead of the Lua globals
+    pub strict: bool, // If the suspicious constructs are errors instead of warnings
+    pub report_unsupported: bool, // If the unsupported nodes are recorded instead of failing
+    pub checked_int: bool, // If the integer results are guarded against the precision loss
+    pub debug_info: bool, // If the bytecode contains the debug info (local variable names)
+    pub host_globals: Vec<String>, // The globals that the host program defines in the Lua state
+    pub entry_point: Option<String>, // The function called at the end of the main chunk, its result is returned
+    pub return_last_expr: bool, // If the main chunk returns the value of its last top level expression
+}
+
+impl Default for CompilationOptions {
+    fn default() -> CompilationOptions {
+        CompilationOptions {
+            optimization_level: OPT_LEVEL_SAFE,
+            max_depth: DEFAULT_MAX_DEPTH,
+            sandbox: false,
+            strict: false,
+            report_unsupported: false,
+            checked_int: false,
+            debug_info: false,
+            host_globals: Vec::new(),
+            entry_point: None,
+            return_last_expr: false
+        }
+    }
+}
+
+impl CompilationOptions {
+    /// Create the default compilation options
+    pub fn new() -> CompilationOptions {
+        CompilationOptions::default()
+    }
+}
 
 
 // --- Define the environment structures
 
 #[derive(Debug)]
 pub struct CompilationEnv {
+    options: CompilationOptions,
     bytecode: Program,
 
     global_var: HashSet<String>,
+    declared_globals: HashSet<String>, // The globals declared by the top level values of the script
+    cached_builtins: HashSet<String>,
     local_env_stack: Vec<LocalEnv>,
 
     module_name: String,
+    node_depth: usize,
+    warnings: Vec<LKQLWarning>,
+    unsupported_nodes: BTreeMap<String, usize>,
+    annotations: Vec<Annotation>,
+}
+
+/// An annotation of a declaration, like "@check(message=\"...\")" on a rule function
+#[derive(Debug, Clone)]
+pub struct Annotation {
+    pub decl_name: String, // The name of the annotated declaration
+    pub name: String, // The annotation name, without the "@"
+    pub arguments: Vec<String>, // The source text of the arguments, in order
+    pub location: SourceLocation,
+}
+
+/// The state of a generic for loop over a table, between its opening and its closing
+/// The loop uses three control slots just below its base, in the order of the Lua generic for :
+///   base - 3 : the generator, the "next" function
+///   base - 2 : the state, the iterated table
+///   base - 1 : the control variable, nil at start then the iteration index set by ISNEXT
+/// The key and the value of the current iteration are in the base and base + 1 slots
+#[derive(Debug)]
+pub struct TableLoop {
+    pub key_slot: u8,
+    pub value_slot: u8,
+    slots: Vec<u8>,
+    body_label: u64,
+    iter_label: u64,
+}
+
+/// The contiguous block of slots of a call : the callee in the base slot followed by the
+/// arguments, the results are left from the base slot
+#[derive(Debug)]
+pub struct CallFrame {
+    pub base: u8,
+    pub arg_count: u8,
+    owned_slots: Vec<u8>,
+}
+
+impl CallFrame {
+    /// Get the slot of the argument at the given index, from 0
+    pub fn arg_slot(&self, index: u8) -> u8 {
+        self.base + 1 + index
+    }
 }
 
 impl CompilationEnv {
     /// Create a new compilation environment
-    pub fn new() -> CompilationEnv {
+    pub fn new(options: CompilationOptions) -> CompilationEnv {
+        let debug_info = options.debug_info;
         let mut res = CompilationEnv {
-            bytecode: Program::new(),
+            options,
+            bytecode: new_program(debug_info),
 
             global_var: HashSet::new(),
-            local_env_stack: vec![LocalEnv::new(0, 0)],
-
-            module_name: String::from("")
+            declared_globals: HashSet::new(),
+            cached_builtins: HashSet::new(),
+            local_env_stack: vec![LocalEnv::new(0, 0, debug_info)],
+
+            module_name: String::from(""),
+            node_depth: 0,
+            warnings: Vec::new(),
+            unsupported_nodes: BTreeMap::new(),
+            annotations: Vec::new()
         };
         add_builtins(&mut res);
+        res.add_host_globals();
+        res.init_sandbox();
 
         res
     }
 
+    /// Reset the environment to its initial state to reuse it for another compilation
+    /// The options are kept but all global symbols are cleared, only the builtins are added back
+    pub fn reset(&mut self) {
+        self.bytecode = new_program(self.options.debug_info);
+        self.global_var.clear();
+        self.declared_globals.clear();
+        self.cached_builtins.clear();
+        self.local_env_stack = vec![LocalEnv::new(0, 0, self.options.debug_info)];
+        self.module_name = String::from("");
+        self.node_depth = 0;
+        self.warnings.clear();
+        self.unsupported_nodes.clear();
+        self.annotations.clear();
+        add_builtins(self);
+        self.add_host_globals();
+        self.init_sandbox();
+    }
+
+    /// Set the chunk name written in the bytecode header, LuaJIT shows it in the error messages
+    /// The stripped bytecode has no name in its header and takes the one given at the loading
+    pub fn set_chunk_name(&mut self, name: String) {
+        if !self.bytecode.header.is_stripped() {
+            self.bytecode.header.chunk_name = Vec::from(name);
+        }
+    }
+
+    /// Get the compiled program
+    pub fn get_program(&self) -> &Program {
+        &self.bytecode
+    }
+
+    /// Take the compiled program out of the environment
+    pub fn take_program(&mut self) -> Program {
+        std::mem::replace(&mut self.bytecode, Program::new())
+    }
+
     /// Get the bytecode, result of the compilation
     pub fn get_bytecode(&self) -> Vec<u8> {
         self.bytecode.encode()
     }
 
+    /// Get if the suspicious constructs must be reported as errors
+    pub fn is_strict(&self) -> bool {
+        self.options.strict
+    }
+
+    // --- Diagnostics
+
+    /// Add a warning to the compilation diagnostics
+    pub fn add_warning(&mut self, warning: LKQLWarning) {
+        self.warnings.push(warning);
+    }
+
+    /// Get the warnings emitted during the compilation
+    pub fn get_warnings(&self) -> &Vec<LKQLWarning> {
+        &self.warnings
+    }
+
+    /// Get if the integer operation results must be guarded against the precision loss
+    pub fn is_int_checked(&self) -> bool {
+        self.options.checked_int
+    }
+
+    /// Get the name of the function to call at the end of the main chunk, if any
+    pub fn get_entry_point(&self) -> Option<String> {
+        self.options.entry_point.clone()
+    }
+
+    /// Get if the main chunk returns the value of its last top level expression
+    pub fn returns_last_expr(&self) -> bool {
+        self.options.return_last_expr
+    }
+
+    /// Get the declared global symbols, the builtins included
+    pub fn get_globals(&self) -> &HashSet<String> {
+        &self.global_var
+    }
+
+    /// Get if the unsupported nodes are recorded instead of failing the compilation
+    pub fn is_reporting_unsupported(&self) -> bool {
+        self.options.report_unsupported
+    }
+
+    /// Record an encountered node kind that has no compilation handler
+    pub fn add_unsupported_node(&mut self, kind_name: String) {
+        *self.unsupported_nodes.entry(kind_name).or_insert(0) += 1;
+    }
+
+    /// Get the encountered unsupported node kinds with their number of occurrences
+    pub fn get_unsupported_nodes(&self) -> &BTreeMap<String, usize> {
+        &self.unsupported_nodes
+    }
+
+    /// Record the annotation of a declaration
+    pub fn add_annotation(&mut self, annotation: Annotation) {
+        self.annotations.push(annotation);
+    }
+
+    /// Get the recorded declaration annotations, in source order
+    pub fn get_annotations(&self) -> &Vec<Annotation> {
+        &self.annotations
+    }
+
+    // --- Nesting depth
+
+    /// Enter a node compilation, return false if the maximum nesting depth is reached
+    pub fn enter_node(&mut self) -> bool {
+        if self.node_depth >= self.options.max_depth {
+            return false;
+        }
+        self.node_depth += 1;
+        true
+    }
+
+    /// Exit a node compilation
+    pub fn exit_node(&mut self) {
+        self.node_depth -= 1;
+    }
+
     // --- Env management
 
     /// Open a new local environment and place it at the top of the stack
+    /// The current local environment is always the first of the stack
     pub fn open_env(&mut self, arg_count: u8) {
-        let new_env = LocalEnv::new(self.local_env_stack.first().unwrap().depth, arg_count);
-        self.local_env_stack.push(new_env);
+        let new_env = LocalEnv::new(self.local_env_stack.first().unwrap().depth + 1, arg_count, self.options.debug_info);
+        self.local_env_stack.insert(0, new_env);
     }
 
-    /// Close the currently open local environment
-    pub fn close_env(&mut self) {
+    /// Close the currently open local environment, it fails if its code cannot be encoded
+    /// The main local env stays on the stack marked as finalized, so a late emission is caught
+    pub fn close_env(&mut self) -> Result<(), LKQLError> {
         // Close the current local env and put it into the program
-        let mut to_close = self.local_env_stack.pop().unwrap();
-        to_close.finalize();
+        let optimization_level = self.options.optimization_level;
+        let to_close = self.current_env();
+        to_close.finalize(optimization_level)?;
+        if self.local_env_stack.len() == 1 {
+            let to_close = self.local_env_stack.first_mut().unwrap();
+            let prototype = std::mem::replace(&mut to_close.prototype, Prototype::new(0));
+            self.bytecode.prototypes.push(prototype);
+            return Ok(());
+        }
+        let to_close = self.local_env_stack.remove(0);
         self.bytecode.prototypes.push(to_close.prototype);
 
         // Tell the upper env that it has a child
-        if !self.local_env_stack.is_empty() {
-            self.local_env_stack.first_mut().unwrap().has_child = true;
+        self.local_env_stack.first_mut().unwrap().has_child = true;
+        Ok(())
+    }
+
+    /// Get the current local env to generate code in it, it must not be finalized
+    fn current_env(&mut self) -> &mut LocalEnv {
+        let local_env = self.local_env_stack.first_mut().unwrap();
+        if local_env.finalized {
+            panic!("Internal error : code generation in a closed environment, the main prototype is already finalized");
         }
+        local_env
     }
 
     /// Open a new pseudo local environment
@@ -70,28 +308,246 @@ impl CompilationEnv {
         local_env.open_pseudo_env();
     }
 
+    /// Get if the compilation is currently in the top level scope of the script
+    pub fn is_top_level(&self) -> bool {
+        self.local_env_stack.len() == 1 && self.local_env_stack.first().unwrap().local_var_stack.len() == 1
+    }
+
     /// Close the current pseudo local environment
     pub fn close_pseudo_env(&mut self) {
         let mut local_env = self.local_env_stack.first_mut().unwrap();
         local_env.close_pseudo_env();
     }
 
-    /// Just add the global symbol to the context
+    /// Just add the global symbol to the context, a builtin redefined by the script is not
+    /// cached anymore
     pub fn add_global(&mut self, name: String) {
+        self.cached_builtins.remove(&name);
         self.global_var.insert(name);
     }
 
+    /// Declare a global for a top level value of the script, unlike the other globals it
+    /// cannot be declared twice
+    pub fn add_declared_global(&mut self, name: String) {
+        self.declared_globals.insert(name.clone());
+        self.add_global(name);
+    }
+
+    /// Get if the global is declared by a top level value of the script
+    pub fn is_declared_global(&self, name: &str) -> bool {
+        self.declared_globals.contains(name)
+    }
+
+    /// Declare a global that the host program defines in the Lua state, like a registered host
+    /// function, it is kept in the options so it is declared again after a reset
+    pub fn add_host_global(&mut self, name: String) {
+        if !self.options.host_globals.contains(&name) {
+            self.options.host_globals.push(name.clone());
+        }
+        self.add_global(name);
+    }
+
+    /// Add the globals of the host program from the options
+    fn add_host_globals(&mut self) {
+        for name in self.options.host_globals.clone() {
+            self.add_global(name);
+        }
+    }
+
+    /// Mark the builtin as cached in a local of the main chunk
+    pub fn add_cached_builtin(&mut self, name: String) {
+        self.cached_builtins.insert(name);
+    }
+
+    /// Get if the builtin is cached in a local of the main chunk
+    pub fn is_cached_builtin(&self, name: &str) -> bool {
+        self.cached_builtins.contains(name)
+    }
+
     /// Get if the global variable exists
     pub fn get_global(&self, name: &str) -> bool {
         self.global_var.contains(name)
     }
 
-    /// Add the symbol to the local ones and return the associated slot or name
-    pub fn add_local(&mut self, name: String) -> LocalResult {
+    /// Emit the reading of the global variable in the given slot
+    /// Return false if there is no slot left for the sandbox table or the name key
+    pub fn emit_global_get(&mut self, slot: u8, name: &str) -> bool {
+        let name_index = self.add_string_constant(String::from(name));
+        if !self.options.sandbox {
+            self.add_instruction(IRInstruction::AD(IRInstAD::new(
+                GGET,
+                IRArg::Slot(slot),
+                IRArg::Str(name_index)
+            )));
+            return true;
+        }
+
+        // Load the sandbox table in the result slot if it is not a local
+        let (env_slot, _) = match self.load_sandbox_env(Some(slot)) {
+            Some(res) => res,
+            None => { return false; }
+        };
+        self.emit_table_access(TGETS, TGETV, slot, env_slot, name_index)
+    }
+
+    /// Emit the writing of the value slot in the global variable
+    /// Return false if there is no slot left for the sandbox table or the name key
+    pub fn emit_global_set(&mut self, slot: u8, name: &str) -> bool {
+        let name_index = self.add_string_constant(String::from(name));
+        if !self.options.sandbox {
+            self.add_instruction(IRInstruction::AD(IRInstAD::new(
+                GSET,
+                IRArg::Slot(slot),
+                IRArg::Str(name_index)
+            )));
+            return true;
+        }
+
+        let (env_slot, is_loaded) = match self.load_sandbox_env(None) {
+            Some(res) => res,
+            None => { return false; }
+        };
+        let is_emitted = self.emit_table_access(TSETS, TSETV, slot, env_slot, name_index);
+        if is_loaded {
+            self.free_tmp(env_slot);
+        }
+        is_emitted
+    }
+
+    /// Cache the builtins in the main chunk locals at the aggressive optimization level, it must
+    /// be done before the compilation of the script with the read counts of its builtins
+    pub fn init_builtins_cache(&mut self, read_counts: &HashMap<String, usize>) {
+        if self.options.optimization_level >= OPT_LEVEL_AGGRESSIVE {
+            cache_builtins(self, read_counts);
+        }
+    }
+
+    /// Bind the sandbox table to the first local of the main chunk if the sandbox is enabled
+    fn init_sandbox(&mut self) {
+        if !self.options.sandbox {
+            return;
+        }
+        match self.add_local(String::from(SANDBOX_GLOBAL_NAME)) {
+            Some(LocalResult::Slot(slot)) => {
+                let name_index = self.add_string_constant(String::from(SANDBOX_GLOBAL_NAME));
+                self.add_instruction(IRInstruction::AD(IRInstAD::new(
+                    GGET,
+                    IRArg::Slot(slot),
+                    IRArg::Str(name_index)
+                )));
+            }
+            _ => panic!("The first local of the main chunk is always in a slot")
+        }
+    }
+
+    /// Get the slot of the sandbox table and if it has been loaded from an upvalue, in this case
+    /// it is loaded in the given slot or in a new temporary one, none if there is no slot left
+    fn load_sandbox_env(&mut self, dest: Option<u8>) -> Option<(u8, bool)> {
+        if let LocalResult::Slot(slot) = self.get_local(SANDBOX_GLOBAL_NAME) {
+            return Some((slot, false));
+        }
+        match self.get_upvalue(SANDBOX_GLOBAL_NAME) {
+            UpvalueResult::Slot(uv) => {
+                let slot = match dest {
+                    Some(slot) => slot,
+                    None => match self.new_tmp() {
+                        Some(slot) => slot,
+                        None => { return None; }
+                    }
+                };
+                self.add_instruction(IRInstruction::AD(IRInstAD::new(
+                    UGET,
+                    IRArg::Slot(slot),
+                    IRArg::Upvalue(uv)
+                )));
+                Some((slot, true))
+            }
+            _ => panic!("The sandbox table is always reachable from the main chunk")
+        }
+    }
+
+    /// Emit a table access with a string key, the key is loaded in a slot if its constant
+    /// index doesn't fit in the C operand. Return false if there is no slot left for the key
+    fn emit_table_access(&mut self, str_op: u8, var_op: u8, slot: u8, table_slot: u8, name_index: u16) -> bool {
+        if name_index <= 0xFF {
+            self.add_instruction(IRInstruction::ABC(IRInstABC::new(
+                str_op,
+                IRArg::Slot(slot),
+                IRArg::Slot(table_slot),
+                IRArg::Str(name_index)
+            )));
+        } else {
+            let key_slot = match self.new_tmp() {
+                Some(slot) => slot,
+                None => { return false; }
+            };
+            self.add_instruction(IRInstruction::AD(IRInstAD::new(
+                KSTR,
+                IRArg::Slot(key_slot),
+                IRArg::Str(name_index)
+            )));
+            self.add_instruction(IRInstruction::ABC(IRInstABC::new(
+                var_op,
+                IRArg::Slot(slot),
+                IRArg::Slot(table_slot),
+                IRArg::Slot(key_slot)
+            )));
+            self.free_tmp(key_slot);
+        }
+        true
+    }
+
+    /// Emit an arithmetic operation between a slot and a numeric constant, like the table
+    /// accesses the constant is loaded in a slot if its index doesn't fit in the C operand
+    /// Return false if there is no slot left for the constant
+    pub fn emit_arith_constant(&mut self, num_op: u8, var_op: u8, dest: u8, slot: u8, num_index: u16) -> bool {
+        if num_index <= 0xFF {
+            self.add_instruction(IRInstruction::ABC(IRInstABC::new(
+                num_op,
+                IRArg::Slot(dest),
+                IRArg::Slot(slot),
+                IRArg::Num(num_index)
+            )));
+        } else {
+            let num_slot = match self.new_tmp() {
+                Some(slot) => slot,
+                None => { return false; }
+            };
+            self.add_instruction(IRInstruction::AD(IRInstAD::new(
+                KNUM,
+                IRArg::Slot(num_slot),
+                IRArg::Num(num_index)
+            )));
+            self.add_instruction(IRInstruction::ABC(IRInstABC::new(
+                var_op,
+                IRArg::Slot(dest),
+                IRArg::Slot(slot),
+                IRArg::Slot(num_slot)
+            )));
+            self.free_tmp(num_slot);
+        }
+        true
+    }
+
+    /// Add the symbol to the local ones and return the associated slot or name, none if all the
+    /// slots of the function are occupied
+    pub fn add_local(&mut self, name: String) -> Option<LocalResult> {
         let mut local_env = self.local_env_stack.first_mut().unwrap();
         local_env.add_local(name)
     }
 
+    /// Add the symbol as an alias of the local in the given slot, no slot is allocated
+    pub fn add_local_alias(&mut self, name: String, slot: u8) -> LocalResult {
+        let local_env = self.local_env_stack.first_mut().unwrap();
+        local_env.add_local_alias(name, slot)
+    }
+
+    /// Get if the symbol is already declared in the current pseudo local environment
+    pub fn is_declared_in_scope(&self, name: &str) -> bool {
+        let local_env = self.local_env_stack.first().unwrap();
+        local_env.is_declared_in_scope(name)
+    }
+
     /// Get the slot or local name for the original name, this only looks in the current local env
     pub fn get_local(&self, name: &str) -> LocalResult {
         let local_env = self.local_env_stack.first().unwrap();
@@ -127,7 +583,9 @@ impl CompilationEnv {
         match upper_local_res {
             // If the name is an upper local
             LocalResult::Slot(slot) => {
-                new_uv_reference = 0xC000 | (slot as u16);
+                // LKQL values are never reassigned, so the captured locals are immutable
+                new_uv_reference = UV_LOCAL | UV_IMMUTABLE | (slot as u16);
+                self.local_env_stack.get_mut(depth + 1).unwrap().captured_slots.insert(slot);
             }
 
             // If the name is an upper pseudo-local
@@ -140,6 +598,7 @@ impl CompilationEnv {
                 let upper_res = self.lookup_uv(name, depth + 1);
                 match upper_res {
                     UpvalueResult::Slot(slot) => {
+                        // The upvalue of the upper env is referenced by its index only
                         new_uv_reference = slot as u16;
                     }
                     _ => { return upper_res; }
@@ -173,18 +632,40 @@ impl CompilationEnv {
         self.local_env_stack.first_mut().unwrap().return_slot = slot;
     }
 
-    /// Get a temporary slot
-    pub fn new_tmp(&mut self) -> u8 {
-        let mut local_env = self.local_env_stack.first_mut().unwrap();
+    /// Get the frame size of the current prototype, including all used temporary slots
+    pub fn current_frame_size(&self) -> u8 {
+        self.local_env_stack.first().unwrap().frame_size
+    }
+
+    /// Get a temporary slot, none if all the slots are busy
+    pub fn new_tmp(&mut self) -> Option<u8> {
+        let local_env = self.current_env();
         local_env.new_tmp()
     }
 
-    /// Get n temporary contiguous slots
-    pub fn new_tmps(&mut self, n: u8) -> Vec<u8> {
-        let mut local_env = self.local_env_stack.first_mut().unwrap();
+    /// Get n temporary contiguous slots, none if there is no such free slot range
+    pub fn new_tmps(&mut self, n: u8) -> Option<Vec<u8>> {
+        let local_env = self.current_env();
         local_env.new_tmps(n)
     }
 
+    /// Get n temporary contiguous slots from the given one, none if one of them is busy
+    pub fn new_tmps_at(&mut self, start: u8, n: u8) -> Option<Vec<u8>> {
+        let local_env = self.current_env();
+        local_env.new_tmps_at(start as usize, n)
+    }
+
+    /// Get n temporary contiguous slots above all the busy ones, this is where the call frames
+    /// go so a call can leave a variable number of results on the stack
+    pub fn new_top_tmps(&mut self, n: u8) -> Option<Vec<u8>> {
+        let local_env = self.current_env();
+        let top = match local_env.occupied_slot.iter().rposition(|occupied| *occupied) {
+            Some(last) => last + 1,
+            None => 0
+        };
+        local_env.new_tmps_at(top, n)
+    }
+
     /// Free an temporary used slot
     pub fn free_tmp(&mut self, slot: u8) {
         let mut local_env = self.local_env_stack.first_mut().unwrap();
@@ -207,12 +688,232 @@ impl CompilationEnv {
         local_env.new_label()
     }
 
+    /// Place the label on the next added instruction
+    pub fn place_label(&mut self, label: u64) {
+        let local_env = self.local_env_stack.first_mut().unwrap();
+        local_env.pending_labels.push(label);
+    }
+
     /// Add an instruction to the current prototype
     pub fn add_instruction(&mut self, inst: IRInstruction) {
-        let mut local_env = self.local_env_stack.first_mut().unwrap();
+        let local_env = self.current_env();
         local_env.add_instruction(inst);
     }
 
+    /// Add an unconditional jump to the given label
+    pub fn add_jump(&mut self, label: u64) {
+        // The A operand tells the JIT which slots are still alive, so keep all used slots
+        let base = self.local_env_stack.first().unwrap().frame_size;
+        self.add_instruction(IRInstruction::AD(IRInstAD::new(
+            JMP,
+            IRArg::Slot(base),
+            IRArg::Jump(label)
+        )));
+    }
+
+    /// Emit a branch on the truthiness of the value in the slot, with the Lua semantics also
+    /// used by LKQL : only null and false are falsy, so 0 and the empty string are truthy
+    pub fn emit_truthy_branch(&mut self, slot: u8, true_label: u64, false_label: u64) {
+        // ISF executes the next jump when the value is falsy
+        self.add_instruction(IRInstruction::AD(IRInstAD::new(
+            ISF,
+            IRArg::None,
+            IRArg::Slot(slot)
+        )));
+        self.add_jump(false_label);
+        self.add_jump(true_label);
+    }
+
+    /// Open a generic for loop over the table in the given slot with the fast table iteration
+    /// protocol : ISNEXT checks the control slots and jumps to the ITERN of the loop end
+    /// The loop slots are at the top of the stack since an iteration may call the generator
+    pub fn open_table_loop(&mut self, table_slot: u8) -> Option<TableLoop> {
+        let slots = match self.new_top_tmps(5) {
+            Some(slots) => slots,
+            None => { return None; }
+        };
+        let base = slots[3];
+
+        // Initialize the control slots
+        if !self.emit_global_get(base - 3, NEXT_GLOBAL_NAME) {
+            self.free_tmps(slots);
+            return None;
+        }
+        self.add_instruction(IRInstruction::AD(IRInstAD::new(
+            MOV,
+            IRArg::Slot(base - 2),
+            IRArg::Slot(table_slot)
+        )));
+        self.emit_load_nil(base - 1, 1);
+
+        // Jump to the iteration, the loop body starts just after
+        let body_label = self.new_label();
+        let iter_label = self.new_label();
+        self.add_instruction(IRInstruction::AD(IRInstAD::new(
+            ISNEXT,
+            IRArg::Slot(base),
+            IRArg::Jump(iter_label)
+        )));
+        self.place_label(body_label);
+
+        Some(TableLoop {
+            key_slot: base,
+            value_slot: base + 1,
+            slots,
+            body_label,
+            iter_label
+        })
+    }
+
+    /// Close the generic for loop after its body : ITERN gets the next key and value, and ITERL
+    /// goes back to the body while the key is not nil
+    pub fn close_table_loop(&mut self, table_loop: TableLoop) {
+        let base = table_loop.key_slot;
+        self.place_label(table_loop.iter_label);
+        self.add_instruction(IRInstruction::ABC(IRInstABC::new(
+            ITERN,
+            IRArg::Slot(base),
+            IRArg::Literal(3),
+            IRArg::Literal(3)
+        )));
+        self.add_instruction(IRInstruction::AD(IRInstAD::new(
+            ITERL,
+            IRArg::Slot(base),
+            IRArg::Jump(table_loop.body_label)
+        )));
+        self.free_tmps(table_loop.slots);
+    }
+
+    /// Open a call frame for the given argument count at the top of the stack, since the call
+    /// clobbers the slots above its base. Return none if there is no room for it
+    pub fn open_call_frame(&mut self, arg_count: u8) -> Option<CallFrame> {
+        let slots = match self.new_top_tmps(arg_count + 1) {
+            Some(slots) => slots,
+            None => { return None; }
+        };
+        Some(CallFrame {
+            base: slots[0],
+            arg_count,
+            owned_slots: slots
+        })
+    }
+
+    /// Open a call frame on a base slot which is already busy and is the last busy one, like
+    /// the slot of a multiple result argument in an enclosing call frame
+    pub fn open_call_frame_at(&mut self, base: u8, arg_count: u8) -> Option<CallFrame> {
+        let slots = match self.new_tmps_at(base + 1, arg_count) {
+            Some(slots) => slots,
+            None => { return None; }
+        };
+        Some(CallFrame {
+            base,
+            arg_count,
+            owned_slots: slots
+        })
+    }
+
+    /// Emit the call of the filled frame, the result operand is the wanted result count plus
+    /// one, zero for all results. With a multiple result last argument, the call is a CALLM
+    /// and only the fixed arguments are counted
+    pub fn emit_call(&mut self, frame: &CallFrame, result_operand: u16, is_multiple: bool) {
+        if is_multiple {
+            self.add_instruction(IRInstruction::ABC(IRInstABC::new(
+                CALLM,
+                IRArg::Slot(frame.base),
+                IRArg::Literal(result_operand),
+                IRArg::Literal((frame.arg_count - 1) as u16)
+            )));
+        } else {
+            self.add_instruction(IRInstruction::ABC(IRInstABC::new(
+                CALL,
+                IRArg::Slot(frame.base),
+                IRArg::Literal(result_operand),
+                IRArg::Literal((frame.arg_count + 1) as u16)
+            )));
+        }
+    }
+
+    /// Free the slots of the call frame
+    pub fn close_call_frame(&mut self, frame: CallFrame) {
+        self.free_tmps(frame.owned_slots);
+    }
+
+    /// Call the runtime function with the given global name on the values of the argument slots
+    /// and put its single result in the result slot
+    /// Return false if there is no room for the call frame
+    pub fn emit_runtime_call(&mut self, name: &str, arg_slots: &[u8], res_slot: u8) -> bool {
+        let frame = match self.open_call_frame(arg_slots.len() as u8) {
+            Some(frame) => frame,
+            None => { return false; }
+        };
+
+        // Fill the call frame and call the function
+        if !self.emit_global_get(frame.base, name) {
+            self.close_call_frame(frame);
+            return false;
+        }
+        for (i, arg_slot) in arg_slots.iter().enumerate() {
+            self.add_instruction(IRInstruction::AD(IRInstAD::new(
+                MOV,
+                IRArg::Slot(frame.arg_slot(i as u8)),
+                IRArg::Slot(*arg_slot)
+            )));
+        }
+        self.emit_call(&frame, 2, false);
+        self.add_instruction(IRInstruction::AD(IRInstAD::new(
+            MOV,
+            IRArg::Slot(res_slot),
+            IRArg::Slot(frame.base)
+        )));
+        self.close_call_frame(frame);
+        true
+    }
+
+    /// Emit the raising of a runtime error with the given message, the emitted call never
+    /// returns. Return false if there is no slot left for the call frame
+    pub fn emit_error(&mut self, message: &str) -> bool {
+        let frame = match self.open_call_frame(1) {
+            Some(frame) => frame,
+            None => { return false; }
+        };
+
+        // Call the error function with the message constant
+        if !self.emit_global_get(frame.base, ERROR_GLOBAL_NAME) {
+            self.close_call_frame(frame);
+            return false;
+        }
+        let message_index = self.add_string_constant(String::from(message));
+        self.add_instruction(IRInstruction::AD(IRInstAD::new(
+            KSTR,
+            IRArg::Slot(frame.arg_slot(0)),
+            IRArg::Str(message_index)
+        )));
+        self.emit_call(&frame, 1, false);
+        self.close_call_frame(frame);
+        true
+    }
+
+    /// Load nil in the count adjacent slots from the given one, a single slot is loaded with KPRI
+    /// and a range is cleared with one KNIL instead of a run of KPRI
+    pub fn emit_load_nil(&mut self, slot: u8, count: u8) {
+        if count == 0 {
+            return;
+        }
+        if count == 1 {
+            self.add_instruction(IRInstruction::AD(IRInstAD::new(
+                KPRI,
+                IRArg::Slot(slot),
+                IRArg::Primitive(Primitive::Nil)
+            )));
+        } else {
+            self.add_instruction(IRInstruction::AD(IRInstAD::new(
+                KNIL,
+                IRArg::Slot(slot),
+                IRArg::Slot(slot + count - 1)
+            )));
+        }
+    }
+
     // --- Constants
 
     /// Add a string constant to the current prototype and return its index
@@ -220,6 +921,27 @@ impl CompilationEnv {
         let mut local_env = self.local_env_stack.first_mut().unwrap();
         local_env.add_string_constant(string)
     }
+
+    /// Add a constant table to the current prototype and return its index, it is the template
+    /// a TDUP instruction duplicates
+    pub fn add_table_constant(&mut self, table: KTable) -> u16 {
+        let local_env = self.local_env_stack.first_mut().unwrap();
+        local_env.prototype.complex_constants.insert(0, ComplexConstant::Table(table));
+        (local_env.prototype.complex_constants.len() - 1) as u16
+    }
+
+    /// Add a child constant for the last closed prototype and return its index
+    pub fn add_child_constant(&mut self) -> u16 {
+        let local_env = self.local_env_stack.first_mut().unwrap();
+        local_env.prototype.complex_constants.insert(0, ComplexConstant::Child);
+        (local_env.prototype.complex_constants.len() - 1) as u16
+    }
+
+    /// Add a numeric constant to the current prototype and return its index
+    pub fn add_numeric_constant(&mut self, constant: NumericConstant) -> u16 {
+        let local_env = self.local_env_stack.first_mut().unwrap();
+        local_env.add_numeric_constant(constant)
+    }
 }
 
 
@@ -233,23 +955,42 @@ pub struct LocalEnv {
     local_var_overflow_stack: Vec<HashSet<String>>, // This is a cheat to avoid the Lua local var limitation (200)
 
     string_constant_cache: HashMap<String, u16>, // The cache that goes from the String to the constant index
+    numeric_constant_cache: HashMap<(bool, u64), u16>, // The cache that goes from the (is integer, bits) to the constant index
 
     expr_result_slot: Option<u8>, // The slot to put the result of the current expression in
     return_slot: Option<u8>, // The slot to return at the end of the fun (if -1 return nothing)
 
     upvalues: HashMap<String, u8>, // This map goes from the var name to the upvalue index
+    captured_slots: HashSet<u8>, // The local slots captured as upvalues by a child env
+    slot_aliases: HashMap<u8, usize>, // The number of alias names sharing a local slot
 
     frame_size: u8, // The frame size for the prototype
     label_counter: u64, // The counter for the jump labels
+    pending_labels: Vec<u64>, // The labels to put on the next added instruction
     has_child: bool, // If the local environment comport one or more child env
+    finalized: bool, // If the prototype of the local environment is finalized
+
+    debug_info: bool, // If the live ranges of the local variables are recorded
+    var_ranges: Vec<VarRange>, // The live ranges of the local variables, in declaration order
 
     ir: Vec<IRInstruction>, // The intermediary representation of the code
     prototype: Prototype, // The bytecode of the local environment
 }
 
+/// The live range of a local variable, delimited by the labels of its first instruction and of
+/// the first instruction after its scope, a range without end lasts until the function end
+#[derive(Debug)]
+struct VarRange {
+    name: String,
+    slot: u8,
+    scope_level: usize,
+    start_label: u64,
+    end_label: Option<u64>,
+}
+
 impl LocalEnv {
     /// Create a new local environment
-    fn new(depth: usize, arg_count: u8) -> LocalEnv {
+    fn new(depth: usize, arg_count: u8, debug_info: bool) -> LocalEnv {
         LocalEnv {
             depth,
 
@@ -258,15 +999,23 @@ impl LocalEnv {
             local_var_overflow_stack: vec![HashSet::new()],
 
             string_constant_cache: HashMap::new(),
+            numeric_constant_cache: HashMap::new(),
 
             expr_result_slot: None,
             return_slot: None,
 
             upvalues: HashMap::new(),
+            captured_slots: HashSet::new(),
+            slot_aliases: HashMap::new(),
 
             frame_size: 0,
             label_counter: 0,
+            pending_labels: Vec::new(),
             has_child: false,
+            finalized: false,
+
+            debug_info,
+            var_ranges: Vec::new(),
 
             ir: Vec::new(),
             prototype: Prototype::new(arg_count)
@@ -274,28 +1023,74 @@ impl LocalEnv {
     }
 
     /// Finalize the local environment just before pushing it in the program
-    fn finalize(&mut self) {
+    fn finalize(&mut self, optimization_level: u8) -> Result<(), LKQLError> {
         // Return the result of the function TODO push it into the IR
         if self.return_slot.is_none() {
-            self.ir.push(IRInstruction::AD(IRInstAD::new(
+            self.add_instruction(IRInstruction::AD(IRInstAD::new(
                 RET0,
                 IRArg::Slot(0),
                 IRArg::Literal(1)
             )));
         } else {
-            self.ir.push(IRInstruction::AD(IRInstAD::new(
+            self.add_instruction(IRInstruction::AD(IRInstAD::new(
                 RET1,
                 IRArg::Slot(self.return_slot.unwrap()),
                 IRArg::Literal(2)
             )));
         }
 
-        let mut code = process_ir(&mut self.ir);
-        self.prototype.frame_size = self.frame_size;
+        self.prototype.check_constants()?;
+        check_constant_args(&self.ir, self.prototype.complex_constants.len(), self.prototype.numeric_constants.len())?;
+
+        // The frame size comes from the slots the final instructions access, a temporary slot
+        // which was briefly allocated high doesn't inflate it
+        let (mut code, frame_size) = match process_ir(&mut self.ir, optimization_level) {
+            Err(e) => { return Err(e); }
+            Ok(res) => res
+        };
         self.prototype.instructions.append(&mut code);
+        if self.debug_info {
+            self.prototype.debug_info = Some(self.build_debug_info());
+        }
 
-        // Set the prototype flags
+        // Like LuaJIT's parser, never produce a frame smaller than one slot, even for an
+        // empty function (e.g. an empty LKQL script), nor than the arguments
+        self.prototype.frame_size = frame_size.max(MIN_FRAME_SIZE).max(self.prototype.arg_count);
+
+        // Set the prototype flags, the main chunk is always variadic like in Lua
         if self.has_child { self.prototype.flags |= FLAG_P_HAS_CHILD }
+        if self.depth == 0 { self.prototype.flags |= FLAG_P_IS_VARIADIC }
+        self.prototype.derive_flags();
+        self.finalized = true;
+        Ok(())
+    }
+
+    /// Create the debug info of the prototype from the processed IR, the instructions have no
+    /// line information yet so all line deltas are null
+    fn build_debug_info(&self) -> DebugInfo {
+        let mut res = DebugInfo::new();
+        res.line_info = vec![0; self.ir.len()];
+
+        // The upvalue names in the order of their indexes
+        let mut upvalues: Vec<(&String, &u8)> = self.upvalues.iter().collect();
+        upvalues.sort_by_key(|(_, index)| **index);
+        res.upval_names = upvalues.into_iter().map(|(name, _)| name.clone()).collect();
+
+        // The pcs start after the function header, LuaJIT maps the n-th live variable to the
+        // slot n, so the variables starting at the same pc are ordered by slot
+        let end_pc = self.ir.len() as u32 + 1;
+        let mut var_infos: Vec<(u8, VarInfo)> = Vec::new();
+        for range in &self.var_ranges {
+            let start_pc = get_label_position(&self.ir, range.start_label).map_or(end_pc, |pos| pos as u32 + 1);
+            let range_end_pc = match range.end_label {
+                Some(label) => get_label_position(&self.ir, label).map_or(end_pc, |pos| pos as u32 + 1),
+                None => end_pc
+            };
+            var_infos.push((range.slot, VarInfo::new(range.name.clone(), start_pc, range_end_pc.max(start_pc))));
+        }
+        var_infos.sort_by_key(|(slot, var_info)| (var_info.start_pc, *slot));
+        res.var_infos = var_infos.into_iter().map(|(_, var_info)| var_info).collect();
+        res
     }
 
     /// Open a pseudo local environment
@@ -307,23 +1102,50 @@ impl LocalEnv {
 
     /// Close the current pseudo local environment
     fn close_pseudo_env(&mut self) {
+        // End the live ranges of the scope locals
+        if self.debug_info {
+            let scope_level = self.local_var_stack.len();
+            self.end_var_ranges(|range| range.scope_level == scope_level);
+        }
+
         // Pop all stack
         let local_var = self.local_var_stack.pop().unwrap();
         self.local_var_overflow_stack.pop();
 
-        // Free the occupied local slots
+        // Close the upvalues of the captured locals since their slots are going to be reused
+        let mut first_captured: Option<u8> = None;
+        for slot in local_var.values() {
+            if self.captured_slots.remove(slot) && first_captured.is_none_or(|first| *slot < first) {
+                first_captured = Some(*slot);
+            }
+        }
+        if let Some(first) = first_captured {
+            self.add_instruction(IRInstruction::AD(IRInstAD::new(
+                UCLO,
+                IRArg::Slot(first),
+                IRArg::JumpLiteral(JUMP_BIASING)
+            )));
+        }
+
+        // Release the occupied local slots
         for (_, slot) in local_var {
-            self.free_slot(slot);
+            self.release_local_slot(slot);
         }
     }
 
     /// Get the next free slot and set it to occupied
+    /// The lowest free slot is always taken, locals and temporaries alike, so the allocations
+    /// only depend on the sequence of allocations and frees made by the compiled nodes and the
+    /// same script always gives the same bytecode. The hash maps of the environment must not be
+    /// iterated in a way that changes the emitted code, their order differs between runs
+    /// The slots above the LuaJIT maximum are never given, so the frame size always fits
     fn get_new_slot(&mut self) -> Option<u8> {
-        for i in 0..self.occupied_slot.len() {
+        for i in 0..MAX_SLOTS {
             if !self.occupied_slot[i] {
                 self.occupied_slot[i] = true;
                 let slot = i as u8;
                 if slot >= self.frame_size { self.frame_size = slot + 1; }
+                lkql_trace!("Allocate the slot {} at depth {}", slot, self.depth);
                 return Some(slot);
             }
         }
@@ -336,10 +1158,14 @@ impl LocalEnv {
         self.occupied_slot[slot as usize] = false;
     }
 
-    /// Add a local variable to the local environment
-    fn add_local(&mut self, name: String) -> LocalResult {
+    /// Add a local variable to the local environment, none if there is no free slot even for
+    /// the time to move the local to the overflow names
+    fn add_local(&mut self, name: String) -> Option<LocalResult> {
         // Get the next available slot
-        let slot = self.get_new_slot().unwrap_or(255);
+        let slot = match self.get_new_slot() {
+            Some(slot) => slot,
+            None => { return None; }
+        };
 
         // If the slot is over 220 then free it and get to the cheat local vars
         if slot >= 220 {
@@ -347,32 +1173,96 @@ impl LocalEnv {
             self.free_slot(slot);
 
             // Get the local name and put it in the overflow set
-            let local_var_overflow = self.local_var_overflow_stack.first_mut().unwrap();
+            let local_var_overflow = self.local_var_overflow_stack.last_mut().unwrap();
             let depth_name = name_with_depth(&*name, self.depth);
 
             local_var_overflow.insert(depth_name.clone());
-            LocalResult::Name(depth_name)
+            Some(LocalResult::Name(depth_name))
         } else {
-            // Just add the slot in the local variable env
-            let local_var = self.local_var_stack.first_mut().unwrap();
-            local_var.insert(name, slot);
-            LocalResult::Slot(slot)
+            self.bind_local(name, slot);
+            Some(LocalResult::Slot(slot))
         }
     }
 
+    /// Add a local variable sharing the slot of another local, the LKQL values are immutable so
+    /// the slot is only freed when all the names sharing it are out of scope
+    fn add_local_alias(&mut self, name: String, slot: u8) -> LocalResult {
+        *self.slot_aliases.entry(slot).or_insert(0) += 1;
+        self.bind_local(name, slot);
+        LocalResult::Slot(slot)
+    }
+
+    /// Bind the name to the slot in the innermost pseudo local environment
+    fn bind_local(&mut self, name: String, slot: u8) {
+        // Record the live range of the local, it ends the one of a shadowed local
+        if self.debug_info {
+            let scope_level = self.local_var_stack.len();
+            self.end_var_ranges(|range| range.scope_level == scope_level && range.name == name);
+            let start_label = self.new_label();
+            self.pending_labels.push(start_label);
+            self.var_ranges.push(VarRange {
+                name: name.clone(),
+                slot,
+                scope_level,
+                start_label,
+                end_label: None
+            });
+        }
+
+        // Add the slot in the local variable env, a shadowed local of the same scope is not
+        // reachable anymore so its slot is released, unless a closure still references it
+        let local_var = self.local_var_stack.last_mut().unwrap();
+        match local_var.insert(name, slot) {
+            Some(old_slot) if !self.captured_slots.contains(&old_slot) => self.release_local_slot(old_slot),
+            _ => ()
+        }
+    }
+
+    /// Release the slot of a local which is out of scope, it is freed if no alias still uses it
+    fn release_local_slot(&mut self, slot: u8) {
+        match self.slot_aliases.get_mut(&slot) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => { self.slot_aliases.remove(&slot); }
+            None => self.free_slot(slot)
+        }
+    }
+
+    /// End the live ranges which are still open and verify the predicate at the next instruction
+    fn end_var_ranges<P: Fn(&VarRange) -> bool>(&mut self, predicate: P) {
+        let mut end_label: Option<u64> = None;
+        for i in 0..self.var_ranges.len() {
+            let range = self.var_ranges.get(i).unwrap();
+            if range.end_label.is_none() && predicate(range) {
+                if end_label.is_none() {
+                    let label = self.new_label();
+                    self.pending_labels.push(label);
+                    end_label = Some(label);
+                }
+                self.var_ranges.get_mut(i).unwrap().end_label = end_label;
+            }
+        }
+    }
+
+    /// Get if the symbol is declared in the innermost pseudo local environment
+    fn is_declared_in_scope(&self, name: &str) -> bool {
+        let depth_name = name_with_depth(name, self.depth);
+        self.local_var_stack.last().unwrap().contains_key(name) ||
+            self.local_var_overflow_stack.last().unwrap().contains(&depth_name)
+    }
+
     /// Get the local symbol associated slot or name
     fn get_local(&self, name: &str) -> LocalResult {
         // Prepare the depth name
         let depth_name = name_with_depth(name, self.depth);
 
-        // Iterate on all pseudo local environment
-        for i in 0..self.local_var_stack.len() {
+        // Iterate on all pseudo local environment, from the innermost one
+        for i in (0..self.local_var_stack.len()).rev() {
             let local_var = self.local_var_stack.get(i).unwrap();
             if local_var.contains_key(name) {
                 return LocalResult::Slot(*local_var.get(name).unwrap());
             } else {
                 let local_var_overflow = self.local_var_overflow_stack.get(i).unwrap();
-                if local_var_overflow.contains(name) { return LocalResult::Name(depth_name); }
+                if local_var_overflow.contains(&depth_name) { return LocalResult::Name(depth_name); }
             }
         }
 
@@ -397,33 +1287,58 @@ impl LocalEnv {
         }
     }
 
-    /// Create and return a new temporary slot
-    fn new_tmp(&mut self) -> u8 {
-        self.get_new_slot().expect("Cannot get new tmp, all slots are busy")
+    /// Create and return a new temporary slot, none if all the slots are busy
+    fn new_tmp(&mut self) -> Option<u8> {
+        self.get_new_slot()
     }
 
     /// Get n contiguous slots
-    fn new_tmps(&mut self, n: u8) -> Vec<u8> {
+    fn new_tmps(&mut self, n: u8) -> Option<Vec<u8>> {
         // Prepare the working variables
-        let mut start: Option<u8> = None;
+        let mut start: Option<usize> = None;
+        if n == 0 { return Some(Vec::new()); }
+        if n as usize > MAX_SLOTS { return None; }
 
-        // Look for n contiguous slots
-        for i in 0..self.occupied_slot.len() {
+        // Look for n contiguous slots, the range never goes above the maximum frame size
+        for i in 0..MAX_SLOTS {
             if !self.occupied_slot[i] {
                 if start.is_none() {
-                    start = Some(i as u8);
+                    start = Some(i);
                 }
 
-                if (i as u8) - start.unwrap() == n - 1 {
-                    return (start.unwrap()..((i as u8) + 1)).collect()
+                if i - start.unwrap() == (n as usize) - 1 {
+                    // Occupy the slots and grow the frame to contain them
+                    for slot in start.unwrap()..(i + 1) {
+                        self.occupied_slot[slot] = true;
+                    }
+                    if i + 1 > self.frame_size as usize { self.frame_size = (i + 1) as u8; }
+                    lkql_trace!("Allocate the contiguous slots {} to {} at depth {}", start.unwrap(), i, self.depth);
+                    return Some((start.unwrap()..(i + 1)).map(|slot| slot as u8).collect())
                 }
             } else {
                 start = None;
             }
         }
 
-        // Panic when there is not more contiguous slots
-        panic!("Cannot get {} contiguous slots", n);
+        // Return none when there is not more contiguous slots
+        None
+    }
+
+    /// Get n contiguous slots from the given one
+    fn new_tmps_at(&mut self, start: usize, n: u8) -> Option<Vec<u8>> {
+        // Verify that all the slots exist and are free
+        let end = start + (n as usize);
+        if end > MAX_SLOTS || self.occupied_slot[start..end].iter().any(|occupied| *occupied) {
+            return None;
+        }
+
+        // Occupy the slots and grow the frame to contain them
+        for slot in start..end {
+            self.occupied_slot[slot] = true;
+        }
+        if end > self.frame_size as usize { self.frame_size = end as u8; }
+        lkql_trace!("Allocate the contiguous slots {} to {} at depth {}", start, end, self.depth);
+        Some((start..end).map(|slot| slot as u8).collect())
     }
 
     /// Free a temporary used slot
@@ -437,8 +1352,11 @@ impl LocalEnv {
         self.label_counter
     }
 
-    /// Add an instruction to the list
-    fn add_instruction(&mut self, inst: IRInstruction) {
+    /// Add an instruction to the list, placing the pending labels on it
+    fn add_instruction(&mut self, mut inst: IRInstruction) {
+        for label in self.pending_labels.drain(..) {
+            inst.add_label(label);
+        }
         self.ir.push(inst);
     }
 
@@ -460,6 +1378,28 @@ impl LocalEnv {
             res
         }
     }
+
+    /// Add the numeric constant and return its position
+    fn add_numeric_constant(&mut self, constant: NumericConstant) -> u16 {
+        // Get the cache key of the constant, integers and numbers are different constants
+        let key = match &constant {
+            NumericConstant::Int(int) => (true, *int as u64),
+            NumericConstant::Num(knum) => (false, knum.value.to_bits())
+        };
+
+        // If the cache already contains the constant just return its index
+        if self.numeric_constant_cache.contains_key(&key) {
+            *self.numeric_constant_cache.get(&key).unwrap()
+        }
+
+        // Else, create a new constant and put it in the cache
+        else {
+            self.prototype.numeric_constants.push(constant);
+            let res = (self.prototype.numeric_constants.len() - 1) as u16;
+            self.numeric_constant_cache.insert(key, res);
+            res
+        }
+    }
 }
 
 
@@ -480,7 +1420,273 @@ pub enum UpvalueResult {
 
 // --- Util functions
 
+/// Create an empty program, stripped of the debug info unless it is wanted
+fn new_program(debug_info: bool) -> Program {
+    let mut res = Program::new();
+    if debug_info {
+        res.header = Header::new_with_debug(String::new());
+    }
+    res
+}
+
 /// Get the name of the variable with the wanted lexical depth
 fn name_with_depth(name: &str, depth: usize) -> String {
     String::from("_").repeat(depth) + name
-}
\ No newline at end of file
+}
+
+// --- Tests of the slot allocation
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The temporaries and the locals stop at the LuaJIT maximum frame size, the locals above
+    /// the slot limit are named globals
+    #[test]
+    fn slot_limit() {
+        let mut env = CompilationEnv::new(CompilationOptions::new());
+        let mut locals = Vec::new();
+        for i in 0..MAX_SLOTS + 10 {
+            locals.push(env.add_local(format!("local_{}", i)));
+        }
+        assert!(locals.iter().all(|local| local.is_some()));
+        assert!(matches!(locals[0], Some(LocalResult::Slot(_))));
+        assert!(matches!(locals[MAX_SLOTS], Some(LocalResult::Name(_))));
+
+        while env.new_tmp().is_some() {}
+        assert_eq!(env.current_frame_size() as usize, MAX_SLOTS);
+        assert!(env.add_local(String::from("last")).is_none());
+        assert!(env.new_top_tmps(1).is_none());
+        assert!(env.new_tmps_at((MAX_SLOTS - 1) as u8, 2).is_none());
+    }
+
+    /// The contiguous temporaries fit in the maximum frame size, a range which doesn't is none
+    #[test]
+    fn contiguous_slot_limit() {
+        let mut env = CompilationEnv::new(CompilationOptions::new());
+        assert!(env.new_tmps((MAX_SLOTS + 1) as u8).is_none());
+        assert!(env.new_tmps(u8::MAX).is_none());
+
+        let slots = env.new_tmps(MAX_SLOTS as u8).unwrap();
+        assert_eq!(slots.last().map(|slot| *slot as usize), Some(MAX_SLOTS - 1));
+        assert_eq!(env.current_frame_size() as usize, MAX_SLOTS);
+        assert!(env.new_tmps(1).is_none());
+    }
+
+    /// Three adjacent slots are cleared by one KNIL, a single slot is loaded by a KPRI
+    #[test]
+    fn load_nil() {
+        use crate::lkqlc::ir::OPT_LEVEL_NONE;
+
+        let mut options = CompilationOptions::new();
+        options.optimization_level = OPT_LEVEL_NONE;
+        let mut env = CompilationEnv::new(options);
+        let slots = env.new_tmps(4).unwrap();
+        env.emit_load_nil(slots[0], 3);
+        env.emit_load_nil(slots[3], 1);
+        env.emit_load_nil(slots[3], 0);
+        assert!(env.close_env().is_ok());
+
+        let main = &env.get_program().prototypes[0];
+        assert_eq!(main.op_codes(), vec![KNIL, KPRI, RET0], "{}", main.disassemble());
+    }
+
+    /// The code generation after the closing of the main environment is an internal error
+    /// instead of landing in another prototype
+    #[test]
+    #[should_panic(expected = "code generation in a closed environment")]
+    fn emit_after_close() {
+        let mut env = CompilationEnv::new(CompilationOptions::new());
+        assert!(env.close_env().is_ok());
+        assert_eq!(env.get_program().prototypes.len(), 1);
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(KNIL, IRArg::Slot(0), IRArg::Slot(0))));
+    }
+
+    /// A table loop visits all the values of a table, the ones of its array and map parts
+    #[cfg(not(feature = "bytecode-only"))]
+    #[test]
+    fn table_loop_sum() {
+        use crate::lkqlc::bc::{ADDVV, CALL, ISEQN, KSHORT, KTable, TableItem, TDUP};
+        use crate::lkqlc::test_utils::run;
+
+        let mut env = CompilationEnv::new(CompilationOptions::new());
+        let (table_slot, sum_slot) = (env.new_tmp().unwrap(), env.new_tmp().unwrap());
+        let mut table = KTable::new();
+        table.array = vec![TableItem::Int(10), TableItem::Int(20), TableItem::Int(30)];
+        table.map.insert(TableItem::String(KStr::new(String::from("a"))), TableItem::Int(40));
+        let table_index = env.add_table_constant(table);
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(TDUP, IRArg::Slot(table_slot), IRArg::Tab(table_index))));
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(KSHORT, IRArg::Slot(sum_slot), IRArg::SignedLiteral(0))));
+
+        // Sum the values in the loop
+        let table_loop = env.open_table_loop(table_slot).unwrap();
+        env.add_instruction(IRInstruction::ABC(IRInstABC::new(
+            ADDVV,
+            IRArg::Slot(sum_slot),
+            IRArg::Slot(sum_slot),
+            IRArg::Slot(table_loop.value_slot)
+        )));
+        env.close_table_loop(table_loop);
+
+        // Raise an error if the sum is wrong
+        let ok_label = env.new_label();
+        let expected_index = env.add_numeric_constant(NumericConstant::Int(100));
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(ISEQN, IRArg::Slot(sum_slot), IRArg::Num(expected_index))));
+        env.add_jump(ok_label);
+        let error_slot = env.new_tmp().unwrap();
+        assert!(env.emit_global_get(error_slot, "error"));
+        env.add_instruction(IRInstruction::ABC(IRInstABC::new(CALL, IRArg::Slot(error_slot), IRArg::Literal(1), IRArg::Literal(1))));
+        env.place_label(ok_label);
+        env.free_tmp(error_slot);
+        assert!(env.close_env().is_ok());
+
+        let program = env.take_program();
+        assert!(program.prototypes[0].op_codes().contains(&ITERN), "{}", program.disassemble());
+        assert_eq!(run(&program.encode()), Ok(()), "{}", program.disassemble());
+    }
+
+    /// An arithmetic with a numeric constant beyond the C operand range loads the constant in a
+    /// slot, and the operation with a lower constant still uses it directly
+    #[cfg(not(feature = "bytecode-only"))]
+    #[test]
+    fn wide_arith_constant() {
+        use crate::lkqlc::bc::{ADDVN, ADDVV, CALL, ISEQN, KNUM, KSHORT};
+        use crate::lkqlc::test_utils::run;
+
+        let mut env = CompilationEnv::new(CompilationOptions::new());
+        let sum_slot = env.new_tmp().unwrap();
+        let indexes: Vec<u16> = (0..300).map(|i| env.add_numeric_constant(NumericConstant::Int(1000 + i))).collect();
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(KSHORT, IRArg::Slot(sum_slot), IRArg::SignedLiteral(5))));
+        assert!(env.emit_arith_constant(ADDVN, ADDVV, sum_slot, sum_slot, indexes[299]));
+        assert!(env.emit_arith_constant(ADDVN, ADDVV, sum_slot, sum_slot, indexes[0]));
+
+        // Raise an error if the sum is wrong
+        let ok_label = env.new_label();
+        let expected_index = env.add_numeric_constant(NumericConstant::Int(2304));
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(ISEQN, IRArg::Slot(sum_slot), IRArg::Num(expected_index))));
+        env.add_jump(ok_label);
+        let error_slot = env.new_tmp().unwrap();
+        assert!(env.emit_global_get(error_slot, "error"));
+        env.add_instruction(IRInstruction::ABC(IRInstABC::new(CALL, IRArg::Slot(error_slot), IRArg::Literal(1), IRArg::Literal(1))));
+        env.place_label(ok_label);
+        env.free_tmp(error_slot);
+        assert!(env.close_env().is_ok());
+
+        let program = env.take_program();
+        let op_codes = program.prototypes[0].op_codes();
+        assert_eq!(op_codes.iter().filter(|op_code| **op_code == KNUM).count(), 1, "{}", program.disassemble());
+        assert!(op_codes.contains(&ADDVV) && op_codes.contains(&ADDVN), "{}", program.disassemble());
+        assert_eq!(run(&program.encode()), Ok(()), "{}", program.disassemble());
+    }
+
+    /// A function with more locals than slots compiles, and too many simultaneous temporaries
+    /// is a compilation error instead of a crash
+    #[cfg(not(feature = "bytecode-only"))]
+    #[test]
+    fn too_many_slots() {
+        use crate::errors::E_TOO_COMPLEX;
+        use crate::lkqlc::compile_lkql_buffer_with_env;
+        use crate::lkqlc::test_utils::{compile_and_run, test_options};
+
+        let mut source = String::from("fun many() = { val v0 = 1; ");
+        for i in 1..MAX_SLOTS + 10 {
+            source.push_str(&format!("val v{} = v{} + 1; ", i, i - 1));
+        }
+        source.push_str(&format!("v{} }}\nassert(many() == {})", MAX_SLOTS + 9, MAX_SLOTS + 10));
+        let env = compile_and_run(&source);
+        assert!(env.get_program().prototypes.iter().all(|proto| proto.frame_size as usize <= MAX_SLOTS));
+
+        let mut nested = String::from("fun nested(x) = ");
+        for _ in 0..MAX_SLOTS + 10 {
+            nested.push_str("x + (");
+        }
+        nested.push('x');
+        nested.push_str(&")".repeat(MAX_SLOTS + 10));
+        let mut options = test_options();
+        options.max_depth = 4 * MAX_SLOTS;
+        let mut env = CompilationEnv::new(options);
+        match compile_lkql_buffer_with_env(&nested, "unit test", &mut env) {
+            Err(e) => assert_eq!(e.code, E_TOO_COMPLEX),
+            Ok(_) => panic!("The nested additions are expected to need too many slots")
+        }
+    }
+
+    /// With the debug info, the encoded function lists its parameters, live from the first
+    /// instruction, then its locals, and the stripped bytecode has no debug info
+    #[cfg(not(feature = "bytecode-only"))]
+    #[test]
+    fn parameter_names_debug_info() {
+        use crate::lkqlc::test_utils::{compile_with, run, test_options};
+
+        let source = "fun scale(value, factor) = { val res = value * factor; res + 1 }\nassert(scale(2, 3) == 7)";
+        let mut options = test_options();
+        options.debug_info = true;
+        let (_, bytecode) = compile_with(source, options);
+        assert_eq!(run(&bytecode), Ok(()));
+        let program = Program::decode(&bytecode).unwrap_or_else(|e| panic!("{}", e));
+        let function = &program.prototypes[0];
+        let debug_info = function.debug_info.as_ref().expect("The function has no debug info");
+        let names: Vec<&str> = debug_info.var_infos.iter().map(|var_info| var_info.name.as_str()).collect();
+        assert_eq!(names, vec!["value", "factor", "res"], "{}", function.disassemble());
+        let (params, locals) = debug_info.var_infos.split_at(2);
+        assert!(params.iter().all(|var_info| var_info.start_pc == 1 && var_info.end_pc >= locals[0].end_pc));
+        assert!(locals[0].start_pc > 1);
+
+        let (_, stripped) = compile_with(source, test_options());
+        let program = Program::decode(&stripped).unwrap_or_else(|e| panic!("{}", e));
+        assert!(program.prototypes.iter().all(|proto| proto.debug_info.is_none()));
+    }
+
+    /// In sandbox mode the globals are read from the sandbox table, a host global which is not
+    /// in it is nil, and the global table is only read once to get the sandbox
+    #[cfg(not(feature = "bytecode-only"))]
+    #[test]
+    fn sandbox_globals() {
+        use crate::lkqlc::test_utils::{compile_with, main_prototype, test_options};
+        use crate::luajit;
+
+        let source = "val s = tostring(1)\nval t = print";
+        let run = |sandbox: bool| {
+            let mut options = test_options();
+            options.sandbox = sandbox;
+            options.host_globals.push(String::from("tostring"));
+            let (env, bytecode) = compile_with(source, options);
+            let lua_state = luajit::init_env();
+            if sandbox {
+                luajit::set_global_sandbox(&lua_state);
+            }
+            let res = luajit::try_run_lua_bytecode(&lua_state, &bytecode, "sandbox");
+            luajit::close_env(&lua_state);
+            (main_prototype(&env).op_codes(), res)
+        };
+
+        let (op_codes, res) = run(false);
+        assert_eq!(res, Ok(()));
+        assert!(op_codes.contains(&GSET) && !op_codes.contains(&TSETS));
+
+        let (op_codes, res) = run(true);
+        assert!(res.is_err());
+        assert_eq!(op_codes.iter().filter(|op_code| **op_code == GGET).count(), 1);
+        assert!(!op_codes.contains(&GSET));
+        assert!(op_codes.contains(&TGETS) && op_codes.contains(&TSETS));
+    }
+
+    /// A local captured two levels up is a local reference of the middle function, which the
+    /// inner function references as an outer upvalue
+    #[cfg(not(feature = "bytecode-only"))]
+    #[test]
+    fn upvalue_reference_bits() {
+        use crate::lkqlc::test_utils::{compile_with, run, test_options};
+
+        let source = "fun outer() = { val x = 7; val middle = () => { val inner = () => x; inner() }; middle() }\nassert(outer() == 7)";
+        let (_, bytecode) = compile_with(source, test_options());
+        assert_eq!(run(&bytecode), Ok(()));
+
+        // The prototypes are ordered from the innermost function to the main chunk
+        let program = Program::decode(&bytecode).unwrap_or_else(|e| panic!("{}", e));
+        let (inner, middle) = (&program.prototypes[0], &program.prototypes[1]);
+        assert_eq!(inner.upval_references, vec![0]);
+        assert_eq!(middle.upval_references.len(), 1);
+        assert_eq!(middle.upval_references[0] & (UV_LOCAL | UV_IMMUTABLE), UV_LOCAL | UV_IMMUTABLE);
+    }
+}
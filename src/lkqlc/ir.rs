@@ -6,8 +6,10 @@ representation between LKQL and LuaJIT bytecode
 
 // --- Enum that contains the IR instruction
 
-use std::mem::replace;
-use crate::lkqlc::bc::{BCInstABC, BCInstAD, BCInstruction, JUMP_BIASING};
+use std::mem::take;
+use crate::errors::{E_INVALID_BYTECODE, E_TOO_COMPLEX, LKQLError};
+use crate::lkql_trace;
+use crate::lkqlc::bc::{op_name, BCInstABC, BCInstAD, BCInstruction, ADDVN, CALL, CALLM, CALLMT, CALLT, FNEW, FORI, FORL, GGET, GSET, ISEQN, ISEQS, ISF, ISNEN, ISNES, ISNEXT, ITERC, ITERL, ITERN, JFORL, JMP, JUMP_BIASING, KNIL, KNUM, KPRI, KSTR, LOOP, MODNV, MOV, RET, RET0, RET1, RETM, TDUP, TGETS, TSETS, UCLO, USETN, USETS, VARG};
 
 #[derive(Debug)]
 pub enum IRInstruction {
@@ -16,6 +18,30 @@ pub enum IRInstruction {
 }
 
 impl IRInstruction {
+    /// Add a label to the instruction, to make it a jump target
+    pub fn add_label(&mut self, label: u64) {
+        match self {
+            IRInstruction::ABC(inst) => inst.labels.push(label),
+            IRInstruction::AD(inst) => inst.labels.push(label)
+        }
+    }
+
+    /// Get if the instruction is labelled with the given label
+    pub fn has_label(&self, label: u64) -> bool {
+        match self {
+            IRInstruction::ABC(inst) => inst.labels.contains(&label),
+            IRInstruction::AD(inst) => inst.labels.contains(&label)
+        }
+    }
+
+    /// Get the labels of the instruction
+    pub fn labels(&self) -> Vec<u64> {
+        match self {
+            IRInstruction::ABC(inst) => inst.labels.clone(),
+            IRInstruction::AD(inst) => inst.labels.clone()
+        }
+    }
+
     pub fn to_bc_instruction(&self) -> BCInstruction {
         match self {
             IRInstruction::ABC(inst) => {
@@ -32,7 +58,7 @@ impl IRInstruction {
 
 #[derive(Debug)]
 pub struct IRInstABC {
-    label: u64,
+    labels: Vec<u64>,
     op_code: u8,
     a: IRArg,
     b: IRArg,
@@ -42,7 +68,7 @@ pub struct IRInstABC {
 impl IRInstABC {
     pub fn new(op_code: u8, a: IRArg, b: IRArg, c: IRArg) -> IRInstABC {
         IRInstABC {
-            label: 0,
+            labels: Vec::new(),
             op_code,
             a,
             b,
@@ -53,7 +79,7 @@ impl IRInstABC {
 
 #[derive(Debug)]
 pub struct IRInstAD {
-    label: u64,
+    labels: Vec<u64>,
     op_code: u8,
     a: IRArg,
     d: IRArg
@@ -62,7 +88,7 @@ pub struct IRInstAD {
 impl IRInstAD {
     pub fn new(op_code: u8, a: IRArg, d: IRArg) -> IRInstAD {
         IRInstAD {
-            label: 0,
+            labels: Vec::new(),
             op_code,
             a,
             d
@@ -183,12 +209,31 @@ pub enum Primitive {
 }
 
 
+// --- Optimization levels
+
+pub const OPT_LEVEL_NONE: u8 = 0; // No optimization, the IR is translated as is
+pub const OPT_LEVEL_SAFE: u8 = 1; // Only optimizations that cannot change the program behavior
+pub const OPT_LEVEL_AGGRESSIVE: u8 = 2; // All optimizations
+
+
 // --- Functions
 
 /// Process the intermediary representation and return the instructions and the frame size
-pub fn process_ir(ir: &mut Vec<IRInstruction>) -> Vec<BCInstruction> {
-    // Process the slots and the jumps
-    process_jumps(ir);
+/// It fails if a jump cannot be encoded
+pub fn process_ir(ir: &mut Vec<IRInstruction>, optimization_level: u8) -> Result<(Vec<BCInstruction>, u8), LKQLError> {
+    // Run the optimization passes according to the level
+    if optimization_level >= OPT_LEVEL_SAFE {
+        remove_unreachable(ir);
+        remove_self_moves(ir);
+        merge_nil_clears(ir);
+        remove_jumps_to_next(ir);
+    }
+
+    // Compute the frame size from the slots the remaining instructions access, then process
+    // the jumps
+    let frame_size = accessed_frame_size(ir);
+    clamp_jump_bases(ir, frame_size);
+    process_jumps(ir)?;
 
     // Translate the IR instruction to BC instructions
     let mut res = Vec::new();
@@ -196,11 +241,280 @@ pub fn process_ir(ir: &mut Vec<IRInstruction>) -> Vec<BCInstruction> {
         res.push(ir_inst.to_bc_instruction());
     }
 
+    Ok((res, frame_size))
+}
+
+/// Verify that the constant operands reference the right constant table and are in its range,
+/// the numeric operations (Ex: ADDVN, KNUM) take numeric constant indexes and the string, table
+/// and function ones (Ex: KSTR, GGET, TDUP) complex constant indexes
+pub fn check_constant_args(ir: &Vec<IRInstruction>, complex_count: usize, numeric_count: usize) -> Result<(), LKQLError> {
+    for inst in ir {
+        let (op_code, arg) = match inst {
+            IRInstruction::ABC(abc_inst) => (abc_inst.op_code, &abc_inst.c),
+            IRInstruction::AD(ad_inst) => (ad_inst.op_code, &ad_inst.d)
+        };
+        let is_valid = match op_code {
+            ADDVN..=MODNV | KNUM | ISEQN | ISNEN | USETN => {
+                matches!(arg, IRArg::Num(index) if (*index as usize) < numeric_count)
+            }
+            KSTR | GGET | GSET | TGETS | TSETS | ISEQS | ISNES | USETS => {
+                matches!(arg, IRArg::Str(index) if (*index as usize) < complex_count)
+            }
+            TDUP => matches!(arg, IRArg::Tab(index) if (*index as usize) < complex_count),
+            FNEW => matches!(arg, IRArg::Func(index) if (*index as usize) < complex_count),
+            _ => true
+        };
+        if !is_valid {
+            return Err(LKQLError::new(
+                E_INVALID_BYTECODE,
+                format!("Invalid constant operand {:?} of a {} instruction", arg, op_name(op_code))
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Compute the frame size needed by the instructions : one more than the highest slot they
+/// access. The slots allocated during the compilation but not accessed anymore, like the ones
+/// of the removed moves, don't inflate the frame
+fn accessed_frame_size(ir: &Vec<IRInstruction>) -> u8 {
+    let mut res: usize = 0;
+    for inst in ir {
+        res = res.max(slot_extent(inst));
+    }
+    u8::try_from(res).unwrap_or(u8::MAX)
+}
+
+/// Get one more than the highest slot accessed by the instruction, or 0 if it accesses none
+/// The instructions working on slot ranges (calls, iterators, returns...) get their range end
+fn slot_extent(inst: &IRInstruction) -> usize {
+    match inst {
+        IRInstruction::ABC(inst) => {
+            let a = inst.a.as_16() as usize;
+            let b = inst.b.as_16() as usize;
+            let c = inst.c.as_16() as usize;
+            match inst.op_code {
+                // The function and its arguments, then the results from A
+                CALL => a + c.max(b.saturating_sub(1)).max(1),
+                CALLM => a + (c + 1).max(b.saturating_sub(1)),
+                // The control slots below A are copied from A to A + 2 for the generator call
+                ITERC | ITERN => a + 3.max(b.saturating_sub(1)),
+                VARG => a + b.saturating_sub(1),
+                _ => slot_arg_extent(&inst.a).max(slot_arg_extent(&inst.b)).max(slot_arg_extent(&inst.c))
+            }
+        }
+        IRInstruction::AD(inst) => {
+            let a = inst.a.as_8() as usize;
+            match inst.op_code {
+                // The A operand of these is a slot base which is not accessed
+                JMP | UCLO | LOOP => 0,
+                KNIL => inst.d.as_16() as usize + 1,
+                RET => a + (inst.d.as_16() as usize).saturating_sub(1),
+                RETM => a + inst.d.as_16() as usize,
+                ISNEXT => a,
+                ITERL => a + 1,
+                FORI | FORL | JFORL => a + 4,
+                _ => slot_arg_extent(&inst.a).max(slot_arg_extent(&inst.d))
+            }
+        }
+    }
+}
+
+/// Get one more than the slot of the operand if it is one, else 0
+fn slot_arg_extent(arg: &IRArg) -> usize {
+    match arg {
+        IRArg::Slot(slot) => *slot as usize + 1,
+        _ => 0
+    }
+}
+
+/// Limit the slot base of the jumps to the frame size, it was the frame size at the jump
+/// emission which may be above the final one
+fn clamp_jump_bases(ir: &mut Vec<IRInstruction>, frame_size: u8) {
+    for inst in ir {
+        match inst {
+            IRInstruction::AD(inst) if inst.op_code == JMP => {
+                match inst.a {
+                    IRArg::Slot(base) if base > frame_size => { inst.a = IRArg::Slot(frame_size); }
+                    _ => ()
+                }
+            }
+            _ => ()
+        }
+    }
+}
+
+/// Remove the instructions that no path from the first one reaches, like the code after an
+/// unconditional jump or a return. The labels of a removed instruction go to the next kept one
+/// since the debug info may reference them
+/// The last instruction is the return of the function, it is kept so the moved labels always
+/// have an instruction
+fn remove_unreachable(ir: &mut Vec<IRInstruction>) {
+    if ir.is_empty() {
+        return;
+    }
+
+    // Walk the control flow from the first instruction
+    let mut reachable = vec![false; ir.len()];
+    let mut to_visit = vec![0];
+    while let Some(i) = to_visit.pop() {
+        if i >= ir.len() || reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+        to_visit.append(&mut successors(ir, i));
+    }
+    let last = ir.len() - 1;
+    reachable[last] = true;
+
+    // Keep the reachable instructions
+    let mut pending_labels = Vec::new();
+    for (mut inst, is_reachable) in take(ir).into_iter().zip(reachable) {
+        if is_reachable {
+            for label in pending_labels.drain(..) {
+                inst.add_label(label);
+            }
+            ir.push(inst);
+        } else {
+            lkql_trace!("Remove the unreachable instruction {}", op_name(match &inst {
+                IRInstruction::AD(ad_inst) => ad_inst.op_code,
+                IRInstruction::ABC(abc_inst) => abc_inst.op_code
+            }));
+            pending_labels.append(&mut inst.labels());
+        }
+    }
+}
+
+/// Get the positions of the instructions which can be executed after the given one
+/// A conditional instruction executes the next one, the jump of its branch, or skips it
+fn successors(ir: &[IRInstruction], i: usize) -> Vec<usize> {
+    let (op_code, target) = match ir.get(i).unwrap() {
+        IRInstruction::AD(inst) => {
+            let target = match inst.d {
+                IRArg::Jump(label) => get_label_position(ir, label),
+                IRArg::JumpLiteral(offset) => usize::try_from(i as isize + 1 + offset as isize - JUMP_BIASING as isize).ok(),
+                _ => None
+            };
+            (inst.op_code, target)
+        }
+        IRInstruction::ABC(inst) => (inst.op_code, None)
+    };
+
+    let mut res: Vec<usize> = target.into_iter().collect();
+    match op_code {
+        RETM | RET | RET0 | RET1 | CALLMT | CALLT => (),
+        JMP | UCLO | ISNEXT if target.is_some() => (),
+        _ if is_conditional(ir.get(i).unwrap()) => {
+            res.push(i + 1);
+            res.push(i + 2);
+        }
+        _ => res.push(i + 1)
+    }
     res
 }
 
+/// Remove the moves from a slot to itself, their labels go to the next instruction
+fn remove_self_moves(ir: &mut Vec<IRInstruction>) {
+    let mut i = 0;
+    while i < ir.len() {
+        let is_self_move = match ir.get(i).unwrap() {
+            IRInstruction::AD(inst) => {
+                inst.op_code == MOV && inst.a.as_8() == inst.d.as_8()
+            }
+            _ => false
+        };
+
+        // The last instruction is always the return so a removed move always has a successor
+        if is_self_move && i + 1 < ir.len() {
+            let removed = ir.remove(i);
+            for label in removed.labels() {
+                ir.get_mut(i).unwrap().add_label(label);
+            }
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// Merge the adjacent nil clears (KNIL, KPRI nil) of contiguous or overlapping slot ranges
+/// into one KNIL. An instruction with a label is a jump target so it is never merged in the
+/// previous one
+fn merge_nil_clears(ir: &mut Vec<IRInstruction>) {
+    let mut i = 0;
+    while i + 1 < ir.len() {
+        let merged_range = match (nil_clear_range(ir.get(i).unwrap()), nil_clear_range(ir.get(i + 1).unwrap())) {
+            (Some((first, last)), Some((next_first, next_last))) if ir.get(i + 1).unwrap().labels().is_empty() &&
+                (next_first as u16) <= (last as u16) + 1 && (first as u16) <= (next_last as u16) + 1 => {
+                Some((first.min(next_first), last.max(next_last)))
+            }
+            _ => None
+        };
+
+        match merged_range {
+            Some((first, last)) => {
+                ir.remove(i + 1);
+                let labels = ir.get(i).unwrap().labels();
+                let mut merged = IRInstruction::AD(IRInstAD::new(KNIL, IRArg::Slot(first), IRArg::Slot(last)));
+                for label in labels {
+                    merged.add_label(label);
+                }
+                ir[i] = merged;
+            }
+            None => { i += 1; }
+        }
+    }
+}
+
+/// Get the first and last slots set to nil by the instruction, none if it is not a nil clear
+fn nil_clear_range(inst: &IRInstruction) -> Option<(u8, u8)> {
+    match inst {
+        IRInstruction::AD(ad) if ad.op_code == KNIL => Some((ad.a.as_8(), ad.d.as_8())),
+        IRInstruction::AD(ad) if ad.op_code == KPRI && matches!(ad.d, IRArg::Primitive(Primitive::Nil)) => {
+            Some((ad.a.as_8(), ad.a.as_8()))
+        }
+        _ => None
+    }
+}
+
+/// Remove the unconditional jumps to the next instruction, except the ones following a
+/// conditional instruction since they are part of the branch
+fn remove_jumps_to_next(ir: &mut Vec<IRInstruction>) {
+    let mut i = 0;
+    while i + 1 < ir.len() {
+        let jumps_to_next = match ir.get(i).unwrap() {
+            IRInstruction::AD(inst) => {
+                match inst.d {
+                    IRArg::Jump(label) => inst.op_code == JMP && ir.get(i + 1).unwrap().has_label(label),
+                    _ => false
+                }
+            }
+            _ => false
+        };
+        let follows_condition = i > 0 && is_conditional(ir.get(i - 1).unwrap());
+
+        if jumps_to_next && !follows_condition {
+            let removed = ir.remove(i);
+            for label in removed.labels() {
+                ir.get_mut(i).unwrap().add_label(label);
+            }
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// Get if the instruction is a conditional one, which must be followed by a jump
+fn is_conditional(inst: &IRInstruction) -> bool {
+    match inst {
+        IRInstruction::AD(ad_inst) => ad_inst.op_code <= ISF,
+        _ => false
+    }
+}
+
 /// Function to process the jump instruction with the labelled instructions
-fn process_jumps(ir: &mut Vec<IRInstruction>) {
+/// The biased offset is a 16 bit operand, so a jump goes at most 0x8000 instructions backward
+/// and 0x7FFF instructions forward from the next instruction, a farther target is an error
+fn process_jumps(ir: &mut [IRInstruction]) -> Result<(), LKQLError> {
     // Iterate over all IR instructions
     for i in 0..ir.len() {
         // Get the current instruction
@@ -214,18 +528,37 @@ fn process_jumps(ir: &mut Vec<IRInstruction>) {
 
                         // Get the current position and the target label position
                         let current_pos = i + 1;
-                        let target_pos = get_label_position(ir, label)
-                            .expect("Cannot process IR : label not found");
+                        let target_pos = match get_label_position(ir, label) {
+                            None => {
+                                return Err(LKQLError::new(
+                                    E_INVALID_BYTECODE,
+                                    format!("Cannot process IR : label {} not found", label)
+                                ));
+                            }
+                            Some(pos) => pos
+                        };
 
                         // Compute the offset
-                        let mut offset: isize = (target_pos as isize) - (target_pos as isize);
+                        let mut offset: isize = (target_pos as isize) - (current_pos as isize);
                         offset += (JUMP_BIASING as isize);
 
+                        lkql_trace!("Resolve the jump at {} to the label {} at {}", i, label, target_pos);
+
+                        // Verify that the biased offset fits in the operand
+                        let biased_offset = match u16::try_from(offset) {
+                            Err(_) => {
+                                return Err(LKQLError::new(
+                                    E_TOO_COMPLEX,
+                                    format!("Jump target too far ({} instructions), split the function", offset - (JUMP_BIASING as isize))
+                                ));
+                            }
+                            Ok(o) => o
+                        };
+
                         // Updating the current instruction operand
                         match ir.get_mut(i).unwrap() {
                             IRInstruction::AD(to_change) => {
-                                to_change.d =
-                                    IRArg::JumpLiteral(u16::try_from(offset).expect("Jump is too long and cannot be handled by LuaJIT"));
+                                to_change.d = IRArg::JumpLiteral(biased_offset);
                             }
                             _ => ()
                         }
@@ -237,22 +570,202 @@ fn process_jumps(ir: &mut Vec<IRInstruction>) {
             _ => ()
         }
     }
+
+    Ok(())
 }
 
 /// Get the position of the given label in the instruction vector
-fn get_label_position(ir: &Vec<IRInstruction>, label: u64) -> Option<usize> {
-    for i in 0..ir.len() {
-        let ir_inst = ir.get(i).unwrap();
+pub fn get_label_position(ir: &[IRInstruction], label: u64) -> Option<usize> {
+    ir.iter().position(|inst| inst.has_label(label))
+}
 
-        match ir_inst {
-            IRInstruction::ABC(abc_inst) => {
-                if abc_inst.label == label { return Some(i); }
-            }
-            IRInstruction::AD(ad_inst) => {
-                if ad_inst.label == label { return Some(i); }
-            }
+// --- Tests of the IR processing
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lkqlc::bc::{Program, ADDVV, ISGE, ISLT, KSHORT};
+    use crate::lkqlc::builder::ProgramBuilder;
+
+    fn ad(op_code: u8, a: IRArg, d: IRArg) -> IRInstruction {
+        IRInstruction::AD(IRInstAD::new(op_code, a, d))
+    }
+
+    fn nil_clear(slot: u8) -> IRInstruction {
+        ad(KPRI, IRArg::Slot(slot), IRArg::Primitive(Primitive::Nil))
+    }
+
+    fn ret() -> IRInstruction {
+        ad(RET0, IRArg::Slot(0), IRArg::Literal(1))
+    }
+
+    /// Resolve the jumps of a counting loop, encode and decode it, then verify that the decoded
+    /// offsets lead to the labelled instructions, backward and forward
+    #[test]
+    fn jump_offsets() {
+        // The loop is : i = 0; while not (i >= 10) do i = i + 1 end
+        let (loop_label, end_label) = (1, 2);
+        let mut ir = vec![
+            ad(KSHORT, IRArg::Slot(0), IRArg::SignedLiteral(0)),
+            ad(KSHORT, IRArg::Slot(2), IRArg::SignedLiteral(1)),
+            ad(KSHORT, IRArg::Slot(1), IRArg::SignedLiteral(10)),
+            ad(ISGE, IRArg::Slot(0), IRArg::Slot(1)),
+            ad(JMP, IRArg::Slot(3), IRArg::Jump(end_label)),
+            IRInstruction::ABC(IRInstABC::new(ADDVV, IRArg::Slot(0), IRArg::Slot(0), IRArg::Slot(2))),
+            ad(JMP, IRArg::Slot(3), IRArg::Jump(loop_label)),
+            ret()
+        ];
+        ir[2].add_label(loop_label);
+        ir[7].add_label(end_label);
+
+        // Encode the processed instructions and decode them
+        let (instructions, frame_size) = process_ir(&mut ir, OPT_LEVEL_NONE).unwrap_or_else(|e| panic!("{}", e));
+        let mut builder = ProgramBuilder::new();
+        builder.set_frame_size(frame_size);
+        for inst in &instructions {
+            match inst {
+                BCInstruction::Ad(ad) => builder.emit_ad(ad.op_code, ad.a, ad.d),
+                BCInstruction::Abc(abc) => builder.emit_abc(abc.op_code, abc.a, abc.b, abc.c)
+            };
+        }
+        let program = builder.finish().unwrap_or_else(|e| panic!("{}", e));
+        let decoded = Program::decode(&program.encode()).unwrap_or_else(|e| panic!("{}", e));
+
+        // The offset is relative to the instruction after the jump
+        let main = decoded.prototypes.last().unwrap();
+        for (index, target) in [(4, 7), (6, 2)] {
+            let offset = match &main.instructions[index] {
+                BCInstruction::Ad(ad) => ad.jump_offset(),
+                _ => None
+            };
+            assert_eq!(offset.map(|offset| index as i32 + 1 + offset), Some(target), "{}", main.disassemble());
         }
     }
 
-    None
-}
\ No newline at end of file
+    /// Three adjacent nil clears become one KNIL
+    #[test]
+    fn merge_nil_clears() {
+        let mut ir = vec![
+            nil_clear(1),
+            ad(KNIL, IRArg::Slot(2), IRArg::Slot(3)),
+            nil_clear(0),
+            ret()
+        ];
+        let (instructions, _) = process_ir(&mut ir, OPT_LEVEL_SAFE).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(instructions, vec![BCInstAD::emit(KNIL, 0, 3), BCInstAD::emit(RET0, 0, 1)]);
+    }
+
+    /// A labelled nil clear is a jump target, it is kept apart from the previous one
+    #[test]
+    fn keep_labelled_nil_clear() {
+        let mut ir = vec![nil_clear(0), nil_clear(1), ret()];
+        ir[1].add_label(1);
+        let (instructions, _) = process_ir(&mut ir, OPT_LEVEL_SAFE).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(instructions.len(), 3);
+    }
+
+    /// An instruction after a return is removed while both branches of the condition are kept,
+    /// and the jump lands on its target in the pruned layout
+    #[test]
+    fn remove_dead_code() {
+        let then_label = 1;
+        let mut ir = vec![
+            ad(KSHORT, IRArg::Slot(0), IRArg::SignedLiteral(1)),
+            ad(KSHORT, IRArg::Slot(1), IRArg::SignedLiteral(2)),
+            ad(ISLT, IRArg::Slot(0), IRArg::Slot(1)),
+            ad(JMP, IRArg::Slot(2), IRArg::Jump(then_label)),
+            ad(RET1, IRArg::Slot(0), IRArg::Literal(2)),
+            ad(KSHORT, IRArg::Slot(2), IRArg::SignedLiteral(3)),
+            ad(RET1, IRArg::Slot(1), IRArg::Literal(2)),
+            ret()
+        ];
+        ir[6].add_label(then_label);
+
+        let (instructions, _) = process_ir(&mut ir, OPT_LEVEL_SAFE).unwrap_or_else(|e| panic!("{}", e));
+        let op_codes: Vec<u8> = instructions.iter().map(|inst| inst.op_code()).collect();
+        assert_eq!(op_codes, vec![KSHORT, KSHORT, ISLT, JMP, RET1, RET1, RET0]);
+        assert_eq!(instructions[3], BCInstAD::emit(JMP, 2, JUMP_BIASING + 1));
+    }
+
+    /// Build the IR of a jump to the label, placed at the given position among filler
+    /// instructions, with the label on the target position
+    fn jump_ir(jump_pos: usize, target_pos: usize, len: usize) -> Vec<IRInstruction> {
+        let label = 1;
+        let mut ir: Vec<IRInstruction> = (0..len)
+            .map(|_| ad(KSHORT, IRArg::Slot(0), IRArg::SignedLiteral(0)))
+            .collect();
+        ir[jump_pos] = ad(JMP, IRArg::Slot(1), IRArg::Jump(label));
+        ir[target_pos].add_label(label);
+        ir
+    }
+
+    /// Get the resolved operand of the jump at the position
+    fn jump_operand(ir: &Vec<IRInstruction>, jump_pos: usize) -> Option<u16> {
+        match &ir[jump_pos] {
+            IRInstruction::AD(inst) => match inst.d {
+                IRArg::JumpLiteral(operand) => Some(operand),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    /// The farthest jumps in both directions are resolved to the bounds of the operand, one
+    /// instruction further is an error
+    #[test]
+    fn jump_boundaries() {
+        let max_forward = (u16::MAX - JUMP_BIASING) as usize;
+        let mut ir = jump_ir(0, max_forward + 1, max_forward + 2);
+        assert!(process_jumps(&mut ir).is_ok());
+        assert_eq!(jump_operand(&ir, 0), Some(u16::MAX));
+        let mut ir = jump_ir(0, max_forward + 2, max_forward + 3);
+        assert!(matches!(process_jumps(&mut ir), Err(e) if e.code == E_TOO_COMPLEX));
+
+        let max_backward = JUMP_BIASING as usize;
+        let mut ir = jump_ir(max_backward - 1, 0, max_backward);
+        assert!(process_jumps(&mut ir).is_ok());
+        assert_eq!(jump_operand(&ir, max_backward - 1), Some(0));
+        let mut ir = jump_ir(max_backward, 0, max_backward + 1);
+        assert!(matches!(process_jumps(&mut ir), Err(e) if e.code == E_TOO_COMPLEX));
+    }
+
+    /// A jump to a label which is on no instruction is an error instead of a crash
+    #[test]
+    fn missing_label() {
+        let mut ir = vec![ad(JMP, IRArg::Slot(0), IRArg::Jump(1)), ret()];
+        ir[1].add_label(2);
+        assert!(matches!(process_jumps(&mut ir), Err(e) if e.code == E_INVALID_BYTECODE));
+        assert!(process_ir(&mut vec![ad(JMP, IRArg::Slot(0), IRArg::Jump(1)), ret()], OPT_LEVEL_SAFE).is_err());
+    }
+
+    /// A wide temporary removed by the optimizations doesn't inflate the frame, while a call
+    /// accounts for its function, its arguments and its results
+    #[test]
+    fn frame_size_of_accessed_slots() {
+        let frame_size = |optimization_level: u8| {
+            let mut ir = vec![
+                ad(KSHORT, IRArg::Slot(0), IRArg::SignedLiteral(1)),
+                ad(MOV, IRArg::Slot(9), IRArg::Slot(9)),
+                IRInstruction::ABC(IRInstABC::new(CALL, IRArg::Slot(1), IRArg::Literal(3), IRArg::Literal(2))),
+                ret()
+            ];
+            process_ir(&mut ir, optimization_level).unwrap_or_else(|e| panic!("{}", e)).1
+        };
+        assert_eq!(frame_size(OPT_LEVEL_NONE), 10);
+        assert_eq!(frame_size(OPT_LEVEL_SAFE), 3);
+    }
+
+    /// A numeric operation must reference an existing numeric constant, a string index given to
+    /// an arithmetic is an error like an index out of its table
+    #[test]
+    fn constant_operand_tables() {
+        let arith = |c: IRArg| vec![IRInstruction::ABC(IRInstABC::new(ADDVN, IRArg::Slot(0), IRArg::Slot(0), c))];
+        assert!(check_constant_args(&arith(IRArg::Num(0)), 1, 1).is_ok());
+        assert!(matches!(check_constant_args(&arith(IRArg::Str(0)), 1, 1), Err(e) if e.code == E_INVALID_BYTECODE));
+        assert!(matches!(check_constant_args(&arith(IRArg::Num(1)), 2, 1), Err(e) if e.code == E_INVALID_BYTECODE));
+
+        let load = |d: IRArg| vec![ad(KSTR, IRArg::Slot(0), d)];
+        assert!(check_constant_args(&load(IRArg::Str(0)), 1, 1).is_ok());
+        assert!(check_constant_args(&load(IRArg::Num(0)), 1, 1).is_err());
+    }
+}
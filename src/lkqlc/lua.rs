@@ -0,0 +1,318 @@
+/*
+This module contains the Lua source backend, it emits readable Lua code instead of the bytecode
+It walks the same node tree as the bytecode compiler to show what the compiler intends, and its
+output runs on any Lua interpreter. Only a subset of the nodes is supported for now : the
+literals, the function calls and the declarations and operators around them
+*/
+
+use std::collections::HashSet;
+use crate::errors::{E_UNKNOWN_OPERATOR, E_UNSUPPORTED, LKQLError};
+use crate::lkql_wrapper::*;
+use crate::lkqlc::builtins::LENGTH_BUILTIN_NAME;
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::{decimal_literal, integer_literal, paren_expr, string_literal};
+use crate::lkqlc::nodes::fun_decl::duplicate_param_error;
+
+
+// --- Constants
+
+// The names that are keywords in Lua but not in LKQL, they cannot be used as Lua identifiers
+const LUA_KEYWORDS: [&str; 9] = ["break", "do", "elseif", "end", "for", "goto", "local", "repeat", "until"];
+
+// The indentation of the function bodies
+const INDENT: &str = "    ";
+
+
+// --- The Lua source emitter
+
+pub struct LuaEmitter {
+    source: String, // The emitted Lua source
+    scopes: Vec<HashSet<String>>, // The names declared by the script, one set per function
+}
+
+impl LuaEmitter {
+    /// Create a new emitter with an empty source
+    pub fn new() -> LuaEmitter {
+        LuaEmitter {
+            source: String::new(),
+            scopes: vec![HashSet::new()],
+        }
+    }
+
+    /// Emit the Lua source of a script from the root node of its unit
+    pub fn emit_unit(mut self, root: &Node) -> Result<String, LKQLError> {
+        for child in root.children() {
+            if child.is_trivia() {
+                continue;
+            }
+            match self.emit_statement(&child) {
+                Err(e) => { return Err(e); }
+                Ok(_) => {}
+            }
+        }
+        Ok(self.source)
+    }
+
+    // --- Statements
+
+    /// Emit a top level element of the script as a Lua statement
+    fn emit_statement(&mut self, node: &Node) -> Result<(), LKQLError> {
+        match node.kind() {
+            lkql_node_kind_enum_lkql_val_decl => {
+                let name = match self.declare(&node.field(lkql_val_decl_f_identifier)) {
+                    Err(e) => { return Err(e); }
+                    Ok(name) => name
+                };
+                let value = match self.emit_expr(&node.field(lkql_val_decl_f_value)) {
+                    Err(e) => { return Err(e); }
+                    Ok(value) => value
+                };
+                self.source.push_str(&format!("local {} = {}\n", name, value));
+            }
+            lkql_node_kind_enum_lkql_fun_decl => {
+                // The top level functions are globals, like in the bytecode
+                let name = match self.declare(&node.field(lkql_fun_decl_f_name)) {
+                    Err(e) => { return Err(e); }
+                    Ok(name) => name
+                };
+                let function = match self.emit_function(&node.field(lkql_fun_decl_f_fun_expr)) {
+                    Err(e) => { return Err(e); }
+                    Ok(function) => function
+                };
+                self.source.push_str(&format!("{} = {}\n", name, function));
+            }
+            lkql_node_kind_enum_lkql_decl_annotation => (),
+            _ => {
+                let expr = match self.emit_expr(node) {
+                    Err(e) => { return Err(e); }
+                    Ok(expr) => expr
+                };
+                // Only a call is a Lua statement, other values are discarded in a local
+                if node.kind() == lkql_node_kind_enum_lkql_fun_call && !expr.starts_with('#') {
+                    self.source.push_str(&format!("{}\n", expr));
+                } else {
+                    self.source.push_str(&format!("local _ = {}\n", expr));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // --- Expressions
+
+    /// Get the Lua source of an expression node
+    fn emit_expr(&mut self, node: &Node) -> Result<String, LKQLError> {
+        match node.kind() {
+            // -- Literals
+            lkql_node_kind_enum_lkql_bool_literal_true => Ok(String::from("true")),
+            lkql_node_kind_enum_lkql_bool_literal_false => Ok(String::from("false")),
+            lkql_node_kind_enum_lkql_null_literal => Ok(String::from("nil")),
+            lkql_node_kind_enum_lkql_integer_literal => integer_literal::literal_value(node).map(|value| value.to_string()),
+            lkql_node_kind_enum_lkql_decimal_literal => decimal_literal::literal_value(node).map(|value| format!("{:?}", value)),
+            lkql_node_kind_enum_lkql_string_literal => match string_literal::constant_value(node) {
+                Some(value) => Ok(quote_string(&value)),
+                None => Err(unsupported(node))
+            },
+            lkql_node_kind_enum_lkql_list_literal => {
+                let exprs = node.field(lkql_list_literal_f_exprs);
+                let mut elements = Vec::new();
+                for element in exprs.children() {
+                    match self.emit_expr(&element) {
+                        Err(e) => { return Err(e); }
+                        Ok(element) => elements.push(element)
+                    }
+                }
+                Ok(format!("{{{}}}", elements.join(", ")))
+            }
+
+            // -- Variables and calls
+            lkql_node_kind_enum_lkql_identifier => lua_identifier(node),
+            lkql_node_kind_enum_lkql_fun_call => self.emit_call(node),
+            lkql_node_kind_enum_lkql_anonymous_function => self.emit_function(node),
+            lkql_node_kind_enum_lkql_paren_expr => self.emit_expr(&paren_expr::inner_expr(node)).map(|expr| format!("({})", expr)),
+
+            // -- Operators, always parenthesized so the Lua precedences don't matter
+            lkql_node_kind_enum_lkql_arith_bin_op |
+            lkql_node_kind_enum_lkql_rel_bin_op |
+            lkql_node_kind_enum_lkql_bin_op => {
+                let op = node.field(lkql_bin_op_f_op);
+                let lua_op = match op.kind() {
+                    lkql_node_kind_enum_lkql_op_plus => "+",
+                    lkql_node_kind_enum_lkql_op_minus => "-",
+                    lkql_node_kind_enum_lkql_op_mul => "*",
+                    lkql_node_kind_enum_lkql_op_div => "/",
+                    lkql_node_kind_enum_lkql_op_concat => "..",
+                    lkql_node_kind_enum_lkql_op_eq => "==",
+                    lkql_node_kind_enum_lkql_op_neq => "~=",
+                    lkql_node_kind_enum_lkql_op_lt => "<",
+                    lkql_node_kind_enum_lkql_op_leq => "<=",
+                    lkql_node_kind_enum_lkql_op_gt => ">",
+                    lkql_node_kind_enum_lkql_op_geq => ">=",
+                    lkql_node_kind_enum_lkql_op_and => "and",
+                    lkql_node_kind_enum_lkql_op_or => "or",
+                    _ => { return Err(unknown_operator(&op)); }
+                };
+                let left = match self.emit_expr(&node.field(lkql_bin_op_f_left)) {
+                    Err(e) => { return Err(e); }
+                    Ok(left) => left
+                };
+                let right = match self.emit_expr(&node.field(lkql_bin_op_f_right)) {
+                    Err(e) => { return Err(e); }
+                    Ok(right) => right
+                };
+                Ok(format!("({} {} {})", left, lua_op, right))
+            }
+            lkql_node_kind_enum_lkql_un_op => {
+                let op = node.field(lkql_un_op_f_op);
+                let operand = match self.emit_expr(&node.field(lkql_un_op_f_operand)) {
+                    Err(e) => { return Err(e); }
+                    Ok(operand) => operand
+                };
+                match op.kind() {
+                    lkql_node_kind_enum_lkql_op_plus => Ok(operand),
+                    lkql_node_kind_enum_lkql_op_minus => Ok(format!("(- {})", operand)),
+                    lkql_node_kind_enum_lkql_op_not => Ok(format!("(not {})", operand)),
+                    _ => Err(unknown_operator(&op))
+                }
+            }
+
+            _ => Err(unsupported(node))
+        }
+    }
+
+    /// Get the Lua source of a function call, the length builtin is the Lua length operator
+    fn emit_call(&mut self, node: &Node) -> Result<String, LKQLError> {
+        let fun_id = node.field(lkql_fun_call_f_name);
+        let fun_name = match lua_identifier(&fun_id) {
+            Err(e) => { return Err(e); }
+            Ok(name) => name
+        };
+
+        // Get the arguments, only the positional ones are supported
+        let mut args = Vec::new();
+        for arg in node.field(lkql_fun_call_f_arguments).children() {
+            if arg.kind() != lkql_node_kind_enum_lkql_expr_arg {
+                return Err(LKQLError::new_located(
+                    E_UNSUPPORTED,
+                    String::from("Named arguments are not supported"),
+                    arg.location()
+                ));
+            }
+            match self.emit_expr(&arg.field(lkql_expr_arg_f_value_expr)) {
+                Err(e) => { return Err(e); }
+                Ok(arg) => args.push(arg)
+            }
+        }
+
+        if fun_name == LENGTH_BUILTIN_NAME && args.len() == 1 && !self.is_declared(&fun_name) {
+            return Ok(format!("#{}", args[0]));
+        }
+        Ok(format!("{}({})", fun_name, args.join(", ")))
+    }
+
+    /// Get the Lua source of a function expression, named or anonymous, its body is returned
+    fn emit_function(&mut self, fun_node: &Node) -> Result<String, LKQLError> {
+        self.scopes.push(HashSet::new());
+        let res = self.emit_function_in_scope(fun_node);
+        self.scopes.pop();
+        res
+    }
+
+    /// Get the Lua source of a function expression in its already opened scope
+    fn emit_function_in_scope(&mut self, fun_node: &Node) -> Result<String, LKQLError> {
+        let mut params = Vec::new();
+        let mut prologue = String::new();
+        for param in fun_node.field(lkql_base_function_f_parameters).children() {
+            let param_id = param.field(lkql_parameter_decl_f_param_identifier);
+            if self.scopes.last().unwrap().contains(&param_id.text()) {
+                return Err(duplicate_param_error(&param_id.text(), &param_id));
+            }
+            let name = match self.declare(&param_id) {
+                Err(e) => { return Err(e); }
+                Ok(name) => name
+            };
+
+            // An omitted argument with a default value is set at the function start
+            let default_expr = param.field(lkql_parameter_decl_f_default_expr);
+            if !default_expr.is_null() {
+                match self.emit_expr(&default_expr) {
+                    Err(e) => { return Err(e); }
+                    Ok(default) => prologue.push_str(&format!("{}if {} == nil then {} = {} end\n", INDENT, name, name, default))
+                }
+            }
+            params.push(name);
+        }
+        let body = match self.emit_expr(&fun_node.field(lkql_base_function_f_body_expr)) {
+            Err(e) => { return Err(e); }
+            Ok(body) => body
+        };
+        Ok(format!("function({})\n{}{}return {}\nend", params.join(", "), prologue, INDENT, body))
+    }
+
+    // --- Names
+
+    /// Declare the name of the identifier in the current function and return its Lua form
+    fn declare(&mut self, identifier: &Node) -> Result<String, LKQLError> {
+        let name = match lua_identifier(identifier) {
+            Err(e) => { return Err(e); }
+            Ok(name) => name
+        };
+        self.scopes.last_mut().unwrap().insert(name.clone());
+        Ok(name)
+    }
+
+    /// Get if the name is declared by the script in the current function or an enclosing one
+    fn is_declared(&self, name: &str) -> bool {
+        self.scopes.iter().any(|scope| scope.contains(name))
+    }
+}
+
+
+// --- Util functions
+
+/// Get the Lua identifier of an LKQL identifier, the Lua keywords cannot be used
+fn lua_identifier(identifier: &Node) -> Result<String, LKQLError> {
+    let name = identifier.text();
+    if LUA_KEYWORDS.contains(&name.as_str()) {
+        return Err(LKQLError::new_located(
+            E_UNSUPPORTED,
+            format!("The identifier {} is a Lua keyword", name),
+            identifier.location()
+        ));
+    }
+    Ok(name)
+}
+
+/// Quote a string value as a Lua string literal, the control characters are escaped in decimal
+fn quote_string(value: &str) -> String {
+    let mut res = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => res.push_str(&format!("\\{}", c as u32)),
+            c => res.push(c)
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Create the error for a node the Lua backend cannot emit
+fn unsupported(node: &Node) -> LKQLError {
+    LKQLError::new_located(
+        E_UNSUPPORTED,
+        format!("The Lua backend doesn't support the {} nodes", node.kind_name()),
+        node.location()
+    )
+}
+
+/// Create the error for an unknown operator node
+fn unknown_operator(op: &Node) -> LKQLError {
+    LKQLError::new_located(
+        E_UNKNOWN_OPERATOR,
+        format!("Unknown operator : {}", op.kind_name()),
+        op.location()
+    )
+}
@@ -0,0 +1,291 @@
+/*
+This module contains the safe wrapper of the langkit nodes
+The node handlers work on it instead of the raw entities, so the FFI calls on the nodes are all
+made here. A node stays valid as long as its analysis context, which the compiler never frees
+*/
+
+use std::os::raw::{c_int, c_uint};
+use std::ptr::null_mut;
+use widestring::U32Str;
+use crate::errors::SourceLocation;
+use crate::lkql_wrapper::*;
+use crate::lkqlc::ir::Primitive;
+
+
+// --- The node wrapper
+
+// The kind name of the null node, it is only shown in the messages
+const NULL_KIND_NAME: &str = "<null>";
+
+/// The type of the generated field accessors, like "lkql_val_decl_f_value"
+pub type FieldAccessor = unsafe extern "C" fn(*mut lkql_node, *mut lkql_node) -> c_int;
+
+#[derive(Clone, Copy)]
+pub struct Node {
+    entity: lkql_base_entity,
+}
+
+impl Node {
+    /// Create the null node, like an absent optional child
+    pub fn null() -> Node {
+        Node {
+            entity: lkql_base_entity {
+                node: null_mut(),
+                info: lkql_internal_entity_info {
+                    md: lkql_internal_metadata {},
+                    rebindings: null_mut(),
+                    from_rebound: 0
+                }
+            }
+        }
+    }
+
+    /// Wrap a raw entity, it must come from langkit or be null
+    pub unsafe fn from_entity(entity: lkql_base_entity) -> Node {
+        Node { entity }
+    }
+
+    /// Get the root node of the analysis unit
+    pub unsafe fn unit_root(unit: lkql_analysis_unit) -> Node {
+        let mut res = Node::null();
+        lkql_unit_root(unit, &mut res.entity);
+        res
+    }
+
+    /// Get the raw entity, for the code that passes the node through the lua stack
+    pub fn entity(&self) -> lkql_base_entity {
+        self.entity
+    }
+
+    /// Get if the node is the null node
+    pub fn is_null(&self) -> bool {
+        self.entity.node.is_null()
+    }
+
+    /// Get if the node is a list child without meaning for the compilation : an absent child or
+    /// a ghost node, which has no token. Comments are token trivia so they are never in the nodes
+    pub fn is_trivia(&self) -> bool {
+        match self.raw() {
+            None => true,
+            Some(raw) => unsafe { lkql_node_is_ghost(raw) != 0 }
+        }
+    }
+
+    /// Get the kind of the node, the null node has the kind 0 which is no langkit kind
+    pub fn kind(&self) -> lkql_node_kind_enum {
+        match self.raw() {
+            None => 0,
+            Some(raw) => unsafe { lkql_node_kind(raw) }
+        }
+    }
+
+    /// Get the name of the node kind, like "ValDecl"
+    pub fn kind_name(&self) -> String {
+        if self.is_null() {
+            return String::from(NULL_KIND_NAME);
+        }
+        let kind = self.kind();
+        unsafe {
+            let mut text = new_text();
+            lkql_kind_name(kind, &mut text);
+            text_to_string(&mut text)
+        }
+    }
+
+    /// Get the source text of the node, the null node has no text
+    pub fn text(&self) -> String {
+        match self.raw() {
+            None => String::new(),
+            Some(raw) => unsafe {
+                let mut text = new_text();
+                lkql_node_text(raw, &mut text);
+                text_to_string(&mut text)
+            }
+        }
+    }
+
+    /// Get the location of the node in the LKQL sources
+    pub fn location(&self) -> SourceLocation {
+        let mut sloc_range = lkql_source_location_range {
+            start: lkql_source_location { line: 0, column: 0 },
+            end: lkql_source_location { line: 0, column: 0 }
+        };
+        if let Some(raw) = self.raw() {
+            unsafe { lkql_node_sloc_range(raw, &mut sloc_range); }
+        }
+        SourceLocation {
+            line: sloc_range.start.line,
+            column: sloc_range.start.column
+        }
+    }
+
+    /// Get the analysis context owning the node, other sources are parsed in it so they share
+    /// its lifetime. Null if langkit cannot get the unit of the node
+    pub fn context(&self) -> lkql_analysis_context {
+        let raw = match self.raw() {
+            None => { return null_mut(); }
+            Some(raw) => raw
+        };
+        let mut unit: lkql_analysis_unit = null_mut();
+        unsafe {
+            if lkql_node_unit(raw, &mut unit) == 0 || unit.is_null() {
+                return null_mut();
+            }
+            lkql_unit_context(unit)
+        }
+    }
+
+    /// Get the number of children of the node, the absent ones included
+    pub fn children_count(&self) -> u32 {
+        match self.raw() {
+            None => 0,
+            Some(raw) => unsafe { lkql_node_children_count(raw) as u32 }
+        }
+    }
+
+    /// Get the child at the given index, the null node if it is absent
+    pub fn child(&self, index: u32) -> Node {
+        let mut res = Node::null();
+        if let Some(raw) = self.raw() {
+            unsafe { lkql_node_child(raw, index as c_uint, &mut res.entity); }
+        }
+        res
+    }
+
+    /// Get all the children of the node, the absent ones included
+    pub fn children(&self) -> Vec<Node> {
+        (0..self.children_count()).map(|i| self.child(i)).collect()
+    }
+
+    /// Get a field of the node with its generated accessor, the null node if it is absent
+    /// An accessor of another node kind fails in langkit and also gives the null node
+    pub fn field(&self, accessor: FieldAccessor) -> Node {
+        let raw = match self.raw() {
+            None => { return Node::null(); }
+            Some(raw) => raw
+        };
+        let mut res = Node::null();
+        if unsafe { accessor(raw, &mut res.entity) } == 0 {
+            return Node::null();
+        }
+        res
+    }
+
+    /// Test if the node is a constant
+    pub fn is_literal(&self) -> bool {
+        match self.kind() {
+            lkql_node_kind_enum_lkql_null_literal |
+            lkql_node_kind_enum_lkql_unit_literal |
+            lkql_node_kind_enum_lkql_bool_literal_true |
+            lkql_node_kind_enum_lkql_bool_literal_false |
+            lkql_node_kind_enum_lkql_integer_literal |
+            lkql_node_kind_enum_lkql_string_literal |
+            lkql_node_kind_enum_lkql_list_literal |
+            lkql_node_kind_enum_lkql_object_literal |
+            lkql_node_kind_enum_lkql_block_string_literal => true,
+            _ => false
+        }
+    }
+
+    /// Get the primitive value of the node if it is a null or boolean literal
+    pub fn primitive(&self) -> Option<Primitive> {
+        match self.kind() {
+            lkql_node_kind_enum_lkql_null_literal => Some(Primitive::Nil),
+            lkql_node_kind_enum_lkql_bool_literal_true => Some(Primitive::True),
+            lkql_node_kind_enum_lkql_bool_literal_false => Some(Primitive::False),
+            _ => None
+        }
+    }
+
+    // --- Internal functions
+
+    /// Get the pointer to give to langkit, none for the null node which langkit cannot query
+    fn raw(&self) -> Option<*mut lkql_node> {
+        if self.is_null() {
+            return None;
+        }
+        Some(&self.entity as *const lkql_node as *mut lkql_node)
+    }
+}
+
+
+// --- The langkit texts
+
+/// Create a new text structure
+fn new_text() -> lkql_text {
+    lkql_text {
+        chars: null_mut(),
+        length: 0,
+        is_allocated: 0
+    }
+}
+
+/// Translate an LKQL text to a string and release it, the invalid code points are replaced
+unsafe fn text_to_string(text: &mut lkql_text) -> String {
+    let res = if text.chars.is_null() || text.length <= 0 {
+        String::new()
+    } else {
+        U32Str::from_ptr(text.chars, text.length as usize).to_string_lossy()
+    };
+    lkql_destroy_text(text);
+    res
+}
+
+
+// --- Tests of the node wrapper
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lkqlc::parse_lkql_buffer_root;
+
+    /// The null node answers all the queries without calling langkit
+    #[test]
+    fn query_null_node() {
+        let node = Node::null();
+        assert!(node.is_trivia());
+        assert_eq!(node.kind(), 0);
+        assert_eq!(node.kind_name(), NULL_KIND_NAME);
+        assert_eq!(node.text(), "");
+        assert_eq!(node.children_count(), 0);
+        assert!(node.child(0).is_null());
+        assert!(node.field(lkql_val_decl_f_value).is_null());
+        assert_eq!(node.location().line, 0);
+        assert!(node.context().is_null());
+    }
+
+    /// The invalid code points of a text are replaced instead of failing
+    #[test]
+    fn decode_invalid_text() {
+        let mut chars: Vec<u32> = vec!['a' as u32, 0xD800, 'b' as u32];
+        let mut text = lkql_text {
+            chars: chars.as_mut_ptr(),
+            length: chars.len() as c_int,
+            is_allocated: 0
+        };
+        assert_eq!(unsafe { text_to_string(&mut text) }, "a\u{FFFD}b");
+    }
+
+    /// Walk a parsed unit with the node wrapper and verify what it reports
+    #[test]
+    fn walk_unit() {
+        let root = parse_lkql_buffer_root("val x = 12\nval y = x", "node wrapper").unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(root.kind(), lkql_node_kind_enum_lkql_top_level_list);
+        let decls: Vec<Node> = root.children().into_iter().filter(|child| !child.is_trivia()).collect();
+        assert_eq!(decls.len(), 2);
+        assert!(decls.iter().all(|decl| decl.kind() == lkql_node_kind_enum_lkql_val_decl));
+
+        // The fields give the declaration parts, a literal value and an identifier value
+        let value = decls[0].field(lkql_val_decl_f_value);
+        assert!(value.is_literal());
+        assert_eq!(value.text(), "12");
+        assert_eq!(value.kind_name(), "IntegerLiteral");
+        let value = decls[1].field(lkql_val_decl_f_value);
+        assert!(!value.is_literal());
+        assert_eq!(value.text(), "x");
+        assert_eq!(decls[1].location().line, 2);
+
+        // A field of another node kind is the null node
+        assert!(decls[0].field(lkql_if_then_else_f_condition).is_null());
+    }
+}
@@ -6,4 +6,23 @@ pub mod top_level_list;
 pub mod fun_call;
 pub mod bool_literal;
 pub mod integer_literal;
-pub mod string_literal;
\ No newline at end of file
+pub mod decimal_literal;
+pub mod string_literal;
+pub mod null_literal;
+pub mod identifier;
+pub mod bin_op;
+pub mod arith_bin_op;
+pub mod rel_bin_op;
+pub mod in_clause;
+pub mod un_op;
+pub mod block_expr;
+pub mod val_decl;
+pub mod fun_decl;
+pub mod lambda;
+pub mod if_then_else;
+pub mod paren_expr;
+pub mod unit_literal;
+pub mod list_literal;
+pub mod pattern;
+pub mod query;
+pub mod decl_annotation;
\ No newline at end of file
@@ -0,0 +1,288 @@
+/*
+Functions for the arithmetic binary operations (+, -, *, /, &)
+*/
+
+use crate::errors::{E_UNKNOWN_OPERATOR, LKQLError};
+use crate::lkql_wrapper::*;
+use crate::lkqlc::bc::{ADDNV, ADDVN, ADDVV, CAT, DIVVV, KNum, MULVV, NumericConstant, SUBVV};
+use crate::lkqlc::builtins::CHECK_INT_GLOBAL_NAME;
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::ir::{IRArg, IRInstABC, IRInstruction};
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::fun_call::frame_error;
+use crate::lkqlc::nodes::{decimal_literal, integer_literal, paren_expr, string_literal};
+use crate::lkqlc::{compile_in_slot, compile_in_target, compile_in_tmp, compile_node};
+
+
+// An operand of a concatenation chain, the adjacent constant strings are merged
+enum ConcatOperand {
+    Constant(String),
+    Expr(Node)
+}
+
+/// Compile an arithmetic binary operation
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Get the operands and the operator
+    let left = node.field(lkql_bin_op_f_left);
+    let op = node.field(lkql_bin_op_f_op);
+    let right = node.field(lkql_bin_op_f_right);
+
+    // If the result is not needed, just compile the operands for their side effects
+    let res_slot = env.get_expr_slot();
+    if res_slot.is_none() {
+        match compile_node(&left, env) {
+            Err(e) => { return Err(e); }
+            Ok(_) => {}
+        }
+        return compile_node(&right, env);
+    }
+    let res_slot = res_slot.unwrap();
+
+    match op.kind() {
+        lkql_node_kind_enum_lkql_op_plus => compile_integer_op(node, &left, ADDVV, &right, res_slot, env),
+        lkql_node_kind_enum_lkql_op_minus => compile_integer_op(node, &left, SUBVV, &right, res_slot, env),
+        lkql_node_kind_enum_lkql_op_mul => compile_integer_op(node, &left, MULVV, &right, res_slot, env),
+        lkql_node_kind_enum_lkql_op_div => emit_arith(&left, DIVVV, &right, res_slot, env),
+        lkql_node_kind_enum_lkql_op_concat => compile_concat(node, &left, &right, res_slot, env),
+        _ => Err(LKQLError::new_located(
+            E_UNKNOWN_OPERATOR,
+            String::from("Unknown arithmetic operator"),
+            node.location()
+        ))
+    }
+}
+
+/// Compile an operation which keeps integer operands integer, in checked integer mode its
+/// result is guarded against the precision loss of the doubles
+fn compile_integer_op(
+    node: &Node,
+    left: &Node,
+    op_code: u8,
+    right: &Node,
+    res_slot: u8,
+    env: &mut CompilationEnv
+) -> Result<(), LKQLError> {
+    match emit_arith(left, op_code, right, res_slot, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+    if env.is_int_checked() && !env.emit_runtime_call(CHECK_INT_GLOBAL_NAME, &[res_slot], res_slot) {
+        return Err(frame_error(node));
+    }
+    Ok(())
+}
+
+/// Compile the operands and emit the arithmetic instruction on them
+/// A numeric literal operand is read from the constants by the VN (right constant) or NV (left
+/// constant) form of the operation, the constant is always the C operand and these forms keep
+/// the operand order so they are also used for the non commutative operations
+fn emit_arith(
+    left: &Node,
+    op_code: u8,
+    right: &Node,
+    res_slot: u8,
+    env: &mut CompilationEnv
+) -> Result<(), LKQLError> {
+    match numeric_constant(right) {
+        Err(e) => { return Err(e); }
+        Ok(Some(constant)) => {
+            let num_index = env.add_numeric_constant(constant);
+            if num_index <= 0xFF {
+                return emit_arith_constant(left, op_code - (ADDVV - ADDVN), num_index, res_slot, env);
+            }
+        }
+        Ok(None) => ()
+    }
+    match numeric_constant(left) {
+        Err(e) => { return Err(e); }
+        Ok(Some(constant)) => {
+            let num_index = env.add_numeric_constant(constant);
+            if num_index <= 0xFF {
+                return emit_arith_constant(right, op_code - (ADDVV - ADDNV), num_index, res_slot, env);
+            }
+        }
+        Ok(None) => ()
+    }
+
+    // The left operand can be computed in the result slot, it is free until the operation
+    let (left_tmp, left_slot) = match compile_in_target(left, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+    let (right_tmp, right_slot) = match compile_in_tmp(right, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+
+    env.add_instruction(IRInstruction::ABC(IRInstABC::new(
+        op_code,
+        IRArg::Slot(res_slot),
+        IRArg::Slot(left_slot),
+        IRArg::Slot(right_slot)
+    )));
+
+    if left_tmp.is_some() {
+        env.free_tmp(left_tmp.unwrap());
+    }
+    env.free_tmp(right_tmp);
+    Ok(())
+}
+
+/// Compile the variable operand and emit the arithmetic instruction between it and the numeric
+/// constant
+fn emit_arith_constant(
+    operand: &Node,
+    op_code: u8,
+    num_index: u16,
+    res_slot: u8,
+    env: &mut CompilationEnv
+) -> Result<(), LKQLError> {
+    let (tmp, slot) = match compile_in_target(operand, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+    env.add_instruction(IRInstruction::ABC(IRInstABC::new(
+        op_code,
+        IRArg::Slot(res_slot),
+        IRArg::Slot(slot),
+        IRArg::Num(num_index)
+    )));
+    if tmp.is_some() {
+        env.free_tmp(tmp.unwrap());
+    }
+    Ok(())
+}
+
+/// Get the numeric constant of an integer or decimal literal, or of its negation (Ex: "-1" is
+/// a unary operation on a literal), none for the other nodes
+pub fn numeric_constant(node: &Node) -> Result<Option<NumericConstant>, LKQLError> {
+    match node.kind() {
+        lkql_node_kind_enum_lkql_un_op => {
+            if node.field(lkql_un_op_f_op).kind() != lkql_node_kind_enum_lkql_op_minus {
+                return Ok(None);
+            }
+            match numeric_constant(&node.field(lkql_un_op_f_operand)) {
+                Err(e) => Err(e),
+                Ok(Some(NumericConstant::Int(int))) => Ok(Some(match int.checked_neg() {
+                    Some(negated) => NumericConstant::Int(negated),
+                    None => NumericConstant::Num(KNum::new(-(int as f64)))
+                })),
+                Ok(Some(NumericConstant::Num(num))) => Ok(Some(NumericConstant::Num(KNum::new(-num.value)))),
+                Ok(None) => Ok(None)
+            }
+        }
+        lkql_node_kind_enum_lkql_integer_literal => {
+            match integer_literal::literal_value(node) {
+                Err(e) => Err(e),
+                Ok(value) => Ok(Some(match i32::try_from(value) {
+                    Ok(int) => NumericConstant::Int(int),
+                    Err(_) => NumericConstant::Num(KNum::new(value as f64))
+                }))
+            }
+        }
+        lkql_node_kind_enum_lkql_decimal_literal => {
+            match decimal_literal::literal_value(node) {
+                Err(e) => Err(e),
+                Ok(value) => Ok(Some(NumericConstant::Num(KNum::new(value))))
+            }
+        }
+        _ => Ok(None)
+    }
+}
+
+/// Compile a string concatenation, the whole chain of concatenations is flattened and its
+/// adjacent constant strings are folded at compile time
+/// CAT works on a range of consecutive slots which are at the top of the stack since a
+/// concatenation can call a metamethod, a fully constant chain is a single KSTR
+fn compile_concat(
+    node: &Node,
+    left: &Node,
+    right: &Node,
+    res_slot: u8,
+    env: &mut CompilationEnv
+) -> Result<(), LKQLError> {
+    let mut operands = Vec::new();
+    flatten_concat(left, &mut operands);
+    flatten_concat(right, &mut operands);
+
+    if operands.len() == 1 {
+        if let ConcatOperand::Constant(value) = &operands[0] {
+            string_literal::emit_constant(value.clone(), res_slot, env);
+            return Ok(());
+        }
+    }
+
+    let slots = match env.new_top_tmps(operands.len() as u8) {
+        Some(slots) if operands.len() <= 0xFF => slots,
+        _ => { return Err(frame_error(node)); }
+    };
+    for (i, operand) in operands.iter_mut().enumerate() {
+        match operand {
+            ConcatOperand::Constant(value) => string_literal::emit_constant(value.clone(), slots[i], env),
+            ConcatOperand::Expr(expr) => {
+                match compile_in_slot(expr, Some(slots[i]), env) {
+                    Err(e) => { return Err(e); }
+                    Ok(_) => {}
+                }
+            }
+        }
+    }
+
+    env.add_instruction(IRInstruction::ABC(IRInstABC::new(
+        CAT,
+        IRArg::Slot(res_slot),
+        IRArg::Slot(slots[0]),
+        IRArg::Slot(slots[operands.len() - 1])
+    )));
+    env.free_tmps(slots);
+    Ok(())
+}
+
+/// Append the operands of a concatenation chain in their evaluation order, a constant string
+/// is merged in the previous operand if it is also constant
+fn flatten_concat(node: &Node, operands: &mut Vec<ConcatOperand>) {
+    match node.kind() {
+        lkql_node_kind_enum_lkql_paren_expr => {
+            return flatten_concat(&paren_expr::inner_expr(node), operands);
+        }
+        lkql_node_kind_enum_lkql_arith_bin_op => {
+            let op = node.field(lkql_bin_op_f_op);
+            if op.kind() == lkql_node_kind_enum_lkql_op_concat {
+                let left = node.field(lkql_bin_op_f_left);
+                let right = node.field(lkql_bin_op_f_right);
+                flatten_concat(&left, operands);
+                flatten_concat(&right, operands);
+                return;
+            }
+        }
+        _ => ()
+    }
+
+    match (string_literal::constant_value(node), operands.last_mut()) {
+        (Some(value), Some(ConcatOperand::Constant(previous))) => previous.push_str(&value),
+        (Some(value), _) => operands.push(ConcatOperand::Constant(value)),
+        (None, _) => operands.push(ConcatOperand::Expr(*node))
+    }
+}
+
+
+// --- Tests of the arithmetic operations
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::bc::{BCInstruction, KNum, NumericConstant, ADDVN};
+    use crate::lkqlc::test_utils::compile_and_run;
+
+    /// The numeric literal operand of an arithmetic is a constant of the numeric table, which the
+    /// operation references by its index
+    #[test]
+    fn numeric_literal_operand() {
+        let env = compile_and_run("fun f(x) = x + 1.5\nassert(f(1) == 2.5)");
+        let function = &env.get_program().prototypes[0];
+        let index = function.instructions.iter().find_map(|inst| match inst {
+            BCInstruction::Abc(abc) if abc.op_code == ADDVN => Some(abc.c as usize),
+            _ => None
+        }).unwrap_or_else(|| panic!("No ADDVN instruction :\n{}", function.disassemble()));
+        assert_eq!(function.numeric_constants.get(index), Some(&NumericConstant::Num(KNum::new(1.5))));
+    }
+}
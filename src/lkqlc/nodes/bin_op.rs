@@ -0,0 +1,107 @@
+/*
+Functions for the boolean binary operations (and, or)
+*/
+
+use crate::errors::{E_UNKNOWN_OPERATOR, LKQLError};
+use crate::lkql_wrapper::*;
+use crate::lkqlc::bc::{ISF, ISFC, IST, ISTC};
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction};
+use crate::lkqlc;
+use crate::lkqlc::node::Node;
+use crate::lkqlc::{compile_in_slot, compile_in_target};
+
+
+/// Compile a boolean operation, the result is the value of the operand which decides it like
+/// in Lua : the left operand is copied to the result by ISFC (and) or ISTC (or) when it decides
+/// the result, else the right operand is evaluated in the result slot
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // If the result is not needed, just short-circuit the operands
+    let res_slot = env.get_expr_slot();
+    if res_slot.is_none() {
+        let end_label = env.new_label();
+        match compile_branch(node, end_label, end_label, env) {
+            Err(e) => { return Err(e); }
+            Ok(_) => {}
+        }
+        env.place_label(end_label);
+        return Ok(());
+    }
+    let res_slot = res_slot.unwrap();
+
+    // Get the operands and the operator
+    let left = node.field(lkql_bin_op_f_left);
+    let op = node.field(lkql_bin_op_f_op);
+    let right = node.field(lkql_bin_op_f_right);
+    let is_and = match op.kind() {
+        lkql_node_kind_enum_lkql_op_and => true,
+        lkql_node_kind_enum_lkql_op_or => false,
+        _ => {
+            return Err(LKQLError::new_located(
+                E_UNKNOWN_OPERATOR,
+                String::from("Unknown boolean operator"),
+                node.location()
+            ));
+        }
+    };
+
+    // Compile the left operand, the left value is the result if it is falsy for an "and" and
+    // truthy for an "or", the test is without copy if the value is already in the result slot
+    let (left_tmp, left_slot) = match compile_in_target(&left, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+    let end_label = env.new_label();
+    let op_code = match (is_and, left_slot == res_slot) {
+        (true, true) => ISF,
+        (true, false) => ISFC,
+        (false, true) => IST,
+        (false, false) => ISTC
+    };
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        op_code,
+        if left_slot == res_slot { IRArg::None } else { IRArg::Slot(res_slot) },
+        IRArg::Slot(left_slot)
+    )));
+    env.add_jump(end_label);
+    if left_tmp.is_some() {
+        env.free_tmp(left_tmp.unwrap());
+    }
+
+    // Else the right operand is the result
+    match compile_in_slot(&right, Some(res_slot), env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+    env.place_label(end_label);
+
+    Ok(())
+}
+
+/// Compile a boolean operation as a condition with the short-circuit evaluation of the operands,
+/// they are themselves compiled as conditions so comparisons are never materialized
+pub fn compile_branch(node: &Node, true_label: u64, false_label: u64, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Get the operands and the operator
+    let left = node.field(lkql_bin_op_f_left);
+    let op = node.field(lkql_bin_op_f_op);
+    let right = node.field(lkql_bin_op_f_right);
+
+    // The right operand is only evaluated if the left one doesn't decide the result
+    let right_label = env.new_label();
+    let res = match op.kind() {
+        lkql_node_kind_enum_lkql_op_and => lkqlc::compile_branch(&left, right_label, false_label, env),
+        lkql_node_kind_enum_lkql_op_or => lkqlc::compile_branch(&left, true_label, right_label, env),
+        _ => Err(LKQLError::new_located(
+            E_UNKNOWN_OPERATOR,
+            String::from("Unknown boolean operator"),
+            node.location()
+        ))
+    };
+    match res {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+
+    env.place_label(right_label);
+    lkqlc::compile_branch(&right, true_label, false_label, env)
+}
@@ -0,0 +1,65 @@
+/*
+Functions for the block expressions
+*/
+
+use crate::errors::LKQLError;
+use crate::lkql_wrapper::*;
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::unit_literal::load_unit;
+use crate::lkqlc::{compile_in_slot, compile_node};
+
+
+/// Compile a block expression, its local declarations are scoped in a pseudo environment and
+/// its value is the value of the final expression
+/// LKQL has no "let ... in" expression, a block with declarations is its equivalent : the
+/// locals are only visible in the block and their slots are freed after the final expression
+/// A block ending with a declaration has no final expression, its value is unit
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let res_slot = env.get_expr_slot();
+    env.open_pseudo_env();
+
+    // Compile the body elements, their values are not needed
+    let body = node.field(lkql_block_expr_f_body);
+    for element in body.children() {
+        if element.is_trivia() {
+            continue;
+        }
+        env.set_expr_slot(None);
+        match compile_body_element(&element, env) {
+            Err(e) => { return Err(e); }
+            Ok(_) => {}
+        }
+    }
+
+    // Compile the final expression in the result slot, the value may be in a block local
+    // which is going to be freed so it is always copied
+    let expr = node.field(lkql_block_expr_f_expr);
+    if expr.is_trivia() {
+        if let Some(slot) = res_slot {
+            match load_unit(node, slot, env) {
+                Err(e) => { return Err(e); }
+                Ok(_) => {}
+            }
+        }
+    } else {
+        match compile_in_slot(&expr, res_slot, env) {
+            Err(e) => { return Err(e); }
+            Ok(_) => {}
+        }
+    }
+
+    // Close the block scope and reset the expression slot
+    env.close_pseudo_env();
+    env.set_expr_slot(res_slot);
+    Ok(())
+}
+
+/// Compile an element of the block body, a declaration or an expression
+fn compile_body_element(element: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    match element.kind() {
+        lkql_node_kind_enum_lkql_block_body_decl => compile_node(&element.field(lkql_block_body_decl_f_decl), env),
+        lkql_node_kind_enum_lkql_block_body_expr => compile_node(&element.field(lkql_block_body_expr_f_expr), env),
+        _ => compile_node(element, env)
+    }
+}
@@ -0,0 +1,61 @@
+/*
+Functions for the decimal literals in LKQL
+*/
+
+use crate::errors::{E_INVALID_LITERAL, LKQLError};
+use crate::lkqlc::bc::{KNUM, KNum, NumericConstant};
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction};
+use crate::lkqlc::node::Node;
+
+
+/// Compile a decimal literal, it is always a number constant even if it has an integer value
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let expr_slot = env.get_expr_slot();
+    if expr_slot.is_some() {
+        let value = match literal_value(node) {
+            Err(e) => { return Err(e); }
+            Ok(value) => value
+        };
+        let num_index = env.add_numeric_constant(NumericConstant::Num(KNum::new(value)));
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(
+            KNUM,
+            IRArg::Slot(expr_slot.unwrap()),
+            IRArg::Num(num_index)
+        )));
+    }
+
+    Ok(())
+}
+
+/// Get the value of a decimal literal node, the digits can be separated by underscores
+pub fn literal_value(node: &Node) -> Result<f64, LKQLError> {
+    let text = node.text();
+    match text.replace('_', "").parse::<f64>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => Err(LKQLError::new_located(
+            E_INVALID_LITERAL,
+            format!("Invalid decimal literal : {}", text),
+            node.location()
+        ))
+    }
+}
+
+
+// --- Tests of the decimal literals
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::bc::{KNum, NumericConstant};
+    use crate::lkqlc::test_utils::{compile_and_run, main_prototype};
+
+    /// A decimal literal is a number constant, even with an integer value
+    #[test]
+    fn number_constants() {
+        let env = compile_and_run("val x = 1.5\nval y = 1.0\nassert(x + 1.25 == 2.75)\nassert(y == x - 0.5)\nassert(0.1 + 0.2 != 0.3)");
+        let constants = &main_prototype(&env).numeric_constants;
+        assert!(constants.contains(&NumericConstant::Num(KNum::new(1.5))), "{:?}", constants);
+        assert!(constants.contains(&NumericConstant::Num(KNum::new(1.0))), "{:?}", constants);
+        assert!(!constants.contains(&NumericConstant::Int(1)), "{:?}", constants);
+    }
+}
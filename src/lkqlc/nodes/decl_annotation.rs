@@ -0,0 +1,60 @@
+/*
+Functions for the declaration annotations, like "@check" on the rule functions
+The annotations have no meaning for the compiled code, they are recorded in the environment
+for the tools that look for the rules of a script
+*/
+
+use crate::errors::LKQLError;
+use crate::lkql_wrapper::*;
+use crate::lkqlc::env::{Annotation, CompilationEnv};
+use crate::lkqlc::node::Node;
+
+
+/// Compile an annotation node met outside of its declaration, it produces no code
+pub fn compile(_node: &Node, _env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    Ok(())
+}
+
+/// Record the annotation of the declaration with the given name, if it has one
+/// The arguments are kept as their source text, in order
+pub fn record(decl: &Node, decl_name: &str, env: &mut CompilationEnv) {
+    let annotation = decl.field(lkql_declaration_f_annotation);
+    if annotation.is_null() {
+        return;
+    }
+
+    let arg_list = annotation.field(lkql_decl_annotation_f_arguments);
+    let arguments = if arg_list.is_null() {
+        Vec::new()
+    } else {
+        arg_list.children().iter()
+            .filter(|arg| !arg.is_trivia())
+            .map(|arg| arg.text())
+            .collect()
+    };
+    env.add_annotation(Annotation {
+        decl_name: String::from(decl_name),
+        name: annotation.field(lkql_decl_annotation_f_name).text(),
+        arguments,
+        location: annotation.location()
+    });
+}
+
+
+// --- Tests of the annotations
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::test_utils::compile_and_run;
+
+    /// The annotation of a declaration is recorded with its arguments, the code runs as usual
+    #[test]
+    fn record_annotation() {
+        let env = compile_and_run("@check(message=\"no literal\")\nfun is_literal(x) = x == 1\nassert(is_literal(1))");
+        let annotations = env.get_annotations();
+        assert_eq!(annotations.len(), 1);
+        assert_eq!(annotations[0].decl_name, "is_literal");
+        assert_eq!(annotations[0].name, "check");
+        assert_eq!(annotations[0].arguments.len(), 1);
+    }
+}
@@ -2,64 +2,273 @@
 Functions for the function call nodes
 */
 
-use crate::errors::LKQLError;
-use crate::lkql_wrapper::{lkql_base_entity, lkql_fun_call_f_arguments, lkql_fun_call_f_name};
-use crate::lkqlc::env::{CompilationEnv, LocalResult};
-use crate::lkqlc::{compile_node, load_var_copy, new_node, node_text};
-use crate::lkqlc::bc::{CALL, MOV};
-use crate::lkqlc::ir::{IRArg, IRInstABC, IRInstAD, IRInstruction};
+use crate::errors::{E_TOO_COMPLEX, E_UNDECLARED_VAR, E_UNSUPPORTED, LKQLError};
+use crate::lkql_wrapper::*;
+use crate::lkqlc::builtins::LENGTH_BUILTIN_NAME;
+use crate::lkqlc::env::{CallFrame, CompilationEnv, LocalResult, UpvalueResult, MAX_SLOTS};
+use crate::lkqlc::node::Node;
+use crate::lkqlc::{compile_in_slot, compile_in_tmp, compile_node, load_var_copy};
+use crate::lkqlc::bc::{LEN, MOV};
+use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction};
 
 
 /// Compile a function call node to LuaJIT bytecode
-pub unsafe fn compile(node: &mut lkql_base_entity, env: &mut CompilationEnv) -> Result<(), LKQLError>  {
-    // Get the two slots for the function and the argument table
-    let slots = env.new_tmps(2);
+/// The call frame is placed at the top of the stack : the function followed by its arguments
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError>  {
+    if is_length_call(node, env) {
+        return compile_length(node, env);
+    }
     let res_slot = env.get_expr_slot();
-    let fun_slot = slots[0];
-    let arg_slot = slots[1];
-
-    // Get the function name
-    let mut fun_id = new_node();
-    lkql_fun_call_f_name(node, &mut fun_id);
-    let fun_name = node_text(&mut fun_id);
-
-    // Load the function variable in the slot
-    env.set_expr_slot(Some(fun_slot));
-    load_var_copy(&*fun_name, env);
 
-    // Get the function argument list
-    let mut arg_list = new_node();
-    lkql_fun_call_f_arguments(node, &mut arg_list);
+    // Open the call frame for the function and its arguments
+    let arg_list = node.field(lkql_fun_call_f_arguments);
+    let arg_count = match frame_arg_count(node, &arg_list) {
+        Err(e) => { return Err(e); }
+        Ok(count) => count
+    };
+    let frame = match env.open_call_frame(arg_count) {
+        Some(frame) => frame,
+        None => { return Err(frame_error(node)); }
+    };
 
-    // Process the arguments to create the calling table
-    env.set_expr_slot(Some(arg_slot));
-    match compile_node(&mut arg_list, env) {
+    // Fill the call frame and call the function, keeping one result if needed
+    let result_count = if res_slot.is_some() { 1 } else { 0 };
+    match compile_call(node, &frame, result_count + 1, env) {
         Err(e) => { return Err(e); }
         Ok(_) => {}
     }
 
-    // Call the function
-    env.add_instruction(IRInstruction::ABC(IRInstABC::new(
-        CALL,
-        IRArg::Slot(fun_slot),
-        IRArg::Literal(1),
-        IRArg::Literal(2)
-    )));
-
-    // Set the expression result
+    // Set the expression result, the result replaces the function in the frame
     if res_slot.is_some() {
         env.add_instruction(IRInstruction::AD(IRInstAD::new(
             MOV,
             IRArg::Slot(res_slot.unwrap()),
-            IRArg::Slot(fun_slot)
+            IRArg::Slot(frame.base)
         )));
     }
 
-    // Free the temporary slots
-    env.free_tmps(slots);
+    // Free the call frame and reset the expr slot
+    env.close_call_frame(frame);
+    env.set_expr_slot(res_slot);
+
+    Ok(())
+}
+
+/// Compile a function call which leaves all its results on the stack from the base slot, the
+/// base slot must be the last busy slot of the frame
+/// This is used for a call as last argument of another call, so all its results are arguments
+pub fn compile_multiple_results(node: &Node, base_slot: u8, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Put the arguments just after the base slot
+    let arg_list = node.field(lkql_fun_call_f_arguments);
+    let arg_count = match frame_arg_count(node, &arg_list) {
+        Err(e) => { return Err(e); }
+        Ok(count) => count
+    };
+    let frame = match env.open_call_frame_at(base_slot, arg_count) {
+        Some(frame) => frame,
+        None => { return Err(frame_error(node)); }
+    };
+
+    // Call the function with a variable result count
+    let res = compile_call(node, &frame, 0, env);
+    env.close_call_frame(frame);
+    res
+}
 
-    // Reset the expr slot
+/// Load the function and the arguments in the call frame slots and emit the call, the
+/// result operand is the wanted result count plus one, zero for all results
+/// A last argument which is a call is expanded to all its results with CALLM
+fn compile_call(node: &Node, frame: &CallFrame, result_operand: u16, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Load the function variable in the base slot
+    let fun_id = node.field(lkql_fun_call_f_name);
+    let fun_name = fun_id.text();
+    let res_slot = env.get_expr_slot();
+    env.set_expr_slot(Some(frame.base));
+    let is_loaded = match load_var_copy(&*fun_name, env) {
+        Some(is_loaded) => is_loaded,
+        None => { return Err(frame_error(node)); }
+    };
     env.set_expr_slot(res_slot);
+    if !is_loaded {
+        return Err(LKQLError::new_located(
+            E_UNDECLARED_VAR,
+            format!("Unknown symbol : {}", fun_name),
+            fun_id.location()
+        ));
+    }
+
+    // Compile the arguments in their slots
+    let arg_list = node.field(lkql_fun_call_f_arguments);
+    let arg_count = arg_list.children_count();
+    let mut is_multiple = false;
+    let mut i: u32 = 0;
+    while i < arg_count {
+        let arg = arg_list.child(i);
+        if arg.kind() != lkql_node_kind_enum_lkql_expr_arg {
+            return Err(LKQLError::new_located(
+                E_UNSUPPORTED,
+                String::from("Named arguments are not supported"),
+                arg.location()
+            ));
+        }
+        let value = arg.field(lkql_expr_arg_f_value_expr);
+
+        let arg_slot = frame.arg_slot(i as u8);
+        let res = if i == arg_count - 1 && value.kind() == lkql_node_kind_enum_lkql_fun_call && !is_length_call(&value, env) {
+            is_multiple = true;
+            compile_multiple_results(&value, arg_slot, env)
+        } else {
+            compile_in_slot(&value, Some(arg_slot), env)
+        };
+        match res {
+            Err(e) => { return Err(e); }
+            Ok(_) => {}
+        }
+        i += 1;
+    }
+
+    env.emit_call(frame, result_operand, is_multiple);
+    Ok(())
+}
+
+/// Get if the call is a call of the length builtin, the name must not be bound by the script
+fn is_length_call(node: &Node, env: &mut CompilationEnv) -> bool {
+    let fun_id = node.field(lkql_fun_call_f_name);
+    let fun_name = fun_id.text();
+    if fun_name != LENGTH_BUILTIN_NAME {
+        return false;
+    }
+    match env.get_local(&fun_name) {
+        LocalResult::NotFound => (),
+        _ => { return false; }
+    }
+    match env.get_upvalue(&fun_name) {
+        UpvalueResult::NotFound => !env.get_global(&fun_name),
+        _ => false
+    }
+}
 
+/// Compile a call of the length builtin to a LEN instruction, the length of a string is its
+/// size in bytes and the length of a list its element count since the lists start at 1
+fn compile_length(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let arg_list = node.field(lkql_fun_call_f_arguments);
+    if arg_list.children_count() != 1 {
+        return Err(LKQLError::new_located(
+            E_UNSUPPORTED,
+            format!("The {} builtin expects one argument", LENGTH_BUILTIN_NAME),
+            node.location()
+        ));
+    }
+    let arg = arg_list.child(0);
+    if arg.kind() != lkql_node_kind_enum_lkql_expr_arg {
+        return Err(LKQLError::new_located(
+            E_UNSUPPORTED,
+            String::from("Named arguments are not supported"),
+            arg.location()
+        ));
+    }
+    let value = arg.field(lkql_expr_arg_f_value_expr);
+
+    // If the length is not needed, just compile the argument for its side effects
+    let res_slot = env.get_expr_slot();
+    if res_slot.is_none() {
+        return compile_node(&value, env);
+    }
+
+    let (tmp, value_slot) = match compile_in_tmp(&value, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        LEN,
+        IRArg::Slot(res_slot.unwrap()),
+        IRArg::Slot(value_slot)
+    )));
+    env.free_tmp(tmp);
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Get the argument count of a call, the function and its arguments must fit in the frame
+fn frame_arg_count(node: &Node, arg_list: &Node) -> Result<u8, LKQLError> {
+    let arg_count = arg_list.children_count() as usize;
+    if arg_count >= MAX_SLOTS {
+        return Err(frame_error(node));
+    }
+    Ok(arg_count as u8)
+}
+
+/// Create the error for a call frame that doesn't fit in the stack
+pub fn frame_error(node: &Node) -> LKQLError {
+    LKQLError::new_located(
+        E_TOO_COMPLEX,
+        String::from("Too many simultaneous values, simplify the expression"),
+        node.location()
+    )
+}
+
+
+// --- Tests of the function calls
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::E_TOO_COMPLEX;
+    use crate::lkqlc::bc::{BCInstruction, CALL, CALLM, KSHORT, LEN};
+    use crate::lkqlc::env::MAX_SLOTS;
+    use crate::lkqlc::test_utils::{compile_and_run, compile_error, main_prototype};
+
+    /// A call whose frame cannot fit in the slots is an error located on the call, whatever
+    /// the argument count
+    #[test]
+    fn too_many_arguments() {
+        for arg_count in [MAX_SLOTS, u8::MAX as usize, MAX_SLOTS + 50] {
+            let source = format!("fun f(x) = x\nval r = f({})", vec!["1"; arg_count].join(", "));
+            let e = compile_error(&source);
+            assert_eq!(e.code, E_TOO_COMPLEX, "{}", e);
+            assert_eq!(e.location.map(|location| location.line), Some(2));
+        }
+    }
+
+    /// The results of a call as last argument are the tail arguments of the enclosing call
+    #[test]
+    fn call_as_last_argument() {
+        let env = compile_and_run(
+            "fun add(a, b) = a + b\nfun id(x) = x\n\
+             assert(add(1, id(2)) == 3)\nassert(add(id(4), add(1, id(2))) == 7)"
+        );
+        let main = main_prototype(&env);
+        assert!(main.op_codes().contains(&CALLM), "{}", main.disassemble());
+    }
+
+    /// The function and its three arguments are in contiguous slots from the call base
+    #[test]
+    fn three_argument_frame() {
+        let env = compile_and_run("fun mix(a, b, c) = a * 100 + b * 10 + c\nval x = mix(1, 2, 3)\nassert(x == 123)");
+        let main = main_prototype(&env);
+        let base = main.instructions.iter().find_map(|inst| match inst {
+            BCInstruction::Abc(abc) if abc.op_code == CALL && abc.c == 4 => Some(abc.a),
+            _ => None
+        }).unwrap_or_else(|| panic!("No call with three arguments :\n{}", main.disassemble()));
+        for (offset, value) in [(1, 1), (2, 2), (3, 3)] {
+            let is_loaded = main.instructions.iter().any(|inst| matches!(
+                inst,
+                BCInstruction::Ad(ad) if ad.op_code == KSHORT && ad.a == base + offset && ad.d == value
+            ));
+            assert!(is_loaded, "The argument {} is not in the slot {}\n{}", value, base + offset, main.disassemble());
+        }
+    }
+
+    /// The length of a string or a list is a LEN instruction, unless the script defines its
+    /// own length function
+    #[test]
+    fn length_builtin() {
+        let env = compile_and_run(
+            "val s = \"hello\"\nval l = [1, s, 3]\nassert(length(s) == 5)\nassert(length(l) == 3)\nassert(length([]) == 0)"
+        );
+        let main = main_prototype(&env);
+        assert_eq!(main.op_codes().iter().filter(|op_code| **op_code == LEN).count(), 3, "{}", main.disassemble());
+
+        let env = compile_and_run("fun length(x) = 42\nassert(length(\"hello\") == 42)");
+        let main = main_prototype(&env);
+        assert!(!main.op_codes().contains(&LEN), "{}", main.disassemble());
+    }
+}
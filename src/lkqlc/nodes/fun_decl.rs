@@ -0,0 +1,183 @@
+/*
+Functions for the function declarations
+*/
+
+use crate::errors::{E_REDECLARATION, E_TOO_COMPLEX, LKQLError};
+use crate::lkql_wrapper::*;
+use crate::lkqlc::bc::{FNEW, ISNEP};
+use crate::lkqlc::env::{CompilationEnv, LocalResult};
+use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction, Primitive};
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::decl_annotation;
+use crate::lkqlc::nodes::fun_call::frame_error;
+use crate::lkqlc::{check_redeclaration, compile_in_slot, compile_in_tmp};
+
+
+/// Compile a function declaration, a function declared at the top level is a global
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Get the function name and the function expression
+    let name_node = node.field(lkql_fun_decl_f_name);
+    let name = name_node.text();
+    let fun_expr = node.field(lkql_fun_decl_f_fun_expr);
+    decl_annotation::record(node, &name, env);
+
+    // Declare the function before compiling it so it can call itself
+    let res_slot = env.get_expr_slot();
+    let binding = if env.is_top_level() {
+        env.add_global(name.clone());
+        LocalResult::Name(name)
+    } else {
+        match check_redeclaration(&name, &name_node, env) {
+            Err(e) => { return Err(e); }
+            Ok(_) => {}
+        }
+        match env.add_local(name) {
+            Some(local) => local,
+            None => { return Err(frame_error(node)); }
+        }
+    };
+
+    // Create the closure in the local slot or in a temporary one to set the global
+    match binding {
+        LocalResult::Slot(slot) => {
+            match compile_function(&fun_expr, slot, env) {
+                Err(e) => { return Err(e); }
+                Ok(_) => {}
+            }
+        }
+        LocalResult::Name(global_name) => {
+            let tmp = match env.new_tmp() {
+                Some(slot) => slot,
+                None => { return Err(frame_error(node)); }
+            };
+            match compile_function(&fun_expr, tmp, env) {
+                Err(e) => { return Err(e); }
+                Ok(_) => {}
+            }
+            if !env.emit_global_set(tmp, &global_name) {
+                return Err(frame_error(node));
+            }
+            env.free_tmp(tmp);
+        }
+        LocalResult::NotFound => ()
+    }
+
+    // A declaration has no value
+    env.set_expr_slot(res_slot);
+    Ok(())
+}
+
+/// Compile a function expression in a child prototype and create its closure in the given slot
+/// The prototype arity is the parameter count, LuaJIT puts the arguments in the slots 0..n
+pub fn compile_function(fun_node: &Node, dest_slot: u8, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Get the parameters and open the function environment
+    let params = fun_node.field(lkql_base_function_f_parameters);
+    let param_count = params.children_count();
+    if param_count > u8::MAX as u32 {
+        return Err(LKQLError::new_located(
+            E_TOO_COMPLEX,
+            format!("Too many parameters ({}), the limit is {}", param_count, u8::MAX),
+            params.location()
+        ));
+    }
+    env.open_env(param_count as u8);
+
+    // Bind the parameters to the first slots, in order, a second parameter with the same name
+    // would hide the first one so it is always an error
+    let mut i: u32 = 0;
+    while i < param_count {
+        let param = params.child(i);
+        let param_id = param.field(lkql_parameter_decl_f_param_identifier);
+        let param_name = param_id.text();
+        if env.is_declared_in_scope(&param_name) {
+            return Err(duplicate_param_error(&param_name, &param_id));
+        }
+        match env.add_local(param_name) {
+            Some(LocalResult::Slot(slot)) if slot as u32 == i => (),
+            _ => { return Err(frame_error(&param)); }
+        }
+        i += 1;
+    }
+
+    // Set the default values of the omitted arguments, they are nil in their slot
+    let mut i: u32 = 0;
+    while i < param_count {
+        let default_expr = params.child(i).field(lkql_parameter_decl_f_default_expr);
+        if !default_expr.is_null() {
+            match compile_default(&default_expr, i as u8, env) {
+                Err(e) => { return Err(e); }
+                Ok(_) => {}
+            }
+        }
+        i += 1;
+    }
+
+    // Compile the body, its value is the function result
+    let body = fun_node.field(lkql_base_function_f_body_expr);
+    let (_, value_slot) = match compile_in_tmp(&body, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+    env.set_return_slot(Some(value_slot));
+    match env.close_env() {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+
+    // Create the closure in the parent prototype
+    let child_index = env.add_child_constant();
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        FNEW,
+        IRArg::Slot(dest_slot),
+        IRArg::Func(child_index)
+    )));
+
+    Ok(())
+}
+
+/// Emit the prologue code putting the default value in the parameter slot if the argument is
+/// nil, so an explicit null argument also takes the default like an omitted one
+/// The test is on nil and not on the truthiness, a false argument is kept
+fn compile_default(default_expr: &Node, slot: u8, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let given_label = env.new_label();
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        ISNEP,
+        IRArg::Slot(slot),
+        IRArg::Primitive(Primitive::Nil)
+    )));
+    env.add_jump(given_label);
+    match compile_in_slot(default_expr, Some(slot), env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+    env.place_label(given_label);
+    Ok(())
+}
+
+/// Create the error for a parameter name already used by a previous parameter
+pub fn duplicate_param_error(name: &str, param_id: &Node) -> LKQLError {
+    LKQLError::new_located(
+        E_REDECLARATION,
+        format!("The parameter {} is declared twice", name),
+        param_id.location()
+    )
+}
+
+
+// --- Tests of the function declarations
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::E_REDECLARATION;
+    use crate::lkqlc::test_utils::compile_error;
+
+    /// A duplicate parameter is an error located on its line, whatever the strict mode
+    #[test]
+    fn duplicate_parameter() {
+        for source in ["fun f(x, x) = x", "val f = (a, b, a) => b"] {
+            let e = compile_error(source);
+            assert_eq!(e.code, E_REDECLARATION, "{}", e);
+            assert_eq!(e.location.map(|location| location.line), Some(1));
+        }
+    }
+}
@@ -0,0 +1,52 @@
+/*
+Functions for the identifier nodes
+*/
+
+use crate::errors::{E_UNDECLARED_VAR, LKQLError};
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::node::Node;
+use crate::lkqlc::load_var;
+use crate::lkqlc::nodes::fun_call::frame_error;
+
+
+/// Compile an identifier as a variable read
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Verify that the identifier value is needed
+    if env.get_expr_slot().is_some() {
+        let name = node.text();
+        let is_loaded = match load_var(&*name, env) {
+            Some(is_loaded) => is_loaded,
+            None => { return Err(frame_error(node)); }
+        };
+        if !is_loaded {
+            return Err(LKQLError::new_located(
+                E_UNDECLARED_VAR,
+                format!("Unknown symbol : {}", name),
+                node.location()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+
+// --- Tests of the identifiers
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::E_UNDECLARED_VAR;
+    use crate::lkqlc::test_utils::compile_error;
+
+    /// The read of an undeclared variable or the call of an undeclared function has the stable
+    /// code of the undeclared symbols, and the location of the symbol
+    #[test]
+    fn undeclared_symbol_code() {
+        for source in ["val x = 1\nval y = x + z", "val x = 1\nval y = f(x)"] {
+            let e = compile_error(source);
+            assert_eq!(e.code, E_UNDECLARED_VAR, "{}", e);
+            assert_eq!(e.code, "E-UNDECLARED-VAR");
+            assert_eq!(e.location.map(|location| location.line), Some(2));
+        }
+    }
+}
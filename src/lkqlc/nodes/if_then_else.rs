@@ -0,0 +1,91 @@
+/*
+Functions for the conditional expressions
+*/
+
+use crate::errors::LKQLError;
+use crate::lkql_wrapper::*;
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::node::Node;
+use crate::lkqlc::{compile_branch, compile_in_slot};
+
+
+/// Compile a conditional expression, both branches put their value in the expression slot
+/// A conditional whose value is not needed is a statement : no slot is reserved nor filled,
+/// the branches are compiled without expression slot for their side effects only. Without else
+/// branch it is just the then branch guarded by the condition
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Get the condition and the branches
+    let condition = node.field(lkql_if_then_else_f_condition);
+    let then_expr = node.field(lkql_if_then_else_f_then_expr);
+    let else_expr = node.field(lkql_if_then_else_f_else_expr);
+
+    // Prepare the labels
+    let res_slot = env.get_expr_slot();
+    let then_label = env.new_label();
+    let else_label = env.new_label();
+    let end_label = env.new_label();
+
+    // Compile the condition and branch on it, a statement without else branch has nothing to
+    // run when the condition is false
+    let is_guard = res_slot.is_none() && else_expr.is_null();
+    match compile_branch(&condition, then_label, if is_guard { end_label } else { else_label }, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+
+    // Compile the then branch
+    env.place_label(then_label);
+    match compile_in_slot(&then_expr, res_slot, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+    if is_guard {
+        env.place_label(end_label);
+        return Ok(());
+    }
+    env.add_jump(end_label);
+
+    // Compile the else branch, an absent one has the null value
+    env.place_label(else_label);
+    if !else_expr.is_null() {
+        match compile_in_slot(&else_expr, res_slot, env) {
+            Err(e) => { return Err(e); }
+            Ok(_) => {}
+        }
+    } else if let Some(slot) = res_slot {
+        env.emit_load_nil(slot, 1);
+    }
+    env.place_label(end_label);
+
+    Ok(())
+}
+
+
+// --- Tests of the conditional expressions
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::bc::{IST, ISF, ISFC, ISLT, ISTC, KPRI};
+    use crate::lkqlc::test_utils::compile_and_run;
+
+    /// A conditional on a comparison jumps to the branches without materializing a boolean
+    #[test]
+    fn branch_on_comparison() {
+        let env = compile_and_run("fun min(a, b) = if a < b then a else b\nassert(min(1, 2) == 1)\nassert(min(4, 3) == 3)");
+        let function = &env.get_program().prototypes[0];
+        let op_codes = function.op_codes();
+        assert!(op_codes.contains(&ISLT), "{}", function.disassemble());
+        assert!(!op_codes.iter().any(|op_code| [KPRI, IST, ISF, ISTC, ISFC].contains(op_code)), "{}", function.disassemble());
+    }
+
+    /// A condition is false only for null and false, the zero and the empty string are true
+    /// like in Lua
+    #[test]
+    fn truthiness() {
+        compile_and_run(
+            "fun truthy(x) = if x then true else false\n\
+             assert(not truthy(null))\nassert(not truthy(false))\n\
+             assert(truthy(0))\nassert(truthy(\"\"))\nassert(truthy(true))\nassert(truthy([]))"
+        );
+    }
+}
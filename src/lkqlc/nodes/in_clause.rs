@@ -0,0 +1,136 @@
+/*
+Functions for the membership tests (in, not in)
+*/
+
+use crate::errors::LKQLError;
+use crate::lkql_wrapper::*;
+use crate::lkqlc::bc::{ISNEV, KPRI};
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction, Primitive};
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::fun_call::frame_error;
+use crate::lkqlc::{compile_in_tmp, compile_node};
+
+
+/// Compile a membership test, a "not in" test materializes the inverted boolean directly
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // If the result is not needed, just compile the operands for their side effects
+    let res_slot = env.get_expr_slot();
+    if res_slot.is_none() {
+        let value_expr = node.field(lkql_in_clause_f_value_expr);
+        let list_expr = node.field(lkql_in_clause_f_list_expr);
+        match compile_node(&value_expr, env) {
+            Err(e) => { return Err(e); }
+            Ok(_) => {}
+        }
+        return compile_node(&list_expr, env);
+    }
+
+    // Emit the search, it jumps to the found label when the value is in the list
+    let found_label = env.new_label();
+    let not_found_label = env.new_label();
+    let end_label = env.new_label();
+    match compile_membership(node, found_label, not_found_label, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+
+    // Materialize the result in the expression slot
+    let negated = is_negated(node);
+    env.place_label(not_found_label);
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        KPRI,
+        IRArg::Slot(res_slot.unwrap()),
+        IRArg::Primitive(if negated { Primitive::True } else { Primitive::False })
+    )));
+    env.add_jump(end_label);
+    env.place_label(found_label);
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        KPRI,
+        IRArg::Slot(res_slot.unwrap()),
+        IRArg::Primitive(if negated { Primitive::False } else { Primitive::True })
+    )));
+    env.place_label(end_label);
+
+    Ok(())
+}
+
+/// Compile a membership test as a condition, a "not in" test just swaps the labels
+pub fn compile_branch(node: &Node, true_label: u64, false_label: u64, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    if is_negated(node) {
+        compile_membership(node, false_label, true_label, env)
+    } else {
+        compile_membership(node, true_label, false_label, env)
+    }
+}
+
+/// Get if the membership test is a "not in" one
+fn is_negated(node: &Node) -> bool {
+    let has_not = node.field(lkql_in_clause_f_has_not);
+    has_not.kind() == lkql_node_kind_enum_lkql_not_present
+}
+
+/// Compile the search of the value in the list, the elements are compared with the Lua equality
+/// and the code jumps to the found label on the first equal one, else to the not found label
+fn compile_membership(node: &Node, found_label: u64, not_found_label: u64, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let value_expr = node.field(lkql_in_clause_f_value_expr);
+    let list_expr = node.field(lkql_in_clause_f_list_expr);
+
+    // Evaluate the value then the list
+    let (value_tmp, value_slot) = match compile_in_tmp(&value_expr, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+    let (list_tmp, list_slot) = match compile_in_tmp(&list_expr, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+
+    // Leave the loop on the first equal element
+    let table_loop = match env.open_table_loop(list_slot) {
+        Some(table_loop) => table_loop,
+        None => { return Err(frame_error(node)); }
+    };
+    let next_label = env.new_label();
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        ISNEV,
+        IRArg::Slot(table_loop.value_slot),
+        IRArg::Slot(value_slot)
+    )));
+    env.add_jump(next_label);
+    env.add_jump(found_label);
+    env.place_label(next_label);
+    env.close_table_loop(table_loop);
+    env.add_jump(not_found_label);
+
+    env.free_tmp(list_tmp);
+    env.free_tmp(value_tmp);
+    Ok(())
+}
+
+
+// --- Tests of the membership tests
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::bc::{FLAG_P_HAS_ILOOP, NOT};
+    use crate::lkqlc::test_utils::{compile_and_run, main_prototype};
+
+    /// Only the prototype of the function looping on the list has the loop flag
+    #[test]
+    fn loop_flag() {
+        let env = compile_and_run("fun has(l, x) = x in l\nassert(has([1, 2], 2))");
+        let prototypes = &env.get_program().prototypes;
+        assert_eq!(prototypes.len(), 2);
+        assert_ne!(prototypes[0].flags & FLAG_P_HAS_ILOOP, 0);
+        assert_eq!(main_prototype(&env).flags & FLAG_P_HAS_ILOOP, 0);
+    }
+
+    /// A "not in" test materializes the inverted boolean without a separate NOT
+    #[test]
+    fn not_in() {
+        let env = compile_and_run("val missing = 3 not in [1, 2, 4]\nval present = 2 not in [1, 2, 4]\nassert(missing)\nassert(present == false)");
+        let main = main_prototype(&env);
+        assert!(!main.op_codes().contains(&NOT), "{}", main.disassemble());
+    }
+}
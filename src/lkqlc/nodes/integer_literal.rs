@@ -2,14 +2,129 @@
 Functions for the integer literals in LKQL
 */
 
-use crate::errors::LKQLError;
-use crate::lkql_wrapper::lkql_base_entity;
+use crate::errors::{E_INVALID_LITERAL, LKQLError};
+use crate::lkqlc::bc::{KNUM, KNum, KSHORT, NumericConstant};
 use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction};
+use crate::lkqlc::node::Node;
 
 
 /// Compile a integer literal
-pub unsafe fn compile(node: &mut lkql_base_entity, env: &mut CompilationEnv) -> Result<(), LKQLError>  {
-    println!("TODO : Compile integer literal");
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError>  {
+    let expr_slot = env.get_expr_slot();
+    if expr_slot.is_some() {
+        let value = match literal_value(node) {
+            Err(e) => { return Err(e); }
+            Ok(value) => value
+        };
+        load_integer(value, expr_slot.unwrap(), env);
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Get the value of an integer literal node
+pub fn literal_value(node: &Node) -> Result<i64, LKQLError> {
+    let text = node.text();
+    match parse_integer(&text) {
+        Some(value) => Ok(value),
+        None => Err(LKQLError::new_located(
+            E_INVALID_LITERAL,
+            format!("Invalid integer literal : {}", text),
+            node.location()
+        ))
+    }
+}
+
+/// Parse the text of an integer literal, it can be decimal, prefixed by 0x, 0o or 0b, or an
+/// Ada based literal like 16#FF#, the digits can be separated by underscores
+/// Return none if the literal is malformed or doesn't fit in 64 bits
+pub fn parse_integer(text: &str) -> Option<i64> {
+    let lower = text.to_lowercase();
+
+    // Get the radix and the digits of the literal
+    let (radix, digits) = if lower.starts_with("0x") {
+        (16, &lower[2..])
+    } else if lower.starts_with("0o") {
+        (8, &lower[2..])
+    } else if lower.starts_with("0b") {
+        (2, &lower[2..])
+    } else if lower.ends_with('#') && lower.find('#') != lower.rfind('#') {
+        let sharp = lower.find('#').unwrap();
+        let radix = match parse_digits(&lower[..sharp], 10) {
+            Some(radix) if radix >= 2 && radix <= 16 => radix as u32,
+            _ => { return None; }
+        };
+        (radix, &lower[sharp + 1..lower.len() - 1])
+    } else {
+        (10, &lower[..])
+    };
+
+    parse_digits(digits, radix)
+}
+
+/// Parse the digits in the given radix, underscores are only allowed between two digits
+fn parse_digits(digits: &str, radix: u32) -> Option<i64> {
+    if digits.is_empty() || digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return None;
+    }
+    if !digits.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    i64::from_str_radix(&digits.replace('_', ""), radix).ok()
+}
+
+/// Emit the loading of an integer value in the given slot
+pub fn load_integer(value: i64, slot: u8, env: &mut CompilationEnv) {
+    // Small integers are directly encoded in the instruction
+    if value >= (i16::MIN as i64) && value <= (i16::MAX as i64) {
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(
+            KSHORT,
+            IRArg::Slot(slot),
+            IRArg::SignedLiteral(value as i16)
+        )));
+    }
+
+    // Other ones are placed in the numeric constants
+    else {
+        let constant = if value >= (i32::MIN as i64) && value <= (i32::MAX as i64) {
+            NumericConstant::Int(value as i32)
+        } else {
+            NumericConstant::Num(KNum::new(value as f64))
+        };
+        let num_index = env.add_numeric_constant(constant);
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(
+            KNUM,
+            IRArg::Slot(slot),
+            IRArg::Num(num_index)
+        )));
+    }
+}
+
+
+// --- Tests of the integer literals
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The decimal, prefixed and based literals have their value, the malformed ones or the
+    /// ones beyond 64 bits have none
+    #[test]
+    fn parse_literals() {
+        for (text, value) in [
+            ("0", 0), ("42", 42), ("1_000_000", 1_000_000),
+            ("0xFF", 255), ("0Xff", 255), ("0o17", 15), ("0b1010_1010", 170),
+            ("16#FF#", 255), ("2#1010#", 10), ("8#777#", 511), ("10#9_9#", 99),
+            ("9223372036854775807", i64::MAX)
+        ] {
+            assert_eq!(parse_integer(text), Some(value), "{}", text);
+        }
+        for text in [
+            "", "_1", "1_", "1__0", "0x", "0xG", "0b2", "1#0#", "17#1#", "16##", "16#F", "16#F#F#",
+            "9223372036854775808", "1.5"
+        ] {
+            assert_eq!(parse_integer(text), None, "{}", text);
+        }
+    }
+}
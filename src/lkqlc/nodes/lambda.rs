@@ -0,0 +1,43 @@
+/*
+Functions for the anonymous functions (lambdas)
+*/
+
+use crate::errors::LKQLError;
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::fun_decl::compile_function;
+
+
+/// Compile an anonymous function, its closure is the expression value and is not bound to any
+/// name, the captured variables are accessed through upvalues like in a named function
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // A closure creation has no side effect, so there is nothing to do if the value is unused
+    let expr_slot = env.get_expr_slot();
+    if expr_slot.is_none() {
+        return Ok(());
+    }
+
+    compile_function(node, expr_slot.unwrap(), env)
+}
+
+
+// --- Tests of the lambdas
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::bc::{FNEW, GSET, UGET};
+    use crate::lkqlc::test_utils::compile_and_run;
+
+    /// A lambda stored in a local is called, it reads the captured local through an upvalue
+    #[test]
+    fn call_local_lambda() {
+        let env = compile_and_run(
+            "fun add_to(base) = {\n    val add = (x) => x + base;\n    add(2)\n}\nassert(add_to(40) == 42)"
+        );
+        let prototypes = &env.get_program().prototypes;
+        let (lambda, function) = (&prototypes[0], &prototypes[1]);
+        assert!(lambda.op_codes().contains(&UGET), "{}", lambda.disassemble());
+        assert!(function.op_codes().contains(&FNEW), "{}", function.disassemble());
+        assert!(!function.op_codes().contains(&GSET), "{}", function.disassemble());
+    }
+}
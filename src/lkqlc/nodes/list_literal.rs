@@ -0,0 +1,219 @@
+/*
+Functions for the list literals
+*/
+
+use crate::errors::LKQLError;
+use crate::lkql_wrapper::*;
+use crate::lkqlc::bc::{KStr, KNum, KTable, TableItem, TDUP, TNEW, TSETB, TSETV};
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::ir::{IRArg, IRInstABC, IRInstAD, IRInstruction};
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::{decimal_literal, integer_literal, string_literal};
+use crate::lkqlc::nodes::fun_call::frame_error;
+use crate::lkqlc::{compile_in_tmp, compile_node};
+
+
+/// Compile a list literal to a Lua array, the LKQL lists are indexed from 1
+/// The constant elements (Ex: numbers, strings, booleans) are placed in a template table
+/// duplicated by TDUP, so a fully constant list is a single instruction, and the other elements
+/// are stored after
+/// A single element list is then a TDUP of a constant table, or a TNEW sized for the element
+/// followed by one TSETB
+/// The table constants cannot be nested, so the constant elements of a list of lists are in the
+/// templates of the inner lists
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let exprs = node.field(lkql_list_literal_f_exprs);
+    let expr_count = exprs.children_count();
+
+    // If the list is not needed, just compile the elements for their side effects
+    let res_slot = env.get_expr_slot();
+    if res_slot.is_none() {
+        let mut i: u32 = 0;
+        while i < expr_count {
+            let expr = exprs.child(i);
+            match compile_node(&expr, env) {
+                Err(e) => { return Err(e); }
+                Ok(_) => {}
+            }
+            i += 1;
+        }
+        return Ok(());
+    }
+    let res_slot = res_slot.unwrap();
+
+    // Find the constant elements once, they are not stored after the table creation
+    let mut is_constant = Vec::with_capacity(expr_count as usize);
+    let mut template = KTable::new();
+    template.array.push(TableItem::Nil);
+    let mut i: u32 = 0;
+    while i < expr_count {
+        let expr = exprs.child(i);
+        match constant_item(&expr) {
+            Err(e) => { return Err(e); }
+            Ok(Some(item)) => {
+                template.array.push(item);
+                is_constant.push(true);
+            }
+            Ok(None) => {
+                template.array.push(TableItem::Nil);
+                is_constant.push(false);
+            }
+        }
+        i += 1;
+    }
+    let has_constant = template.array.iter().any(|item| !matches!(item, TableItem::Nil));
+
+    // Create the table from the template, or an empty one with the array size
+    if has_constant {
+        let table_index = env.add_table_constant(template);
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(
+            TDUP,
+            IRArg::Slot(res_slot),
+            IRArg::Tab(table_index)
+        )));
+    } else {
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(
+            TNEW,
+            IRArg::Slot(res_slot),
+            IRArg::Literal(if expr_count + 1 < 0x7FF { (expr_count + 1) as u16 } else { 0 })
+        )));
+    }
+
+    // Store the other elements
+    let mut i: u32 = 0;
+    while i < expr_count {
+        let expr = exprs.child(i);
+        if !is_constant[i as usize] {
+            match store_element(&expr, res_slot, i + 1, env) {
+                Err(e) => { return Err(e); }
+                Ok(_) => {}
+            }
+        }
+        i += 1;
+    }
+
+    Ok(())
+}
+
+/// Get the table item of a constant element, none if the element is not a literal
+/// The null literal is a nil item, the template hole is what storing nil in the array would
+/// give, so it needs no instruction
+fn constant_item(expr: &Node) -> Result<Option<TableItem>, LKQLError> {
+    match expr.kind() {
+        lkql_node_kind_enum_lkql_null_literal => Ok(Some(TableItem::Nil)),
+        lkql_node_kind_enum_lkql_bool_literal_true => Ok(Some(TableItem::True)),
+        lkql_node_kind_enum_lkql_bool_literal_false => Ok(Some(TableItem::False)),
+        lkql_node_kind_enum_lkql_integer_literal => {
+            match integer_literal::literal_value(expr) {
+                Err(e) => Err(e),
+                Ok(value) => Ok(Some(match i32::try_from(value) {
+                    Ok(int) => TableItem::Int(int),
+                    Err(_) => TableItem::Num(KNum::new(value as f64))
+                }))
+            }
+        }
+        lkql_node_kind_enum_lkql_decimal_literal => {
+            match decimal_literal::literal_value(expr) {
+                Err(e) => Err(e),
+                Ok(value) => Ok(Some(TableItem::Num(KNum::new(value))))
+            }
+        }
+        lkql_node_kind_enum_lkql_string_literal => {
+            Ok(string_literal::constant_value(expr).map(|value| TableItem::String(KStr::new(value))))
+        }
+        _ => Ok(None)
+    }
+}
+
+/// Compile the element and store it at the given index of the table
+fn store_element(expr: &Node, table_slot: u8, index: u32, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let (tmp, value_slot) = match compile_in_tmp(expr, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+
+    // Small indexes are encoded in the instruction
+    if index <= 0xFF {
+        env.add_instruction(IRInstruction::ABC(IRInstABC::new(
+            TSETB,
+            IRArg::Slot(value_slot),
+            IRArg::Slot(table_slot),
+            IRArg::Literal(index as u16)
+        )));
+    } else {
+        let index_slot = match env.new_tmp() {
+            Some(slot) => slot,
+            None => { return Err(frame_error(expr)); }
+        };
+        integer_literal::load_integer(index as i64, index_slot, env);
+        env.add_instruction(IRInstruction::ABC(IRInstABC::new(
+            TSETV,
+            IRArg::Slot(value_slot),
+            IRArg::Slot(table_slot),
+            IRArg::Slot(index_slot)
+        )));
+        env.free_tmp(index_slot);
+    }
+
+    env.free_tmp(tmp);
+    Ok(())
+}
+
+
+// --- Tests of the list literals
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::bc::{BCInstruction, KNUM, TDUP, TNEW, TSETB, TSETV};
+    use crate::lkqlc::test_utils::{compile_and_run, main_prototype};
+
+    /// Each constant inner list is duplicated from its own table constant since the table
+    /// constants cannot be nested, and the outer list is created empty
+    #[test]
+    fn nested_constant_lists() {
+        let env = compile_and_run("val lists = [[1, 2], [\"three\"]]\nassert(length(lists) == 2)");
+        let main = main_prototype(&env);
+        let op_codes = main.op_codes();
+        assert_eq!(op_codes.iter().filter(|op_code| **op_code == TDUP).count(), 2, "{}", main.disassemble());
+        assert_eq!(op_codes.iter().filter(|op_code| **op_code == TNEW).count(), 1, "{}", main.disassemble());
+    }
+
+    /// A constant list is duplicated from its table constant instead of storing each element
+    #[test]
+    fn constant_list() {
+        let env = compile_and_run("val l = [1, 2.5, \"three\", true]\nassert(length(l) == 4)\nassert(\"three\" in l)");
+        let main = main_prototype(&env);
+        let op_codes = main.op_codes();
+        assert_eq!(op_codes.iter().filter(|op_code| **op_code == TDUP).count(), 1, "{}", main.disassemble());
+        assert!(!op_codes.iter().any(|op_code| [TNEW, TSETB, TSETV].contains(op_code)), "{}", main.disassemble());
+    }
+
+    /// A single element list passed as the argument of a call is a TNEW sized for the element
+    /// and one TSETB, or a TDUP when the element is constant
+    #[test]
+    fn single_element_argument() {
+        let env = compile_and_run("fun size(l) = length(l)\nval x = 2\nassert(size([x]) == 1)\nassert(size([1]) == 1)");
+        let main = main_prototype(&env);
+        let op_codes = main.op_codes();
+        let count = |op: u8| op_codes.iter().filter(|op_code| **op_code == op).count();
+        assert_eq!((count(TNEW), count(TSETB), count(TDUP)), (1, 1, 1), "{}", main.disassemble());
+        let is_sized = main.instructions.iter().any(|inst| matches!(
+            inst,
+            BCInstruction::Ad(ad) if ad.op_code == TNEW && ad.d == 2
+        ));
+        assert!(is_sized, "{}", main.disassemble());
+    }
+
+    /// The elements beyond the signed 16 bit indexes are stored at their index loaded from a
+    /// numeric constant, so the list has no hole
+    #[test]
+    fn long_list() {
+        let elements = vec!["x"; 32769].join(", ");
+        let env = compile_and_run(&format!(
+            "val x = 7\nval y = 8\nval l = [{}, y]\nassert(length(l) == 32770)\nassert(length(filter(l, (e) => e == y)) == 1)",
+            elements
+        ));
+        let op_codes = main_prototype(&env).op_codes();
+        assert!(op_codes.contains(&TSETV) && op_codes.contains(&KNUM));
+    }
+}
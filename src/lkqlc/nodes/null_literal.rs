@@ -0,0 +1,18 @@
+/*
+Functions for the null literal
+*/
+
+use crate::errors::LKQLError;
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::node::Node;
+
+
+/// Compile a null literal
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let expr_slot = env.get_expr_slot();
+    if expr_slot.is_some() {
+        env.emit_load_nil(expr_slot.unwrap(), 1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,40 @@
+/*
+Functions for the parenthesized expressions
+*/
+
+use crate::errors::LKQLError;
+use crate::lkql_wrapper::lkql_paren_expr_f_expr;
+use crate::lkqlc::compile_node;
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::node::Node;
+
+
+/// Compile a parenthesized expression, langkit keeps a node for the parentheses so the inner
+/// expression is compiled in place with the same expression slot
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    compile_node(&inner_expr(node), env)
+}
+
+/// Get the inner expression of the parenthesized expression
+pub fn inner_expr(node: &Node) -> Node {
+    node.field(lkql_paren_expr_f_expr)
+}
+
+
+// --- Tests of the parenthesized expressions
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::bc::MOV;
+    use crate::lkqlc::test_utils::compile_and_run;
+
+    /// The parentheses group the operations, and add no move of the inner value
+    #[test]
+    fn grouped_operations() {
+        let env = compile_and_run(
+            "fun f(x) = (x + 2) * 3\nassert(f(1) == 9)\nassert((1 + 2) * 3 == 9)\nassert(1 + 2 * 3 == 7)\nassert(((f(0))) == 6)"
+        );
+        let function = &env.get_program().prototypes[0];
+        assert!(!function.op_codes().contains(&MOV), "{}", function.disassemble());
+    }
+}
@@ -0,0 +1,218 @@
+/*
+Functions for the patterns, used by the queries to filter the traversed nodes
+*/
+
+use crate::errors::{E_UNSUPPORTED, LKQLError};
+use crate::lkql_wrapper::*;
+use crate::lkqlc::bc::{ISF, ISNEN, ISNES, KSTR, LEN, MOV, NumericConstant, TGETB};
+use crate::lkqlc::builtins::{IS_LIST_GLOBAL_NAME, NODE_PROPERTY_GLOBAL_NAME};
+use crate::lkqlc::env::{CompilationEnv, LocalResult};
+use crate::lkqlc::ir::{IRArg, IRInstABC, IRInstAD, IRInstruction};
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::fun_call::frame_error;
+
+
+/// Compile the test of the pattern against the value in the subject slot
+/// The code falls through when the value matches and jumps to the false label otherwise
+/// The binding patterns declare locals, so the caller opens a pseudo environment for them
+pub fn compile_match(pattern: &Node, subject_slot: u8, false_label: u64, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    match pattern.kind() {
+        lkql_node_kind_enum_lkql_node_kind_pattern => compile_kind_match(pattern, subject_slot, false_label, env),
+        lkql_node_kind_enum_lkql_binding_pattern => compile_binding_match(pattern, subject_slot, false_label, env),
+        lkql_node_kind_enum_lkql_universal_pattern => Ok(()),
+        lkql_node_kind_enum_lkql_list_pattern => compile_list_match(pattern, subject_slot, false_label, env),
+        _ => Err(LKQLError::new_located(
+            E_UNSUPPORTED,
+            String::from("This pattern is not supported"),
+            pattern.location()
+        ))
+    }
+}
+
+/// Compile the test of a binding pattern, the subject is put in a new local named by the
+/// binding then the value pattern is tested
+fn compile_binding_match(pattern: &Node, subject_slot: u8, false_label: u64, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let binding = pattern.field(lkql_binding_pattern_f_binding);
+    let local = match env.add_local(binding.text()) {
+        Some(local) => local,
+        None => { return Err(frame_error(pattern)); }
+    };
+    match local {
+        LocalResult::Slot(slot) => {
+            env.add_instruction(IRInstruction::AD(IRInstAD::new(
+                MOV,
+                IRArg::Slot(slot),
+                IRArg::Slot(subject_slot)
+            )));
+        }
+        LocalResult::Name(global_name) => {
+            if !env.emit_global_set(subject_slot, &global_name) {
+                return Err(frame_error(pattern));
+            }
+        }
+        LocalResult::NotFound => ()
+    }
+
+    let value_pattern = pattern.field(lkql_binding_pattern_f_value_pattern);
+    compile_match(&value_pattern, subject_slot, false_label, env)
+}
+
+/// Compile the test of a node kind pattern, the kind name of the subject is compared to the
+/// pattern one
+fn compile_kind_match(pattern: &Node, subject_slot: u8, false_label: u64, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let kind_id = pattern.field(lkql_node_kind_pattern_f_kind_name);
+    let kind_name = kind_id.text();
+
+    // Get the subject kind name with the node property function
+    let property_slot = match env.new_tmp() {
+        Some(slot) => slot,
+        None => { return Err(frame_error(pattern)); }
+    };
+    let property_index = env.add_string_constant(String::from("kind_name"));
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        KSTR,
+        IRArg::Slot(property_slot),
+        IRArg::Str(property_index)
+    )));
+    if !env.emit_runtime_call(NODE_PROPERTY_GLOBAL_NAME, &[subject_slot, property_slot], property_slot) {
+        return Err(frame_error(pattern));
+    }
+
+    // Compare it with the pattern kind name
+    let kind_index = env.add_string_constant(kind_name);
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        ISNES,
+        IRArg::Slot(property_slot),
+        IRArg::Str(kind_index)
+    )));
+    env.add_jump(false_label);
+    env.free_tmp(property_slot);
+    Ok(())
+}
+
+/// Compile the test of a list pattern, the subject length must be the number of element
+/// patterns then each element is tested against the pattern at its position
+/// The subject must be a list, the splat patterns are not supported yet
+fn compile_list_match(pattern: &Node, subject_slot: u8, false_label: u64, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Get the element patterns
+    let patterns = pattern.field(lkql_list_pattern_f_patterns);
+    let mut elements = Vec::new();
+    let pattern_count = patterns.children_count();
+    let mut i: u32 = 0;
+    while i < pattern_count {
+        let element = patterns.child(i);
+        if !element.is_trivia() {
+            if element.kind() == lkql_node_kind_enum_lkql_splat_pattern || elements.len() >= 0xFF {
+                return Err(LKQLError::new_located(
+                    E_UNSUPPORTED,
+                    String::from("This list pattern is not supported"),
+                    element.location()
+                ));
+            }
+            elements.push(element);
+        }
+        i += 1;
+    }
+
+    // Verify the subject is a list, the length of a node would raise an error
+    let element_slot = match env.new_tmp() {
+        Some(slot) => slot,
+        None => { return Err(frame_error(pattern)); }
+    };
+    if !env.emit_runtime_call(IS_LIST_GLOBAL_NAME, &[subject_slot], element_slot) {
+        return Err(frame_error(pattern));
+    }
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        ISF,
+        IRArg::None,
+        IRArg::Slot(element_slot)
+    )));
+    env.add_jump(false_label);
+
+    // Verify the subject length
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        LEN,
+        IRArg::Slot(element_slot),
+        IRArg::Slot(subject_slot)
+    )));
+    let count_index = env.add_numeric_constant(NumericConstant::Int(elements.len() as i32));
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        ISNEN,
+        IRArg::Slot(element_slot),
+        IRArg::Num(count_index)
+    )));
+    env.add_jump(false_label);
+
+    // Test the elements, the LKQL lists are indexed from 1
+    for (i, element) in elements.iter_mut().enumerate() {
+        env.add_instruction(IRInstruction::ABC(IRInstABC::new(
+            TGETB,
+            IRArg::Slot(element_slot),
+            IRArg::Slot(subject_slot),
+            IRArg::Literal((i + 1) as u16)
+        )));
+        compile_match(element, element_slot, false_label, env)?;
+    }
+    env.free_tmp(element_slot);
+    Ok(())
+}
+
+
+// --- Tests of the patterns
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::E_UNKNOWN_NODE;
+    use crate::lkqlc::bc::{ISF, ISNEN, ISNES, LEN, TGETB};
+    use crate::lkqlc::builtins::IS_LIST_GLOBAL_NAME;
+    use crate::lkqlc::test_utils::{compile, compile_error, compile_with, main_prototype, run_on_unit, test_options};
+
+    /// The universal pattern matches every node without any test, so it selects more nodes
+    /// than a kind pattern
+    #[test]
+    fn universal_pattern() {
+        let (env, bytecode) = compile("val all = select *\nassert(length(all) > length(select Identifier))");
+        let main = main_prototype(&env);
+        assert_eq!(main.op_codes().iter().filter(|op_code| **op_code == ISNES).count(), 1, "{}", main.disassemble());
+
+        assert_eq!(run_on_unit("val x = 1\nval y = x", &bytecode), Ok(()));
+    }
+
+    /// A two element list pattern first tests that the subject is a list, the traversed nodes
+    /// are not so the query selects nothing instead of raising an error on their length
+    #[test]
+    fn two_element_list_pattern() {
+        let (env, bytecode) = compile("val pairs = select [*, Identifier]\nassert(length(pairs) == 0)");
+        let main = main_prototype(&env);
+        let op_codes = main.op_codes();
+        let count = |op: u8| op_codes.iter().filter(|op_code| **op_code == op).count();
+        assert_eq!((count(ISF), count(LEN), count(ISNEN), count(TGETB)), (1, 1, 1, 2), "{}", main.disassemble());
+        let guard = op_codes.iter().position(|op_code| *op_code == ISF).unwrap();
+        assert!(op_codes.iter().position(|op_code| *op_code == LEN).unwrap() > guard, "{}", main.disassemble());
+
+        assert_eq!(run_on_unit("val x = 1\nval y = [x, x]", &bytecode), Ok(()));
+    }
+
+    /// The list test of the patterns accepts the lists and rejects the nodes and the other values
+    #[test]
+    fn list_pattern_subjects() {
+        let mut options = test_options();
+        options.host_globals.push(String::from(IS_LIST_GLOBAL_NAME));
+        let (_, bytecode) = compile_with(
+            "assert(__lkql_is_list([1, 2]))\n\
+             assert(__lkql_is_list([]))\n\
+             assert(not __lkql_is_list(root))\n\
+             assert(not __lkql_is_list(\"ab\"))",
+            options
+        );
+        assert_eq!(run_on_unit("val x = 1", &bytecode), Ok(()));
+    }
+
+    /// The compiler has no "match" expression yet, so the universal pattern of a match arm is
+    /// reported as an unknown node instead of being compiled
+    #[test]
+    fn match_universal_pattern() {
+        let error = compile_error("val x = 1\nval y = match x\n    case * => 1");
+        assert_eq!(error.code, E_UNKNOWN_NODE, "{}", error);
+    }
+}
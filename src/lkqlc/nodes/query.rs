@@ -0,0 +1,221 @@
+/*
+Functions for the queries (from ... select ...)
+*/
+
+use crate::errors::{E_UNSUPPORTED, LKQLError};
+use crate::lkql_wrapper::*;
+use crate::lkqlc::bc::{ADDVN, ADDVV, KSHORT, MOV, NumericConstant, TNEW, TSETV};
+use crate::lkqlc::builtins::{DESCENDANTS_GLOBAL_NAME, ROOT_GLOBAL_NAME};
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstABC, IRInstruction};
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::fun_call::frame_error;
+use crate::lkqlc::nodes::pattern;
+use crate::lkqlc::compile_in_tmp;
+
+
+/// Compile a query, the nodes of the traversed subtree matching the pattern are collected in
+/// an array, or the first one is the result for a "select first" query
+/// The traversal root is the "from" expression if any, else the analysed unit root
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let through_expr = node.field(lkql_query_f_through_expr);
+    if !through_expr.is_null() {
+        return Err(LKQLError::new_located(
+            E_UNSUPPORTED,
+            String::from("Queries with a \"through\" expression are not supported"),
+            through_expr.location()
+        ));
+    }
+
+    // The query is evaluated even if its value is unused, like a call
+    let expr_slot = env.get_expr_slot();
+    let res_slot = match expr_slot {
+        Some(slot) => slot,
+        None => match env.new_tmp() {
+            Some(slot) => slot,
+            None => { return Err(frame_error(node)); }
+        }
+    };
+
+    // Get the traversal root in the nodes slot
+    let from_expr = node.field(lkql_query_f_from_expr);
+    let nodes_slot = if from_expr.is_null() {
+        let slot = match env.new_tmp() {
+            Some(slot) => slot,
+            None => { return Err(frame_error(node)); }
+        };
+        if !env.emit_global_get(slot, ROOT_GLOBAL_NAME) {
+            return Err(frame_error(node));
+        }
+        slot
+    } else {
+        match compile_in_tmp(&from_expr, env) {
+            Err(e) => { return Err(e); }
+            Ok((tmp, value_slot)) => {
+                if tmp != value_slot {
+                    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+                        MOV,
+                        IRArg::Slot(tmp),
+                        IRArg::Slot(value_slot)
+                    )));
+                }
+                tmp
+            }
+        }
+    };
+
+    // Replace the root by the array of the subtree nodes
+    if !env.emit_runtime_call(DESCENDANTS_GLOBAL_NAME, &[nodes_slot], nodes_slot) {
+        return Err(frame_error(node));
+    }
+
+    // Filter the nodes with the pattern
+    let query_kind = node.field(lkql_query_f_query_kind);
+    let pattern = node.field(lkql_query_f_pattern);
+    let res = if query_kind.kind() == lkql_node_kind_enum_lkql_query_kind_first {
+        compile_select_first(node, &pattern, nodes_slot, res_slot, env)
+    } else {
+        compile_select_all(node, &pattern, nodes_slot, res_slot, env)
+    };
+
+    // Free the temporary slots
+    env.free_tmp(nodes_slot);
+    if expr_slot.is_none() {
+        env.free_tmp(res_slot);
+    }
+    res
+}
+
+/// Collect the nodes matching the pattern in a new array in the result slot
+fn compile_select_all(
+    node: &Node,
+    pattern: &Node,
+    nodes_slot: u8,
+    res_slot: u8,
+    env: &mut CompilationEnv
+) -> Result<(), LKQLError> {
+    // Create the result array and its element count
+    let count_slot = match env.new_tmp() {
+        Some(slot) => slot,
+        None => { return Err(frame_error(node)); }
+    };
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        TNEW,
+        IRArg::Slot(res_slot),
+        IRArg::Literal(0)
+    )));
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        KSHORT,
+        IRArg::Slot(count_slot),
+        IRArg::SignedLiteral(0)
+    )));
+
+    // Append each matching node to the result
+    let table_loop = match env.open_table_loop(nodes_slot) {
+        Some(table_loop) => table_loop,
+        None => { return Err(frame_error(node)); }
+    };
+    let next_label = env.new_label();
+    env.open_pseudo_env();
+    match pattern::compile_match(pattern, table_loop.value_slot, next_label, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+    let one_index = env.add_numeric_constant(NumericConstant::Int(1));
+    if !env.emit_arith_constant(ADDVN, ADDVV, count_slot, count_slot, one_index) {
+        return Err(frame_error(node));
+    }
+    env.add_instruction(IRInstruction::ABC(IRInstABC::new(
+        TSETV,
+        IRArg::Slot(table_loop.value_slot),
+        IRArg::Slot(res_slot),
+        IRArg::Slot(count_slot)
+    )));
+    env.place_label(next_label);
+    env.close_pseudo_env();
+    env.close_table_loop(table_loop);
+
+    env.free_tmp(count_slot);
+    Ok(())
+}
+
+/// Put the first node matching the pattern in the result slot, or null if there is none
+fn compile_select_first(
+    node: &Node,
+    pattern: &Node,
+    nodes_slot: u8,
+    res_slot: u8,
+    env: &mut CompilationEnv
+) -> Result<(), LKQLError> {
+    env.emit_load_nil(res_slot, 1);
+
+    // Leave the loop on the first matching node
+    let table_loop = match env.open_table_loop(nodes_slot) {
+        Some(table_loop) => table_loop,
+        None => { return Err(frame_error(node)); }
+    };
+    let next_label = env.new_label();
+    let end_label = env.new_label();
+    env.open_pseudo_env();
+    match pattern::compile_match(pattern, table_loop.value_slot, next_label, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        MOV,
+        IRArg::Slot(res_slot),
+        IRArg::Slot(table_loop.value_slot)
+    )));
+    env.add_jump(end_label);
+    env.place_label(next_label);
+    env.close_pseudo_env();
+    env.close_table_loop(table_loop);
+    env.place_label(end_label);
+
+    Ok(())
+}
+
+
+// --- Tests of the queries
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::E_UNDECLARED_VAR;
+    use crate::lkqlc::bc::MOV;
+    use crate::lkqlc::test_utils::{compile, compile_error, run_on_unit};
+
+    /// A query with a "from" expression only visits the subtree of its value, while a bare
+    /// query visits the whole analysed unit
+    #[test]
+    fn from_select() {
+        let (_, bytecode) = compile(
+            "val decl = node_property(root, \"child\", 2)\n\
+             val under_decl = from decl select Identifier\n\
+             val everywhere = select Identifier\n\
+             val first_id = from decl select first Identifier\n\
+             assert(length(under_decl) == 2)\n\
+             assert(length(everywhere) == 3)\n\
+             assert(node_property(first_id, \"text\") == \"y\")"
+        );
+
+        assert_eq!(run_on_unit("val x = 1\nval y = x", &bytecode), Ok(()));
+    }
+
+    /// The binding of a pattern is a local of the query iteration holding the matched node, it
+    /// is not visible after the query
+    #[test]
+    fn binding_pattern() {
+        let (env, bytecode) = compile(
+            "fun count_ids() = { val ids = select id@Identifier; length(ids) }\n\
+             assert(count_ids() == 3)"
+        );
+        let function = &env.get_program().prototypes[0];
+        assert!(function.op_codes().contains(&MOV), "{}", function.disassemble());
+
+        assert_eq!(run_on_unit("val x = 1\nval y = x", &bytecode), Ok(()));
+
+        let e = compile_error("fun leak() = {\n    val ids = select id@Identifier;\n    id\n}");
+        assert_eq!(e.code, E_UNDECLARED_VAR, "{}", e);
+        assert_eq!(e.location.map(|location| location.line), Some(3));
+    }
+}
@@ -0,0 +1,265 @@
+/*
+Functions for the relational binary operations (==, !=, <, <=, >, >=)
+*/
+
+use crate::errors::{E_UNKNOWN_OPERATOR, LKQLError};
+use crate::lkql_trace;
+use crate::lkql_wrapper::*;
+use crate::lkqlc::bc::{ISEQN, ISEQP, ISEQV, ISGE, ISGT, ISLE, ISLT, ISNEN, ISNEP, ISNEV, KPRI};
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction, Primitive};
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::arith_bin_op::numeric_constant;
+use crate::lkqlc::{compile_in_target, compile_in_tmp, compile_node};
+
+
+/// Compile a relational binary operation
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Get the operands and the operator
+    let left = node.field(lkql_bin_op_f_left);
+    let op = node.field(lkql_bin_op_f_op);
+    let right = node.field(lkql_bin_op_f_right);
+    let op_kind = op.kind();
+
+    // If the result is not needed, just compile the operands for their side effects
+    let res_slot = env.get_expr_slot();
+    if res_slot.is_none() {
+        match compile_node(&left, env) {
+            Err(e) => { return Err(e); }
+            Ok(_) => {}
+        }
+        return compile_node(&right, env);
+    }
+
+    // Emit the comparison, it executes the next jump if it is true
+    let true_label = env.new_label();
+    let end_label = env.new_label();
+    match compile_comparison(node, &left, op_kind, &right, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+    env.add_jump(true_label);
+
+    // Materialize the comparison result in the expression slot
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        KPRI,
+        IRArg::Slot(res_slot.unwrap()),
+        IRArg::Primitive(Primitive::False)
+    )));
+    env.add_jump(end_label);
+    env.place_label(true_label);
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        KPRI,
+        IRArg::Slot(res_slot.unwrap()),
+        IRArg::Primitive(Primitive::True)
+    )));
+    env.place_label(end_label);
+
+    Ok(())
+}
+
+/// Compile a relational binary operation as a condition, the comparison jumps directly to the
+/// labels without materializing a boolean
+pub fn compile_branch(node: &Node, true_label: u64, false_label: u64, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let left = node.field(lkql_bin_op_f_left);
+    let op = node.field(lkql_bin_op_f_op);
+    let right = node.field(lkql_bin_op_f_right);
+    let op_kind = op.kind();
+
+    match compile_comparison(node, &left, op_kind, &right, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+    env.add_jump(true_label);
+    env.add_jump(false_label);
+    Ok(())
+}
+
+/// Emit the comparison instruction of the operands, the caller must add the jump to execute
+/// when the comparison is true just after it
+fn compile_comparison(
+    node: &Node,
+    left: &Node,
+    op_kind: lkql_node_kind_enum,
+    right: &Node,
+    env: &mut CompilationEnv
+) -> Result<(), LKQLError> {
+    match op_kind {
+        lkql_node_kind_enum_lkql_op_eq => compile_equality(left, right, ISEQV, ISEQN, ISEQP, env),
+        lkql_node_kind_enum_lkql_op_neq => compile_equality(left, right, ISNEV, ISNEN, ISNEP, env),
+        lkql_node_kind_enum_lkql_op_lt => compile_ordering(left, right, ISLT, env),
+        lkql_node_kind_enum_lkql_op_leq => compile_ordering(left, right, ISLE, env),
+        lkql_node_kind_enum_lkql_op_gt => compile_ordering(left, right, ISGT, env),
+        lkql_node_kind_enum_lkql_op_geq => compile_ordering(left, right, ISGE, env),
+        _ => Err(LKQLError::new_located(
+            E_UNKNOWN_OPERATOR,
+            String::from("Unknown relational operator"),
+            node.location()
+        ))
+    }
+}
+
+/// Emit an equality test, comparing against null, true or false uses the primitive opcode
+/// The unit value is not a primitive : "x == ()" loads the unit sentinel and compares the
+/// variables, so it is never equal to null
+/// Comparing against a number literal, or a negated one, uses the numeric opcode with the
+/// literal as a constant, a value of another type is just not equal to it
+/// The ordering of two values has no such opcode, and the ISNUM guards are not emitted : the
+/// generic comparisons already take the numeric fast path of the VM and of the JIT, so
+/// tracking which slots are known to be numeric would only remove type checks the JIT already
+/// specializes away
+fn compile_equality(
+    left: &Node,
+    right: &Node,
+    var_op: u8,
+    num_op: u8,
+    pri_op: u8,
+    env: &mut CompilationEnv
+) -> Result<(), LKQLError> {
+    // Equality is symmetric, so put the primitive operand on the right
+    let (var_node, pri_node) = if left.primitive().is_some() && right.primitive().is_none() {
+        (right, left)
+    } else {
+        (left, right)
+    };
+
+    // If the right operand is a primitive, compare with it directly
+    let primitive = pri_node.primitive();
+    if primitive.is_some() {
+        lkql_trace!("Compare with the primitive {:?}", primitive.unwrap());
+        let (tmp, value_slot) = match compile_in_target(var_node, env) {
+            Err(e) => { return Err(e); }
+            Ok(res) => res
+        };
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(
+            pri_op,
+            IRArg::Slot(value_slot),
+            IRArg::Primitive(primitive.unwrap())
+        )));
+        if tmp.is_some() {
+            env.free_tmp(tmp.unwrap());
+        }
+        return Ok(());
+    }
+
+    // If an operand is a number literal, compare with it as a numeric constant
+    let (var_node, num_node) = if is_number_literal(var_node) && !is_number_literal(pri_node) {
+        (pri_node, var_node)
+    } else {
+        (var_node, pri_node)
+    };
+    let constant = match numeric_constant(num_node) {
+        Err(e) => { return Err(e); }
+        Ok(constant) => constant
+    };
+    if constant.is_some() {
+        let num_index = env.add_numeric_constant(constant.unwrap());
+        let (tmp, value_slot) = match compile_in_target(var_node, env) {
+            Err(e) => { return Err(e); }
+            Ok(res) => res
+        };
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(
+            num_op,
+            IRArg::Slot(value_slot),
+            IRArg::Num(num_index)
+        )));
+        if tmp.is_some() {
+            env.free_tmp(tmp.unwrap());
+        }
+        return Ok(());
+    }
+
+    // Else compare the two variables
+    compile_ordering(var_node, num_node, var_op, env)
+}
+
+/// Emit a comparison between two variables
+fn compile_ordering(
+    left: &Node,
+    right: &Node,
+    op_code: u8,
+    env: &mut CompilationEnv
+) -> Result<(), LKQLError> {
+    // Compile the left operand in the expression slot when possible and the right one in a
+    // temporary slot, the expression slot is still free while the right operand is computed
+    let (left_tmp, left_slot) = match compile_in_target(left, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+    let (right_tmp, right_slot) = match compile_in_tmp(right, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+
+    // Emit the comparison
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        op_code,
+        IRArg::Slot(left_slot),
+        IRArg::Slot(right_slot)
+    )));
+
+    if left_tmp.is_some() {
+        env.free_tmp(left_tmp.unwrap());
+    }
+    env.free_tmp(right_tmp);
+    Ok(())
+}
+
+/// Get if the node is an integer or decimal literal, or the negation of one
+fn is_number_literal(node: &Node) -> bool {
+    match node.kind() {
+        lkql_node_kind_enum_lkql_integer_literal | lkql_node_kind_enum_lkql_decimal_literal => true,
+        lkql_node_kind_enum_lkql_un_op => {
+            node.field(lkql_un_op_f_op).kind() == lkql_node_kind_enum_lkql_op_minus &&
+                is_number_literal(&node.field(lkql_un_op_f_operand))
+        }
+        _ => false
+    }
+}
+
+
+// --- Tests of the relational operations
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::bc::{ISEQN, ISEQP, ISEQV, ISNEN, ISNEP, ISNEV, UNM};
+    use crate::lkqlc::test_utils::{compile_and_run, main_prototype};
+
+    /// The comparisons with a number literal use the numeric opcodes with a constant operand
+    /// instead of the generic comparison of two slots
+    #[test]
+    fn compare_with_number_literal() {
+        let env = compile_and_run("assert(1 == 1)\nassert(2 != 3)\nassert((1.5 == 2) == false)\nval x = \"1\"\nassert(x != 1)");
+        let main = main_prototype(&env);
+        let op_codes = main.op_codes();
+        assert!(op_codes.contains(&ISEQN) && op_codes.contains(&ISNEN), "{}", main.disassemble());
+        assert!(!op_codes.contains(&ISEQV) && !op_codes.contains(&ISNEV), "{}", main.disassemble());
+    }
+
+    /// A negated number literal is a numeric constant of the comparison, on either side
+    #[test]
+    fn compare_with_negated_literal() {
+        let env = compile_and_run("val x = -1\nassert(x == -1)\nassert(-2 != x)\nassert((x == -1.5) == false)");
+        let main = main_prototype(&env);
+        let op_codes = main.op_codes();
+        assert!(op_codes.contains(&ISEQN) && op_codes.contains(&ISNEN), "{}", main.disassemble());
+        assert!(!op_codes.contains(&ISEQV) && !op_codes.contains(&ISNEV), "{}", main.disassemble());
+        assert!(!op_codes.contains(&UNM), "{}", main.disassemble());
+    }
+
+    /// The comparisons with null and the boolean literals use the primitive opcodes instead of
+    /// loading the primitive in a slot
+    #[test]
+    fn compare_with_primitive() {
+        let env = compile_and_run(
+            "fun is_null(x) = x == null\nfun is_true(x) = x == true\nfun is_not_false(x) = x != false\n\
+             assert(is_null(null))\nassert(not is_null(1))\nassert(is_true(true))\nassert(is_not_false(true))\nassert(not is_not_false(false))"
+        );
+        let prototypes = &env.get_program().prototypes;
+        for (function, op_code) in prototypes.iter().zip([ISEQP, ISEQP, ISNEP]) {
+            let op_codes = function.op_codes();
+            assert!(op_codes.contains(&op_code), "{}", function.disassemble());
+            assert!(!op_codes.contains(&ISEQV) && !op_codes.contains(&ISNEV), "{}", function.disassemble());
+        }
+    }
+}
@@ -2,34 +2,288 @@
 Functions for the string literals
 */
 
-use crate::errors::LKQLError;
-use crate::lkql_wrapper::lkql_base_entity;
-use crate::lkqlc::bc::KSTR;
+use std::ptr::null_mut;
+use crate::errors::{E_INVALID_LITERAL, LKQLError, SourceLocation};
+use crate::lkql_wrapper::*;
+use crate::lkqlc::bc::{CAT, KSTR};
+use crate::lkqlc::builtins::IMG_BUILTIN_NAME;
 use crate::lkqlc::env::CompilationEnv;
-use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction};
-use crate::lkqlc::node_text;
+use crate::lkqlc::emitter::Emitter;
+use crate::lkqlc::ir::{IRArg, IRInstABC, IRInstruction};
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::fun_call::frame_error;
+use crate::lkqlc::{check_context, compile_in_slot, compile_node, parse_lkql_buffer_in};
 
 
+// A piece of a string literal, a constant text or the source of an interpolated expression
+// with the offset of its first character in the literal content
+#[derive(Debug, PartialEq)]
+enum StringPiece {
+    Text(String),
+    Expr(String, usize)
+}
+
 /// Compile a string literal
-pub unsafe fn compile(node: &mut lkql_base_entity, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+/// The "\{expr}" sequences are interpolated : the expressions are converted to strings and
+/// concatenated with the constant pieces, a string without them is a single constant
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Split the string value in pieces
+    let full_str = node.text();
+    let real_str = &full_str[1..full_str.len() - 1];
+    let pieces = match split_interpolation(real_str) {
+        None => {
+            return Err(LKQLError::new_located(
+                E_INVALID_LITERAL,
+                String::from("Unterminated interpolation in the string literal"),
+                node.location()
+            ));
+        }
+        Some(pieces) => pieces
+    };
+    if pieces.iter().any(|piece| matches!(piece, StringPiece::Expr(..))) {
+        return compile_interpolation(node, &pieces, env);
+    }
+
     // Get the expression slot and verify that there is one
     let expr_slot = env.get_expr_slot();
     if expr_slot.is_some() {
-        // Get the string value
-        let full_str = node_text(node);
-        let real_str = &full_str[1..full_str.len() - 1];
-
-        // Add the string in the constant table
-        let str_index = env.add_string_constant(String::from(real_str));
-
-        // Add the instruction to the compilation result
-        let inst = IRInstruction::AD(IRInstAD::new(
-            KSTR,
-            IRArg::Slot(expr_slot.unwrap()),
-            IRArg::Str(str_index)
-        ));
-        env.add_instruction(inst);
+        emit_constant(String::from(real_str), expr_slot.unwrap(), env);
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Add the string to the constant table and load it in the slot
+pub fn emit_constant<E: Emitter>(value: String, slot: u8, emitter: &mut E) {
+    let str_index = emitter.add_string_constant(value);
+    emitter.emit_ad(
+        KSTR,
+        IRArg::Slot(slot),
+        IRArg::Str(str_index)
+    );
+}
+
+/// Get the value of a string literal without interpolation, it is a compile time constant
+/// Return none if the node is not such a literal
+pub fn constant_value(node: &Node) -> Option<String> {
+    if node.kind() != lkql_node_kind_enum_lkql_string_literal {
+        return None;
+    }
+    let full_str = node.text();
+    match split_interpolation(&full_str[1..full_str.len() - 1]) {
+        Some(pieces) if pieces.len() == 1 => match &pieces[0] {
+            StringPiece::Text(text) => Some(text.clone()),
+            StringPiece::Expr(..) => None
+        },
+        _ => None
+    }
+}
+
+/// Compile an interpolated string, the pieces are put in consecutive slots at the top of the
+/// stack and concatenated by one CAT
+fn compile_interpolation(node: &Node, pieces: &Vec<StringPiece>, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // If the string is not needed, just compile the expressions for their side effects
+    let res_slot = env.get_expr_slot();
+    if res_slot.is_none() {
+        for piece in pieces {
+            if let StringPiece::Expr(source, offset) = piece {
+                let expr = match parse_expression(node, source, *offset) {
+                    Err(e) => { return Err(e); }
+                    Ok(expr) => expr
+                };
+                match compile_node(&expr, env) {
+                    Err(e) => { return Err(e); }
+                    Ok(_) => {}
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let slots = match env.new_top_tmps(pieces.len() as u8) {
+        Some(slots) if pieces.len() <= 0xFF => slots,
+        _ => { return Err(frame_error(node)); }
+    };
+    for (i, piece) in pieces.iter().enumerate() {
+        match piece {
+            StringPiece::Text(text) => emit_constant(text.clone(), slots[i], env),
+            StringPiece::Expr(source, offset) => {
+                let expr = match parse_expression(node, source, *offset) {
+                    Err(e) => { return Err(e); }
+                    Ok(expr) => expr
+                };
+                match compile_in_slot(&expr, Some(slots[i]), env) {
+                    Err(e) => { return Err(e); }
+                    Ok(_) => {}
+                }
+                if !env.emit_runtime_call(IMG_BUILTIN_NAME, &[slots[i]], slots[i]) {
+                    return Err(frame_error(node));
+                }
+            }
+        }
+    }
+
+    env.add_instruction(IRInstruction::ABC(IRInstABC::new(
+        CAT,
+        IRArg::Slot(res_slot.unwrap()),
+        IRArg::Slot(slots[0]),
+        IRArg::Slot(slots[pieces.len() - 1])
+    )));
+    env.free_tmps(slots);
+    Ok(())
+}
+
+/// Split the content of a string literal in constant texts and interpolated expressions, an
+/// expression goes from "\{" to the matching closing brace
+/// The escape sequences are kept in the texts, so an escaped backslash followed by a brace
+/// (Ex: "\\{x}") is not an interpolation
+/// Return none if an interpolation is not terminated
+fn split_interpolation(content: &str) -> Option<Vec<StringPiece>> {
+    let mut res = Vec::new();
+    let mut text = String::new();
+    let mut chars = content.chars().enumerate().peekable();
+    while let Some((_, c)) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            None => {
+                text.push(c);
+                continue;
+            }
+            Some((_, '{')) => (),
+            Some((_, escaped)) => {
+                text.push(c);
+                text.push(escaped);
+                continue;
+            }
+        }
+
+        // Read the expression source until the matching brace
+        let offset = match chars.peek() {
+            None => { return None; }
+            Some((offset, _)) => *offset
+        };
+        let mut source = String::new();
+        let mut depth = 1;
+        loop {
+            let next = match chars.next() {
+                None => { return None; }
+                Some((_, next)) => next
+            };
+            if next == '{' {
+                depth += 1;
+            } else if next == '}' {
+                depth -= 1;
+                if depth == 0 { break; }
+            }
+            source.push(next);
+        }
+        if !text.is_empty() {
+            res.push(StringPiece::Text(std::mem::take(&mut text)));
+        }
+        res.push(StringPiece::Expr(source, offset));
+    }
+    if !text.is_empty() || res.is_empty() {
+        res.push(StringPiece::Text(text));
+    }
+    Some(res)
+}
+
+/// Parse the source of an interpolated expression of the string literal node, the offset is
+/// the one of the source in the literal content
+/// The source is parsed in the context of the literal, placed at its position in the script so
+/// the locations of its nodes and of its syntax errors are the ones in the literal
+fn parse_expression(node: &Node, source: &str, offset: usize) -> Result<Node, LKQLError> {
+    // Put the source at its line and column, the tabulations before it are kept to expand
+    // them like in the script
+    let location = node.location();
+    let literal = node.text();
+    let mut buffer = "\n".repeat(location.line.saturating_sub(1) as usize);
+    buffer.push_str(&" ".repeat(location.column as usize));
+    for c in literal.chars().skip(1).take(offset) {
+        buffer.push(if c == '\t' { '\t' } else { ' ' });
+    }
+    buffer.push_str(source);
+
+    // Parse it in the context of the literal, each interpolation is its own unit
+    let ctx = match check_context(node.context()) {
+        Err(e) => { return Err(e); }
+        Ok(ctx) => ctx
+    };
+    let name = format!("<interpolation {}:{}:{}>", location.line, location.column, offset);
+    let unit = match unsafe { parse_lkql_buffer_in(ctx, &buffer, &name, lkql_grammar_rule_LKQL_GRAMMAR_RULE_EXPR_RULE) } {
+        Err(e) => { return Err(e); }
+        Ok(unit) => unit
+    };
+
+    // Report the first syntax error at its location in the literal
+    let res = unsafe { Node::unit_root(unit) };
+    if unsafe { lkql_unit_diagnostic_count(unit) } > 0 || res.is_null() {
+        let mut diagnostic = lkql_diagnostic {
+            sloc_range: lkql_source_location_range {
+                start: lkql_source_location { line: 0, column: 0 },
+                end: lkql_source_location { line: 0, column: 0 }
+            },
+            message: lkql_text { chars: null_mut(), length: 0, is_allocated: 0 }
+        };
+        let error_location = if unsafe { lkql_unit_diagnostic(unit, 0, &mut diagnostic) } != 0 {
+            SourceLocation { line: diagnostic.sloc_range.start.line, column: diagnostic.sloc_range.start.column }
+        } else {
+            location
+        };
+        return Err(LKQLError::new_located(
+            E_INVALID_LITERAL,
+            format!("Invalid interpolated expression : {}", source),
+            error_location
+        ));
+    }
+    Ok(res)
+}
+
+
+// --- Tests of the string literals
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::E_UNDECLARED_VAR;
+    use crate::lkqlc::test_utils::{compile, compile_and_run, compile_error, main_prototype};
+
+    /// The interpolations are found after the escape sequences, an escaped backslash doesn't
+    /// start one
+    #[test]
+    fn split_escapes() {
+        let text = |value: &str| StringPiece::Text(String::from(value));
+        let expr = |value: &str, offset: usize| StringPiece::Expr(String::from(value), offset);
+        assert_eq!(split_interpolation("\\\\{x}"), Some(vec![text("\\\\{x}")]));
+        assert_eq!(split_interpolation("\\\"\\{x}"), Some(vec![text("\\\""), expr("x", 4)]));
+        assert_eq!(split_interpolation("a\\{f({})}b"), Some(vec![text("a"), expr("f({})", 3), text("b")]));
+        assert_eq!(split_interpolation("\\\\\\{x}"), Some(vec![text("\\\\"), expr("x", 4)]));
+        assert_eq!(split_interpolation(""), Some(vec![text("")]));
+        assert_eq!(split_interpolation("\\{x"), None);
+    }
+
+    /// The interpolated values are concatenated, a string without interpolation is a constant
+    #[test]
+    fn interpolation() {
+        compile_and_run("val x = 40\nassert(\"a\\{x + 2}b\" == \"a42b\")\nassert(\"\\{x}\\{x}\" == \"4040\")");
+        let (env, _) = compile("val s = \"\\\\{x}\"");
+        assert!(!main_prototype(&env).op_codes().contains(&CAT), "{}", main_prototype(&env).disassemble());
+    }
+
+    /// The errors in an interpolated expression are located in the literal
+    #[test]
+    fn interpolation_errors() {
+        let e = compile_error("val x = 1\nval s = \"a\\{y}\"");
+        assert_eq!(e.code, E_UNDECLARED_VAR);
+        assert_eq!(e.location.map(|location| (location.line, location.column)), Some((2, 13)));
+
+        let e = compile_error("val x = 1\nval s = \"a\\{x +}\"");
+        assert_eq!(e.code, E_INVALID_LITERAL);
+        let location = e.location.unwrap();
+        assert_eq!(location.line, 2);
+        assert!(location.column >= 13, "{}", location.column);
+    }
+}
@@ -2,27 +2,76 @@
 Functions for the top level list node
 */
 
-use std::os::raw::c_uint;
 use crate::errors::LKQLError;
-use crate::lkql_wrapper::{lkql_base_entity, lkql_node_child, lkql_node_children_count};
+use crate::lkql_wrapper::*;
+use crate::lkqlc::{compile_in_tmp, compile_node};
 use crate::lkqlc::env::CompilationEnv;
-use crate::lkqlc::{compile_node, new_node};
+use crate::lkqlc::node::Node;
 
 
 /// Compile a top level list node
-pub unsafe fn compile(node: &mut lkql_base_entity, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+/// An empty list (empty or commented out script) compiles nothing, the main prototype then only
+/// contains the final return and is still a valid chunk
+/// The absent and ghost children are skipped
+/// When the main chunk returns the last expression (Ex: for the REPL), the last child is kept in
+/// a slot returned at the end of the chunk, unless it is a declaration
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let children: Vec<Node> = node.children().into_iter().filter(|child| !child.is_trivia()).collect();
+    let returned_index = if env.returns_last_expr() && children.last().map_or(false, |child| !is_declaration(child)) {
+        Some(children.len() - 1)
+    } else {
+        None
+    };
+
     // Compile all children
-    let children_count = lkql_node_children_count(node);
-    let mut i: c_uint = 0;
-    while i < children_count {
-        let mut child = new_node();
-        lkql_node_child(node, i, &mut child);
-        match compile_node(&mut child, env) {
+    for (i, child) in children.iter().enumerate() {
+        if Some(i) == returned_index {
+            let (_, value_slot) = match compile_in_tmp(child, env) {
+                Err(e) => { return Err(e); }
+                Ok(res) => res
+            };
+            env.set_return_slot(Some(value_slot));
+            continue;
+        }
+        match compile_node(child, env) {
             Err(e) => { return Err(e); }
             Ok(_) => {}
         }
-        i += 1;
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Get if the top level node is a declaration, which has no value to return
+fn is_declaration(node: &Node) -> bool {
+    node.kind() == lkql_node_kind_enum_lkql_val_decl || node.kind() == lkql_node_kind_enum_lkql_fun_decl
+}
+
+
+// --- Tests of the top level list
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::bc::RET0;
+    use crate::lkqlc::test_utils::{compile_and_run, main_prototype};
+
+    /// An empty or commented out script is a chunk which only returns
+    #[test]
+    fn empty_script() {
+        for source in ["", "\n\n", "# Nothing to do\n"] {
+            let env = compile_and_run(source);
+            assert_eq!(env.get_program().prototypes.len(), 1);
+            let main = main_prototype(&env);
+            assert_eq!(main.op_codes(), vec![RET0], "{}", main.disassemble());
+        }
+    }
+
+    /// The comments between and inside the declarations are not compiled
+    #[test]
+    fn commented_script() {
+        compile_and_run(
+            "# The increment\nfun inc(x) = {\n    # Inline comment in a block\n    val y = x + 1; # After a value\n    y\n}\n\n\
+             # Check it\nassert(inc(1) == 2) # Trailing comment\n# Last line"
+        );
+    }
+}
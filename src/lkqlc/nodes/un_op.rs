@@ -0,0 +1,135 @@
+/*
+Functions for the unary operations (-, +, not)
+*/
+
+use crate::errors::{E_UNKNOWN_OPERATOR, LKQLError};
+use crate::lkql_wrapper::*;
+use crate::lkqlc::bc::{KPRI, NOT, UNM};
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction, Primitive};
+use crate::lkqlc;
+use crate::lkqlc::node::Node;
+use crate::lkqlc::{compile_in_target, compile_node, compile_value_branch};
+use crate::lkqlc::nodes::integer_literal;
+
+
+/// Compile a unary operation
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Get the operator and the operand
+    let op = node.field(lkql_un_op_f_op);
+    let operand = node.field(lkql_un_op_f_operand);
+    let op_kind = op.kind();
+
+    // If the result is not needed, just compile the operand for its side effects
+    let res_slot = env.get_expr_slot();
+    if res_slot.is_none() {
+        return compile_node(&operand, env);
+    }
+
+    match op_kind {
+        lkql_node_kind_enum_lkql_op_plus => compile_node(&operand, env),
+        lkql_node_kind_enum_lkql_op_minus => compile_minus(&operand, res_slot.unwrap(), env),
+        lkql_node_kind_enum_lkql_op_not => compile_not(&operand, res_slot.unwrap(), env),
+        _ => Err(LKQLError::new_located(
+            E_UNKNOWN_OPERATOR,
+            String::from("Unknown unary operator"),
+            node.location()
+        ))
+    }
+}
+
+/// Compile a unary operation as a condition, a "not" just swaps the branch labels
+pub fn compile_branch(node: &Node, true_label: u64, false_label: u64, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let op = node.field(lkql_un_op_f_op);
+    if op.kind() != lkql_node_kind_enum_lkql_op_not {
+        return compile_value_branch(node, true_label, false_label, env);
+    }
+
+    let operand = node.field(lkql_un_op_f_operand);
+    lkqlc::compile_branch(&operand, false_label, true_label, env)
+}
+
+/// Compile the arithmetic negation, a negated integer literal is a constant
+fn compile_minus(operand: &Node, res_slot: u8, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Fold the negation of an integer literal
+    if operand.kind() == lkql_node_kind_enum_lkql_integer_literal {
+        let value = match integer_literal::literal_value(operand) {
+            Err(e) => { return Err(e); }
+            Ok(value) => value
+        };
+        match value.checked_neg() {
+            Some(negated) => {
+                integer_literal::load_integer(negated, res_slot, env);
+                return Ok(());
+            }
+            None => ()
+        }
+    }
+
+    // Else negate the value at runtime
+    emit_unary(operand, UNM, res_slot, env)
+}
+
+/// Compile the logical negation, the negation of a literal is a constant
+fn compile_not(operand: &Node, res_slot: u8, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Fold the negation of a scalar literal, only null and false are falsy
+    let operand_kind = operand.kind();
+    if operand.primitive().is_some() ||
+       operand_kind == lkql_node_kind_enum_lkql_integer_literal ||
+       operand_kind == lkql_node_kind_enum_lkql_string_literal {
+        let negated = match operand.primitive() {
+            Some(Primitive::Nil) | Some(Primitive::False) => Primitive::True,
+            _ => Primitive::False
+        };
+        env.add_instruction(IRInstruction::AD(IRInstAD::new(
+            KPRI,
+            IRArg::Slot(res_slot),
+            IRArg::Primitive(negated)
+        )));
+        return Ok(());
+    }
+
+    // Else negate the value at runtime
+    emit_unary(operand, NOT, res_slot, env)
+}
+
+/// Compile the operand, in the result slot when possible, and emit the unary instruction on it
+fn emit_unary(operand: &Node, op_code: u8, res_slot: u8, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let (tmp, value_slot) = match compile_in_target(operand, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+    env.add_instruction(IRInstruction::AD(IRInstAD::new(
+        op_code,
+        IRArg::Slot(res_slot),
+        IRArg::Slot(value_slot)
+    )));
+    if tmp.is_some() {
+        env.free_tmp(tmp.unwrap());
+    }
+    Ok(())
+}
+
+
+// --- Tests of the unary operations
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::bc::{KNUM, KSHORT, NOT, UNM};
+    use crate::lkqlc::test_utils::{compile_and_run, main_prototype};
+
+    /// The negations of literals are constants, small integers are in KSHORT and the other ones
+    /// in the numeric constants, while the negation of a variable is computed at runtime
+    #[test]
+    fn fold_negated_literals() {
+        let env = compile_and_run("val a = -5\nval b = -100000\nval c = not true\nval d = not null\nassert(a + 5 == 0)\nassert(b + 100000 == 0)\nassert(c == false)\nassert(d == true)");
+        let main = main_prototype(&env);
+        let op_codes = main.op_codes();
+        assert!(op_codes.contains(&KSHORT) && op_codes.contains(&KNUM), "{}", main.disassemble());
+        assert!(!op_codes.contains(&UNM) && !op_codes.contains(&NOT), "{}", main.disassemble());
+
+        let env = compile_and_run("val a = 5\nval b = -a\nval c = not b\nassert(b + 5 == 0)\nassert(c == false)");
+        let op_codes = main_prototype(&env).op_codes();
+        assert!(op_codes.contains(&UNM) && op_codes.contains(&NOT), "{}", main_prototype(&env).disassemble());
+    }
+}
@@ -0,0 +1,47 @@
+/*
+Functions for the unit literal
+*/
+
+use crate::errors::LKQLError;
+use crate::lkqlc::builtins::UNIT_GLOBAL_NAME;
+use crate::lkqlc::env::CompilationEnv;
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::fun_call::frame_error;
+
+
+/// Compile a unit literal by loading the unit sentinel value
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    let expr_slot = env.get_expr_slot();
+    if expr_slot.is_some() {
+        return load_unit(node, expr_slot.unwrap(), env);
+    }
+
+    Ok(())
+}
+
+/// Load the unit sentinel value in the slot, it is also the value of the constructs without one
+/// The node is the one the error is reported on if there is no slot left to read the value
+pub fn load_unit(node: &Node, slot: u8, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    if !env.emit_global_get(slot, UNIT_GLOBAL_NAME) {
+        return Err(frame_error(node));
+    }
+    Ok(())
+}
+
+
+// --- Tests of the unit literal
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::test_utils::compile_and_run;
+
+    /// The unit value is equal to itself only, it is not null
+    #[test]
+    fn unit_is_not_null() {
+        compile_and_run(
+            "fun is_unit(x) = x == ()\nfun is_null(x) = x == null\n\
+             assert(is_unit(()))\nassert(not is_unit(null))\n\
+             assert(is_null(null))\nassert(not is_null(()))\nassert(() != null)"
+        );
+    }
+}
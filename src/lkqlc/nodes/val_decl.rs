@@ -0,0 +1,162 @@
+/*
+Functions for the value declarations
+*/
+
+use crate::errors::LKQLError;
+use crate::lkql_wrapper::*;
+use crate::lkqlc::bc::MOV;
+use crate::lkqlc::env::{CompilationEnv, LocalResult};
+use crate::lkqlc::ir::{IRArg, IRInstAD, IRInstruction};
+use crate::lkqlc::node::Node;
+use crate::lkqlc::nodes::decl_annotation;
+use crate::lkqlc::nodes::fun_call::frame_error;
+use crate::lkqlc::{check_redeclaration, compile_in_tmp};
+
+
+/// Compile a value declaration as a new local variable, a value declared at the top level is a
+/// global
+pub fn compile(node: &Node, env: &mut CompilationEnv) -> Result<(), LKQLError> {
+    // Get the declared name and the value
+    let identifier = node.field(lkql_val_decl_f_identifier);
+    let name = identifier.text();
+    let value = node.field(lkql_val_decl_f_value);
+    decl_annotation::record(node, &name, env);
+
+    // Compile the value before declaring the local, so it cannot reference itself
+    let res_slot = env.get_expr_slot();
+    let (tmp, value_slot) = match compile_in_tmp(&value, env) {
+        Err(e) => { return Err(e); }
+        Ok(res) => res
+    };
+    match check_redeclaration(&name, &identifier, env) {
+        Err(e) => { return Err(e); }
+        Ok(_) => {}
+    }
+
+    // Set the global before freeing the temporary slot, the sandbox table may need another one
+    if env.is_top_level() {
+        env.add_declared_global(name.clone());
+        if !env.emit_global_set(value_slot, &name) {
+            return Err(frame_error(node));
+        }
+        env.free_tmp(tmp);
+        env.set_expr_slot(res_slot);
+        return Ok(());
+    }
+    env.free_tmp(tmp);
+
+    // The values are immutable, so the value of another local is aliased instead of copied
+    if value_slot != tmp && is_local_read(&value, value_slot, env) {
+        env.add_local_alias(name, value_slot);
+        env.set_expr_slot(res_slot);
+        return Ok(());
+    }
+
+    // Declare the local and put the value in it, the local often gets the freed temporary slot
+    // where the value is already computed
+    let local = match env.add_local(name) {
+        Some(local) => local,
+        None => { return Err(frame_error(node)); }
+    };
+    match local {
+        LocalResult::Slot(slot) if slot == value_slot => (),
+        LocalResult::Slot(slot) => {
+            env.add_instruction(IRInstruction::AD(IRInstAD::new(
+                MOV,
+                IRArg::Slot(slot),
+                IRArg::Slot(value_slot)
+            )));
+        }
+        LocalResult::Name(global_name) => {
+            if !env.emit_global_set(value_slot, &global_name) {
+                return Err(frame_error(node));
+            }
+        }
+        LocalResult::NotFound => ()
+    }
+
+    // A declaration has no value
+    env.set_expr_slot(res_slot);
+    Ok(())
+}
+
+/// Get if the value is an identifier read in place from the slot of a local of the function
+fn is_local_read(value: &Node, value_slot: u8, env: &CompilationEnv) -> bool {
+    if value.kind() != lkql_node_kind_enum_lkql_identifier {
+        return false;
+    }
+    match env.get_local(&value.text()) {
+        LocalResult::Slot(slot) => slot == value_slot,
+        _ => false
+    }
+}
+
+
+// --- Tests of the value declarations
+
+#[cfg(test)]
+mod tests {
+    use crate::lkqlc::bc::{GGET, GSET, MOV, UGET};
+    use crate::lkqlc::test_utils::{compile_and_run, main_prototype};
+
+    /// A top level value read by a function declared after it is set and read as a global,
+    /// not captured as an upvalue of the main chunk
+    #[test]
+    fn top_level_value_is_global() {
+        let env = compile_and_run("val base = 40\nfun add_base(x) = x + base\nassert(add_base(2) == 42)");
+        let main = main_prototype(&env);
+        assert!(main.op_codes().contains(&GSET), "{}", main.disassemble());
+        let function = &env.get_program().prototypes[0];
+        assert!(function.op_codes().contains(&GGET), "{}", function.disassemble());
+        assert!(!function.op_codes().contains(&UGET), "{}", function.disassemble());
+    }
+
+    /// A value of another local is an alias of its slot, even through a chain of aliases
+    #[test]
+    fn alias_local() {
+        let env = compile_and_run("fun f(x) = { val y = x; val z = y; z + y }\nassert(f(2) == 4)");
+        let function = &env.get_program().prototypes[0];
+        assert!(!function.op_codes().contains(&MOV), "{}", function.disassemble());
+        assert!(function.frame_size <= 2, "{}", function.disassemble());
+    }
+
+    /// A value declared twice in the same scope is a warning which keeps the last value, or an
+    /// error located on the second declaration in strict mode
+    #[test]
+    fn redeclaration() {
+        use crate::errors::{E_REDECLARATION, W_REDECLARATION};
+        use crate::lkqlc::compile_lkql_buffer_with_env;
+        use crate::lkqlc::env::CompilationEnv;
+        use crate::lkqlc::test_utils::test_options;
+
+        let source = "fun f() = {\n    val x = 1;\n    val x = 2;\n    x\n}\nassert(f() == 2)";
+        let env = compile_and_run(source);
+        let warnings = env.get_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code, W_REDECLARATION);
+        assert_eq!(warnings[0].location.map(|location| location.line), Some(3));
+
+        let mut options = test_options();
+        options.strict = true;
+        let mut env = CompilationEnv::new(options);
+        match compile_lkql_buffer_with_env(source, "unit test", &mut env) {
+            Err(e) => {
+                assert_eq!(e.code, E_REDECLARATION, "{}", e);
+                assert_eq!(e.location.map(|location| location.line), Some(3));
+            }
+            Ok(_) => panic!("The redeclaration is expected to be an error in strict mode")
+        }
+    }
+
+    /// The value of an operation is computed in the slot of the declared local, without a move
+    #[test]
+    fn operation_in_local_slot() {
+        let env = compile_and_run(
+            "fun f(y, z) = { val x = y + z; x * 2 }\nfun g(a) = { val n = -a; val b = not (n < 0); b }\n\
+             assert(f(1, 2) == 6)\nassert(g(-1))"
+        );
+        for function in &env.get_program().prototypes[..2] {
+            assert!(!function.op_codes().contains(&MOV), "{}", function.disassemble());
+        }
+    }
+}
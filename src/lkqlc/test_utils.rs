@@ -0,0 +1,92 @@
+/*
+This module contains the helpers of the unit tests which compile LKQL snippets
+The snippets verify their own results with the Lua "assert" function, so a wrong result is a
+runtime error of the compiled chunk
+*/
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::errors::LKQLError;
+use crate::lkqlc::bc::Prototype;
+use crate::lkqlc::compile_lkql_buffer_with_env;
+use crate::lkqlc::env::{CompilationEnv, CompilationOptions};
+use crate::luajit;
+
+
+// --- Compilation helpers
+
+// The Lua function the snippets use to verify their results
+pub const ASSERT_GLOBAL_NAME: &str = "assert";
+
+/// Get the default compilation options with the assert function declared
+pub fn test_options() -> CompilationOptions {
+    let mut options = CompilationOptions::new();
+    options.host_globals.push(String::from(ASSERT_GLOBAL_NAME));
+    options
+}
+
+/// Compile the snippet with the given options, panic on a compilation error
+pub fn compile_with(source: &str, options: CompilationOptions) -> (CompilationEnv, Vec<u8>) {
+    let mut env = CompilationEnv::new(options);
+    match compile_lkql_buffer_with_env(source, "unit test", &mut env) {
+        Err(e) => panic!("Cannot compile {:?} : {}", source, e),
+        Ok(bytecode) => (env, bytecode)
+    }
+}
+
+/// Compile the snippet with the test options, panic on a compilation error
+pub fn compile(source: &str) -> (CompilationEnv, Vec<u8>) {
+    compile_with(source, test_options())
+}
+
+/// Compile the snippet with the test options and get its compilation error
+pub fn compile_error(source: &str) -> LKQLError {
+    let mut env = CompilationEnv::new(test_options());
+    match compile_lkql_buffer_with_env(source, "unit test", &mut env) {
+        Err(e) => e,
+        Ok(_) => panic!("The compilation of {:?} is expected to fail", source)
+    }
+}
+
+/// Get the main prototype of the compiled program, the child prototypes are before it
+pub fn main_prototype(env: &CompilationEnv) -> &Prototype {
+    env.get_program().prototypes.last().unwrap()
+}
+
+
+// --- Execution helpers
+
+/// Run the bytecode in a fresh lua state and return the runtime error if any
+pub fn run(bytecode: &Vec<u8>) -> Result<(), String> {
+    let lua_state = luajit::init_env();
+    let res = luajit::try_run_lua_bytecode(&lua_state, bytecode, "unit test");
+    luajit::close_env(&lua_state);
+    res
+}
+
+/// Compile and run the snippet, panic on a compilation or runtime error
+pub fn compile_and_run(source: &str) -> CompilationEnv {
+    let (env, bytecode) = compile(source);
+    if let Err(message) = run(&bytecode) {
+        panic!("Runtime error in {:?} : {}\n{}", source, message, env.get_program().disassemble());
+    }
+    env
+}
+
+// The number of the analysed unit files written by the tests, each run has its own file since
+// the tests run in parallel
+static UNIT_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Run the bytecode in a fresh lua state with the given LKQL source as the analysed unit, and
+/// return the runtime error if any
+pub fn run_on_unit(source: &str, bytecode: &Vec<u8>) -> Result<(), String> {
+    let index = UNIT_FILE_COUNT.fetch_add(1, Ordering::Relaxed);
+    let file = std::env::temp_dir().join(format!("lkql_jit_unit_{}_{}.lkql", std::process::id(), index));
+    std::fs::write(&file, source).unwrap();
+
+    let mut lua_state = luajit::init_env();
+    let res = luajit::set_global_unit(&mut lua_state, &file, &None, false)
+        .and_then(|_| luajit::try_run_lua_bytecode(&lua_state, bytecode, "unit test"));
+    luajit::close_env(&lua_state);
+    let _ = std::fs::remove_file(&file);
+    res
+}
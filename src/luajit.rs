@@ -5,9 +5,14 @@ All luajit calls should be done here
 
 mod lkql_lib;
 
-use std::ffi::{c_void, CString};
+use std::ffi::{c_void, CStr, CString};
+use std::ptr::null_mut;
 use std::os::raw::{c_char, c_int};
-use crate::luajit::lkql_lib::lkql_openlib;
+use std::path::PathBuf;
+use crate::lkql_wrapper::{lkql_analysis_context, lkql_context_decref, lkql_get_analysis_unit_from_file, lkql_grammar_rule_LKQL_GRAMMAR_RULE_MAIN_RULE_RULE};
+use crate::lkqlc::{check_unit, new_analysis_context};
+use crate::luajit::lkql_lib::{lkql_open_sandbox, lkql_openlib, lkql_register_host_function, lkql_result_image, lkql_set_files, lkql_set_unit};
+pub use crate::luajit::lkql_lib::{start_print_capture, stop_print_capture, HostFunction, HostValue};
 
 
 // --- Define the c function signatures
@@ -18,6 +23,9 @@ extern "C" {
     fn luaL_loadfile(state: *mut c_void, file: *const c_char) -> c_int;
     fn luaL_loadbuffer(state: *mut c_void, buffer: *const c_char, size: usize, name: *const c_char) -> c_int;
     fn lua_call(state: *mut c_void, nargs: c_int, nresults: c_int) -> c_int;
+    fn lua_pcall(state: *mut c_void, nargs: c_int, nresults: c_int, errfunc: c_int) -> c_int;
+    fn lua_tolstring(state: *mut c_void, index: c_int, len: *mut usize) -> *const c_char;
+    fn lua_settop(state: *mut c_void, index: c_int);
     fn lua_close(state: *mut c_void);
 }
 
@@ -26,6 +34,8 @@ extern "C" {
 
 pub struct LuaState {
     state: *mut c_void,
+    host_functions: Vec<Box<HostFunction>>, // The registered host functions, the state points to them
+    analysis_context: lkql_analysis_context, // The context of the analysed unit, null before it is parsed
 }
 
 
@@ -40,14 +50,80 @@ pub fn init_env() -> LuaState {
         lkql_openlib(state);
         LuaState {
             state,
+            host_functions: Vec::new(),
+            analysis_context: null_mut(),
         }
     }
 }
 
-/// Close the lua environment
+/// Set the sandbox table as a global of the lua environment, the bytecode compiled in sandbox
+/// mode only sees the LKQL library through it
+pub fn set_global_sandbox(l: &LuaState) {
+    unsafe {
+        lkql_open_sandbox(l.state);
+    }
+}
+
+/// Set the paths of the analysed files as the "files" global, an array of absolute paths
+/// The sandbox table must already be set if the bytecode runs in sandbox mode
+pub fn set_global_files(l: &LuaState, files: &Vec<PathBuf>, sandbox: bool) {
+    let paths: Vec<String> = files.iter()
+        .map(|file| file.canonicalize().unwrap_or(file.clone()).to_string_lossy().into_owned())
+        .collect();
+    unsafe {
+        lkql_set_files(l.state, &paths, sandbox);
+    }
+}
+
+/// Parse the analysed file and set its unit and root node as the "unit" and "root" globals
+/// The sandbox table must already be set if the bytecode runs in sandbox mode
+/// Only the LKQL langkit library is linked, so the file is parsed as an LKQL unit. The nodes
+/// are valid as long as the analysis context, the state keeps it until it is closed
+pub fn set_global_unit(l: &mut LuaState, file: &PathBuf, charset: &Option<String>, sandbox: bool) -> Result<(), String> {
+    let file_c = CString::new(file.canonicalize().unwrap_or(file.clone()).to_string_lossy().into_owned()).unwrap();
+    let charset_c = charset.as_ref().map(|charset| CString::new(charset.as_str()).unwrap());
+    unsafe {
+        if l.analysis_context.is_null() {
+            l.analysis_context = match new_analysis_context() {
+                Err(e) => { return Err(e.message); }
+                Ok(ctx) => ctx
+            };
+        }
+        let unit = match check_unit(lkql_get_analysis_unit_from_file(
+            l.analysis_context,
+            file_c.as_ptr(),
+            charset_c.as_ref().map_or(std::ptr::null(), |charset| charset.as_ptr()),
+            0,
+            lkql_grammar_rule_LKQL_GRAMMAR_RULE_MAIN_RULE_RULE
+        )) {
+            Err(e) => { return Err(e.message); }
+            Ok(unit) => unit
+        };
+        lkql_set_unit(l.state, unit, sandbox);
+    }
+    Ok(())
+}
+
+/// Register a Rust function as a global callable from the scripts, also in the sandbox table if
+/// the bytecode runs in sandbox mode, which must already be set
+/// The compiler must know the name too : declare it with CompilationEnv::add_host_global or in
+/// the host globals of the compilation options
+pub fn register_host_function<F>(l: &mut LuaState, name: &str, sandbox: bool, function: F)
+    where F: Fn(&[HostValue]) -> Result<HostValue, String> + 'static {
+    let function: Box<HostFunction> = Box::new(Box::new(function));
+    unsafe {
+        lkql_register_host_function(l.state, name, &*function, sandbox);
+    }
+    l.host_functions.push(function);
+}
+
+/// Close the lua environment, then release the analysis context that its nodes come from
 pub fn close_env(l: &LuaState) {
     unsafe {
         lua_close(l.state);
+        if !l.analysis_context.is_null() {
+            lkql_context_decref(l.analysis_context);
+        }
     }
 }
 
@@ -85,6 +161,30 @@ pub fn run_lua_buffer(l: &LuaState, buffer: &str, name: &str) {
     }
 }
 
+/// Load and call the bytecode buffer in protected mode, its results are left on the stack
+/// The error message is removed from the stack and returned
+unsafe fn protected_run(l: &LuaState, bytecode: &Vec<u8>, name: &str, result_count: c_int) -> Result<(), String> {
+    let buffer_c = bytecode.as_ptr() as *const c_char;
+    let name_c = CString::new(name).unwrap();
+    let mut status = luaL_loadbuffer(l.state, buffer_c, bytecode.len(), name_c.as_ptr());
+    if status == 0 {
+        status = lua_pcall(l.state, 0, result_count, 0);
+    }
+    if status == 0 {
+        return Ok(());
+    }
+
+    // Get the error message on the top of the stack
+    let message_c = lua_tolstring(l.state, -1, null_mut());
+    let message = if message_c.is_null() {
+        String::from("Unknown lua error")
+    } else {
+        CStr::from_ptr(message_c).to_string_lossy().into_owned()
+    };
+    lua_settop(l.state, -2);
+    Err(message)
+}
+
 /// Function to run a lua bytecode buffer
 pub fn run_lua_bytecode(l: &LuaState, bytecode: &Vec<u8>, name: &str) {
     let buffer_c = bytecode.as_ptr() as *const c_char;
@@ -101,3 +201,105 @@ pub fn run_lua_bytecode(l: &LuaState, bytecode: &Vec<u8>, name: &str) {
         }
     }
 }
+
+/// Function to run a lua source buffer in protected mode, LuaJIT loads the source and the
+/// bytecode with the same function
+pub fn try_run_lua_source(l: &LuaState, source: &str, name: &str) -> Result<(), String> {
+    try_run_lua_bytecode(l, &Vec::from(source), name)
+}
+
+/// Function to run a lua bytecode buffer in protected mode, the loading or execution error
+/// message is returned instead of panicking
+pub fn try_run_lua_bytecode(l: &LuaState, bytecode: &Vec<u8>, name: &str) -> Result<(), String> {
+    unsafe { protected_run(l, bytecode, name, 0) }
+}
+
+/// Function to run a lua bytecode buffer in protected mode and get the image of its result, as
+/// the "img" builtin shows it, none if the chunk returns nothing or nil
+pub fn try_eval_lua_bytecode(l: &LuaState, bytecode: &Vec<u8>, name: &str) -> Result<Option<String>, String> {
+    unsafe {
+        match protected_run(l, bytecode, name, 1) {
+            Err(message) => { return Err(message); }
+            Ok(_) => {}
+        }
+        let image = lkql_result_image(l.state, -1);
+        lua_settop(l.state, -2);
+        Ok(image)
+    }
+}
+
+
+// --- Tests of the lua environment
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lkqlc::test_utils::{compile, run_on_unit};
+
+    /// A script reads the kind of the root node of the analysed unit, and the unit is shown as
+    /// such instead of the unit value
+    #[test]
+    fn analysed_unit_globals() {
+        let (_, bytecode) = compile("assert(node_property(root, \"kind_name\") == \"TopLevelList\")\nassert(img(unit) == \"<analysis unit>\")");
+
+        assert_eq!(run_on_unit("val x = 1", &bytecode), Ok(()));
+    }
+
+    /// A script reads the absolute paths of the analysed files, in sandbox mode too where the
+    /// results are verified by a host function since "assert" is not in the sandbox
+    #[test]
+    fn analysed_files_global() {
+        use crate::lkqlc::test_utils::{compile_with, test_options};
+
+        let file = std::env::temp_dir().join("lkql_jit_analysed_file.adb");
+        std::fs::write(&file, "procedure Main is begin null; end Main;").unwrap();
+        let path = file.canonicalize().unwrap().to_string_lossy().replace('\\', "\\\\");
+        let source = format!("check(length(files) == 2)\ncheck(\"{}\" in files)", path);
+
+        for sandbox in [false, true] {
+            let mut options = test_options();
+            options.sandbox = sandbox;
+            options.host_globals.push(String::from("check"));
+            let (_, bytecode) = compile_with(&source, options);
+            let mut lua_state = init_env();
+            if sandbox {
+                set_global_sandbox(&lua_state);
+            }
+            set_global_files(&lua_state, &vec![file.clone(), PathBuf::from("other.adb")], sandbox);
+            register_host_function(&mut lua_state, "check", sandbox, |args| match args {
+                [HostValue::Bool(true)] => Ok(HostValue::Null),
+                _ => Err(String::from("Check failed"))
+            });
+            let res = try_run_lua_bytecode(&lua_state, &bytecode, "analysed files");
+            close_env(&lua_state);
+            assert_eq!(res, Ok(()), "In sandbox mode : {}", sandbox);
+        }
+        let _ = std::fs::remove_file(&file);
+    }
+
+    /// The VM grows the lua stack for the frame of the chunk, the values in its last slots
+    /// don't overflow it
+    #[test]
+    fn wide_frame() {
+        use crate::lkqlc::bc::{GGET, KSTR, CALL, RET0};
+        use crate::lkqlc::builder::ProgramBuilder;
+        use crate::lkqlc::env::MAX_SLOTS;
+
+        let frame_size = MAX_SLOTS as u8;
+        let mut builder = ProgramBuilder::new();
+        let error_index = builder.add_string("error");
+        let message_index = builder.add_string("last slots");
+        builder
+            .set_frame_size(frame_size)
+            .emit_ad(GGET, frame_size - 2, error_index)
+            .emit_ad(KSTR, frame_size - 1, message_index)
+            .emit_abc(CALL, frame_size - 2, 1, 2)
+            .emit_ad(RET0, 0, 1);
+        let bytecode = builder.finish().unwrap_or_else(|e| panic!("{}", e)).encode();
+
+        let lua_state = init_env();
+        let res = try_run_lua_bytecode(&lua_state, &bytecode, "wide frame");
+        close_env(&lua_state);
+        assert!(res.unwrap_err().ends_with("last slots"));
+    }
+}
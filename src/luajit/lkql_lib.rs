@@ -3,45 +3,693 @@ This module contains all functions and utils to register the LKQL standard libra
 in the lua context
 */
 
-use std::ffi::{c_void, CString};
+use std::cell::RefCell;
+use std::ffi::{c_void, CStr, CString};
+use std::io::Write;
+use std::mem::size_of;
 use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr::null_mut;
+use crate::lkql_wrapper::{lkql_analysis_unit, lkql_base_entity, lkql_lkql_node_next_sibling, lkql_lkql_node_parent, lkql_lkql_node_previous_sibling};
+use crate::lkqlc::node::Node;
+use crate::lkqlc::builtins::{BuiltinImplementation, BUILTINS, ANALYSIS_UNIT_GLOBAL_NAME, CHECK_INT_GLOBAL_NAME, DESCENDANTS_GLOBAL_NAME, ERROR_GLOBAL_NAME, FILES_GLOBAL_NAME, IMG_BUILTIN_NAME, IS_LIST_GLOBAL_NAME, NEXT_GLOBAL_NAME, NODE_PROPERTY_GLOBAL_NAME, PRINT_BUILTIN_NAME, PRINT_NO_NL_BUILTIN_NAME, ROOT_GLOBAL_NAME, SANDBOX_GLOBAL_NAME, UNIT_GLOBAL_NAME};
 
 
 // --- Define the c function signatures
 
 extern "C" {
-    fn lua_pushcclosure(l: *mut c_void, c_fn: unsafe extern "C" fn(*mut c_void) -> c_int, n: c_int);
+    fn lua_pushcclosure(l: *mut c_void, c_fn: LuaCFunction, n: c_int);
     fn lua_setfield(l: *mut c_void, index: c_int, key: *const c_char);
+    fn lua_getfield(l: *mut c_void, index: c_int, key: *const c_char);
+    fn lua_touserdata(l: *mut c_void, index: c_int) -> *mut c_void;
+    fn lua_tolstring(l: *mut c_void, index: c_int, len: *mut usize) -> *const c_char;
+    fn lua_newuserdata(l: *mut c_void, size: usize) -> *mut c_void;
+    fn lua_getmetatable(l: *mut c_void, index: c_int) -> c_int;
+    fn lua_setmetatable(l: *mut c_void, index: c_int) -> c_int;
+    fn lua_rawequal(l: *mut c_void, index1: c_int, index2: c_int) -> c_int;
+    fn luaL_newmetatable(l: *mut c_void, name: *const c_char) -> c_int;
+    fn lua_pushnil(l: *mut c_void);
+    fn lua_pushstring(l: *mut c_void, s: *const c_char);
+    fn lua_pushlightuserdata(l: *mut c_void, p: *mut c_void);
+    fn lua_createtable(l: *mut c_void, narr: c_int, nrec: c_int);
+    fn lua_gettop(l: *mut c_void) -> c_int;
+    fn lua_settop(l: *mut c_void, index: c_int);
+    fn lua_pushvalue(l: *mut c_void, index: c_int);
+    fn lua_tonumber(l: *mut c_void, index: c_int) -> f64;
+    fn lua_rawseti(l: *mut c_void, index: c_int, n: c_int);
+    fn lua_type(l: *mut c_void, index: c_int) -> c_int;
+    fn lua_toboolean(l: *mut c_void, index: c_int) -> c_int;
+    fn lua_pushboolean(l: *mut c_void, b: c_int);
+    fn lua_pushnumber(l: *mut c_void, n: f64);
+    fn luaL_loadbuffer(l: *mut c_void, buffer: *const c_char, size: usize, name: *const c_char) -> c_int;
+    fn lua_pcall(l: *mut c_void, nargs: c_int, nresults: c_int, errfunc: c_int) -> c_int;
 }
 
+// The error raising unwinds the stack up to the protected call, over the frames of the library
+// functions which must then be "C-unwind" too
+extern "C-unwind" {
+    fn lua_error(l: *mut c_void) -> c_int;
+}
+
+// The type of the library functions called by lua
+type LuaCFunction = unsafe extern "C-unwind" fn(*mut c_void) -> c_int;
+
+const LUA_REGISTRYINDEX: c_int = -10000;
+const LUA_GLOBALSINDEX: c_int = -10002;
+const LUA_FIRST_UPVALUE: c_int = LUA_GLOBALSINDEX - 1;
+
+// The lua value types
+const LUA_TNIL: c_int = 0;
+const LUA_TBOOLEAN: c_int = 1;
+const LUA_TLIGHTUSERDATA: c_int = 2;
+const LUA_TNUMBER: c_int = 3;
+const LUA_TSTRING: c_int = 4;
+const LUA_TTABLE: c_int = 5;
+const LUA_TFUNCTION: c_int = 6;
+const LUA_TUSERDATA: c_int = 7;
+
+// The name of the analysis unit metatable in the registry, the nodes have no metatable
+const UNIT_METATABLE_NAME: &str = "lkql_analysis_unit";
+
+// The doubles represent exactly all the integers up to 2^53
+const MAX_EXACT_INT: f64 = 9007199254740992.0;
+
+// The printed text goes in this buffer instead of the standard output while it is set
+thread_local! {
+    static PRINT_CAPTURE: RefCell<Option<String>> = RefCell::new(None);
+}
+
+
 // --- Global functions for lkql
 
-/// The LKQL printing function
+/// The LKQL printing function, show the image of its argument and a new line
 #[no_mangle]
-pub unsafe extern "C" fn lkql_print(l: *mut c_void) -> c_int {
-    println!("This is my LKQL printing funtions");
+pub unsafe extern "C-unwind" fn lkql_print(l: *mut c_void) -> c_int {
+    write_output(&format!("{}\n", value_image(l, 1)));
     0
 }
 
+/// The LKQL printing function without the new line, so several calls can fill one line
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lkql_print_no_nl(l: *mut c_void) -> c_int {
+    write_output(&value_image(l, 1));
+    0
+}
+
+/// The LKQL node property calling function, arguments are the node, the property name and the
+/// property arguments
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lkql_node_property(l: *mut c_void) -> c_int {
+    return_or_raise(l, node_property(l))
+}
+
+/// The query traversal function, return the array of the nodes of the subtree rooted at the
+/// argument node, in prefix order and including the root
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lkql_descendants(l: *mut c_void) -> c_int {
+    let root = match to_node(l, 1) {
+        None => { return raise_error(l, String::from("Queries expect a node as traversal root")); }
+        Some(root) => root
+    };
+
+    lua_createtable(l, 0, 0);
+    let mut count: c_int = 0;
+    push_descendants(l, &root, &mut count);
+    1
+}
+
+/// The integer guard of the checked integer mode, return its argument or raise an error if it
+/// is beyond the range where the doubles represent all the integers exactly
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lkql_check_int(l: *mut c_void) -> c_int {
+    let value = lua_tonumber(l, 1);
+    if value.abs() > MAX_EXACT_INT {
+        return raise_error(l, format!("Integer overflow, {} cannot be represented exactly", value));
+    }
+    lua_pushvalue(l, 1);
+    1
+}
+
+/// The error raising function of the compiled runtime checks, the message argument is the
+/// error value
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lkql_error(l: *mut c_void) -> c_int {
+    if lua_tolstring(l, 1, null_mut()).is_null() {
+        return raise_error(l, String::from("Runtime error without message"));
+    }
+    lua_settop(l, 1);
+    lua_error(l)
+}
+
+/// The list test of the list patterns, return true if the argument is a table, the nodes and
+/// the other values have no length to read
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lkql_is_list(l: *mut c_void) -> c_int {
+    lua_pushboolean(l, (lua_type(l, 1) == LUA_TTABLE) as c_int);
+    1
+}
+
+/// The LKQL image function, return the string representation of its argument
+/// The unit value is the light userdata and the nodes are shown with their kind
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lkql_img(l: *mut c_void) -> c_int {
+    match lua_type(l, 1) {
+        LUA_TNUMBER | LUA_TSTRING => {
+            // Lua converts the number in place
+            lua_tolstring(l, 1, null_mut());
+            lua_settop(l, 1);
+            1
+        }
+        _ => push_string(l, &value_image(l, 1))
+    }
+}
+
+
+// --- Node properties
+
+/// Call the node property named by the second argument with the next arguments
+unsafe fn node_property(l: *mut c_void) -> Result<c_int, String> {
+    let prop_name_c = lua_tolstring(l, 2, null_mut());
+    let node = match to_node(l, 1) {
+        Some(node) if !prop_name_c.is_null() => node,
+        _ => { return Err(String::from("node_property expects a node and a property name")); }
+    };
+    let prop_name = CStr::from_ptr(prop_name_c).to_string_lossy();
+    let property = match NODE_PROPERTIES.iter().find(|property| property.name == prop_name) {
+        None => { return Err(format!("Unknown node property {}", prop_name)); }
+        Some(property) => property
+    };
+
+    // The arguments are after the node and the property name
+    let arg_count = lua_gettop(l) - 2;
+    if arg_count != property.arg_count {
+        return Err(format!("The node property {} expects {} arguments, got {}", property.name, property.arg_count, arg_count));
+    }
+    (property.call)(l, &node, 3)
+}
+
+/// The source text of the node
+unsafe fn property_text(l: *mut c_void, node: &Node, _arg_index: c_int) -> Result<c_int, String> {
+    Ok(push_string(l, &node.text()))
+}
+
+/// The name of the node kind, like "ValDecl"
+unsafe fn property_kind_name(l: *mut c_void, node: &Node, _arg_index: c_int) -> Result<c_int, String> {
+    Ok(push_string(l, &node.kind_name()))
+}
+
+/// The number of children of the node, the absent ones included
+unsafe fn property_children_count(l: *mut c_void, node: &Node, _arg_index: c_int) -> Result<c_int, String> {
+    lua_pushnumber(l, node.children_count() as f64);
+    Ok(1)
+}
+
+/// The child at the 1-based index given as argument, like the list elements, nil if it is absent
+unsafe fn property_child(l: *mut c_void, node: &Node, arg_index: c_int) -> Result<c_int, String> {
+    let count = node.children_count();
+    let index = lua_tonumber(l, arg_index);
+    if lua_type(l, arg_index) != LUA_TNUMBER || index.fract() != 0.0 || index < 1.0 || index > count as f64 {
+        return Err(format!("The child index must be an integer between 1 and {}", count));
+    }
+    Ok(push_node(l, node.child(index as u32 - 1).entity()))
+}
+
+/// The parent of the node, nil for the root
+unsafe fn property_parent(l: *mut c_void, node: &Node, _arg_index: c_int) -> Result<c_int, String> {
+    call_node_accessor(l, node, "parent", lkql_lkql_node_parent)
+}
+
+/// The previous sibling of the node, nil for the first child
+unsafe fn property_previous_sibling(l: *mut c_void, node: &Node, _arg_index: c_int) -> Result<c_int, String> {
+    call_node_accessor(l, node, "previous_sibling", lkql_lkql_node_previous_sibling)
+}
+
+/// The next sibling of the node, nil for the last child
+unsafe fn property_next_sibling(l: *mut c_void, node: &Node, _arg_index: c_int) -> Result<c_int, String> {
+    call_node_accessor(l, node, "next_sibling", lkql_lkql_node_next_sibling)
+}
+
+/// Call the langkit property returning a node and push its result
+unsafe fn call_node_accessor(
+    l: *mut c_void,
+    node: &Node,
+    name: &str,
+    accessor: unsafe extern "C" fn(*mut lkql_base_entity, *mut lkql_base_entity) -> c_int
+) -> Result<c_int, String> {
+    let mut entity = node.entity();
+    let mut result = Node::null().entity();
+    if accessor(&mut entity, &mut result) == 0 {
+        return Err(format!("Failed to evaluate the property {}", name));
+    }
+    Ok(push_node(l, result))
+}
+
+
+// --- Host functions
+
+/// A value exchanged with a host function, the values without Rust equivalent (lists, nodes,
+/// functions...) are given as Other and cannot be returned
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Other,
+}
+
+/// A function of the host program callable from the scripts, it receives the positional
+/// arguments and its error is raised as a Lua error
+pub type HostFunction = Box<dyn Fn(&[HostValue]) -> Result<HostValue, String>>;
+
+/// The C function of all the host functions, the called function is its light userdata upvalue
+unsafe extern "C-unwind" fn lkql_host_call(l: *mut c_void) -> c_int {
+    return_or_raise(l, host_call(l))
+}
+
+/// Call the host function with the arguments and push its result
+/// The arguments and the result are dropped when this returns, before an error is raised
+unsafe fn host_call(l: *mut c_void) -> Result<c_int, String> {
+    let function = &*(lua_touserdata(l, LUA_FIRST_UPVALUE) as *const HostFunction);
+    let args: Vec<HostValue> = (1..=lua_gettop(l)).map(|index| to_host_value(l, index)).collect();
+    let res = match catch_unwind(AssertUnwindSafe(|| function(&args))) {
+        Err(_) => Err(String::from("The host function panicked")),
+        Ok(res) => res
+    };
+    match res {
+        Err(message) => Err(message),
+        Ok(HostValue::Other) => Err(String::from("A host function cannot return a value of another type")),
+        Ok(HostValue::Null) => { lua_pushnil(l); Ok(1) }
+        Ok(HostValue::Bool(value)) => { lua_pushboolean(l, value as c_int); Ok(1) }
+        Ok(HostValue::Number(value)) => { lua_pushnumber(l, value); Ok(1) }
+        Ok(HostValue::String(value)) => Ok(push_string(l, &value))
+    }
+}
+
 
 // --- List for the library definition
 
-const FUNC_NAMES: [&str; 1] = [
-    "print"
+const FUNC_NAMES: [&str; 8] = [
+    PRINT_BUILTIN_NAME,
+    PRINT_NO_NL_BUILTIN_NAME,
+    NODE_PROPERTY_GLOBAL_NAME,
+    IMG_BUILTIN_NAME,
+    DESCENDANTS_GLOBAL_NAME,
+    CHECK_INT_GLOBAL_NAME,
+    ERROR_GLOBAL_NAME,
+    IS_LIST_GLOBAL_NAME
 ];
-const FUNC_REF: [unsafe extern "C" fn(*mut c_void) -> c_int; 1] = [
-    lkql_print
+const FUNC_REF: [LuaCFunction; 8] = [
+    lkql_print,
+    lkql_print_no_nl,
+    lkql_node_property,
+    lkql_img,
+    lkql_descendants,
+    lkql_check_int,
+    lkql_error,
+    lkql_is_list
+];
+
+// --- List of the node properties callable with "node_property"
+
+/// A node property : its name, its number of arguments and the function which calls it with
+/// the node and the stack index of the first argument, and returns the number of pushed results
+struct NodeProperty {
+    name: &'static str,
+    arg_count: c_int,
+    call: unsafe fn(*mut c_void, &Node, c_int) -> Result<c_int, String>,
+}
+
+const NODE_PROPERTIES: [NodeProperty; 7] = [
+    NodeProperty { name: "text", arg_count: 0, call: property_text },
+    NodeProperty { name: "kind_name", arg_count: 0, call: property_kind_name },
+    NodeProperty { name: "children_count", arg_count: 0, call: property_children_count },
+    NodeProperty { name: "child", arg_count: 1, call: property_child },
+    NodeProperty { name: "parent", arg_count: 0, call: property_parent },
+    NodeProperty { name: "previous_sibling", arg_count: 0, call: property_previous_sibling },
+    NodeProperty { name: "next_sibling", arg_count: 0, call: property_next_sibling },
 ];
 
 
 // --- Util functions
 
+/// Start to capture the printed text instead of writing it on the standard output
+pub fn start_print_capture() {
+    PRINT_CAPTURE.with(|capture| *capture.borrow_mut() = Some(String::new()));
+}
+
+/// Stop the capture of the printed text and return it
+pub fn stop_print_capture() -> String {
+    PRINT_CAPTURE.with(|capture| capture.borrow_mut().take().unwrap_or_default())
+}
+
 /// Load the LKQL library in the lua context
 pub unsafe fn lkql_openlib(l: *mut c_void) {
-    // Put the global functions to the lua context
+    lkql_openlib_in(l, LUA_GLOBALSINDEX);
+}
+
+/// Create the sandbox table with the LKQL library and set it as the global that sandboxed
+/// bytecode resolves its globals in, the Lua globals are not reachable from it
+pub unsafe fn lkql_open_sandbox(l: *mut c_void) {
+    lua_createtable(l, 0, (FUNC_NAMES.len() + 2) as c_int);
+    let sandbox_index = lua_gettop(l);
+    lkql_openlib_in(l, sandbox_index);
+
+    // The table loops need the "next" generator
+    let next_name = CString::new(NEXT_GLOBAL_NAME).unwrap();
+    lua_getfield(l, LUA_GLOBALSINDEX, next_name.as_ptr());
+    lua_setfield(l, sandbox_index, next_name.as_ptr());
+
+    let sandbox_name = CString::new(SANDBOX_GLOBAL_NAME).unwrap();
+    lua_setfield(l, LUA_GLOBALSINDEX, sandbox_name.as_ptr());
+}
+
+/// Set the array of the analysed file paths as a global, also in the sandbox table if the
+/// bytecode runs in sandbox mode
+pub unsafe fn lkql_set_files(l: *mut c_void, files: &Vec<String>, sandbox: bool) {
+    // Create the 1-based array of the paths
+    lua_createtable(l, files.len() as c_int, 0);
+    for (i, file) in files.iter().enumerate() {
+        push_string(l, file);
+        lua_rawseti(l, -2, (i + 1) as c_int);
+    }
+
+    set_global(l, FILES_GLOBAL_NAME, sandbox);
+}
+
+/// Set the analysed unit and its root node as globals, also in the sandbox table if the
+/// bytecode runs in sandbox mode
+/// The unit is a userdata with the unit metatable, to tell it from the nodes, and the root node
+/// a userdata like all the nodes
+pub unsafe fn lkql_set_unit(l: *mut c_void, unit: lkql_analysis_unit, sandbox: bool) {
+    let data = lua_newuserdata(l, size_of::<lkql_analysis_unit>()) as *mut lkql_analysis_unit;
+    data.write(unit);
+    let metatable_name = CString::new(UNIT_METATABLE_NAME).unwrap();
+    luaL_newmetatable(l, metatable_name.as_ptr());
+    lua_setmetatable(l, -2);
+    set_global(l, ANALYSIS_UNIT_GLOBAL_NAME, sandbox);
+
+    push_node(l, Node::unit_root(unit).entity());
+    set_global(l, ROOT_GLOBAL_NAME, sandbox);
+}
+
+/// Set the host function as a global with the given name, also in the sandbox table if the
+/// bytecode runs in sandbox mode
+/// The function must live as long as the lua state, only its address is kept
+pub unsafe fn lkql_register_host_function(l: *mut c_void, name: &str, function: *const HostFunction, sandbox: bool) {
+    lua_pushlightuserdata(l, function as *mut c_void);
+    lua_pushcclosure(l, lkql_host_call, 1);
+    set_global(l, name, sandbox);
+}
+
+/// Pop the value at the top of the lua stack and set it as the global with the given name, also
+/// in the sandbox table if the bytecode runs in sandbox mode
+unsafe fn set_global(l: *mut c_void, name: &str, sandbox: bool) {
+    let name_c = CString::new(name).unwrap();
+    if sandbox {
+        let sandbox_name = CString::new(SANDBOX_GLOBAL_NAME).unwrap();
+        lua_getfield(l, LUA_GLOBALSINDEX, sandbox_name.as_ptr());
+        lua_pushvalue(l, -2);
+        lua_setfield(l, -2, name_c.as_ptr());
+        lua_settop(l, -2);
+    }
+    lua_setfield(l, LUA_GLOBALSINDEX, name_c.as_ptr());
+}
+
+/// Put the LKQL library in the table at the given absolute stack index
+unsafe fn lkql_openlib_in(l: *mut c_void, index: c_int) {
+    // Put the global functions in the table
     for i in 0..FUNC_NAMES.len() {
         let name = CString::new(FUNC_NAMES[i]).unwrap();
         lua_pushcclosure(l, FUNC_REF[i], 0);
-        lua_setfield(l, -10002, name.as_ptr());
+        lua_setfield(l, index, name.as_ptr());
+    }
+
+    // Put the builtins written in Lua, the C ones are in the global functions
+    for builtin in &BUILTINS {
+        if let BuiltinImplementation::Lua(source) = builtin.implementation {
+            let name = CString::new(builtin.name).unwrap();
+            if luaL_loadbuffer(l, source.as_ptr() as *const c_char, source.len(), name.as_ptr()) != 0 ||
+               lua_pcall(l, 0, 1, 0) != 0 {
+                let message = CStr::from_ptr(lua_tolstring(l, -1, null_mut())).to_string_lossy().into_owned();
+                panic!("Cannot load the builtin {} : {}", builtin.name, message);
+            }
+            lua_setfield(l, index, name.as_ptr());
+        }
+    }
+
+    // Put the unit value in the table
+    let unit_name = CString::new(UNIT_GLOBAL_NAME).unwrap();
+    lua_pushlightuserdata(l, null_mut());
+    lua_setfield(l, index, unit_name.as_ptr());
+}
+
+/// Get the host value of the lua value at the given stack index
+unsafe fn to_host_value(l: *mut c_void, index: c_int) -> HostValue {
+    match lua_type(l, index) {
+        LUA_TNIL => HostValue::Null,
+        LUA_TBOOLEAN => HostValue::Bool(lua_toboolean(l, index) != 0),
+        LUA_TNUMBER => HostValue::Number(lua_tonumber(l, index)),
+        LUA_TSTRING => HostValue::String(CStr::from_ptr(lua_tolstring(l, index, null_mut())).to_string_lossy().into_owned()),
+        _ => HostValue::Other
     }
-}
\ No newline at end of file
+}
+
+/// Get the node at the given stack index, none if the value is not a node : the nodes are the
+/// userdata without metatable
+unsafe fn to_node(l: *mut c_void, index: c_int) -> Option<Node> {
+    if lua_type(l, index) != LUA_TUSERDATA {
+        return None;
+    }
+    if lua_getmetatable(l, index) != 0 {
+        lua_settop(l, -2);
+        return None;
+    }
+    Some(Node::from_entity(*(lua_touserdata(l, index) as *const lkql_base_entity)))
+}
+
+/// Get if the value at the given stack index is the analysis unit, a userdata with the unit
+/// metatable
+unsafe fn is_unit(l: *mut c_void, index: c_int) -> bool {
+    if lua_type(l, index) != LUA_TUSERDATA || lua_getmetatable(l, index) == 0 {
+        return false;
+    }
+    let metatable_name = CString::new(UNIT_METATABLE_NAME).unwrap();
+    lua_getfield(l, LUA_REGISTRYINDEX, metatable_name.as_ptr());
+    let res = lua_rawequal(l, -1, -2) != 0;
+    lua_settop(l, -3);
+    res
+}
+
+/// Push a langkit node on the lua stack as a userdata, or nil for the null node
+unsafe fn push_node(l: *mut c_void, node: lkql_base_entity) -> c_int {
+    if node.node.is_null() {
+        lua_pushnil(l);
+    } else {
+        let data = lua_newuserdata(l, size_of::<lkql_base_entity>()) as *mut lkql_base_entity;
+        data.write(node);
+    }
+    1
+}
+
+/// Append the node and its descendants to the array at the top of the lua stack
+unsafe fn push_descendants(l: *mut c_void, node: &Node, count: &mut c_int) {
+    push_node(l, node.entity());
+    *count += 1;
+    lua_rawseti(l, -2, *count);
+
+    for child in node.children() {
+        if !child.is_null() {
+            push_descendants(l, &child, count);
+        }
+    }
+}
+
+/// Get the image of a value returned to the host program, as the "img" builtin shows it, none
+/// for nil
+pub unsafe fn lkql_result_image(l: *mut c_void, index: c_int) -> Option<String> {
+    if lua_type(l, index) == LUA_TNIL {
+        None
+    } else {
+        Some(value_image(l, index))
+    }
+}
+
+/// Get the string representation of the value at the given stack index, like the img builtin
+/// The unit value is the light userdata, the nodes are shown with their kind and the analysis
+/// unit is shown as such
+unsafe fn value_image(l: *mut c_void, index: c_int) -> String {
+    match lua_type(l, index) {
+        LUA_TNUMBER | LUA_TSTRING => {
+            // Convert a copy so a number argument is left as it is
+            lua_pushvalue(l, index);
+            let image = CStr::from_ptr(lua_tolstring(l, -1, null_mut())).to_string_lossy().into_owned();
+            lua_settop(l, -2);
+            image
+        }
+        LUA_TBOOLEAN => String::from(if lua_toboolean(l, index) != 0 { "true" } else { "false" }),
+        LUA_TLIGHTUSERDATA => String::from("()"),
+        LUA_TUSERDATA => match to_node(l, index) {
+            Some(node) => format!("<{}>", node.kind_name()),
+            None if is_unit(l, index) => String::from("<analysis unit>"),
+            None => String::from("<value>")
+        },
+        LUA_TTABLE => String::from("<list>"),
+        LUA_TFUNCTION => String::from("<function>"),
+        LUA_TNIL => String::from("null"),
+        _ => String::from("<value>")
+    }
+}
+
+/// Write the printed text on the standard output, or in the capture buffer if it is set
+fn write_output(text: &str) {
+    PRINT_CAPTURE.with(|capture| match capture.borrow_mut().as_mut() {
+        Some(buffer) => buffer.push_str(text),
+        None => {
+            print!("{}", text);
+            let _ = std::io::stdout().flush();
+        }
+    });
+}
+
+/// Push a string on the lua stack
+unsafe fn push_string(l: *mut c_void, string: &str) -> c_int {
+    let string_c = CString::new(string).unwrap_or_default();
+    lua_pushstring(l, string_c.as_ptr());
+    1
+}
+
+/// Return the result count of a library function or raise its error, the function must not
+/// own any value itself when it calls this
+unsafe fn return_or_raise(l: *mut c_void, res: Result<c_int, String>) -> c_int {
+    match res {
+        Ok(count) => count,
+        Err(message) => raise_error(l, message)
+    }
+}
+
+/// Raise a lua error with the given message, this function does not return
+/// The error unwinds the frames of the library functions without dropping their values, so the
+/// message is dropped once it is on the lua stack and the callers must not own any value
+unsafe fn raise_error(l: *mut c_void, message: String) -> c_int {
+    push_string(l, &message);
+    drop(message);
+    lua_error(l)
+}
+
+
+// --- Tests of the LKQL library
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::luajit;
+    use crate::lkqlc::parse_lkql_buffer_root;
+
+    /// Run the Lua source in a fresh lua state where the given node is the "tested" global
+    fn run_with_node(source: &str, node: &Node) -> Result<(), String> {
+        let lua_state = luajit::init_env();
+        unsafe {
+            push_node(lua_state.state, node.entity());
+            set_global(lua_state.state, "tested", false);
+        }
+        let res = luajit::try_run_lua_source(&lua_state, source, "LKQL library");
+        luajit::close_env(&lua_state);
+        res
+    }
+
+    /// Call the node properties on a parsed unit, with and without arguments, a wrong argument
+    /// count is an error
+    #[test]
+    fn node_properties() {
+        let root = parse_lkql_buffer_root("val x = 12\nval y = x", "node properties").unwrap_or_else(|e| panic!("{}", e));
+        let source = r#"
+            assert(node_property(tested, "kind_name") == "TopLevelList")
+            assert(node_property(tested, "children_count") == 2)
+            assert(node_property(tested, "parent") == nil)
+            local decl = node_property(tested, "child", 2)
+            assert(node_property(decl, "text") == "val y = x")
+            assert(node_property(node_property(decl, "previous_sibling"), "text") == "val x = 12")
+            assert(node_property(decl, "next_sibling") == nil)
+            local ok, message = pcall(node_property, tested, "parent", 1)
+            assert(not ok and message:find("expects 0 arguments"))
+            ok, message = pcall(node_property, tested, "child", 3)
+            assert(not ok and message:find("between 1 and 2"))
+            ok, message = pcall(node_property, tested, "unknown")
+            assert(not ok and message:find("Unknown node property"))
+        "#;
+        assert_eq!(run_with_node(source, &root), Ok(()));
+    }
+
+    /// Run the Lua source in a fresh lua state with the LKQL library
+    fn run(source: &str) -> Result<(), String> {
+        let lua_state = luajit::init_env();
+        let res = luajit::try_run_lua_source(&lua_state, source, "LKQL library");
+        luajit::close_env(&lua_state);
+        res
+    }
+
+    /// The integer guard returns the exact integers and raises an error for the other ones, the
+    /// error can be caught
+    #[test]
+    fn check_int() {
+        let source = r#"
+            assert(__lkql_check_int(2^53) == 2^53)
+            assert(__lkql_check_int(-42) == -42)
+            local ok, message = pcall(__lkql_check_int, 2^60)
+            assert(not ok and message:find("Integer overflow"))
+        "#;
+        assert_eq!(run(source), Ok(()));
+        match run("__lkql_check_int(-2^54)") {
+            Err(message) => assert!(message.contains("Integer overflow"), "{}", message),
+            Ok(_) => panic!("The overflow is not an error")
+        }
+    }
+
+    /// The error function raises its message argument, a missing message is an error too
+    #[test]
+    fn runtime_error() {
+        let source = r#"
+            local ok, message = pcall(__lkql_error, "custom message")
+            assert(not ok and message == "custom message")
+            ok, message = pcall(__lkql_error, {})
+            assert(not ok and message == "Runtime error without message")
+        "#;
+        assert_eq!(run(source), Ok(()));
+        assert_eq!(run("__lkql_error(\"stop\")"), Err(String::from("stop")));
+    }
+
+    /// The error of a host function is a Lua error caught by a protected call, as the panic of
+    /// a host function, and the state stays usable after them
+    #[test]
+    fn host_function_error() {
+        let mut lua_state = luajit::init_env();
+        luajit::register_host_function(&mut lua_state, "host_fail", false, |args| match args {
+            [HostValue::String(message)] => Err(message.clone()),
+            _ => Ok(HostValue::Bool(true))
+        });
+        luajit::register_host_function(&mut lua_state, "host_panic", false, |_| panic!("host panic"));
+        let source = r#"
+            local ok, message = pcall(host_fail, "host failure")
+            assert(not ok and message == "host failure")
+            ok, message = pcall(host_panic)
+            assert(not ok and message == "The host function panicked")
+            assert(host_fail() == true)
+        "#;
+        let res = luajit::try_run_lua_source(&lua_state, source, "host functions");
+        luajit::close_env(&lua_state);
+        assert_eq!(res, Ok(()));
+    }
+
+    /// The userdata with a metatable are not nodes, the node functions refuse them
+    #[test]
+    fn foreign_userdata() {
+        let source = r#"
+            local value = newproxy(true)
+            assert(img(value) == "<value>")
+            assert(not pcall(node_property, value, "text"))
+            assert(not pcall(__lkql_descendants, value))
+        "#;
+        assert_eq!(run(source), Ok(()));
+    }
+}
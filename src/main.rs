@@ -1,17 +1,12 @@
 /*
-This module is the root module of the project.
+This module is the root module of the executable, built on the library defined in "lib.rs".
 It contains the entry point of LKQL JIT ("fn main()") and the argument parsing logic
 */
 
-extern crate core;
-
-pub mod luajit;
-pub mod lkql_wrapper;
-pub mod lkqlc;
-pub mod errors;
-
 use std::path::PathBuf;
-use clap::{CommandFactory, ErrorKind, Parser};
+use clap::Parser;
+use clap::builder::PossibleValuesParser;
+use lkql_jit::lkqlc;
 
 
 // --- Defining the arguments structure
@@ -31,8 +26,8 @@ pub struct Cli {
     project_file: Option<PathBuf>,
 
     /// Path of the LKQL script to evaluate
-    #[clap(short = 'S', long = "script-path", value_parser, value_name = "FILE")]
-    script_file: PathBuf,
+    #[clap(short = 'S', long = "script-path", value_parser, value_name = "FILE", required_unless_present_any = ["self-test", "repl"])]
+    script_file: Option<PathBuf>,
 
     /// Files to analyze
     #[clap(value_parser)]
@@ -41,21 +36,112 @@ pub struct Cli {
     /// If the bytecode is showed just before the interpretation
     #[clap(short = 'b', long = "bytecode")]
     show_bc: bool,
+
+    /// Optimization level (0 = none, 1 = safe, 2 = aggressive)
+    #[clap(short = 'O', long = "optimization-level", value_parser = clap::value_parser!(u8).range(0..=2), value_name = "LEVEL", default_value_t = 1)]
+    optimization_level: u8,
+
+    /// Maximum nesting depth of the LKQL script nodes
+    #[clap(long = "max-depth", value_parser, value_name = "DEPTH", default_value_t = lkqlc::env::DEFAULT_MAX_DEPTH)]
+    max_depth: usize,
+
+    /// If the script globals are resolved in a sandbox table instead of the Lua globals
+    #[clap(long = "sandbox")]
+    sandbox: bool,
+
+    /// If the suspicious constructs (like redeclarations) are errors instead of warnings
+    #[clap(long = "strict")]
+    strict: bool,
+
+    /// Format of the compilation diagnostics
+    #[clap(long = "format", value_parser = PossibleValuesParser::new(["text", "json"]), value_name = "FORMAT", default_value = "text")]
+    format: String,
+
+    /// If the bytecode size and the prototype measures are reported after the compilation
+    #[clap(long = "measure")]
+    measure: bool,
+
+    /// Report the unsupported node kinds of the script instead of failing on the first one
+    #[clap(long = "report-unsupported")]
+    report_unsupported: bool,
+
+    /// Integer semantics, "checked" raises an error when an integer result loses precision
+    #[clap(long = "int-semantics", value_parser = PossibleValuesParser::new(["double", "checked"]), value_name = "SEMANTICS", default_value = "double")]
+    int_semantics: String,
+
+    /// Run the query on each file separately and continue after a failing file
+    #[clap(long = "keep-going")]
+    keep_going: bool,
+
+    /// Keep the debug info (local variable names) in the bytecode
+    #[clap(short = 'g', long = "debug-info")]
+    debug_info: bool,
+
+    /// Function of the script called after the top level code, the script result is its result
+    #[clap(long = "entry-point", value_parser, value_name = "NAME")]
+    entry_point: Option<String>,
+
+    /// Print the disassembly of the compiled program with its debug info, then run it and report
+    /// the result
+    #[clap(long = "trace-exec")]
+    trace_exec: bool,
+
+    /// Output of the compilation, "lua" prints the Lua source of the script instead of running it
+    #[clap(long = "emit", value_parser = PossibleValuesParser::new(["bytecode", "lua"]), value_name = "OUTPUT", default_value = "bytecode")]
+    emit: String,
+
+    /// Print the node tree of the parsed script, then exit without compiling it
+    #[clap(long = "dump-ast")]
+    dump_ast: bool,
+
+    /// Compile and run the embedded smoke tests to verify the build, then exit
+    #[clap(long = "self-test")]
+    self_test: bool,
+
+    /// Read LKQL inputs interactively, each one is run in the same session and its last
+    /// expression value is printed
+    #[clap(long = "repl")]
+    repl: bool,
 }
 
 
 // --- Defining the entry point of the application
 
-use lkqlc::bc::NumericConstant;
-use crate::errors::LKQLError;
-use crate::lkqlc::bc::KNum;
+#[cfg(not(feature = "bytecode-only"))]
+use std::collections::BTreeMap;
+#[cfg(not(feature = "bytecode-only"))]
+use clap::{CommandFactory, ErrorKind};
+#[cfg(not(feature = "bytecode-only"))]
+use lkql_jit::{errors, luajit, repl, self_test};
+#[cfg(not(feature = "bytecode-only"))]
+use lkql_jit::errors::{LKQLError, LKQLWarning};
+#[cfg(not(feature = "bytecode-only"))]
+use lkql_jit::lkqlc::bc::Program;
+#[cfg(not(feature = "bytecode-only"))]
+use lkql_jit::lkqlc::env::{CompilationEnv, CompilationOptions};
 
 // The main entry point !
+#[cfg(not(feature = "bytecode-only"))]
 fn main() {
     // Parse the arguments
     let args: Cli = Cli::parse();
     let mut cmd = Cli::command();
 
+    // Run the smoke tests without script nor files
+    if args.self_test {
+        let mut options = CompilationOptions::new();
+        options.optimization_level = args.optimization_level;
+        let failure_count = self_test::run(&options);
+        std::process::exit(if failure_count > 0 { 1 } else { 0 });
+    }
+
+    // Explore LKQL interactively without script nor files
+    if args.repl {
+        repl::run(&compilation_options(&args));
+        return;
+    }
+    let script_file = args.script_file.clone().unwrap();
+
     // Verify that there is at least a project file or one file to analyse
     if args.files.len() == 0 && (args.project_file.as_ref().is_none()) {
         cmd.error(
@@ -73,23 +159,309 @@ fn main() {
     }
 
     // Verify that the script file is a valid file
-    if !args.script_file.is_file() {
+    if !script_file.is_file() {
         cmd.error(
             ErrorKind::Io,
             "Provided script file not found",
         ).exit();
     }
 
+    // Verify that the script file is an LKQL script and not one of the files to analyse
+    match check_script_extension(&script_file) {
+        Err(message) => {
+            cmd.error(
+                ErrorKind::InvalidValue,
+                message,
+            ).exit();
+        }
+        Ok(Some(warning)) => eprintln!("warning: {}", warning),
+        Ok(None) => ()
+    }
+
+    // Show the parsed script to separate the parsing issues from the compilation ones
+    if args.dump_ast {
+        match lkqlc::dump_lkql_file_ast(&script_file, &args.charset) {
+            Err(e) => {
+                report_diagnostics(&args, &Vec::new(), Some(&e));
+                std::process::exit(1);
+            }
+            Ok(dump) => print!("{}", dump)
+        }
+        return;
+    }
+
+    // Show the Lua equivalent of the script, to inspect it or run it on another Lua
+    if args.emit == "lua" {
+        match lkqlc::emit_lua_file(&script_file, &args.charset) {
+            Err(e) => {
+                report_diagnostics(&args, &Vec::new(), Some(&e));
+                std::process::exit(1);
+            }
+            Ok(source) => print!("{}", source)
+        }
+        return;
+    }
+
     // Get the LuaJIT bytecode for the lkql script
-    match lkqlc::compile_lkql_file(&args.script_file, &args.charset) {
-        Err(e) => {
-            eprintln!("{}", e.message);
+    let mut env = CompilationEnv::new(compilation_options(&args));
+    let compilation_res = lkqlc::compile_lkql_file_with_env(&script_file, &args.charset, &mut env);
+    report_diagnostics(&args, env.get_warnings(), compilation_res.as_ref().err());
+    if args.report_unsupported {
+        report_unsupported(env.get_unsupported_nodes());
+        return;
+    }
+    if let Ok(bytecode) = compilation_res {
+        if args.show_bc {
+            println!("GENERATED BYTECODE : \n{:X?}", bytecode)
         }
-        Ok(bytecode) => {
-            if args.show_bc {
-                println!("GENERATED BYTECODE : \n{:X?}", bytecode)
+        if args.measure {
+            report_measures(env.get_program(), &bytecode);
+        }
+        if args.trace_exec {
+            print!("{}", env.get_program().disassemble());
+        }
+
+        // Run the bytecode with the files to analyse, one by one in keep going mode
+        if args.keep_going {
+            let failures = run_each_file(&args, &bytecode);
+            for (file, message) in &failures {
+                eprintln!("{}: error: {}", file.to_string_lossy(), message);
             }
-            // TODO : Start the LuaJIT with the generated bytecode
+            if !failures.is_empty() {
+                eprintln!("The analysis failed on {} of {} files", failures.len(), args.files.len());
+                std::process::exit(1);
+            }
+        } else {
+            match run_query(&args, &bytecode, &args.files) {
+                Err(message) => {
+                    if args.trace_exec {
+                        println!("-- EXECUTION FAILED");
+                    }
+                    eprintln!("error: {}", message);
+                    std::process::exit(1);
+                }
+                Ok(_) => ()
+            }
+        }
+        if args.trace_exec {
+            println!("-- EXECUTION SUCCEEDED");
+        }
+    }
+}
+
+// The entry point without langkit, only the bytecode layer is built
+#[cfg(feature = "bytecode-only")]
+fn main() {
+    eprintln!("LKQL JIT is built with the bytecode-only feature, LKQL scripts cannot be compiled");
+    std::process::exit(1);
+}
+
+
+// --- Util functions
+
+/// Get the compilation options from the arguments
+#[cfg(not(feature = "bytecode-only"))]
+fn compilation_options(args: &Cli) -> CompilationOptions {
+    let mut options = CompilationOptions::new();
+    options.optimization_level = args.optimization_level;
+    options.max_depth = args.max_depth;
+    options.sandbox = args.sandbox;
+    options.strict = args.strict;
+    options.report_unsupported = args.report_unsupported;
+    options.checked_int = args.int_semantics == "checked";
+    options.debug_info = args.debug_info || args.trace_exec;
+    options.entry_point = args.entry_point.clone();
+    options
+}
+
+/// Run the query bytecode on the given files in a fresh lua state, so a failing run doesn't
+/// leave anything to the next one
+#[cfg(not(feature = "bytecode-only"))]
+fn run_query(args: &Cli, bytecode: &Vec<u8>, files: &Vec<PathBuf>) -> Result<(), String> {
+    let mut lua_state = luajit::init_env();
+    if args.sandbox {
+        luajit::set_global_sandbox(&lua_state);
+    }
+    luajit::set_global_files(&lua_state, files, args.sandbox);
+    // The query analyses one unit, the one of the first file
+    if let Some(file) = files.first() {
+        if let Err(message) = luajit::set_global_unit(&mut lua_state, file, &args.charset, args.sandbox) {
+            luajit::close_env(&lua_state);
+            return Err(message);
+        }
+    }
+    let res = luajit::try_run_lua_bytecode(&lua_state, bytecode, &lkqlc::chunk_name(args.script_file.as_ref().unwrap()));
+    luajit::close_env(&lua_state);
+    res
+}
+
+/// Run the query bytecode on each file separately, a failing file doesn't stop the others
+/// Return the failing files with their error message
+#[cfg(not(feature = "bytecode-only"))]
+fn run_each_file(args: &Cli, bytecode: &Vec<u8>) -> Vec<(PathBuf, String)> {
+    let mut failures = Vec::new();
+    for file in &args.files {
+        match run_query(args, bytecode, &vec![file.clone()]) {
+            Err(message) => failures.push((file.clone(), message)),
+            Ok(_) => ()
         }
     }
+    failures
+}
+
+/// Check the extension of the script file, an Ada source is an error since it is likely one of
+/// the files to analyse, and another extension than ".lkql" is a warning
+#[cfg(not(feature = "bytecode-only"))]
+fn check_script_extension(script_file: &PathBuf) -> Result<Option<&'static str>, &'static str> {
+    match script_file.extension().and_then(|ext| ext.to_str()) {
+        Some("lkql") => Ok(None),
+        Some("adb") | Some("ads") | Some("ada") | Some("gpr") => Err(
+            "Provided script file is an Ada source, give the LKQL script with -S and the files to analyse as positional arguments"
+        ),
+        _ => Ok(Some("the script file doesn't have the .lkql extension, it may not be an LKQL script"))
+    }
+}
+
+/// Report the compilation diagnostics in the wanted format, the JSON is on the standard output
+/// for the tools and the text on the standard error
+#[cfg(not(feature = "bytecode-only"))]
+fn report_diagnostics(args: &Cli, warnings: &Vec<LKQLWarning>, error: Option<&LKQLError>) {
+    if args.format == "json" {
+        println!("{}", errors::diagnostics_to_json(&args.script_file.as_ref().unwrap().to_string_lossy(), warnings, error));
+        return;
+    }
+
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+    if error.is_some() {
+        eprintln!("{}", error.unwrap());
+    }
+}
+
+/// Report the unsupported node kinds with their number of occurrences on the standard output
+#[cfg(not(feature = "bytecode-only"))]
+fn report_unsupported(unsupported_nodes: &BTreeMap<String, usize>) {
+    if unsupported_nodes.is_empty() {
+        println!("All the script nodes are supported");
+        return;
+    }
+    println!("{:>30} | {:>5}", "UNSUPPORTED NODE KIND", "COUNT");
+    for (kind_name, count) in unsupported_nodes {
+        println!("{:>30} | {:>5}", kind_name, count);
+    }
+}
+
+/// Report the measures of the compiled program on the standard error
+#[cfg(not(feature = "bytecode-only"))]
+fn report_measures(program: &Program, bytecode: &Vec<u8>) {
+    eprint!("{}", measures_table(program, bytecode));
+}
+
+/// Get the table of the compiled program measures, one line per prototype after the totals
+#[cfg(not(feature = "bytecode-only"))]
+fn measures_table(program: &Program, bytecode: &Vec<u8>) -> String {
+    let mut res = format!("Total bytecode size : {} bytes\n", bytecode.len());
+    res.push_str(&format!("Strings duplicated across prototypes : {} bytes\n", program.duplicated_string_size()));
+    res.push_str(&format!("{:>5} | {:>12} | {:>10} | {:>8} | {:>15} | {:>15} | {:>10}\n",
+                          "PROTO", "INSTRUCTIONS", "FRAME SIZE", "UPVALUES", "COMPLEX CONSTS", "NUMERIC CONSTS", "BYTES"));
+    for (i, proto) in program.prototypes.iter().enumerate() {
+        res.push_str(&format!("{:>5} | {:>12} | {:>10} | {:>8} | {:>15} | {:>15} | {:>10}\n",
+                              i,
+                              proto.instruction_count(),
+                              proto.required_frame_size(),
+                              proto.upval_references.len(),
+                              proto.complex_constants.len(),
+                              proto.numeric_constants.len(),
+                              proto.encode(program.header.is_stripped()).len()));
+    }
+    res
+}
+
+
+// --- Tests of the executable
+
+#[cfg(all(test, not(feature = "bytecode-only")))]
+mod tests {
+    use super::*;
+    use lkqlc::bc::{KSHORT, RET0};
+    use lkqlc::builder::ProgramBuilder;
+
+    /// The reported total is the size of the bytecode and each prototype has its line
+    #[test]
+    fn measures_bytecode_size() {
+        let mut builder = ProgramBuilder::new();
+        builder
+            .set_frame_size(1)
+            .emit_ad(KSHORT, 0, 1)
+            .emit_ad(RET0, 0, 1);
+        let program = builder.finish().unwrap_or_else(|e| panic!("{}", e));
+        let bytecode = program.encode();
+
+        let table = measures_table(&program, &bytecode);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], format!("Total bytecode size : {} bytes", bytecode.len()));
+        assert_eq!(lines.len(), 3 + program.prototypes.len(), "{}", table);
+    }
+
+    /// An Ada source given as the script is an error telling how to pass the files, another
+    /// extension is only a warning
+    #[test]
+    fn script_extension() {
+        assert_eq!(check_script_extension(&PathBuf::from("rules/check.lkql")), Ok(None));
+        for file in ["src/main.adb", "src/main.ads", "prj.gpr"] {
+            let message = check_script_extension(&PathBuf::from(file)).unwrap_err();
+            assert!(message.contains("Ada source") && message.contains("-S"), "{}", message);
+        }
+        assert!(matches!(check_script_extension(&PathBuf::from("check.txt")), Ok(Some(_))));
+        assert!(matches!(check_script_extension(&PathBuf::from("check")), Ok(Some(_))));
+    }
+
+    /// In keep going mode a failing file is reported and the next ones still run in a fresh
+    /// lua state
+    #[test]
+    fn keep_going() {
+        let dir = std::env::temp_dir();
+        let (broken, good) = (dir.join("lkql_jit_keep_going_broken.lkql"), dir.join("lkql_jit_keep_going_good.lkql"));
+        std::fs::write(&broken, "1 + 2").unwrap();
+        std::fs::write(&good, "val x = 1").unwrap();
+        let args = Cli::parse_from([
+            "lkql_jit", "-S", "check.lkql", "--keep-going",
+            &broken.to_string_lossy(), &good.to_string_lossy(), &broken.to_string_lossy()
+        ]);
+        let mut options = CompilationOptions::new();
+        options.host_globals.push(String::from("assert"));
+        let bytecode = lkqlc::compile_lkql_buffer(
+            "assert(node_property(node_property(root, \"child\", 1), \"kind_name\") == \"ValDecl\")",
+            "check.lkql",
+            &options
+        ).unwrap_or_else(|e| panic!("{}", e));
+
+        let failures = run_each_file(&args, &bytecode);
+        let _ = std::fs::remove_file(&broken);
+        let _ = std::fs::remove_file(&good);
+        let failed_files: Vec<&PathBuf> = failures.iter().map(|(file, _)| file).collect();
+        assert_eq!(failed_files, vec![&broken, &broken]);
+    }
+
+    /// The trace mode compiles with the debug info, so the disassembly is annotated with the
+    /// locals, then runs the script for its result
+    #[test]
+    fn trace_exec() {
+        let file = std::env::temp_dir().join("lkql_jit_trace_exec.lkql");
+        std::fs::write(&file, "val x = 1").unwrap();
+        let args = Cli::parse_from(["lkql_jit", "-S", "check.lkql", "--trace-exec", &file.to_string_lossy()]);
+        let mut options = compilation_options(&args);
+        assert!(options.debug_info);
+        options.host_globals.push(String::from("assert"));
+        let mut env = CompilationEnv::new(options);
+        let source = "fun incr(y) = y + 1\nassert(incr(1) == 2)";
+        let bytecode = lkqlc::compile_lkql_buffer_with_env(source, "check.lkql", &mut env).unwrap_or_else(|e| panic!("{}", e));
+
+        let disassembly = env.get_program().disassemble();
+        assert!(disassembly.contains("local y") && disassembly.contains("RET0"), "{}", disassembly);
+        let res = run_query(&args, &bytecode, &args.files);
+        let _ = std::fs::remove_file(&file);
+        assert_eq!(res, Ok(()));
+    }
 }
@@ -0,0 +1,141 @@
+/*
+This module contains the interactive mode of "--repl"
+Each input is compiled as its own chunk and run in the same lua state, so the globals declared
+by an input (top level values and functions) are readable by the next ones
+*/
+
+use std::collections::HashSet;
+use std::io::Write;
+use crate::errors::LKQLWarning;
+use crate::lkqlc::compile_lkql_buffer_with_env;
+use crate::lkqlc::env::{CompilationEnv, CompilationOptions};
+use crate::luajit;
+use crate::luajit::LuaState;
+
+
+// --- The REPL session
+
+// The prompt shown before each input
+const PROMPT: &str = "> ";
+
+pub struct ReplSession {
+    lua_state: LuaState,
+    env: CompilationEnv,
+    initial_globals: HashSet<String>, // The globals of a fresh environment, like the builtins
+    input_count: usize,
+}
+
+impl ReplSession {
+    /// Open a session with a fresh lua state, the chunks return their last expression
+    pub fn new(options: &CompilationOptions) -> ReplSession {
+        let mut options = options.clone();
+        options.return_last_expr = true;
+        let lua_state = luajit::init_env();
+        if options.sandbox {
+            luajit::set_global_sandbox(&lua_state);
+        }
+        let env = CompilationEnv::new(options);
+        let initial_globals = env.get_globals().clone();
+        ReplSession {
+            lua_state,
+            env,
+            initial_globals,
+            input_count: 0
+        }
+    }
+
+    /// Compile and run the input, return the image of its last expression value if any
+    /// A compilation or runtime error is returned and the session stays usable
+    pub fn eval(&mut self, input: &str) -> Result<Option<String>, String> {
+        self.input_count += 1;
+        let name = format!("repl:{}", self.input_count);
+        let bytecode = match compile_lkql_buffer_with_env(input, &name, &mut self.env) {
+            Err(e) => { return Err(e.to_string()); }
+            Ok(bytecode) => bytecode
+        };
+
+        // Keep the globals of the input declared for the next ones, the environment is reset
+        // before each compilation. They are kept even if the run fails, the ones set before the
+        // failure are in the lua state
+        let declared: Vec<String> = self.env.get_globals().iter()
+            .filter(|name| !self.initial_globals.contains(*name))
+            .cloned()
+            .collect();
+        for name in declared {
+            self.env.add_host_global(name);
+        }
+
+        luajit::try_eval_lua_bytecode(&self.lua_state, &bytecode, &name)
+    }
+
+    /// Get the warnings of the last compiled input
+    pub fn get_warnings(&self) -> &Vec<LKQLWarning> {
+        self.env.get_warnings()
+    }
+
+    /// Close the session and its lua state
+    pub fn close(self) {
+        luajit::close_env(&self.lua_state);
+    }
+}
+
+
+// --- The interactive loop
+
+/// Read the inputs line by line on the standard input until its end, and print the results
+pub fn run(options: &CompilationOptions) {
+    let mut session = ReplSession::new(options);
+    let stdin = std::io::stdin();
+    loop {
+        print!("{}", PROMPT);
+        let _ = std::io::stdout().flush();
+        let mut line = String::new();
+        match stdin.read_line(&mut line) {
+            Err(_) | Ok(0) => break,
+            Ok(_) => {}
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let res = session.eval(&line);
+        for warning in session.get_warnings() {
+            eprintln!("{}", warning);
+        }
+        match res {
+            Err(message) => eprintln!("error: {}", message),
+            Ok(Some(image)) => println!("{}", image),
+            Ok(None) => ()
+        }
+    }
+    println!();
+    session.close();
+}
+
+
+// --- Tests of the REPL session
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lkqlc::test_utils::test_options;
+
+    /// An input reads the global declared by a previous one, a failing input in between doesn't
+    /// end the session
+    #[test]
+    fn session_keeps_globals() {
+        let mut session = ReplSession::new(&test_options());
+        let inputs = [
+            ("val answer = 40 + 2", Ok(None)),
+            ("unknown_symbol", Err(())),
+            ("answer", Ok(Some(String::from("42")))),
+            ("fun twice(x) = x * 2\ntwice(answer)", Ok(Some(String::from("84"))))
+        ];
+        let results: Vec<Result<Option<String>, ()>> = inputs.iter()
+            .map(|(input, _)| session.eval(input).map_err(|_| ()))
+            .collect();
+        session.close();
+        let expected: Vec<Result<Option<String>, ()>> = inputs.into_iter().map(|(_, expected)| expected).collect();
+        assert_eq!(results, expected);
+    }
+}
@@ -0,0 +1,304 @@
+/*
+This module contains the embedded smoke tests of the "--self-test" mode
+Each case is an LKQL snippet compiled and run through LuaJIT, the snippets verify their own
+results with the Lua "assert" function so a wrong result is a runtime error. This checks that
+the linked LuaJIT and langkit libraries work with the produced bytecode
+The checks of the compiler itself are the unit tests next to the code they test
+*/
+
+use crate::lkqlc::bc::{CALL, GGET, KSTR, RET0};
+use crate::lkqlc::builder::ProgramBuilder;
+use crate::lkqlc::{chunk_name, compile_lkql_buffer, compile_lkql_buffer_with_env, compile_lkql_file, emit_lua_buffer};
+use crate::lkqlc::env::{CompilationEnv, CompilationOptions};
+use crate::luajit;
+use crate::luajit::HostValue;
+
+
+// --- The smoke test cases
+
+// The Lua function the snippets use to verify their results
+const ASSERT_GLOBAL_NAME: &str = "assert";
+
+// The frame size of the program built without LKQL source, near the maximum of 250 slots
+const WIDE_FRAME_SIZE: u8 = 250;
+
+struct SelfTestCase {
+    name: &'static str,
+    source: &'static str,
+    expect_failure: bool, // If the run must fail, to verify that a failing assertion is seen
+}
+
+const CASES: [SelfTestCase; 14] = [
+    SelfTestCase {
+        name: "arithmetic",
+        source: "assert(1 + 2 * 3 == 7)\nassert(10 / 4 == 2.5)\nval x = 2\nval y = 7\nassert(5 - x == 3)\nassert(x - 5 == -3)\nassert(x - y == -5)\nassert(10 / x == 5)\nassert(x / 4 == 0.5)",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "functions",
+        source: "fun double(x) = x * 2\nassert(double(21) == 42)",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "multiple parameters",
+        source: "fun mix(a, b, c) = a * 100 + b * 10 + c\nassert(mix(1, 2, 3) == 123)\nfun last(a, b, c) = c\nassert(last(1, 2, 3) == 3)\nval sub = (x, y, z) => x - y - z\nassert(sub(10, 3, 2) == 5)",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "default parameters",
+        source: "fun add(x, y=10) = x + y\nassert(add(1) == 11)\nassert(add(1, 2) == 3)\nfun flag(b=true) = b\nassert(flag(false) == false)\nassert(flag() == true)",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "lists",
+        source: "val l = [1, 2, 4]\nassert(4 in l)\nassert(3 not in l)\nval b = [true, false]\nassert(false in b)",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "strings",
+        source: "assert(\"lkql\" & \"_jit\" == \"lkql_jit\")\nval x = \"c\"\nassert(\"a\" & \"b\" & x == \"abc\")",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "boolean operators",
+        source: "val t = true\nval f = false\nassert((t and f) == false)\nassert((t and t) == true)\nassert((f or t) == true)\nassert((f or f) == false)\nassert((1 < 2 and 3 > 2) == true)\nassert(((f and t) or t) == true)",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "block scopes",
+        source: "val x = 1\nval y = { val x = 2; x + 1 }\nassert(y == 3)\nassert(x == 1)",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "blocks ending with a declaration",
+        source: "val y = { val x = 1 }\nassert(y == ())\nassert(y != null)\n{ val z = 2 }",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "conditionals",
+        source: "fun sign(x) = if x < 0 then -1 else 1\nassert(sign(-3) == -1)\nif sign(3) == -1 then assert(false)\nassert((if false then 1) == null)",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "statement conditionals",
+        source: "val x = 3\nif x > 2 then print(\"then\") else print(\"else\")\nval y = { if x < 2 then print(\"then\") else print(\"else\"); x }\nassert(y == 3)",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "interpolation",
+        source: "val name = \"lkql\"\nassert(\"hello \\{name}, \\{1 + 2}\" == \"hello lkql, 3\")",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "builtins",
+        source: "assert(img(12) == \"12\")\nval l = map([1, 2, 3], (x) => x * 2)\nassert(length(l) == 3)\nassert(6 in l)\nval odd = filter([1, 2, 3], (x) => x != 2)\nassert(length(odd) == 2)\nassert(2 not in odd)",
+        expect_failure: false
+    },
+    SelfTestCase {
+        name: "failing assertion",
+        source: "assert(1 == 2)",
+        expect_failure: true
+    }
+];
+
+
+// --- The test harness
+
+// The checks of the linked libraries which are not LKQL snippets, with their names
+// The compiler checks are the unit tests of the modules, they don't need the linked libraries
+const CHECKS: [(&str, fn(&CompilationOptions) -> Result<(), String>); 5] = [
+    ("program builder", run_builder_case),
+    ("chunk name", run_chunk_name_case),
+    ("print variants", run_print_case),
+    ("Lua source output", run_lua_emit_case),
+    ("host functions", run_host_function_case),
+];
+
+/// Run all the smoke tests with the given compilation options, report each result on the
+/// standard output and return the number of failed cases
+pub fn run(options: &CompilationOptions) -> usize {
+    let mut options = options.clone();
+    options.sandbox = false;
+    options.host_globals.push(String::from(ASSERT_GLOBAL_NAME));
+
+    let results = CASES.iter()
+        .map(|case| (case.name, run_case(case, &options)))
+        .chain(CHECKS.iter().map(|(name, check)| (*name, check(&options))));
+    let mut case_count = 0;
+    let mut failure_count = 0;
+    for (name, res) in results {
+        case_count += 1;
+        match res {
+            Err(message) => {
+                println!("FAIL {} : {}", name, message);
+                failure_count += 1;
+            }
+            Ok(_) => println!("PASS {}", name)
+        }
+    }
+    println!("{} passed, {} failed", case_count - failure_count, failure_count);
+    failure_count
+}
+
+/// Compile and run a smoke test case in a fresh lua state, the case is compiled twice to
+/// verify that the bytecode is reproducible
+fn run_case(case: &SelfTestCase, options: &CompilationOptions) -> Result<(), String> {
+    let bytecode = match compile_lkql_buffer(case.source, case.name, options) {
+        Err(e) => { return Err(format!("compilation error : {}", e)); }
+        Ok(bytecode) => bytecode
+    };
+    match compile_lkql_buffer(case.source, case.name, options) {
+        Ok(other_bytecode) if other_bytecode == bytecode => (),
+        _ => { return Err(String::from("two compilations give different bytecodes")); }
+    }
+
+    let lua_state = luajit::init_env();
+    let res = luajit::try_run_lua_bytecode(&lua_state, &bytecode, case.name);
+    luajit::close_env(&lua_state);
+
+    match (res, case.expect_failure) {
+        (Ok(_), false) | (Err(_), true) => Ok(()),
+        (Ok(_), true) => Err(String::from("the run was expected to fail")),
+        (Err(message), false) => Err(format!("runtime error : {}", message))
+    }
+}
+
+/// Build a "hello world" program without LKQL source and run it, this checks the bytecode
+/// layer alone
+/// The call is made in the last slots of a wide frame, the VM grows the lua stack for it when
+/// it enters the chunk
+fn run_builder_case(_options: &CompilationOptions) -> Result<(), String> {
+    // The program is : assert("hello world")
+    let mut builder = ProgramBuilder::new();
+    let assert_index = builder.add_string(ASSERT_GLOBAL_NAME);
+    let hello_index = builder.add_string("hello world");
+    builder
+        .set_frame_size(WIDE_FRAME_SIZE)
+        .emit_ad(GGET, WIDE_FRAME_SIZE - 2, assert_index)
+        .emit_ad(KSTR, WIDE_FRAME_SIZE - 1, hello_index)
+        .emit_abc(CALL, WIDE_FRAME_SIZE - 2, 1, 2)
+        .emit_ad(RET0, 0, 1);
+    let bytecode = match builder.finish() {
+        Err(e) => { return Err(format!("build error : {}", e)); }
+        Ok(program) => program.encode()
+    };
+
+    let lua_state = luajit::init_env();
+    let res = luajit::try_run_lua_bytecode(&lua_state, &bytecode, "program builder");
+    luajit::close_env(&lua_state);
+    res.map_err(|message| format!("runtime error : {}", message))
+}
+
+/// Run a failing script from a file, with and without debug info, and verify that the runtime
+/// error shows the script file
+fn run_chunk_name_case(options: &CompilationOptions) -> Result<(), String> {
+    let script = std::env::temp_dir().join("lkql_jit_self_test.lkql");
+    if let Err(e) = std::fs::write(&script, "fun inc(x) = x + 1\nval y = inc(null)") {
+        return Err(format!("cannot write the script : {}", e));
+    }
+    let res = check_chunk_name(&script, options);
+    let _ = std::fs::remove_file(&script);
+    res
+}
+
+/// Compile and run the failing script, the error must name its file
+fn check_chunk_name(script: &std::path::Path, options: &CompilationOptions) -> Result<(), String> {
+    let file_name = script.file_name().unwrap().to_string_lossy().into_owned();
+    for debug_info in [false, true].iter() {
+        let mut options = options.clone();
+        options.debug_info = *debug_info;
+        let bytecode = match compile_lkql_file(&script.to_path_buf(), &None, &options) {
+            Err(e) => { return Err(format!("compilation error : {}", e)); }
+            Ok(bytecode) => bytecode
+        };
+
+        let lua_state = luajit::init_env();
+        let res = luajit::try_run_lua_bytecode(&lua_state, &bytecode, &chunk_name(script));
+        luajit::close_env(&lua_state);
+        match res {
+            Ok(_) => { return Err(String::from("the run was expected to fail")); }
+            Err(message) if !message.contains(&file_name) => {
+                return Err(format!("the error doesn't show the script file : {}", message));
+            }
+            Err(_) => ()
+        }
+    }
+    Ok(())
+}
+
+/// Run a script printing with and without new line and verify the captured output
+fn run_print_case(options: &CompilationOptions) -> Result<(), String> {
+    let source = "print_no_nl(\"a\")\nprint_no_nl(1 + 1)\nprint(\"b\")\nprint(true)";
+    let bytecode = match compile_lkql_buffer(source, "print variants", options) {
+        Err(e) => { return Err(format!("compilation error : {}", e)); }
+        Ok(bytecode) => bytecode
+    };
+
+    let lua_state = luajit::init_env();
+    luajit::start_print_capture();
+    let res = luajit::try_run_lua_bytecode(&lua_state, &bytecode, "print variants");
+    let output = luajit::stop_print_capture();
+    luajit::close_env(&lua_state);
+
+    match res {
+        Err(message) => Err(format!("runtime error : {}", message)),
+        Ok(_) if output == "a2b\ntrue\n" => Ok(()),
+        Ok(_) => Err(format!("unexpected output : {:?}", output))
+    }
+}
+
+/// Emit the Lua source of a small script and run it, it must print like the bytecode
+fn run_lua_emit_case(_options: &CompilationOptions) -> Result<(), String> {
+    let source = match emit_lua_buffer("print(\"hi\")", "Lua source output") {
+        Err(e) => { return Err(format!("emission error : {}", e)); }
+        Ok(source) => source
+    };
+
+    let lua_state = luajit::init_env();
+    luajit::start_print_capture();
+    let res = luajit::try_run_lua_source(&lua_state, &source, "Lua source output");
+    let output = luajit::stop_print_capture();
+    luajit::close_env(&lua_state);
+
+    match res {
+        Err(message) => Err(format!("runtime error : {}\n{}", message, source)),
+        Ok(_) if output == "hi\n" => Ok(()),
+        Ok(_) => Err(format!("unexpected output : {:?}", output))
+    }
+}
+
+/// Register Rust functions in the lua state and call them from a script, the error of a host
+/// function is a runtime error of the script
+fn run_host_function_case(options: &CompilationOptions) -> Result<(), String> {
+    let source = "assert(host_scale(\"ab\", 3) == 6)\nassert(host_scale(null, 3) == null)";
+    let mut env = CompilationEnv::new(options.clone());
+    env.add_host_global(String::from("host_scale"));
+    env.add_host_global(String::from("host_fail"));
+    let bytecode = match compile_lkql_buffer_with_env(source, "host functions", &mut env) {
+        Err(e) => { return Err(format!("compilation error : {}", e)); }
+        Ok(bytecode) => bytecode
+    };
+    let failing_bytecode = match compile_lkql_buffer_with_env("host_fail()", "host functions", &mut env) {
+        Err(e) => { return Err(format!("compilation error : {}", e)); }
+        Ok(bytecode) => bytecode
+    };
+
+    // The length of the string argument multiplied by the number one
+    let mut lua_state = luajit::init_env();
+    luajit::register_host_function(&mut lua_state, "host_scale", false, |args| match args {
+        [HostValue::String(text), HostValue::Number(factor)] => Ok(HostValue::Number(text.len() as f64 * factor)),
+        [HostValue::Null, _] => Ok(HostValue::Null),
+        _ => Err(String::from("host_scale expects a string and a number"))
+    });
+    luajit::register_host_function(&mut lua_state, "host_fail", false, |_| Err(String::from("host failure")));
+    let res = luajit::try_run_lua_bytecode(&lua_state, &bytecode, "host functions");
+    let failing_res = luajit::try_run_lua_bytecode(&lua_state, &failing_bytecode, "host functions");
+    luajit::close_env(&lua_state);
+
+    match (res, failing_res) {
+        (Err(message), _) => Err(format!("runtime error : {}", message)),
+        (Ok(_), Ok(_)) => Err(String::from("the error of the host function is not raised")),
+        (Ok(_), Err(message)) if message.contains("host failure") => Ok(()),
+        (Ok(_), Err(message)) => Err(format!("unexpected error message : {}", message))
+    }
+}
@@ -0,0 +1,61 @@
+/*
+This module contains the tracing facility of LKQL JIT, used to log the compiler decisions
+Traces are written on the standard error only when the LKQL_JIT_TRACE environment variable is set
+*/
+
+use std::env;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+
+// --- The tracing state
+
+pub const TRACE_ENV_VAR: &str = "LKQL_JIT_TRACE";
+
+const TRACE_UNKNOWN: u8 = 0;
+const TRACE_DISABLED: u8 = 1;
+const TRACE_ENABLED: u8 = 2;
+
+static TRACE_STATE: AtomicU8 = AtomicU8::new(TRACE_UNKNOWN);
+
+
+// --- Tracing functions
+
+/// Get if the tracing is enabled, the environment is only read once
+pub fn trace_enabled() -> bool {
+    let mut state = TRACE_STATE.load(Ordering::Relaxed);
+    if state == TRACE_UNKNOWN {
+        state = if env::var_os(TRACE_ENV_VAR).is_some() { TRACE_ENABLED } else { TRACE_DISABLED };
+        TRACE_STATE.store(state, Ordering::Relaxed);
+    }
+    state == TRACE_ENABLED
+}
+
+/// Log a compiler decision, the message is only formatted when the tracing is enabled
+#[macro_export]
+macro_rules! lkql_trace {
+    ($($arg:tt)*) => {
+        if $crate::trace::trace_enabled() {
+            eprintln!("[LKQL TRACE] {}", format!($($arg)*));
+        }
+    };
+}
+
+
+// --- Tests of the tracing
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Without the environment variable nothing is traced, the message is not even formatted
+    #[test]
+    fn silent_by_default() {
+        if env::var_os(TRACE_ENV_VAR).is_some() {
+            return;
+        }
+        assert!(!trace_enabled());
+        let mut is_formatted = false;
+        crate::lkql_trace!("{}", { is_formatted = true; "decision" });
+        assert!(!is_formatted);
+    }
+}